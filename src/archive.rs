@@ -0,0 +1,2762 @@
+//! Operations on an existing archive
+
+use rusqlite::types::ToSql;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use crate::{
+    Error, JOURNAL, KeepNamespaces, Ref, RefName, SharedStore, audit,
+    config_problems, db, decompress, git, journal_committed, keep_namespaces,
+    keep_object, lock, recorded_keep_namespaces, refs_at, replay_journal,
+    sync_store, upstream_gone, write_journal_file,
+};
+
+/// Find the SHA-1 a branch pointed to, either at a given date or the last
+/// position it was seen at
+fn find_branch(
+    repository: &Path,
+    branch: &str,
+    date: Option<&str>,
+) -> Result<String, Error> {
+    let db = db::open(repository)?;
+    let res = if let Some(date) = date {
+        db.query_row(
+            "
+            SELECT sha FROM refs
+            WHERE name=? AND tag=0
+                AND from_date <= ?
+                AND (to_date IS NULL OR to_date > ?);
+            ",
+            &[branch, date, date],
+            |row| row.get(0),
+        )
+    } else {
+        db.query_row(
+            "
+            SELECT sha FROM refs
+            WHERE name=? AND tag=0
+            ORDER BY from_date DESC
+            LIMIT 1;
+            ",
+            &[&branch as &dyn ToSql],
+            |row| row.get(0),
+        )
+    };
+    match res {
+        Ok(sha) => Ok(sha),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(Error::NotFound(match date {
+                Some(date) => format!("branch {} at {}", branch, date),
+                None => format!("branch {}", branch),
+            }))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Recreate a branch from the archive
+///
+/// The branch is looked up in the database, at the given date or where it was
+/// last seen, and created as `refs/heads/<branch>` in the mirror. If `push`
+/// is set, it is pushed back to the upstream repository instead.
+///
+/// Returns the SHA-1 the branch was restored to.
+pub fn restore(
+    repository: &Path,
+    branch: &str,
+    date: Option<&str>,
+    push: bool,
+) -> Result<String, Error> {
+    let _lock = lock(repository)?;
+    let sha = find_branch(repository, branch, date)?;
+    let target = format!("refs/heads/{}", branch);
+    if push {
+        info!("Pushing {} to {} upstream", sha, target);
+        git::push(repository, "origin", &[format!("{}:{}", sha, target)])?;
+    } else {
+        info!("Restoring {} to {}", target, sha);
+        git::make_ref(repository, &target, &sha)?;
+    }
+    Ok(sha)
+}
+
+/// Get all the objects recorded in the database, with whether they are tags
+pub(crate) fn recorded_objects(
+    db: &rusqlite::Connection,
+) -> Result<Vec<(String, bool)>, Error> {
+    let mut objects = Vec::new();
+    let mut stmt = db.prepare(
+        "
+        SELECT sha, tag FROM refs
+        UNION
+        SELECT sha, 0 FROM fork_refs;
+        ",
+    )?;
+    for row in stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| (row.get::<_, String>(0), row.get::<_, bool>(1)),
+    )? {
+        objects.push(row?);
+    }
+    Ok(objects)
+}
+
+/// Create keep-refs for the objects that are not reachable from a local ref
+///
+/// Remote-tracking refs don't count, the next fetch moves or prunes them.
+///
+/// Returns the number of refs that had to be created.
+pub(crate) fn protect(
+    repository: &Path,
+    keep: &KeepNamespaces,
+    objects: &[(String, bool)],
+) -> Result<usize, Error> {
+    let branch_pattern = keep.branch_pattern();
+    let tag_pattern = keep.tag_pattern();
+    let tips = git::ref_targets(
+        repository,
+        &["refs/heads/", "refs/tags/", &branch_pattern, &tag_pattern],
+    )?;
+    let reachable = git::reachable_commits(
+        repository,
+        &[
+            "--branches",
+            "--tags",
+            &format!("--glob={}", branch_pattern),
+            &format!("--glob={}", tag_pattern),
+        ],
+        &[],
+    )?;
+    let mut protected = 0;
+    for (sha, tag) in objects {
+        if tips.contains(sha) || reachable.contains(sha) {
+            continue;
+        }
+        if !git::object_exists(repository, sha)? {
+            return Err(Error::NotFound(format!(
+                "object {} is already missing, not running gc",
+                sha
+            )));
+        }
+        warn!("{} is not protected by a ref, keeping it", sha);
+        keep_object(repository, keep, sha, *tag)?;
+        protected += 1;
+    }
+    Ok(protected)
+}
+
+/// Remove rows of refs and forks' refs, and what refers to them
+pub(crate) fn delete_rows(
+    db: &rusqlite::Connection,
+    refs: &[i64],
+    fork_refs: &[i64],
+) -> Result<(), Error> {
+    let mut statements = Vec::new();
+    for sql in &[
+        "DELETE FROM refs WHERE rowid=?;",
+        "DELETE FROM ref_changes WHERE ref_id=?;",
+        "DELETE FROM rewrite_commits WHERE ref_id=?;",
+        "DELETE FROM branch_renames WHERE old_ref_id=?1 OR new_ref_id=?1;",
+    ] {
+        statements.push(db.prepare(sql)?);
+    }
+    for rowid in refs {
+        for stmt in &mut statements {
+            stmt.execute(&[rowid])?;
+        }
+    }
+    let mut stmt = db.prepare("DELETE FROM fork_refs WHERE rowid=?;")?;
+    for rowid in fork_refs {
+        stmt.execute(&[rowid])?;
+    }
+    Ok(())
+}
+
+/// Find which of those objects are not referenced anymore, and forget their
+/// tag objects
+pub(crate) fn unreferenced_objects<'a, I>(
+    db: &rusqlite::Connection,
+    objects: I,
+) -> Result<Vec<&'a String>, Error>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let remaining: HashSet<String> = recorded_objects(db)?
+        .into_iter()
+        .map(|(sha, _)| sha)
+        .collect();
+    let mut objects: Vec<&String> = objects
+        .into_iter()
+        .filter(|sha| !remaining.contains(*sha))
+        .collect();
+    objects.sort();
+    objects.dedup();
+    let mut stmt = db.prepare("DELETE FROM tag_objects WHERE sha=?;")?;
+    for sha in &objects {
+        stmt.execute(&[sha as &dyn ToSql])?;
+    }
+    Ok(objects)
+}
+
+/// Drop the keep-refs of objects that are not referenced anymore, then
+/// keep again what those were keeping alive for the remaining history
+pub(crate) fn release_objects(
+    repository: &Path,
+    db: &rusqlite::Connection,
+    keep: &KeepNamespaces,
+    objects: &[&String],
+) -> Result<(), Error> {
+    let mut keep_refs: HashSet<String> = HashSet::new();
+    for pattern in &[keep.branch_pattern(), keep.tag_pattern()] {
+        for (name, _) in git::list_ref_names(repository, pattern)? {
+            keep_refs.insert(name.to_string());
+        }
+    }
+    for sha in objects {
+        for name in &[keep.branch(sha), keep.tag(sha)] {
+            if keep_refs.contains(name) {
+                git::delete_ref(repository, name)?;
+            }
+        }
+    }
+    // Those may have been keeping alive objects whose own keep-refs were
+    // dropped as superfluous
+    let objects = recorded_objects(db)?;
+    let report = check(repository, keep, &objects)?;
+    for (sha, tag) in &objects {
+        if report.unprotected.contains(sha) {
+            keep_object(repository, keep, sha, *tag)?;
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of a garbage collection
+#[derive(Debug)]
+pub struct GcReport {
+    /// Number of distinct objects referenced by the database
+    pub checked: usize,
+    /// Number of objects that were not protected by a ref and had to be
+    pub protected: usize,
+}
+
+/// Repack the mirror, making sure everything recorded is kept
+///
+/// Before repacking, every object referenced in the database is checked to be
+/// reachable from a ref, and gets a keep-ref otherwise. Git is then run with
+/// `repack -adk`, or `gc --prune=now` if `prune` is set. Afterwards, every
+/// object is checked again to still exist.
+pub fn gc(repository: &Path, prune: bool) -> Result<GcReport, Error> {
+    let _lock = lock(repository)?;
+    gc_locked(repository, prune)
+}
+
+/// Like `gc()`, for callers that already hold the lock of the mirror
+pub(crate) fn gc_locked(
+    repository: &Path,
+    prune: bool,
+) -> Result<GcReport, Error> {
+    let db = db::open(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+    let objects = recorded_objects(&db)?;
+
+    // Make sure everything is protected
+    let protected = protect(repository, &keep, &objects)?;
+
+    // Objects that are in the shared store will be dropped, make sure the
+    // store has everything first
+    let config = git::mirror_config(repository)?;
+    if let Some(store) = SharedStore::from_config(repository, &config)? {
+        sync_store(repository, &store)?;
+    }
+
+    git::gc(repository, prune)?;
+
+    // Safety pass
+    let mut missing = HashSet::new();
+    for (sha, _) in &objects {
+        if !git::object_exists(repository, sha)? {
+            missing.insert(sha.as_str());
+        }
+    }
+    if !missing.is_empty() {
+        let mut missing: Vec<_> = missing.into_iter().collect();
+        missing.sort();
+        return Err(Error::NotFound(format!(
+            "objects lost during gc: {}",
+            missing.join(", ")
+        )));
+    }
+
+    Ok(GcReport { checked: objects.len(), protected })
+}
+
+/// Problems found by `verify()`
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of distinct objects referenced by the database
+    pub checked: usize,
+    /// Objects referenced by the database that are not in the mirror
+    pub missing: Vec<String>,
+    /// Objects that are not protected from garbage collection by a keep-ref
+    pub unprotected: Vec<String>,
+    /// Keep-refs whose name doesn't match the object they point to
+    pub mismatched: Vec<String>,
+    /// Keep-branches that are already kept alive by another keep-branch
+    pub superfluous: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Whether the archive is fully consistent
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.unprotected.is_empty()
+            && self.mismatched.is_empty()
+            && self.superfluous.is_empty()
+    }
+}
+
+/// Find the keep-branches reachable from the parents of any of them
+///
+/// Those can all be deleted at once: each one is reachable from a
+/// keep-branch that is either kept or itself reachable from another one.
+fn superfluous_branches(
+    repository: &Path,
+    keep_branches: &[(String, String)],
+) -> Result<Vec<String>, Error> {
+    let parents: Vec<String> = keep_branches
+        .iter()
+        .map(|(_, target)| format!("{}^@", target))
+        .collect();
+    let from_parents = git::reachable_commits(repository, &[], &parents)?;
+    Ok(keep_branches
+        .iter()
+        .filter(|(_, target)| from_parents.contains(target))
+        .map(|(name, _)| name.clone())
+        .collect())
+}
+
+/// Check the keep-refs against the database
+fn check(
+    repository: &Path,
+    keep: &KeepNamespaces,
+    objects: &[(String, bool)],
+) -> Result<VerifyReport, Error> {
+    let mut report = VerifyReport {
+        checked: objects.len(),
+        ..Default::default()
+    };
+
+    let keep_branches = git::list_refs(repository, &keep.branch_pattern())?;
+    let kept_tags = git::list_refs(repository, &keep.tag_pattern())?;
+    for (name, target) in &keep_branches {
+        if name[keep.branches.len()..] != target[..] {
+            report.mismatched.push(name.clone());
+        }
+    }
+    for (name, target) in &kept_tags {
+        if name[keep.tags.len()..] != target[..] {
+            report.mismatched.push(name.clone());
+        }
+    }
+
+    report.superfluous = superfluous_branches(repository, &keep_branches)?;
+
+    // Find the objects that are not kept
+    let kept_tags: HashSet<&str> = kept_tags
+        .iter()
+        .map(|(_, target)| target.as_str())
+        .collect();
+    let kept = git::reachable_commits(
+        repository,
+        &[
+            &format!("--glob={}", keep.branch_pattern()),
+            &format!("--glob={}", keep.tag_pattern()),
+        ],
+        &[],
+    )?;
+    for (sha, _) in objects {
+        if !git::object_exists(repository, sha)? {
+            report.missing.push(sha.clone());
+        } else if !kept.contains(sha) && !kept_tags.contains(sha.as_str()) {
+            report.unprotected.push(sha.clone());
+        }
+    }
+    report.unprotected.sort();
+    report.unprotected.dedup();
+    Ok(report)
+}
+
+/// Verify the integrity of the archive
+///
+/// This checks that every object referenced by the database exists in the
+/// mirror and is protected by a keep-ref, and that the keep-refs are
+/// consistent. If `repair` is set, the keep-refs are fixed (missing objects
+/// can't be recovered, though).
+///
+/// The report describes the problems found before any repair.
+pub fn verify(repository: &Path, repair: bool) -> Result<VerifyReport, Error> {
+    let _lock = lock(repository)?;
+    let db = db::open(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+    let objects = recorded_objects(&db)?;
+    let report = check(repository, &keep, &objects)?;
+    if repair && !report.is_ok() {
+        for name in &report.mismatched {
+            warn!("Deleting mismatched keep-ref {}", name);
+            git::delete_ref(repository, name)?;
+        }
+        let pattern = keep.branch_pattern();
+        for name in &report.superfluous {
+            // Deleting one might make others needed, so check again
+            let target = git::get_sha(repository, name)?;
+            let including =
+                git::including_branches(repository, &pattern, &target)?;
+            if including.len() > 1 {
+                info!("Deleting superfluous {}", name);
+                git::delete_ref(repository, name)?;
+            }
+        }
+        // Deleting refs might have left more objects unprotected
+        let after = check(repository, &keep, &objects)?;
+        for (sha, tag) in &objects {
+            if after.unprotected.contains(sha) {
+                info!("Keeping {}", sha);
+                keep_object(repository, &keep, sha, *tag)?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Check the archive like `verify()` does, without writing anything
+///
+/// The database is not upgraded, and the keep-refs are looked for where the
+/// last update left them, rather than moved if their namespaces were changed.
+pub fn check_consistency(repository: &Path) -> Result<VerifyReport, Error> {
+    let db = db::open_read_only(repository)?;
+    let keep = recorded_keep_namespaces(&db)?;
+    let objects = recorded_objects(&db)?;
+    check(repository, &keep, &objects)
+}
+
+/// Outcome of a consolidation
+#[derive(Debug)]
+pub struct ConsolidateReport {
+    /// Number of keep-branches before consolidation
+    pub branches: usize,
+    /// Keep-branches that were deleted
+    pub removed: Vec<String>,
+}
+
+/// Reduce the number of keep-refs
+///
+/// Keep-branches already kept alive by another keep-branch, transitively, are
+/// deleted. If `pack` is set, the remaining refs are then moved into the
+/// packed-refs file, which is much faster for Git to read than loose refs.
+pub fn consolidate(
+    repository: &Path,
+    pack: bool,
+) -> Result<ConsolidateReport, Error> {
+    let _lock = lock(repository)?;
+    let db = db::open(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+    let keep_branches = git::list_refs(repository, &keep.branch_pattern())?;
+    let removed = superfluous_branches(repository, &keep_branches)?;
+    for name in &removed {
+        debug!("Deleting superfluous {}", name);
+        git::delete_ref(repository, name)?;
+    }
+    if pack {
+        git::pack_refs(repository)?;
+    }
+    Ok(ConsolidateReport { branches: keep_branches.len(), removed })
+}
+
+/// Size of the mirror after an update
+#[derive(Debug, Serialize)]
+pub struct SizeRecord {
+    pub date: String,
+    /// Number of objects, loose and packed
+    pub objects: u64,
+    /// Size of the objects on disk, as reported by git-count-objects
+    pub object_bytes: u64,
+    /// Size of the whole mirror directory, including the database
+    pub disk_bytes: u64,
+}
+
+/// Get the size of the mirror after each update, oldest first
+pub fn size_history(repository: &Path) -> Result<Vec<SizeRecord>, Error> {
+    let db = db::open_read_only(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT date, objects, object_bytes, disk_bytes FROM sizes
+        ORDER BY date;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| SizeRecord {
+        date: row.get(0),
+        objects: row.get::<_, i64>(1) as u64,
+        object_bytes: row.get::<_, i64>(2) as u64,
+        disk_bytes: row.get::<_, i64>(3) as u64,
+    })?;
+    let mut history = Vec::new();
+    for row in rows {
+        history.push(row?);
+    }
+    Ok(history)
+}
+
+/// Serialize counts by ref as an object, keeping their order
+fn serialize_counts<S>(
+    counts: &[(RefName, u64)],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_map(counts.iter().map(|(name, count)| (name, count)))
+}
+
+/// Summary of an archive
+#[derive(Debug, Serialize)]
+pub struct ArchiveStats {
+    /// Number of fetches recorded, i.e. snapshots
+    pub snapshots: u64,
+    /// Date of the first and last snapshots
+    pub first_date: Option<String>,
+    pub last_date: Option<String>,
+    /// Number of branches and tags that currently exist upstream
+    pub branches: u64,
+    pub tags: u64,
+    /// Number of branches that were deleted upstream
+    pub deleted_branches: u64,
+    /// Branches that were force-pushed, with how many times, most first
+    #[serde(serialize_with = "serialize_counts")]
+    pub force_pushes: Vec<(RefName, u64)>,
+    /// Number of archived commits that are not reachable upstream anymore
+    pub unreachable_commits: u64,
+    /// Size of the mirror after each update, oldest first
+    pub sizes: Vec<SizeRecord>,
+}
+
+/// Summarize an archive, from the database and the mirror
+pub fn stats(repository: &Path) -> Result<ArchiveStats, Error> {
+    let db = db::open_read_only(repository)?;
+    let (snapshots, first_date, last_date) = db.query_row(
+        "SELECT count(*), min(date), max(date) FROM fetches;",
+        rusqlite::NO_PARAMS,
+        |row| (row.get::<_, i64>(0) as u64, row.get(1), row.get(2)),
+    )?;
+    let (branches, tags, deleted_branches) = db.query_row(
+        "
+        SELECT
+            (SELECT count(*) FROM refs WHERE tag=0 AND to_date IS NULL),
+            (SELECT count(*) FROM refs WHERE tag=1 AND to_date IS NULL),
+            (SELECT count(DISTINCT name) FROM refs r
+             WHERE tag=0 AND NOT EXISTS (
+                 SELECT 1 FROM refs a
+                 WHERE a.name = r.name AND a.tag=0 AND a.to_date IS NULL
+             ));
+        ",
+        rusqlite::NO_PARAMS,
+        |row| (
+            row.get::<_, i64>(0) as u64,
+            row.get::<_, i64>(1) as u64,
+            row.get::<_, i64>(2) as u64,
+        ),
+    )?;
+    let mut force_pushes = Vec::new();
+    {
+        let mut stmt = db.prepare(
+            "
+            SELECT refs.name, count(*) FROM ref_changes
+            INNER JOIN refs ON refs.rowid = ref_changes.ref_id
+            WHERE ref_changes.removed > 0
+            GROUP BY refs.name
+            ORDER BY count(*) DESC, refs.name;
+            ",
+        )?;
+        let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+            (row.get::<_, RefName>(0), row.get::<_, i64>(1) as u64)
+        })?;
+        for row in rows {
+            force_pushes.push(row?);
+        }
+    }
+    let tips: Vec<String> = refs_at(&db, None)?
+        .into_iter()
+        .map(|(_, sha)| sha)
+        .collect();
+    let upstream = git::reachable_commits(repository, &[], &tips)?;
+    let unreachable_commits = git::all_commits(repository)?
+        .difference(&upstream)
+        .count() as u64;
+    Ok(ArchiveStats {
+        snapshots,
+        first_date,
+        last_date,
+        branches,
+        tags,
+        deleted_branches,
+        force_pushes,
+        unreachable_commits,
+        sizes: size_history(repository)?,
+    })
+}
+
+/// A run of `update`
+#[derive(Debug)]
+pub struct UpdateRecord {
+    pub start_date: String,
+    pub end_date: String,
+    /// The error, if the update failed
+    pub error: Option<String>,
+    /// Number of refs created, moved, and deleted, if the update succeeded
+    pub counts: Option<(u64, u64, u64)>,
+}
+
+/// Get the runs of `update`, oldest first
+pub fn update_history(
+    repository: &Path,
+) -> Result<Vec<UpdateRecord>, Error> {
+    let db = db::open(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT start_date, end_date, success, error,
+            new_refs, changed_refs, removed_refs
+        FROM updates
+        ORDER BY start_date;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+        let success: bool = row.get(2);
+        UpdateRecord {
+            start_date: row.get(0),
+            end_date: row.get(1),
+            error: if success {
+                None
+            } else {
+                Some(row.get::<_, Option<String>>(3).unwrap_or_default())
+            },
+            counts: if success {
+                Some((
+                    row.get::<_, i64>(4) as u64,
+                    row.get::<_, i64>(5) as u64,
+                    row.get::<_, i64>(6) as u64,
+                ))
+            } else {
+                None
+            },
+        }
+    })?;
+    let mut history = Vec::new();
+    for row in rows {
+        history.push(row?);
+    }
+    Ok(history)
+}
+
+/// Health of a mirror
+#[derive(Debug)]
+pub struct MirrorStatus {
+    /// End of the last successful update
+    pub last_success: Option<String>,
+    /// End and error of the last failed update
+    pub last_error: Option<(String, String)>,
+    /// Number of refs that currently exist upstream
+    pub live_refs: u64,
+    /// Number of refs that were deleted upstream
+    pub dead_refs: u64,
+    /// Problems with the mirror's settings
+    pub config_problems: Vec<String>,
+    /// Whether the fetched refs don't match the database, because the last
+    /// update was interrupted or rolled back; the next update resets them
+    pub journal_pending: bool,
+    /// Date and error of the update that found the upstream repository
+    /// deleted, if it hasn't been fetched from since
+    pub gone: Option<(String, String)>,
+    /// Outcome of checking the mirror's objects against the database
+    pub verify: VerifyReport,
+}
+
+impl MirrorStatus {
+    /// Whether the last update failed
+    pub fn is_failing(&self) -> bool {
+        match (&self.last_error, &self.last_success) {
+            (Some((error, _)), Some(success)) => error > success,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Whether nothing needs attention
+    pub fn is_ok(&self) -> bool {
+        !self.is_failing()
+            && self.config_problems.is_empty()
+            && !self.journal_pending
+            && self.gone.is_none()
+            && self.verify.is_ok()
+    }
+}
+
+/// Get the health of a mirror
+///
+/// This doesn't talk to the remote, and doesn't change anything. It fails
+/// if the database needs upgrading, which the next update does.
+pub fn status(repository: &Path) -> Result<MirrorStatus, Error> {
+    let db = db::open_read_only(repository)?;
+    let last_success = db.query_row(
+        "SELECT max(end_date) FROM updates WHERE success;",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let last_error = match db.query_row(
+        "
+        SELECT end_date, coalesce(error, '') FROM updates
+        WHERE NOT success
+        ORDER BY end_date DESC
+        LIMIT 1;
+        ",
+        rusqlite::NO_PARAMS,
+        |row| (row.get(0), row.get(1)),
+    ) {
+        Ok(error) => Some(error),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let (live_refs, dead_refs): (i64, i64) = db.query_row(
+        "
+        SELECT
+            (SELECT count(*) FROM refs WHERE to_date IS NULL),
+            (SELECT count(*) FROM (
+                 SELECT DISTINCT name, tag FROM refs r
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM refs a
+                     WHERE a.name = r.name AND a.tag = r.tag
+                         AND a.to_date IS NULL
+                 )
+             ));
+        ",
+        rusqlite::NO_PARAMS,
+        |row| (row.get(0), row.get(1)),
+    )?;
+    Ok(MirrorStatus {
+        last_success,
+        last_error,
+        live_refs: live_refs as u64,
+        dead_refs: dead_refs as u64,
+        config_problems: config_problems(repository)?,
+        journal_pending: repository.join(JOURNAL).exists()
+            && !journal_committed(repository, &db)?,
+        gone: upstream_gone(&db)?,
+        verify: check_consistency(repository)?,
+    })
+}
+
+/// A branch that was probably renamed
+#[derive(Debug, PartialEq)]
+pub struct BranchRename {
+    /// Date of the update that saw the rename
+    pub date: String,
+    pub old_name: RefName,
+    pub new_name: RefName,
+    /// Commit both branches pointed to
+    pub sha: String,
+}
+
+/// Get the branches that were probably renamed, oldest first
+///
+/// Those are branches that were deleted while another one appeared at the same
+/// commit, in the same update.
+pub fn branch_renames(
+    repository: &Path,
+) -> Result<Vec<BranchRename>, Error> {
+    let db = db::open(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT branch_renames.date, old.name, new.name, new.sha
+        FROM branch_renames
+        INNER JOIN refs old ON old.rowid = branch_renames.old_ref_id
+        INNER JOIN refs new ON new.rowid = branch_renames.new_ref_id
+        ORDER BY branch_renames.date, old.name;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| BranchRename {
+        date: row.get(0),
+        old_name: row.get(1),
+        new_name: row.get(2),
+        sha: row.get(3),
+    })?;
+    let mut renames = Vec::new();
+    for row in rows {
+        renames.push(row?);
+    }
+    Ok(renames)
+}
+
+/// A ref alive in a snapshot
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SnapshotRef {
+    pub name: RefName,
+    pub tag: bool,
+    pub sha: String,
+    /// Subject of the commit it points to, if requested and it could be
+    /// found
+    #[serde(skip)]
+    pub subject: Option<String>,
+}
+
+/// Get the branches and tags alive at a given date, or the current ones
+///
+/// If `subjects` is set, the subjects of the commits are also read, from the
+/// database when they were cached there by the updates, or from Git.
+pub fn snapshot(
+    repository: &Path,
+    date: Option<&str>,
+    subjects: bool,
+) -> Result<Vec<SnapshotRef>, Error> {
+    let db = db::open_read_only(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT subject FROM commits
+        WHERE sha=?1
+            OR sha=(SELECT target FROM tag_objects WHERE sha=?1);
+        ",
+    )?;
+    let mut snapshot = Vec::new();
+    for (ref_, sha) in refs_at(&db, date)? {
+        if !subjects {
+            snapshot.push(SnapshotRef {
+                name: ref_.name,
+                tag: ref_.tag,
+                sha,
+                subject: None,
+            });
+            continue;
+        }
+        let subject = match stmt.query_row(&[&sha], |row| row.get(0)) {
+            Ok(subject) => Some(subject),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                match git::log_commits(repository, &sha, 1) {
+                    Ok(commits) => {
+                        commits.into_iter().next().map(|c| c.subject)
+                    }
+                    Err(e) => {
+                        warn!("Can't read commit {}: {}", sha, e);
+                        None
+                    }
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+        snapshot.push(SnapshotRef {
+            name: ref_.name,
+            tag: ref_.tag,
+            sha,
+            subject,
+        });
+    }
+    Ok(snapshot)
+}
+
+/// A ref that is different between two snapshots
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RefDiff {
+    pub name: RefName,
+    pub tag: bool,
+    /// Where it pointed in the first snapshot, `None` if it was created
+    pub old: Option<String>,
+    /// Where it pointed in the second snapshot, `None` if it was deleted
+    pub new: Option<String>,
+    /// Output of `git diff --stat` between the two, if it was moved and that
+    /// was requested
+    #[serde(skip)]
+    pub stat: Option<String>,
+}
+
+/// Compare two snapshots, returning the refs that were created, deleted or
+/// moved between them
+///
+/// A date of `None` means the current refs. If `stat` is set, the changes to
+/// the files are summarized for the refs that moved.
+pub fn compare_snapshots(
+    repository: &Path,
+    from: Option<&str>,
+    to: Option<&str>,
+    stat: bool,
+) -> Result<Vec<RefDiff>, Error> {
+    let db = db::open_read_only(repository)?;
+    let mut refs = BTreeMap::new();
+    for (ref_, sha) in refs_at(&db, from)? {
+        let entry = refs.entry((ref_.tag, ref_.name)).or_insert((None, None));
+        entry.0 = Some(sha);
+    }
+    for (ref_, sha) in refs_at(&db, to)? {
+        let entry = refs.entry((ref_.tag, ref_.name)).or_insert((None, None));
+        entry.1 = Some(sha);
+    }
+    let mut diffs = Vec::new();
+    for ((tag, name), (old, new)) in refs {
+        if old == new {
+            continue;
+        }
+        let stat = match (&old, &new) {
+            (Some(old), Some(new)) if stat => {
+                Some(git::diff_stat(repository, old, new)?)
+            }
+            _ => None,
+        };
+        diffs.push(RefDiff { name, tag, old, new, stat });
+    }
+    Ok(diffs)
+}
+
+/// Get the refs changed by the last update
+pub fn last_changes(repository: &Path) -> Result<Vec<RefDiff>, Error> {
+    let db = db::open(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT name, tag, sha, fetch_id = f.id FROM refs,
+            (SELECT max(rowid) AS id FROM fetches) f
+        WHERE fetch_id = f.id OR to_fetch_id = f.id;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+        (
+            row.get::<_, RefName>(0),
+            row.get::<_, bool>(1),
+            row.get::<_, String>(2),
+            row.get::<_, bool>(3),
+        )
+    })?;
+    let mut refs = BTreeMap::new();
+    for row in rows {
+        let (name, tag, sha, created) = row?;
+        let entry = refs.entry((tag, name)).or_insert((None, None));
+        if created {
+            entry.1 = Some(sha);
+        } else {
+            entry.0 = Some(sha);
+        }
+    }
+    Ok(refs
+        .into_iter()
+        .map(|((tag, name), (old, new))| {
+            RefDiff { name, tag, old, new, stat: None }
+        })
+        .collect())
+}
+
+/// A period during which a commit was on a branch
+#[derive(Debug, PartialEq)]
+pub struct Reachability {
+    /// The branch
+    pub name: RefName,
+    pub from_date: String,
+    /// When it stopped being on the branch, `None` if it still is
+    pub to_date: Option<String>,
+}
+
+/// Find during which periods a commit was reachable from which branches
+///
+/// The commit can be abbreviated; returns its full SHA-1 along with the
+/// periods, ordered by branch then date.
+pub fn when(
+    repository: &Path,
+    commit: &str,
+) -> Result<(String, Vec<Reachability>), Error> {
+    let db = db::open_read_only(repository)?;
+    let commit = match git::resolve_commit(repository, commit)? {
+        Some(sha) => sha,
+        None => return Err(Error::NotFound(format!("commit {}", commit))),
+    };
+    let mut stmt = db.prepare(
+        "
+        SELECT name, from_date, to_date, sha FROM refs
+        WHERE tag=0
+        ORDER BY name, from_date;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+        (
+            row.get::<_, RefName>(0),
+            row.get::<_, String>(1),
+            row.get::<_, Option<String>>(2),
+            row.get::<_, String>(3),
+        )
+    })?;
+    let mut reachable = HashMap::new();
+    let mut periods: Vec<Reachability> = Vec::new();
+    for row in rows {
+        let (name, from_date, to_date, sha) = row?;
+        let contains = match reachable.get(&sha) {
+            Some(&contains) => contains,
+            None => {
+                let contains = git::is_ancestor(repository, &commit, &sha)?;
+                reachable.insert(sha, contains);
+                contains
+            }
+        };
+        if !contains {
+            continue;
+        }
+        // Extend the previous period if this one follows it
+        if let Some(last) = periods.last_mut() {
+            if last.name == name
+                && last.to_date.as_ref() == Some(&from_date)
+            {
+                last.to_date = to_date;
+                continue;
+            }
+        }
+        periods.push(Reachability { name, from_date, to_date });
+    }
+    Ok((commit, periods))
+}
+
+/// What `bisect_date()` checks in each snapshot
+#[derive(Debug, Clone)]
+pub enum BisectTest {
+    /// Whether a commit is reachable from the branch, or from any branch or
+    /// tag if no branch is given
+    Reachable(String),
+    /// Whether a shell command succeeds, run in a checkout of the branch
+    /// (default: master)
+    Command(String),
+}
+
+/// Result of `bisect_date()`
+#[derive(Debug, PartialEq)]
+pub struct BisectReport {
+    /// Whether the test passed in the first snapshots
+    pub initially: bool,
+    /// Last snapshot where the test gave the same result as in the first one
+    pub before: String,
+    /// First snapshot where the result changed
+    pub after: String,
+    /// Number of snapshots that were tested
+    pub tested: usize,
+}
+
+/// Find the first snapshot where a test's result changed, by binary search
+///
+/// The test is assumed to change only once, e.g. a commit that goes away.
+/// Returns `None` if the result is the same in the first and last snapshots.
+/// Commands get the snapshot date in `DOUBLEGIT_SNAPSHOT` and the commit in
+/// `DOUBLEGIT_SHA`; the result is reused for snapshots with the same commit.
+pub fn bisect_date(
+    repository: &Path,
+    branch: Option<&str>,
+    test: &BisectTest,
+) -> Result<Option<BisectReport>, Error> {
+    let db = db::open(repository)?;
+    let test = match *test {
+        BisectTest::Reachable(ref commit) => {
+            match git::resolve_commit(repository, commit)? {
+                Some(sha) => BisectTest::Reachable(sha),
+                None => {
+                    return Err(Error::NotFound(format!("commit {}", commit)));
+                }
+            }
+        }
+        BisectTest::Command(ref command) => {
+            BisectTest::Command(command.clone())
+        }
+    };
+    let branch = match (branch, &test) {
+        (Some(branch), _) => Some(branch),
+        (None, BisectTest::Reachable(_)) => None,
+        (None, BisectTest::Command(_)) => Some("master"),
+    };
+
+    // Find the tips to test in each snapshot
+    let mut stmt =
+        db.prepare("SELECT DISTINCT date FROM fetches ORDER BY date;")?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| row.get(0))?;
+    let mut snapshots: Vec<(String, Vec<String>)> = Vec::new();
+    for row in rows {
+        let date: String = row?;
+        let tips: Vec<String> = refs_at(&db, Some(&date))?
+            .into_iter()
+            .filter(|(ref_, _)| match branch {
+                Some(branch) => {
+                    !ref_.tag && ref_.name.as_bytes() == branch.as_bytes()
+                }
+                None => true,
+            })
+            .map(|(_, sha)| sha)
+            .collect();
+        // A missing branch has no files to run the command on
+        if tips.is_empty() {
+            if let BisectTest::Command(_) = test {
+                continue;
+            }
+        }
+        snapshots.push((date, tips));
+    }
+    if snapshots.len() < 2 {
+        return Ok(None);
+    }
+
+    let mut results: HashMap<String, bool> = HashMap::new();
+    let mut tested = 0;
+    let mut outcome = |(date, tips): &(String, Vec<String>)| {
+        tested += 1;
+        for tip in tips {
+            let result = match results.get(tip) {
+                Some(&result) => result,
+                None => {
+                    let result = match test {
+                        BisectTest::Reachable(ref commit) => {
+                            git::is_ancestor(repository, commit, tip)?
+                        }
+                        BisectTest::Command(ref command) => {
+                            run_bisect_command(repository, command, date, tip)?
+                        }
+                    };
+                    results.insert(tip.clone(), result);
+                    result
+                }
+            };
+            if result {
+                return Ok(true);
+            }
+        }
+        Ok::<_, Error>(false)
+    };
+
+    let initially = outcome(&snapshots[0])?;
+    if outcome(&snapshots[snapshots.len() - 1])? == initially {
+        return Ok(None);
+    }
+    let (mut lo, mut hi) = (0, snapshots.len() - 1);
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if outcome(&snapshots[mid])? == initially {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Ok(Some(BisectReport {
+        initially,
+        before: snapshots[lo].0.clone(),
+        after: snapshots[hi].0.clone(),
+        tested,
+    }))
+}
+
+/// Run a `bisect_date()` command on a checkout of a commit
+fn run_bisect_command(
+    repository: &Path,
+    command: &str,
+    date: &str,
+    sha: &str,
+) -> Result<bool, Error> {
+    let dir = tempfile::Builder::new()
+        .prefix("doublegit_bisect_")
+        .tempdir()?;
+    git::checkout_tree(repository, sha, dir.path())?;
+    info!("Testing {} ({})", date, sha);
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir.path())
+        .env("DOUBLEGIT_SNAPSHOT", date)
+        .env("DOUBLEGIT_SHA", sha)
+        .stdin(process::Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+/// How a ref got to point to a commit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    /// The ref appeared
+    Created,
+    /// The branch moved forward, keeping its previous commits
+    FastForward,
+    /// The branch was rewritten, dropping some of its commits
+    ForcePush,
+    /// The ref moved, but how is not known (e.g. a tag)
+    Moved,
+}
+
+impl Change {
+    /// Name of the change, as shown by `doublegit log`
+    pub fn name(self) -> &'static str {
+        match self {
+            Change::Created => "created",
+            Change::FastForward => "fast-forward",
+            Change::ForcePush => "force-push",
+            Change::Moved => "moved",
+        }
+    }
+}
+
+impl serde::Serialize for Change {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// A row in the history of a ref, with how it changed
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LogEntry {
+    pub name: RefName,
+    pub tag: bool,
+    pub sha: String,
+    pub from_date: String,
+    pub to_date: Option<String>,
+    /// How the ref got to point to `sha`
+    pub change: Change,
+    /// Whether the ref was deleted at `to_date`, rather than moved
+    pub deleted: bool,
+}
+
+/// Get the history of the refs, or of the refs with a given name
+///
+/// This is ordered by branches then tags, then by name and date.
+pub fn ref_log(
+    repository: &Path,
+    name: Option<&str>,
+) -> Result<Vec<LogEntry>, Error> {
+    let db = db::open_read_only(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT r.name, r.tag, r.sha, r.from_date, r.to_date,
+            EXISTS (
+                SELECT 1 FROM refs p
+                WHERE p.name = r.name AND p.tag = r.tag
+                    AND p.to_date = r.from_date
+            ),
+            ref_changes.removed,
+            r.to_date IS NOT NULL AND NOT EXISTS (
+                SELECT 1 FROM refs n
+                WHERE n.name = r.name AND n.tag = r.tag
+                    AND n.from_date = r.to_date
+            )
+        FROM refs r
+        LEFT OUTER JOIN ref_changes ON ref_changes.ref_id = r.rowid
+        WHERE ?1 IS NULL OR r.name = ?1
+        ORDER BY r.tag, r.name, r.from_date;
+        ",
+    )?;
+    let rows = stmt.query_map(&[&name], |row| {
+        let change = match (row.get(5), row.get::<_, Option<i64>>(6)) {
+            (false, _) => Change::Created,
+            (true, Some(0)) => Change::FastForward,
+            (true, Some(_)) => Change::ForcePush,
+            (true, None) => Change::Moved,
+        };
+        LogEntry {
+            name: row.get(0),
+            tag: row.get(1),
+            sha: row.get(2),
+            from_date: row.get(3),
+            to_date: row.get(4),
+            change,
+            deleted: row.get(7),
+        }
+    })?;
+    let mut log = Vec::new();
+    for row in rows {
+        log.push(row?);
+    }
+    if log.is_empty() {
+        if let Some(name) = name {
+            return Err(Error::NotFound(format!("ref {}", name)));
+        }
+    }
+    Ok(log)
+}
+
+/// A row in the history of a tag, with the annotation if it was recorded
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TagEntry {
+    pub name: RefName,
+    /// What the tag pointed to, the tag object if it is annotated
+    pub sha: String,
+    pub from_date: String,
+    pub to_date: Option<String>,
+    /// Whether the tag was deleted at `to_date`, rather than moved
+    pub deleted: bool,
+    /// The object the annotated tag points to, `None` for lightweight tags
+    pub target: Option<String>,
+    /// Tagger of the annotated tag, as `Name <email>`
+    pub tagger: Option<String>,
+    /// Tagger date of the annotated tag, if it has one
+    pub tag_date: Option<String>,
+    /// Message of the annotated tag
+    pub message: Option<String>,
+}
+
+/// Get the history of every tag ever seen, ordered by name then date
+///
+/// If `changed_only` is set, only the tags that were moved or deleted are
+/// returned, since a release that got retagged is usually worth a look.
+pub fn tag_history(
+    repository: &Path,
+    changed_only: bool,
+) -> Result<Vec<TagEntry>, Error> {
+    let db = db::open_read_only(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT r.name, r.sha, r.from_date, r.to_date,
+            r.to_date IS NOT NULL AND NOT EXISTS (
+                SELECT 1 FROM refs n
+                WHERE n.name = r.name AND n.tag = 1
+                    AND n.from_date = r.to_date
+            ),
+            t.target, t.tagger, t.date, t.message
+        FROM refs r
+        LEFT OUTER JOIN tag_objects t ON t.sha = r.sha
+        WHERE r.tag = 1
+        ORDER BY r.name, r.from_date;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| TagEntry {
+        name: row.get(0),
+        sha: row.get(1),
+        from_date: row.get(2),
+        to_date: row.get(3),
+        deleted: row.get(4),
+        target: row.get(5),
+        tagger: row.get(6),
+        tag_date: row.get(7),
+        message: row.get(8),
+    })?;
+    let mut history: Vec<TagEntry> = Vec::new();
+    for row in rows {
+        history.push(row?);
+    }
+    if changed_only {
+        // A tag that is still there at its first SHA-1 has a single row
+        let mut changed = HashSet::new();
+        for entry in &history {
+            if entry.to_date.is_some() {
+                changed.insert(entry.name.clone());
+            }
+        }
+        history.retain(|e| changed.contains(&e.name));
+    }
+    Ok(history)
+}
+
+/// A note from the user
+#[derive(Debug, PartialEq)]
+pub struct Annotation {
+    /// When the note was written
+    pub created: String,
+    /// The snapshot it is about, if it's not about a ref
+    pub snapshot: Option<String>,
+    /// The ref it is about (name, whether it's a tag, and the start of the
+    /// interval), if it's not about a snapshot
+    pub ref_: Option<(RefName, bool, String)>,
+    pub note: String,
+}
+
+/// Attach a note to a snapshot, or to a ref as it was at that date
+///
+/// The date is resolved to the snapshot it falls in, i.e. the last change of
+/// the refs at or before it (`None` means the latest). If a ref is given
+/// (name, and whether it's a tag), the note is attached to the interval during
+/// which it pointed to the same object.
+pub fn annotate(
+    repository: &Path,
+    date: Option<&str>,
+    ref_: Option<(&str, bool)>,
+    note: &str,
+) -> Result<(), Error> {
+    let db = db::open(repository)?;
+    let created = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let res = match ref_ {
+        Some((name, tag)) => db
+            .query_row(
+                "
+                SELECT rowid FROM refs
+                WHERE name=? AND tag=?
+                    AND (? IS NULL OR from_date <= ?)
+                    AND (to_date IS NULL OR to_date > ?);
+                ",
+                &[
+                    &RefName::from(name) as &dyn ToSql,
+                    &tag,
+                    &date,
+                    &date,
+                    &date,
+                ],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|ref_id| (None, Some(ref_id))),
+        None => db
+            .query_row(
+                "
+                SELECT max(date) FROM (
+                    SELECT from_date AS date FROM refs
+                    UNION
+                    SELECT to_date AS date FROM refs
+                )
+                WHERE ? IS NULL OR date <= ?;
+                ",
+                &[&date, &date],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .and_then(|snapshot| match snapshot {
+                Some(s) => Ok((Some(s), None)),
+                None => Err(rusqlite::Error::QueryReturnedNoRows),
+            }),
+    };
+    let (snapshot, ref_id) = match res {
+        Ok(r) => r,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            let date = date.unwrap_or("latest");
+            return Err(Error::NotFound(match ref_ {
+                Some((name, _)) => format!("{} at {}", name, date),
+                None => format!("snapshot at {}", date),
+            }));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    db.execute(
+        "
+        INSERT INTO annotations(created, snapshot, ref_id, note)
+        VALUES(?, ?, ?, ?);
+        ",
+        &[&created as &dyn ToSql, &snapshot, &ref_id, &note],
+    )?;
+    Ok(())
+}
+
+/// Get all the notes, oldest first
+pub fn annotations(repository: &Path) -> Result<Vec<Annotation>, Error> {
+    let db = db::open(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT annotations.created, annotations.snapshot,
+            refs.name, refs.tag, refs.from_date, annotations.note
+        FROM annotations
+        LEFT OUTER JOIN refs ON refs.rowid = annotations.ref_id
+        ORDER BY annotations.created, annotations.rowid;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| Annotation {
+        created: row.get(0),
+        snapshot: row.get(1),
+        ref_: row
+            .get::<_, Option<RefName>>(2)
+            .map(|name| (name, row.get(3), row.get(4))),
+        note: row.get(5),
+    })?;
+    let mut annotations = Vec::new();
+    for row in rows {
+        annotations.push(row?);
+    }
+    Ok(annotations)
+}
+
+/// Options for `search()`
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    /// Maximum number of refs, and of commits, to return
+    pub limit: usize,
+    /// Only return what is not upstream anymore
+    pub deleted_only: bool,
+    /// Only return refs that existed after this date, and commits made after
+    /// it
+    pub since: Option<String>,
+    /// Only return refs that existed before this date, and commits made
+    /// before it
+    pub until: Option<String>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions {
+            limit: 20,
+            deleted_only: false,
+            since: None,
+            until: None,
+        }
+    }
+}
+
+/// A branch or tag whose name matches a search
+#[derive(Debug, PartialEq)]
+pub struct RefMatch {
+    pub name: RefName,
+    pub tag: bool,
+    /// When it was first seen
+    pub from_date: String,
+    /// When it was deleted, `None` if it still exists
+    pub to_date: Option<String>,
+}
+
+/// A commit whose message matches a search
+#[derive(Debug, PartialEq)]
+pub struct CommitMatch {
+    pub sha: String,
+    pub message: String,
+    /// Author date
+    pub date: String,
+    /// Whether it is still reachable from the branches and tags upstream
+    pub upstream: bool,
+}
+
+/// Results of `search()`
+#[derive(Debug, Default)]
+pub struct SearchResults {
+    pub refs: Vec<RefMatch>,
+    pub commits: Vec<CommitMatch>,
+}
+
+/// Search the names of the refs and the archived commit messages
+///
+/// Names match if they contain the query, ignoring case. For messages, the
+/// query uses the SQLite FTS5 syntax, e.g. `fix AND crash` or `"exact
+/// phrase"`. This finds any archived commit, including those that are only
+/// reachable from deleted or rewritten branches; the best matches come first.
+pub fn search(
+    repository: &Path,
+    query: &str,
+    options: &SearchOptions,
+) -> Result<SearchResults, Error> {
+    let db = db::open_read_only(repository)?;
+    let mut results = SearchResults::default();
+
+    let mut stmt = db.prepare(
+        "
+        SELECT name, tag, min(from_date),
+            CASE WHEN max(to_date IS NULL) THEN NULL ELSE max(to_date) END
+        FROM refs
+        WHERE instr(lower(name), lower(?1)) > 0
+        GROUP BY tag, name
+        HAVING (NOT ?2 OR NOT max(to_date IS NULL))
+            AND (?3 IS NULL OR max(to_date IS NULL) OR max(to_date) > ?3)
+            AND (?4 IS NULL OR min(from_date) < ?4)
+        ORDER BY tag, name
+        LIMIT ?5;
+        ",
+    )?;
+    let rows = stmt.query_map(
+        &[
+            &query as &dyn ToSql,
+            &options.deleted_only,
+            &options.since,
+            &options.until,
+            &(options.limit as i64),
+        ],
+        |row| RefMatch {
+            name: row.get(0),
+            tag: row.get(1),
+            from_date: row.get(2),
+            to_date: row.get(3),
+        },
+    )?;
+    for row in rows {
+        results.refs.push(row?);
+    }
+
+    let mut stmt = db.prepare(
+        "
+        SELECT commit_messages.sha, commit_messages.message,
+            (SELECT date FROM commits WHERE commits.sha = commit_messages.sha)
+        FROM commit_search
+        INNER JOIN commit_messages
+            ON commit_messages.rowid = commit_search.rowid
+        WHERE commit_search MATCH ?
+        ORDER BY commit_search.rank;
+        ",
+    )?;
+    let mut rows = stmt.query(&[&query])?;
+    let mut upstream = None;
+    while results.commits.len() < options.limit {
+        let row = match rows.next() {
+            Some(row) => row?,
+            None => break,
+        };
+        let sha: String = row.get(0);
+        let date = match row.get::<_, Option<String>>(2) {
+            Some(date) => date,
+            None => match git::log_commits(repository, &sha, 1)?.pop() {
+                Some(commit) => commit.date,
+                None => continue,
+            },
+        };
+        if options.since.as_ref().map_or(false, |d| date < *d)
+            || options.until.as_ref().map_or(false, |d| date >= *d)
+        {
+            continue;
+        }
+        // Find what is upstream, once there is a match
+        if upstream.is_none() {
+            let tips: Vec<String> = refs_at(&db, None)?
+                .into_iter()
+                .map(|(_, sha)| sha)
+                .collect();
+            upstream = Some(git::reachable_commits(repository, &[], &tips)?);
+        }
+        let is_upstream = upstream.as_ref().unwrap().contains(&sha);
+        if options.deleted_only && is_upstream {
+            continue;
+        }
+        results.commits.push(CommitMatch {
+            sha,
+            message: row.get(1),
+            date,
+            upstream: is_upstream,
+        });
+    }
+    Ok(results)
+}
+
+/// Search the archived commit messages, in an open database
+#[cfg(feature = "web")]
+pub(crate) fn search_commits(
+    db: &rusqlite::Connection,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT commit_messages.sha, commit_messages.message
+        FROM commit_search
+        INNER JOIN commit_messages
+            ON commit_messages.rowid = commit_search.rowid
+        WHERE commit_search MATCH ?
+        ORDER BY commit_search.rank
+        LIMIT ?;
+        ",
+    )?;
+    let rows = stmt.query_map(
+        &[&query as &dyn ToSql, &(limit as i64)],
+        |row| (row.get::<_, String>(0), row.get::<_, String>(1)),
+    )?;
+    let mut results = Vec::new();
+    for row in rows {
+        results.push(row?);
+    }
+    Ok(results)
+}
+
+/// Options for `grep()`
+#[derive(Debug, Clone, Default)]
+pub struct GrepOptions {
+    /// Search the refs alive at this date, instead of the current ones
+    pub date: Option<String>,
+    /// Search every tip ever recorded, including deleted and moved refs
+    pub all: bool,
+    pub ignore_case: bool,
+}
+
+/// A line matching `grep()`, in the files of a ref
+#[derive(Debug, PartialEq)]
+pub struct GrepMatch {
+    pub name: RefName,
+    pub tag: bool,
+    pub sha: String,
+    /// When the ref started pointing to this commit
+    pub from_date: String,
+    /// When it stopped, `None` if it still does
+    pub to_date: Option<String>,
+    pub path: String,
+    /// Line number, starting at 1
+    pub line: u32,
+    pub text: String,
+}
+
+/// Search the files of the recorded branches and tags with `git grep`
+///
+/// The pattern is a basic regular expression, as in `git grep`. Each commit
+/// is only searched once, the matches are reported for every period a ref
+/// pointed to it, ordered by ref then date.
+pub fn grep(
+    repository: &Path,
+    pattern: &str,
+    options: &GrepOptions,
+) -> Result<Vec<GrepMatch>, Error> {
+    let db = db::open(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT name, tag, sha, from_date, to_date FROM refs
+        WHERE ?1
+            OR (?2 IS NULL AND to_date IS NULL)
+            OR (from_date <= ?2 AND (to_date IS NULL OR to_date > ?2))
+        ORDER BY tag, name, from_date;
+        ",
+    )?;
+    let rows = stmt.query_map(
+        &[&options.all as &dyn ToSql, &options.date],
+        |row| {
+            (
+                row.get::<_, RefName>(0),
+                row.get::<_, bool>(1),
+                row.get::<_, String>(2),
+                row.get::<_, String>(3),
+                row.get::<_, Option<String>>(4),
+            )
+        },
+    )?;
+    let mut periods = Vec::new();
+    for row in rows {
+        periods.push(row?);
+    }
+
+    let mut revisions = Vec::new();
+    let mut seen = HashSet::new();
+    for (_, _, sha, _, _) in &periods {
+        if seen.insert(sha.clone()) {
+            if git::object_exists(repository, sha)? {
+                revisions.push(sha.clone());
+            } else {
+                warn!("Object {} is missing, not searching it", sha);
+            }
+        }
+    }
+    let mut lines: HashMap<String, Vec<git::GrepLine>> = HashMap::new();
+    for line in git::grep(
+        repository,
+        pattern,
+        options.ignore_case,
+        &revisions,
+    )? {
+        lines.entry(line.sha.clone()).or_default().push(line);
+    }
+
+    let mut matches = Vec::new();
+    for (name, tag, sha, from_date, to_date) in periods {
+        for line in lines.get(&sha).map_or(&[][..], Vec::as_slice) {
+            matches.push(GrepMatch {
+                name: name.clone(),
+                tag,
+                sha: sha.clone(),
+                from_date: from_date.clone(),
+                to_date: to_date.clone(),
+                path: line.path.clone(),
+                line: line.line,
+                text: line.text.clone(),
+            });
+        }
+    }
+    Ok(matches)
+}
+
+/// Get the output of git-fetch for an update, if `keeptranscripts` was set
+///
+/// This is the last update at or before `date`, or the last one if `date` is
+/// `None`. Returns the date of the update, and what Git printed on stdout and
+/// stderr.
+pub fn transcript(
+    repository: &Path,
+    date: Option<&str>,
+) -> Result<(String, Vec<u8>, Vec<u8>), Error> {
+    let db = db::open(repository)?;
+    let res = db.query_row(
+        "
+        SELECT fetches.date, transcripts.stdout, transcripts.stderr
+        FROM fetches
+        LEFT OUTER JOIN transcripts ON transcripts.fetch_id = fetches.rowid
+        WHERE ? IS NULL OR fetches.date <= ?
+        ORDER BY fetches.date DESC
+        LIMIT 1;
+        ",
+        &[&date as &dyn ToSql, &date],
+        |row| (
+            row.get::<_, String>(0),
+            row.get::<_, Option<Vec<u8>>>(1),
+            row.get::<_, Option<Vec<u8>>>(2),
+        ),
+    );
+    match res {
+        Ok((date, Some(stdout), Some(stderr))) => {
+            Ok((date, decompress(&stdout)?, decompress(&stderr)?))
+        }
+        Ok((date, _, _)) => {
+            Err(Error::NotFound(format!("transcript of update {}", date)))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(Error::NotFound(match date {
+                Some(date) => format!("update at {}", date),
+                None => "update".into(),
+            }))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Namespace where `merge()` fetches the refs of the other mirror
+const MERGE_REFS: &str = "refs/doublegit-merge/";
+
+/// Outcome of a merge
+#[derive(Debug)]
+pub struct MergeReport {
+    /// Number of rows of ref history that were added or changed
+    pub changed: usize,
+    /// Number of objects from the other archive that got a keep-ref
+    pub kept: usize,
+}
+
+/// Which archive a row of history comes from, and its rowid there
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Origin {
+    Dest(i64),
+    Source(i64),
+}
+
+/// A period during which a ref pointed to a commit
+#[derive(Clone, Debug, PartialEq)]
+struct Interval {
+    sha: String,
+    from_date: String,
+    to_date: Option<String>,
+    /// The row this starts like, if any
+    origin: Option<Origin>,
+}
+
+impl Interval {
+    fn covers(&self, date: &str) -> bool {
+        self.from_date.as_str() <= date
+            && self.to_date.as_ref().map_or(true, |to| to.as_str() > date)
+    }
+}
+
+/// Combine the history of a ref recorded by two archives
+///
+/// The dates where anything changed split time into segments. For each one,
+/// the row that starts the latest wins, since it comes from the most recent
+/// observation; on ties, rows that come last in `rows` win. Then consecutive
+/// segments with the same SHA-1 are joined back.
+fn merge_intervals(rows: &[Interval]) -> Vec<Interval> {
+    let mut dates: Vec<&str> = Vec::new();
+    for row in rows {
+        dates.push(&row.from_date);
+        if let Some(ref to) = row.to_date {
+            dates.push(to);
+        }
+    }
+    dates.sort();
+    dates.dedup();
+
+    let mut merged: Vec<Interval> = Vec::new();
+    for (i, &start) in dates.iter().enumerate() {
+        let end = dates.get(i + 1).map(|&d| d.to_owned());
+        let winner = rows
+            .iter()
+            .filter(|row| row.covers(start))
+            .max_by(|a, b| a.from_date.cmp(&b.from_date));
+        let winner = match winner {
+            Some(w) => w,
+            None => continue,
+        };
+        if let Some(last) = merged.last_mut() {
+            if last.sha == winner.sha
+                && last.to_date.as_ref().map(String::as_str) == Some(start)
+            {
+                last.to_date = end;
+                continue;
+            }
+        }
+        merged.push(Interval {
+            sha: winner.sha.clone(),
+            from_date: start.to_owned(),
+            to_date: end,
+            origin: if winner.from_date == start {
+                winner.origin
+            } else {
+                None
+            },
+        });
+    }
+    merged
+}
+
+/// Merge the history of one ref from the attached `source` database
+///
+/// `table` is `refs` or `fork_refs`, and the ref is selected by the
+/// `key_columns` having the values `key`. If `linked` is set, the rows of
+/// `ref_changes` and `rewrite_commits` follow the rows they are attached to.
+///
+/// Returns the number of rows that were added or changed.
+fn merge_ref(
+    tx: &rusqlite::Connection,
+    table: &str,
+    key_columns: &[&str],
+    key: &[&dyn ToSql],
+    linked: bool,
+) -> Result<usize, Error> {
+    let condition = key_columns
+        .iter()
+        .map(|c| format!("{}=?", c))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let mut rows = Vec::new();
+    for &(schema, dest) in &[("source", false), ("main", true)] {
+        let mut stmt = tx.prepare(&format!(
+            "
+            SELECT rowid, sha, from_date, to_date FROM {}.{}
+            WHERE {};
+            ",
+            schema, table, condition,
+        ))?;
+        for row in stmt.query_map(key, |row| {
+            let id = row.get(0);
+            Interval {
+                sha: row.get(1),
+                from_date: row.get(2),
+                to_date: row.get(3),
+                origin: Some(if dest {
+                    Origin::Dest(id)
+                } else {
+                    Origin::Source(id)
+                }),
+            }
+        })? {
+            rows.push(row?);
+        }
+    }
+    let merged = merge_intervals(&rows);
+
+    // Delete what disappeared
+    for row in &rows {
+        if let Some(Origin::Dest(id)) = row.origin {
+            if merged.iter().all(|m| m.origin != row.origin) {
+                tx.execute(
+                    &format!("DELETE FROM main.{} WHERE rowid=?;", table),
+                    &[id],
+                )?;
+                if linked {
+                    tx.execute(
+                        "DELETE FROM main.ref_changes WHERE ref_id=?;",
+                        &[id],
+                    )?;
+                    tx.execute(
+                        "DELETE FROM main.rewrite_commits WHERE ref_id=?;",
+                        &[id],
+                    )?;
+                }
+            }
+        }
+    }
+
+    let mut changed = 0;
+    for interval in &merged {
+        match interval.origin {
+            Some(Origin::Dest(id)) => {
+                if rows.contains(interval) {
+                    continue;
+                }
+                tx.execute(
+                    &format!(
+                        "UPDATE main.{} SET to_date=? WHERE rowid=?;",
+                        table,
+                    ),
+                    &[&interval.to_date as &dyn ToSql, &id],
+                )?;
+            }
+            origin => {
+                let mut params: Vec<&dyn ToSql> = key.to_vec();
+                params.push(&interval.sha);
+                params.push(&interval.from_date);
+                params.push(&interval.to_date);
+                tx.execute(
+                    &format!(
+                        "
+                        INSERT INTO main.{}({}, sha, from_date, to_date)
+                        VALUES({}?, ?, ?);
+                        ",
+                        table,
+                        key_columns.join(", "),
+                        "?, ".repeat(key_columns.len()),
+                    ),
+                    &params,
+                )?;
+                let new_id = tx.last_insert_rowid();
+                if let (true, Some(Origin::Source(id))) = (linked, origin) {
+                    tx.execute(
+                        "
+                        INSERT INTO main.ref_changes(ref_id, added, removed)
+                        SELECT ?, added, removed FROM source.ref_changes
+                        WHERE ref_id=?;
+                        ",
+                        &[new_id, id],
+                    )?;
+                    tx.execute(
+                        "
+                        INSERT INTO main.rewrite_commits(ref_id, sha, removed)
+                        SELECT ?, sha, removed FROM source.rewrite_commits
+                        WHERE ref_id=?;
+                        ",
+                        &[new_id, id],
+                    )?;
+                }
+            }
+        }
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+/// Merge another archive of the same repository into this one
+///
+/// This is for archives made over different periods, or from different
+/// machines. The history of the branches, tags and forks is combined (where
+/// both archives recorded something for the same time, the most recent
+/// observation wins), the objects are fetched from the other mirror and
+/// protected by keep-refs, and the fetches and tombstones of the other archive
+/// are copied over. The other archive is not changed, except for upgrading
+/// its database.
+pub fn merge(repository: &Path, source: &Path) -> Result<MergeReport, Error> {
+    let _lock = lock(repository)?;
+    let source = source.canonicalize()?;
+    db::open(&source)?;
+    let source_db = db::path(&source)?;
+    let source_key = db::key(&source)?;
+
+    let mut db = db::open(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+    let recorded: HashSet<(String, bool)> =
+        recorded_objects(&db)?.into_iter().collect();
+
+    info!("Fetching objects from {}", source.display());
+    git::fetch_all_refs(repository, &source, MERGE_REFS)?;
+
+    db::attach(
+        &db,
+        &source_db,
+        "source",
+        source_key.as_ref().map(String::as_str),
+    )?;
+    let mut changed = 0;
+    {
+        let tx = db::begin(&mut db)?;
+        let mut keys = Vec::new();
+        {
+            let mut stmt =
+                tx.prepare("SELECT DISTINCT name, tag FROM source.refs;")?;
+            for row in stmt.query_map(rusqlite::NO_PARAMS, |row| {
+                (row.get::<_, RefName>(0), row.get::<_, bool>(1))
+            })? {
+                keys.push(row?);
+            }
+        }
+        for (name, tag) in &keys {
+            changed += merge_ref(
+                &tx,
+                "refs",
+                &["name", "tag"],
+                &[name as &dyn ToSql, tag],
+                true,
+            )?;
+        }
+        let mut keys = Vec::new();
+        {
+            let mut stmt = tx.prepare(
+                "SELECT DISTINCT fork, name FROM source.fork_refs;",
+            )?;
+            for row in stmt.query_map(rusqlite::NO_PARAMS, |row| {
+                (row.get::<_, String>(0), row.get::<_, RefName>(1))
+            })? {
+                keys.push(row?);
+            }
+        }
+        for (fork, name) in &keys {
+            changed += merge_ref(
+                &tx,
+                "fork_refs",
+                &["fork", "name"],
+                &[fork as &dyn ToSql, name],
+                false,
+            )?;
+        }
+        tx.execute_batch(
+            "
+            INSERT INTO main.fetches
+            SELECT * FROM source.fetches
+            WHERE date NOT IN (SELECT date FROM main.fetches);
+            INSERT INTO main.tombstones
+            SELECT * FROM source.tombstones
+            WHERE date NOT IN (SELECT date FROM main.tombstones);
+            INSERT INTO main.renames
+            SELECT * FROM source.renames
+            WHERE date NOT IN (SELECT date FROM main.renames);
+            -- Link the rows to the fetches again, some came from the other
+            -- archive or were extended
+            UPDATE main.refs SET
+                fetch_id=(
+                    SELECT max(rowid) FROM main.fetches WHERE date=from_date
+                ),
+                to_fetch_id=(
+                    SELECT max(rowid) FROM main.fetches WHERE date=to_date
+                );
+            UPDATE main.fork_refs SET
+                fetch_id=(
+                    SELECT max(rowid) FROM main.fetches WHERE date=from_date
+                ),
+                to_fetch_id=(
+                    SELECT max(rowid) FROM main.fetches WHERE date=to_date
+                );
+            -- The new commits are not indexed, do it all on next update
+            DELETE FROM main.settings WHERE name='commit_index';
+            ",
+        )?;
+        let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        audit::record(&tx, audit::MERGE, &now)?;
+        tx.commit()?;
+    }
+    db.execute("DETACH DATABASE source;", rusqlite::NO_PARAMS)?;
+
+    // Protect the new objects, then drop the fetched refs
+    let mut kept = 0;
+    for (sha, tag) in recorded_objects(&db)? {
+        if !recorded.contains(&(sha.clone(), tag)) {
+            keep_object(repository, &keep, &sha, tag)?;
+            kept += 1;
+        }
+    }
+    let fetched: Vec<_> = git::list_ref_names(repository, MERGE_REFS)?
+        .into_iter()
+        .map(|(name, _)| (name, None))
+        .collect();
+    git::update_refs(repository, &fetched)?;
+
+    Ok(MergeReport { changed, kept })
+}
+
+/// Get the fetched refs as recorded in the database, in the format of the
+/// journal
+fn current_refs(
+    db: &rusqlite::Connection,
+) -> Result<Vec<(RefName, String)>, Error> {
+    let mut refs = Vec::new();
+    let mut stmt = db.prepare(
+        "SELECT name, tag, sha FROM refs WHERE to_date IS NULL;",
+    )?;
+    let rows = stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| (
+            Ref { name: row.get(0), tag: row.get(1) }.fullname(),
+            row.get::<_, String>(2),
+        ),
+    )?;
+    for row in rows {
+        refs.push(row?);
+    }
+    let mut stmt = db.prepare(
+        "SELECT fork, name, sha FROM fork_refs WHERE to_date IS NULL;",
+    )?;
+    let rows = stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| (
+            row.get::<_, RefName>(1).with_prefix(
+                &format!("refs/remotes/{}/", row.get::<_, String>(0)),
+            ),
+            row.get::<_, String>(2),
+        ),
+    )?;
+    for row in rows {
+        refs.push(row?);
+    }
+    Ok(refs)
+}
+
+/// Name of the bundle of the mirror in a backup
+pub const BACKUP_BUNDLE: &str = "mirror.bundle";
+
+/// Make a copy of an archive that can be restored, without stopping updates
+///
+/// The destination directory is created, and gets a copy of the database, a
+/// bundle of all the refs, and the Git config of the mirror. The database is
+/// copied first, so the objects it references are all in the bundle;
+/// however the fetched refs might have moved since, so the backup also has a
+/// journal with their position as recorded in the database. Restoring it
+/// makes the next update roll them back and record the changes.
+pub fn backup(repository: &Path, dest: &Path) -> Result<(), Error> {
+    let db = db::open(repository)?;
+    fs::create_dir(dest)?;
+    let dest = dest.canonicalize()?;
+
+    // Copy the database, consistently even if it is being written to
+    let db_path = dest.join(db::FILENAME);
+    let key = db::key(repository)?;
+    let key = key.as_ref().map(String::as_str);
+    db::copy(&db, &db_path, key)?;
+
+    // Write the journal, from the copy
+    let refs = current_refs(&db::connect(&db_path, key)?)?;
+    write_journal_file(&dest.join(JOURNAL), &refs, None)?;
+
+    git::bundle_all(repository, &dest.join(BACKUP_BUNDLE))?;
+    fs::copy(repository.join("config"), dest.join("config"))?;
+    Ok(())
+}
+
+/// Outcome of rolling back a fetch
+#[derive(Debug)]
+pub struct RollbackReport {
+    /// Date of the fetch that was rolled back
+    pub date: String,
+    /// Number of rows of history it had added, that were removed
+    pub removed: usize,
+    /// Number of rows of history it had ended, that were extended
+    pub extended: usize,
+    /// Number of objects that are no longer kept
+    pub objects: usize,
+}
+
+/// Undo what a fetch recorded, for example because upstream was broken
+///
+/// This picks the last fetch at or before the given date, or the last one.
+/// The rows of history it added are removed, and the rows it ended are
+/// extended to the next change that is kept. The remote-tracking refs get put
+/// back where the database now says they are by the next update, through the
+/// journal, so that anything that is still different upstream is recorded
+/// again.
+pub fn rollback(
+    repository: &Path,
+    date: Option<&str>,
+) -> Result<RollbackReport, Error> {
+    let _lock = lock(repository)?;
+    let mut db = db::open(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+
+    let tx = db::begin(&mut db)?;
+    let (fetch_id, fetch_date) = match tx.query_row(
+        "
+        SELECT rowid, date FROM fetches
+        WHERE ? IS NULL OR date <= ?
+        ORDER BY date DESC
+        LIMIT 1;
+        ",
+        &[&date as &dyn ToSql, &date],
+        |row| (row.get::<_, i64>(0), row.get::<_, String>(1)),
+    ) {
+        Ok(r) => r,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(Error::NotFound(match date {
+                Some(date) => format!("update at {}", date),
+                None => "update".into(),
+            }));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    info!("Rolling back the fetch at {}", fetch_date);
+
+    let mut extended = 0;
+    let mut created = Vec::new();
+    for (table, same) in &[
+        ("refs", "c.name = refs.name AND c.tag = refs.tag"),
+        ("fork_refs", "c.fork = fork_refs.fork AND c.name = fork_refs.name"),
+    ] {
+        // Rows it replaced now end where their replacement did
+        extended += tx.execute(
+            &format!(
+                "
+                UPDATE {0} SET
+                    to_date=(
+                        SELECT c.to_date FROM {0} c
+                        WHERE {1} AND c.fetch_id=?1
+                    ),
+                    to_fetch_id=(
+                        SELECT c.to_fetch_id FROM {0} c
+                        WHERE {1} AND c.fetch_id=?1
+                    )
+                WHERE to_fetch_id=?1 AND EXISTS (
+                    SELECT 1 FROM {0} c WHERE {1} AND c.fetch_id=?1
+                );
+                ",
+                table, same,
+            ),
+            &[&fetch_id],
+        )?;
+        // Rows it deleted now end when the ref was seen again, if it was
+        extended += tx.execute(
+            &format!(
+                "
+                UPDATE {0} SET
+                    to_date=(
+                        SELECT min(c.from_date) FROM {0} c
+                        WHERE {1} AND c.from_date > {0}.from_date
+                            AND c.fetch_id IS NOT ?1
+                    ),
+                    to_fetch_id=(
+                        SELECT c.fetch_id FROM {0} c
+                        WHERE {1} AND c.from_date > {0}.from_date
+                            AND c.fetch_id IS NOT ?1
+                        ORDER BY c.from_date
+                        LIMIT 1
+                    )
+                WHERE to_fetch_id=?1;
+                ",
+                table, same,
+            ),
+            &[&fetch_id],
+        )?;
+        let mut stmt = tx.prepare(&format!(
+            "SELECT rowid, sha FROM {} WHERE fetch_id=?;",
+            table,
+        ))?;
+        let mut rows = Vec::new();
+        for row in stmt.query_map(&[&fetch_id], |row| {
+            (row.get::<_, i64>(0), row.get::<_, String>(1))
+        })? {
+            rows.push(row?);
+        }
+        created.push(rows);
+    }
+    let (refs, fork_refs) = (&created[0], &created[1]);
+    delete_rows(
+        &tx,
+        &refs.iter().map(|(rowid, _)| *rowid).collect::<Vec<_>>(),
+        &fork_refs.iter().map(|(rowid, _)| *rowid).collect::<Vec<_>>(),
+    )?;
+    let objects = unreferenced_objects(
+        &tx,
+        refs.iter().chain(fork_refs.iter()).map(|(_, sha)| sha),
+    )?;
+    tx.execute("DELETE FROM fetches WHERE rowid=?;", &[&fetch_id])?;
+    tx.execute("DELETE FROM transcripts WHERE fetch_id=?;", &[&fetch_id])?;
+
+    // Have the next update reset the fetched refs, then fetch again
+    write_journal_file(
+        &repository.join(JOURNAL),
+        &current_refs(&tx)?,
+        None,
+    )?;
+
+    let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    audit::record(&tx, audit::ROLLBACK, &now)?;
+    tx.commit()?;
+
+    release_objects(repository, &db, &keep, &objects)?;
+
+    Ok(RollbackReport {
+        date: fetch_date,
+        removed: refs.len() + fork_refs.len(),
+        extended,
+        objects: objects.len(),
+    })
+}
+
+/// Options controlling how a ref gets forgotten
+#[derive(Debug, Clone, Default)]
+pub struct ForgetOptions {
+    /// Only report what would be removed
+    pub dry_run: bool,
+    /// Run a garbage collection afterwards, actually removing the objects
+    pub repack: bool,
+    /// Why the ref is being forgotten, recorded with the redaction
+    pub reason: String,
+}
+
+/// Outcome of forgetting a ref
+#[derive(Debug)]
+pub struct ForgetReport {
+    /// Number of intervals of the ref that were removed
+    pub intervals: usize,
+    /// Number of notes about the ref that were removed
+    pub notes: usize,
+    /// Number of objects that are no longer kept
+    pub objects: usize,
+    /// Whether the ref still existed upstream at the last update; if it
+    /// still does, the next update records it again
+    pub live: bool,
+}
+
+/// Remove all the history of a ref, e.g. to comply with a takedown request
+///
+/// The intervals of the branch or tag (or of the branch of a fork, if `fork`
+/// is given) are removed with the notes about them, its objects are no longer
+/// kept unless other refs still use them, and the commits only it had are
+/// dropped from the search index. The mirror's ref is deleted too. What was
+/// removed is recorded as a redaction, see `redactions()`.
+///
+/// Nothing is removed if a legal hold covers any of the intervals.
+pub fn forget(
+    repository: &Path,
+    fork: Option<&str>,
+    name: &str,
+    tag: bool,
+    options: &ForgetOptions,
+) -> Result<ForgetReport, Error> {
+    let _lock = lock(repository)?;
+    let mut db = db::open(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+
+    let (table, condition) = match fork {
+        Some(_) => ("fork_refs", "r.name = ?1 AND r.fork = ?2"),
+        None => ("refs", "r.name = ?1 AND r.tag = ?2"),
+    };
+    let name = RefName::from(name);
+    let params: [&dyn ToSql; 2] = match fork {
+        Some(ref fork) => [&name, fork],
+        None => [&name, &tag],
+    };
+
+    let tx = db::begin(&mut db)?;
+    let mut rows = Vec::new();
+    let mut live = false;
+    {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT rowid, sha, to_date IS NULL FROM {} r WHERE {};",
+            table, condition,
+        ))?;
+        let map = |row: &rusqlite::Row| {
+            (
+                row.get::<_, i64>(0),
+                row.get::<_, String>(1),
+                row.get::<_, bool>(2),
+            )
+        };
+        for row in stmt.query_map(&params, map)? {
+            let (rowid, sha, current) = row?;
+            live |= current;
+            rows.push((rowid, sha));
+        }
+    }
+    if rows.is_empty() {
+        return Err(Error::NotFound(match fork {
+            Some(fork) => format!("branch {} of fork {}", name, fork),
+            None => format!("{} {}", if tag { "tag" } else { "branch" }, name),
+        }));
+    }
+    let held: i64 = tx.query_row(
+        &format!(
+            "
+            SELECT count(*) FROM legal_holds h
+            WHERE EXISTS (
+                SELECT 1 FROM {} r
+                WHERE {}
+                    AND (h.from_date IS NULL OR r.to_date IS NULL
+                         OR h.from_date < r.to_date)
+                    AND (h.to_date IS NULL OR h.to_date > r.from_date)
+            );
+            ",
+            table, condition,
+        ),
+        &params,
+        |row| row.get(0),
+    )?;
+    if held > 0 {
+        return Err(Error::Held(format!(
+            "{} has history under {} legal holds, release them first",
+            name, held,
+        )));
+    }
+
+    // Remove the intervals, and what refers to them
+    let rowids: Vec<i64> = rows.iter().map(|(rowid, _)| *rowid).collect();
+    let mut notes = 0;
+    if fork.is_none() {
+        let mut stmt = tx.prepare("DELETE FROM annotations WHERE ref_id=?;")?;
+        for rowid in &rowids {
+            notes += stmt.execute(&[rowid])?;
+        }
+        delete_rows(&tx, &rowids, &[])?;
+    } else {
+        delete_rows(&tx, &[], &rowids)?;
+    }
+    let objects = unreferenced_objects(&tx, rows.iter().map(|(_, sha)| sha))?;
+
+    let report = ForgetReport {
+        intervals: rows.len(),
+        notes,
+        objects: objects.len(),
+        live,
+    };
+    if options.dry_run {
+        return Ok(report);
+    }
+    let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    tx.execute(
+        "
+        INSERT INTO redactions(date, fork, name, tag, intervals, reason)
+        VALUES(?, ?, ?, ?, ?, ?);
+        ",
+        &[
+            &now as &dyn ToSql,
+            &fork,
+            &name,
+            &tag,
+            &(rows.len() as i64),
+            &options.reason,
+        ],
+    )?;
+    // The mirror's ref gets reset to what the database now says, i.e.
+    // removed, through the journal
+    write_journal_file(
+        &repository.join(JOURNAL),
+        &current_refs(&tx)?,
+        None,
+    )?;
+    audit::record(&tx, audit::FORGET, &now)?;
+    tx.commit()?;
+    info!(
+        "Forgot {} intervals of {}, {} objects no longer kept",
+        report.intervals, name, report.objects,
+    );
+    replay_journal(repository)?;
+    fs::remove_file(repository.join(JOURNAL))?;
+
+    // Drop their keep-refs, and the commits nothing else has from the index
+    let shas: Vec<String> = objects.iter().map(|sha| (*sha).clone()).collect();
+    let dropped = git::reachable_commits(repository, &[], &shas)?;
+    release_objects(repository, &db, &keep, &objects)?;
+    let remaining = git::all_commits(repository)?;
+    let mut statements = Vec::new();
+    for sql in &[
+        "DELETE FROM commit_messages WHERE sha=?;",
+        "DELETE FROM commits WHERE sha=?;",
+    ] {
+        statements.push(db.prepare(sql)?);
+    }
+    for sha in dropped.difference(&remaining) {
+        for stmt in &mut statements {
+            stmt.execute(&[sha])?;
+        }
+    }
+
+    if options.repack {
+        gc_locked(repository, true)?;
+    }
+
+    Ok(report)
+}
+
+/// A record of history that was removed with `forget()`
+#[derive(Debug)]
+pub struct Redaction {
+    pub date: String,
+    /// The fork the ref was from, if not `origin`
+    pub fork: Option<String>,
+    pub name: RefName,
+    pub tag: bool,
+    /// Number of intervals that were removed
+    pub intervals: i64,
+    pub reason: String,
+}
+
+/// List the redactions made with `forget()`, oldest first
+pub fn redactions(repository: &Path) -> Result<Vec<Redaction>, Error> {
+    let db = db::open(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT date, fork, name, tag, intervals, reason FROM redactions
+        ORDER BY date, rowid;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| Redaction {
+        date: row.get(0),
+        fork: row.get(1),
+        name: row.get(2),
+        tag: row.get(3),
+        intervals: row.get(4),
+        reason: row.get(5),
+    })?;
+    let mut redactions = Vec::new();
+    for row in rows {
+        redactions.push(row?);
+    }
+    Ok(redactions)
+}
+
+/// Outcome of importing history from the reflogs of a clone
+#[derive(Debug)]
+pub struct ImportReport {
+    /// Number of branches and tags history was found for
+    pub refs: usize,
+    /// Number of intervals added
+    pub intervals: usize,
+    /// Number of intervals of the archive that now start earlier
+    pub extended: usize,
+}
+
+/// Turn the positions of a ref into intervals ending at `end`
+///
+/// The positions have to be sorted by date. Those from `end` on are ignored,
+/// when a ref moved several times within a second only the last position is
+/// kept, and consecutive positions on the same object are joined.
+fn import_intervals(
+    positions: &[(String, String)],
+    end: &str,
+) -> Vec<(String, String, String)> {
+    let mut kept: Vec<(String, String)> = Vec::new();
+    for (date, sha) in positions.iter().filter(|(d, _)| **d < *end) {
+        if kept.last().map_or(false, |(d, _)| d == date) {
+            kept.pop();
+        }
+        match kept.last() {
+            Some((_, last_sha)) if last_sha == sha => {}
+            _ => kept.push((date.clone(), sha.clone())),
+        }
+    }
+    let mut intervals = Vec::with_capacity(kept.len());
+    for (i, (date, sha)) in kept.iter().enumerate() {
+        let to_date = kept.get(i + 1).map_or(end, |(d, _)| d.as_str());
+        intervals.push((date.clone(), to_date.to_owned(), sha.clone()));
+    }
+    intervals
+}
+
+/// Seed the archive with history from the reflogs of an existing clone
+///
+/// The branches are read from the clone's remote-tracking branches of
+/// `origin`, or from its local branches if it has none, and each position
+/// in their reflogs becomes an interval. Refs without a reflog, such as tags
+/// or packed refs, are taken to have been created with the commit they point
+/// to. The dates are only as good as those: a reflog records when the clone
+/// saw a change, not when it happened upstream.
+///
+/// Only history from before the first update of the mirror is imported, and
+/// the objects are copied from the clone, including those only reachable
+/// from its reflogs.
+pub fn import_reflog(
+    clone: &Path,
+    repository: &Path,
+) -> Result<ImportReport, Error> {
+    let _lock = lock(repository)?;
+    let mut db = db::open(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+    let first_fetch: Option<String> = db.query_row(
+        "SELECT min(date) FROM fetches;",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let first_fetch = match first_fetch {
+        Some(date) => date,
+        None => {
+            return Err(Error::Config(
+                "the mirror was never updated, update it first".into(),
+            ));
+        }
+    };
+    let earlier: i64 = db.query_row(
+        "SELECT count(*) FROM refs WHERE from_date < ?;",
+        &[&first_fetch],
+        |row| row.get(0),
+    )?;
+    if earlier > 0 {
+        return Err(Error::Config(
+            "the archive already has history from before its first update"
+                .into(),
+        ));
+    }
+
+    // Read the positions of the refs from the clone
+    let to_date = |timestamp: i64| {
+        chrono::NaiveDateTime::from_timestamp(timestamp, 0)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    let commit_date = |sha: &str| -> Result<Option<String>, Error> {
+        Ok(git::log_commits(clone, sha, 1)?.pop().map(|c| c.date))
+    };
+    let mut prefix = "refs/remotes/origin/";
+    let mut branches = git::list_ref_names(clone, prefix)?;
+    if branches.is_empty() {
+        prefix = "refs/heads/";
+        branches = git::list_ref_names(clone, prefix)?;
+    }
+    let mut history = Vec::new();
+    for (fullname, sha) in branches {
+        let name = &fullname.as_bytes()[prefix.len()..];
+        if name == b"HEAD" {
+            continue;
+        }
+        let name = RefName::from(name);
+        let entries = git::reflog(clone, &fullname)?;
+        let mut positions = Vec::new();
+        match entries.first() {
+            // Where it pointed before the reflog starts, if that commit is
+            // older
+            Some(first) => if let Some(ref old) = first.old {
+                if let Some(date) = commit_date(old)? {
+                    if date < to_date(first.timestamp) {
+                        positions.push((date, old.clone()));
+                    }
+                }
+            },
+            None => if let Some(date) = commit_date(&sha)? {
+                positions.push((date, sha));
+            },
+        }
+        for entry in entries {
+            positions.push((to_date(entry.timestamp), entry.new));
+        }
+        history.push((name, false, positions));
+    }
+    for (fullname, sha) in git::list_ref_names(clone, "refs/tags/")? {
+        let name = RefName::from(&fullname.as_bytes()[10..]);
+        if let Some(date) = commit_date(&sha)? {
+            history.push((name, true, vec![(date, sha)]));
+        }
+    }
+
+    info!("Copying objects from {}", clone.display());
+    git::copy_objects(clone, repository)?;
+
+    let mut report = ImportReport { refs: 0, intervals: 0, extended: 0 };
+    let mut kept = HashSet::new();
+    let tx = db::begin(&mut db)?;
+    for (name, tag, positions) in &history {
+        let intervals = import_intervals(positions, &first_fetch);
+        if intervals.is_empty() {
+            continue;
+        }
+        report.refs += 1;
+        for (i, (from_date, to_date, sha)) in intervals.iter().enumerate() {
+            // The last position might be where the archive starts
+            if i + 1 == intervals.len() {
+                let extended = tx.execute(
+                    "
+                    UPDATE refs SET from_date=?
+                    WHERE name=? AND tag=? AND from_date=? AND sha=?;
+                    ",
+                    &[from_date as &dyn ToSql, name, tag, &first_fetch, sha],
+                )?;
+                if extended > 0 {
+                    report.extended += 1;
+                    continue;
+                }
+            }
+            tx.execute(
+                "
+                INSERT INTO refs(name, from_date, to_date, sha, tag)
+                VALUES(?, ?, ?, ?, ?);
+                ",
+                &[name as &dyn ToSql, from_date, to_date, sha, tag],
+            )?;
+            report.intervals += 1;
+            kept.insert((sha.clone(), *tag));
+        }
+    }
+    // The new commits are not indexed, do it all on next update
+    tx.execute(
+        "DELETE FROM settings WHERE name='commit_index';",
+        rusqlite::NO_PARAMS,
+    )?;
+    for (sha, tag) in &kept {
+        keep_object(repository, &keep, sha, *tag)?;
+    }
+    let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    audit::record(&tx, audit::IMPORT, &now)?;
+    tx.commit()?;
+
+    // Older commits are often ancestors of those already kept
+    let pattern = keep.branch_pattern();
+    let keep_branches = git::list_refs(repository, &pattern)?;
+    for name in superfluous_branches(repository, &keep_branches)? {
+        git::delete_ref(repository, &name)?;
+    }
+
+    info!(
+        "Imported {} intervals of {} refs, extended {}",
+        report.intervals, report.refs, report.extended,
+    );
+    Ok(report)
+}
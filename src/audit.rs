@@ -0,0 +1,378 @@
+//! Tamper-evident log of the changes recorded in the archive
+//!
+//! Each update appends an entry to the `audit` table, with the SHA-1 of the
+//! ref changes it recorded and of the previous entry. Editing the history of
+//! the refs afterwards, or removing entries, breaks the chain.
+//!
+//! Operations that legitimately rewrite history (`merge`, `expire`,
+//! `rollback`, `import-reflog` and `forget`) append a checkpoint, which
+//! covers the whole history as it is at that point. Entries before the last
+//! checkpoint can then only be checked to be linked together, not against the
+//! refs they describe.
+//!
+//! The entries can also be sent to a timestamping service, which proves that
+//! they, and so the upstream state they cover, existed at some date.
+
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::path::Path;
+use std::process;
+
+use crate::{Error, RefName, db};
+
+/// Kind of an entry appended by an update
+pub(crate) const UPDATE: &str = "update";
+/// Kind of an entry appended by `archive::merge()`
+pub(crate) const MERGE: &str = "merge";
+/// Kind of an entry appended by `retention::expire()`
+pub(crate) const EXPIRE: &str = "expire";
+/// Kind of an entry appended by `archive::rollback()`
+pub(crate) const ROLLBACK: &str = "rollback";
+/// Kind of an entry appended by `archive::import_reflog()`
+pub(crate) const IMPORT: &str = "import";
+/// Kind of an entry appended by `archive::forget()`
+pub(crate) const FORGET: &str = "forget";
+
+/// Get the rows an entry covers, serialized
+///
+/// Those are the rows that changed at that date for an update, or all the
+/// rows from before that date for a checkpoint. Either way, the rows are as
+/// they were at that date, i.e. a `to_date` after it is ignored.
+fn entry_content(
+    db: &Connection,
+    kind: &str,
+    date: &str,
+) -> Result<Vec<u8>, Error> {
+    let condition = if kind == UPDATE {
+        "from_date = ?1 OR to_date = ?1"
+    } else {
+        "from_date <= ?1"
+    };
+    let mut stmt = db.prepare(&format!(
+        "
+        SELECT '', name, tag, from_date,
+            CASE WHEN to_date > ?1 THEN NULL ELSE to_date END, sha
+        FROM refs
+        WHERE {0}
+        UNION ALL
+        SELECT fork, name, 0, from_date,
+            CASE WHEN to_date > ?1 THEN NULL ELSE to_date END, sha
+        FROM fork_refs
+        WHERE {0}
+        ORDER BY 1, 2, 3, 4;
+        ",
+        condition,
+    ))?;
+    let rows = stmt.query_map(&[&date], |row| {
+        (
+            row.get::<_, String>(0),
+            row.get::<_, RefName>(1),
+            row.get::<_, bool>(2),
+            row.get::<_, String>(3),
+            row.get::<_, Option<String>>(4),
+            row.get::<_, String>(5),
+        )
+    })?;
+    let mut content = Vec::new();
+    for row in rows {
+        let (fork, name, tag, from_date, to_date, sha) = row?;
+        content.extend_from_slice(
+            format!(
+                "{} {} {} {} {} ",
+                from_date,
+                to_date.as_ref().map_or("-", |d| d),
+                sha,
+                if tag { "tag" } else { "branch" },
+                fork,
+            ).as_bytes(),
+        );
+        content.extend_from_slice(name.as_bytes());
+        content.push(b'\n');
+    }
+    Ok(content)
+}
+
+/// Compute the hash of an entry
+fn entry_hash(
+    previous: Option<&str>,
+    kind: &str,
+    date: &str,
+    content: &[u8],
+) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input(format!(
+        "{}\n{}\n{}\n",
+        previous.unwrap_or(""),
+        kind,
+        date,
+    ).as_bytes());
+    hasher.input(content);
+    format!("{:x}", hasher.result())
+}
+
+/// Append an entry to the audit log, covering the current state of the refs
+///
+/// Returns the identifier of the entry and its hash.
+pub(crate) fn record(
+    db: &Connection,
+    kind: &str,
+    date: &str,
+) -> Result<(i64, String), Error> {
+    let previous = match db.query_row(
+        "SELECT hash FROM audit ORDER BY rowid DESC LIMIT 1;",
+        rusqlite::NO_PARAMS,
+        |row| row.get::<_, String>(0),
+    ) {
+        Ok(hash) => Some(hash),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let content = entry_content(db, kind, date)?;
+    let hash = entry_hash(
+        previous.as_ref().map(String::as_str),
+        kind,
+        date,
+        &content,
+    );
+    db.execute(
+        "INSERT INTO audit(date, kind, previous, hash) VALUES(?, ?, ?, ?);",
+        &[&date as &dyn ToSql, &kind, &previous, &hash],
+    )?;
+    Ok((db.last_insert_rowid(), hash))
+}
+
+/// Get a proof that an entry existed at this time, from a timestamping
+/// service
+///
+/// This runs the shell command set in `doublegit.timestampCommand`, with the
+/// hash of the entry in the `DOUBLEGIT_AUDIT_HASH` environment variable; what
+/// it prints is stored as the proof. Failures are recorded, not returned.
+pub(crate) fn timestamp(
+    db: &Connection,
+    command: &str,
+    id: i64,
+    hash: &str,
+    date: &str,
+) -> Result<(), Error> {
+    info!("Timestamping audit entry {}", id);
+    let (proof, error) = match process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("DOUBLEGIT_AUDIT_HASH", hash)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()
+    {
+        Ok(ref output) if output.status.success() => {
+            (Some(output.stdout.clone()), None)
+        }
+        Ok(output) => (
+            None,
+            Some(format!("doublegit.timestampCommand returned {}",
+                         output.status)),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    if let Some(ref e) = error {
+        warn!("Error timestamping audit entry {}: {}", id, e);
+    }
+    db::retry(|| {
+        db.execute(
+            "
+            INSERT INTO timestamps(audit_id, date, proof, error)
+            VALUES(?, ?, ?, ?);
+            ",
+            &[&id as &dyn ToSql, &date, &proof, &error],
+        )
+    })?;
+    Ok(())
+}
+
+/// An entry of the audit log that was timestamped
+#[derive(Debug)]
+pub struct Timestamp {
+    /// Identifier of the entry
+    pub audit_id: i64,
+    /// Date of the entry
+    pub date: String,
+    /// Hash of the entry, which the proof is about
+    pub hash: String,
+    /// The proof, in whatever format the service returned it
+    pub proof: Option<Vec<u8>>,
+    /// Why no proof could be obtained
+    pub error: Option<String>,
+}
+
+/// Get the timestamps of the audit log, oldest first
+pub fn timestamps(repository: &Path) -> Result<Vec<Timestamp>, Error> {
+    let db = db::open(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT timestamps.audit_id, audit.date, audit.hash,
+            timestamps.proof, timestamps.error
+        FROM timestamps
+        INNER JOIN audit ON audit.rowid = timestamps.audit_id
+        ORDER BY timestamps.audit_id, timestamps.rowid;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| Timestamp {
+        audit_id: row.get(0),
+        date: row.get(1),
+        hash: row.get(2),
+        proof: row.get(3),
+        error: row.get(4),
+    })?;
+    let mut timestamps = Vec::new();
+    for row in rows {
+        timestamps.push(row?);
+    }
+    Ok(timestamps)
+}
+
+/// Outcome of checking the audit log
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    /// Number of entries in the log
+    pub entries: usize,
+    /// Number of entries that were checked against the refs, i.e. since the
+    /// last checkpoint
+    pub checked: usize,
+    /// Number of intervals of refs from before the log was started
+    pub unaudited: usize,
+    /// Inconsistencies found, each one means the archive was edited
+    pub problems: Vec<String>,
+}
+
+impl AuditReport {
+    /// Whether no trace of tampering was found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check the audit log against the archive
+pub fn verify(repository: &Path) -> Result<AuditReport, Error> {
+    let db = db::open(repository)?;
+    let mut report = AuditReport::default();
+
+    let mut entries = Vec::new();
+    {
+        let mut stmt = db.prepare(
+            "
+            SELECT rowid, date, kind, previous, hash FROM audit
+            ORDER BY rowid;
+            ",
+        )?;
+        let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+            (
+                row.get::<_, i64>(0),
+                row.get::<_, String>(1),
+                row.get::<_, String>(2),
+                row.get::<_, Option<String>>(3),
+                row.get::<_, String>(4),
+            )
+        })?;
+        for row in rows {
+            entries.push(row?);
+        }
+    }
+    report.entries = entries.len();
+    let checkpoint = entries.iter().rposition(|e| e.2 != UPDATE);
+
+    // Check the chain, and the entries since the last checkpoint against the
+    // refs
+    let mut previous: Option<&str> = None;
+    for (i, (id, date, kind, prev, hash)) in entries.iter().enumerate() {
+        if prev.as_ref().map(String::as_str) != previous {
+            report.problems.push(format!(
+                "audit entry {} ({}) doesn't follow the previous one",
+                id, date,
+            ));
+        }
+        if checkpoint.map_or(true, |c| i >= c) {
+            let content = entry_content(&db, kind, date)?;
+            let expected = entry_hash(
+                prev.as_ref().map(String::as_str),
+                kind,
+                date,
+                &content,
+            );
+            if *hash != expected {
+                report.problems.push(format!(
+                    "refs recorded by audit entry {} ({}) were modified",
+                    id, date,
+                ));
+            }
+            report.checked += 1;
+        }
+        previous = Some(hash);
+    }
+
+    // Check that every change to the refs is covered by the log
+    let first = match entries.first() {
+        Some(e) => e.1.clone(),
+        None => return Ok(report),
+    };
+    let (since, updates): (Option<&str>, HashSet<&str>) = match checkpoint {
+        Some(c) => (
+            Some(&entries[c].1),
+            entries[c + 1..].iter().map(|e| e.1.as_str()).collect(),
+        ),
+        None => (None, entries.iter().map(|e| e.1.as_str()).collect()),
+    };
+    let covered = |date: &str| {
+        updates.contains(date) || since.map_or(false, |s| date <= s)
+    };
+    let mut stmt = db.prepare(
+        "
+        SELECT name, from_date, to_date FROM refs
+        UNION ALL
+        SELECT fork || ':' || name, from_date, to_date FROM fork_refs
+        ORDER BY from_date;
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+        (
+            row.get::<_, RefName>(0),
+            row.get::<_, String>(1),
+            row.get::<_, Option<String>>(2),
+        )
+    })?;
+    for row in rows {
+        let (name, from_date, to_date) = row?;
+        if since.is_none() && *from_date < *first {
+            // From before the log was started, not covered unless it changed
+            // since
+            match to_date {
+                None => {
+                    report.unaudited += 1;
+                    continue;
+                }
+                Some(ref d) if **d < *first => {
+                    report.unaudited += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        } else if !covered(&from_date) {
+            report.problems.push(format!(
+                "{} from {} is not in the audit log",
+                name, from_date,
+            ));
+            continue;
+        }
+        if let Some(ref to_date) = to_date {
+            if !covered(to_date) {
+                report.problems.push(format!(
+                    "{} from {} was closed at {}, which is not in the \
+                     audit log",
+                    name, from_date, to_date,
+                ));
+            }
+        }
+    }
+
+    Ok(report)
+}
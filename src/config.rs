@@ -0,0 +1,379 @@
+//! Settings shared by all the mirrors, from a global config file
+//!
+//! The file is in Git config format, like the settings of the mirrors. It is
+//! `$DOUBLEGIT_CONFIG` if set, otherwise `doublegit/config` in
+//! `$XDG_CONFIG_HOME` (by default `~/.config`):
+//!
+//! ```text
+//! [core]
+//!     git = /usr/local/bin/git
+//!     databaseDir = /var/lib/doublegit
+//!     root = /srv/backups
+//! [mirror]
+//!     sshKey = /etc/doublegit/id_ed25519
+//! [web]
+//!     host = 0.0.0.0
+//!     port = 8080
+//!     user = auditor
+//!     password = correct horse battery staple
+//! [notify]
+//!     command = mail -s "$DOUBLEGIT_REPOSITORY failed" me@example.com
+//! ```
+//!
+//! It is not TOML because no TOML parser is available to this build, and
+//! `git config` already reads and writes this format.
+//!
+//! The `mirror` section gives defaults for the `doublegit.*` settings of every
+//! mirror, which the mirror's own config overrides. Options on the
+//! command-line override the other settings.
+//!
+//! Both can be edited by hand or with `git config`, but `set_mirror_setting()`
+//! and `set_global_setting()` also check the names and values first.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::RwLock;
+
+use crate::{Error, git, settings_problems};
+
+/// The global settings
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// The Git executable, `core.git`
+    pub git: Option<PathBuf>,
+    /// Directory holding the databases, `core.databaseDir`
+    pub database_dir: Option<PathBuf>,
+    /// Directory of mirrors to use when none is given, `core.root`
+    pub root: Option<PathBuf>,
+    /// Defaults for the settings of the mirrors, the `mirror` section
+    ///
+    /// Like `git::mirror_config()`, keys are lowercase.
+    pub mirror: HashMap<String, String>,
+    /// Address the web server binds to, `web.host`
+    pub web_host: Option<String>,
+    /// Port the web server listens on, `web.port`
+    pub web_port: Option<u16>,
+    /// User name for HTTP Basic authentication, `web.user`
+    pub web_user: Option<String>,
+    /// Password for HTTP Basic authentication, `web.password`
+    pub web_password: Option<String>,
+    /// Token for HTTP Bearer authentication, `web.token`
+    pub web_token: Option<String>,
+    /// Shell command run when an update fails, `notify.command`
+    pub notify_command: Option<String>,
+}
+
+/// The settings of a mirror, `doublegit.<name>` in its own config or
+/// `mirror.<name>` in the global config
+pub const MIRROR_SETTINGS: &[&str] = &[
+    "backupRemote",
+    "database",
+    "databaseKey",
+    "databaseKeyCommand",
+    "databaseKeyEnv",
+    "forks",
+    "keepBranchPrefix",
+    "keepTagPrefix",
+    "keepTranscripts",
+    "limitHaves",
+    "negotiationAlgorithm",
+    "noProxy",
+    "protocolVersion",
+    "proxy",
+    "retention",
+    "sharedStore",
+    "sharedStoreName",
+    "sshKey",
+    "sshKnownHosts",
+    "sshKnownHostsFile",
+    "sshOptions",
+    "timestampCommand",
+    "token",
+    "tokenCommand",
+    "tokenEnv",
+    "tokenUser",
+];
+
+/// The settings of the global config, besides the `mirror` section
+pub const GLOBAL_SETTINGS: &[&str] = &[
+    "core.git",
+    "core.databaseDir",
+    "core.root",
+    "web.host",
+    "web.port",
+    "web.user",
+    "web.password",
+    "web.token",
+    "notify.command",
+];
+
+lazy_static! {
+    /// The settings in effect, see `set_global()`
+    static ref GLOBAL: RwLock<Config> = RwLock::new(Config::default());
+}
+
+/// Get the location of the global config file
+///
+/// Returns `None` if neither `DOUBLEGIT_CONFIG`, `XDG_CONFIG_HOME`, nor
+/// `HOME` is set.
+pub fn default_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("DOUBLEGIT_CONFIG") {
+        return Some(path.into());
+    }
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("doublegit").join("config"))
+}
+
+/// Read a global config file
+///
+/// A file that doesn't exist is the same as an empty one. Relative paths are
+/// relative to the directory of the file. There is a single `core.root`: the
+/// daemon sets up the collection there and `watch` serves the mirrors under
+/// their path in it, so a second root could give two mirrors the same URL.
+pub fn load(path: &Path) -> Result<Config, Error> {
+    let mut config = Config::default();
+    if !path.is_file() {
+        return Ok(config);
+    }
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    for (key, value) in git::config_file(path)? {
+        match key.as_str() {
+            "core.git" => config.git = Some(value.into()),
+            "core.databasedir" => {
+                config.database_dir = Some(base.join(value))
+            }
+            "core.root" => {
+                if config.root.is_some() {
+                    return Err(Error::Config(format!(
+                        "core.root is set more than once in {}",
+                        path.display(),
+                    )));
+                }
+                config.root = Some(base.join(value));
+            }
+            "web.host" => config.web_host = Some(value),
+            "web.port" => {
+                config.web_port = Some(value.parse().map_err(|_| {
+                    Error::Config(format!("invalid web.port {:?}", value))
+                })?);
+            }
+            "web.user" => config.web_user = Some(value),
+            "web.password" => config.web_password = Some(value),
+            "web.token" => config.web_token = Some(value),
+            "notify.command" => config.notify_command = Some(value),
+            k if k.starts_with("mirror.") => {
+                config.mirror.insert(k[7..].to_owned(), value);
+            }
+            k => warn!("Unknown setting {} in {}", k, path.display()),
+        }
+    }
+    Ok(config)
+}
+
+/// Set the global settings used by the library
+///
+/// Those are the defaults of the mirrors' settings and the notification
+/// command; the Git executable and the database directory still have to be
+/// set with `set_git_binary()` and `set_database_dir()`.
+pub fn set_global(config: Config) {
+    *GLOBAL.write().unwrap() = config;
+}
+
+/// Get the defaults for the settings of the mirrors
+pub(crate) fn mirror_defaults() -> HashMap<String, String> {
+    GLOBAL.read().unwrap().mirror.clone()
+}
+
+/// Run the notification command for a failed update, if one is set
+///
+/// The command gets the path of the mirror in `DOUBLEGIT_REPOSITORY` and the
+/// error in `DOUBLEGIT_ERROR`. Its own failures are only logged.
+pub(crate) fn notify_failure(repository: &Path, error: &Error) {
+    let command = match GLOBAL.read().unwrap().notify_command {
+        Some(ref c) => c.clone(),
+        None => return,
+    };
+    info!("Running notification command for {:?}", repository);
+    match process::Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .env("DOUBLEGIT_REPOSITORY", repository)
+        .env("DOUBLEGIT_ERROR", error.to_string())
+        .stdin(process::Stdio::null())
+        .status()
+    {
+        Ok(ref status) if status.success() => {}
+        Ok(status) => {
+            warn!("notify.command returned {}", status);
+        }
+        Err(e) => warn!("Error running notify.command: {}", e),
+    }
+}
+
+/// Find a mirror setting from its name, in any case, e.g. `sshkey`
+fn mirror_setting(name: &str) -> Result<&'static str, Error> {
+    MIRROR_SETTINGS
+        .iter()
+        .find(|s| s.eq_ignore_ascii_case(name))
+        .cloned()
+        .ok_or_else(|| Error::Config(format!("unknown setting {:?}", name)))
+}
+
+/// Get the full name of a setting of a mirror, e.g. `doublegit.sshKey`
+///
+/// The `doublegit.` prefix can be omitted, and the case doesn't matter.
+pub fn mirror_setting_name(key: &str) -> Result<String, Error> {
+    let name = match key.get(..10) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("doublegit.") => {
+            &key[10..]
+        }
+        _ => key,
+    };
+    Ok(format!("doublegit.{}", mirror_setting(name)?))
+}
+
+/// Get the full name of a setting of the global config, e.g. `web.port` or
+/// `mirror.sshKey`
+pub fn global_setting_name(key: &str) -> Result<String, Error> {
+    match key.get(..7) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("mirror.") => {
+            return Ok(format!("mirror.{}", mirror_setting(&key[7..])?));
+        }
+        _ => {}
+    }
+    GLOBAL_SETTINGS
+        .iter()
+        .find(|s| s.eq_ignore_ascii_case(key))
+        .map(|s| s.to_string())
+        .ok_or_else(|| Error::Config(format!("unknown setting {:?}", key)))
+}
+
+/// Check a change to the settings of a mirror, or to their defaults
+///
+/// Problems that were already there are not reported, only those the change
+/// would introduce.
+fn check_mirror_change(
+    config: &HashMap<String, String>,
+    name: &str,
+    value: Option<&str>,
+) -> Result<(), Error> {
+    let before = settings_problems(config);
+    let mut config = config.clone();
+    let key = name.to_lowercase();
+    match value {
+        Some(value) => {
+            config.insert(key, value.to_owned());
+        }
+        None => {
+            config.remove(&key);
+        }
+    }
+    match settings_problems(&config)
+        .into_iter()
+        .find(|p| !before.contains(p))
+    {
+        Some(problem) => Err(Error::Config(problem)),
+        None => Ok(()),
+    }
+}
+
+/// Change a `doublegit.*` setting of a mirror, or remove it if `value` is
+/// `None`
+///
+/// The new value is checked first, and nothing is written if it is invalid.
+pub fn set_mirror_setting(
+    repository: &Path,
+    key: &str,
+    value: Option<&str>,
+) -> Result<(), Error> {
+    let key = mirror_setting_name(key)?;
+    let name = &key[10..];
+    // Without a value of its own, the mirror gets the default again
+    let effective = match value {
+        Some(value) => Some(value.to_owned()),
+        None => mirror_defaults().remove(&name.to_lowercase()),
+    };
+    check_mirror_change(
+        &git::mirror_config(repository)?,
+        name,
+        effective.as_ref().map(String::as_str),
+    )?;
+    match value {
+        Some(value) => git::set_config(repository, &key, value),
+        None => git::unset_config(repository, &key),
+    }
+}
+
+/// Change a setting in a global config file, or remove it if `value` is
+/// `None`
+///
+/// Like for `set_mirror_setting()`, the new value is checked first.
+pub fn set_global_setting(
+    path: &Path,
+    key: &str,
+    value: Option<&str>,
+) -> Result<(), Error> {
+    let key = global_setting_name(key)?;
+    if key.starts_with("mirror.") {
+        check_mirror_change(&load(path)?.mirror, &key[7..], value)?;
+    } else if let Some(value) = value {
+        if value.is_empty() {
+            return Err(Error::Config(format!("empty value for {}", key)));
+        }
+        if key == "web.port" && value.parse::<u16>().is_err() {
+            return Err(Error::Config(format!(
+                "invalid web.port {:?}",
+                value,
+            )));
+        }
+        // It is sent as "user:password"
+        if key == "web.user" && value.contains(':') {
+            return Err(Error::Config(format!(
+                "invalid web.user {:?}, can't contain ':'",
+                value,
+            )));
+        }
+    }
+    if value.is_some() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+    } else if !path.exists() {
+        return Ok(());
+    }
+    git::write_config_file(path, &key, value)
+}
+
+/// List the settings of a mirror, including the defaults from the global
+/// config, by full name
+pub fn mirror_settings(
+    repository: &Path,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut settings: Vec<_> = git::mirror_config(repository)?
+        .into_iter()
+        .map(|(key, value)| {
+            let name = mirror_setting_name(&key)
+                .unwrap_or_else(|_| format!("doublegit.{}", key));
+            (name, value)
+        })
+        .collect();
+    settings.sort();
+    Ok(settings)
+}
+
+/// List the settings in a global config file, by full name, in order
+pub fn global_settings(path: &Path) -> Result<Vec<(String, String)>, Error> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    Ok(git::config_file(path)?
+        .into_iter()
+        .map(|(key, value)| {
+            (global_setting_name(&key).unwrap_or(key), value)
+        })
+        .collect())
+}
@@ -0,0 +1,312 @@
+//! Keeping mirrors up to date, running updates periodically
+//!
+//! Each cycle updates all the mirrors found under a directory, like
+//! `doublegit update` does. So that they don't all hit the same server at
+//! once, the start of each update is delayed by an offset derived from the
+//! path of the mirror, the same from one cycle to the next.
+//!
+//! Every update and every cycle is logged as a line of `key=value` fields.
+//!
+//! The daemon runs until a shutdown is requested (see `shutdown`), letting
+//! the running updates stop cleanly.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::{
+    COLLECTION_CONFIG, Error, UpdateOptions, db, find_mirrors,
+    init_collection, shutdown, systemd, update_staggered, upstream_gone,
+};
+
+/// Longest interval accepted, 100 years
+const MAX_INTERVAL_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Parse an interval, a number followed by `s`, `m`, `h` or `d`
+///
+/// It can't be more than 100 years.
+pub fn parse_interval(value: &str) -> Result<Duration, Error> {
+    let value = value.trim();
+    let invalid = || {
+        Error::Config(format!("invalid interval {:?}", value))
+    };
+    if value.len() < 2 {
+        return Err(invalid());
+    }
+    let (number, unit) = value.split_at(value.len() - 1);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    match number.checked_mul(seconds) {
+        Some(seconds) if seconds <= MAX_INTERVAL_SECS => {
+            Ok(Duration::from_secs(seconds))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Options controlling how the daemon runs
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// Time between the start of each cycle
+    pub interval: Duration,
+    /// Spread the start of the updates over this long
+    pub stagger: Duration,
+    /// Number of mirrors to update at the same time
+    pub jobs: usize,
+}
+
+/// Outcome of a cycle
+#[derive(Debug)]
+pub struct CycleReport {
+    /// Number of mirrors that were updated or set up
+    pub mirrors: usize,
+    /// Number of those that failed
+    pub failed: usize,
+    /// How long the cycle took
+    pub duration: Duration,
+}
+
+/// Get the delay before updating a mirror, less than `stagger`
+fn offset(repository: &Path, stagger: Duration) -> Duration {
+    let millis = stagger.as_secs() * 1000
+        + u64::from(stagger.subsec_millis());
+    if millis == 0 {
+        return Duration::from_secs(0);
+    }
+    let mut hasher = DefaultHasher::new();
+    repository.hash(&mut hasher);
+    Duration::from_millis(hasher.finish() % millis)
+}
+
+/// Longest wait between attempts at a mirror whose upstream is gone, 30 days
+const MAX_GONE_BACKOFF: i64 = 30 * 24 * 60 * 60;
+
+/// Whether a mirror should be updated at a given date
+///
+/// Mirrors whose upstream repository was found deleted are tried less and
+/// less often: after each attempt, the wait is as long as the upstream had
+/// been gone then, up to 30 days. Mirrors whose database can't be read are
+/// updated, the error shows up there.
+pub fn is_due<Date>(repository: &Path, date: Date) -> bool
+where
+    Date: Into<chrono::DateTime<chrono::Utc>>,
+{
+    let parse = |d: &str| {
+        chrono::NaiveDateTime::parse_from_str(d, "%Y-%m-%d %H:%M:%S").ok()
+    };
+    let db = match db::open_read_only(repository) {
+        Ok(db) => db,
+        Err(_) => return true,
+    };
+    let gone_since = match upstream_gone(&db) {
+        Ok(Some((date, _))) => date,
+        _ => return true,
+    };
+    let last_attempt = db.query_row(
+        "SELECT max(start_date) FROM updates;",
+        rusqlite::NO_PARAMS,
+        |row| row.get::<_, Option<String>>(0),
+    );
+    let (gone_since, last_attempt) = match (
+        parse(&gone_since),
+        last_attempt.ok().and_then(|d| d).and_then(|d| parse(&d)),
+    ) {
+        (Some(gone_since), Some(last_attempt)) => (gone_since, last_attempt),
+        _ => return true,
+    };
+    let backoff = (last_attempt - gone_since)
+        .max(chrono::Duration::zero())
+        .min(chrono::Duration::seconds(MAX_GONE_BACKOFF));
+    date.into().naive_utc() - last_attempt >= backoff
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_millis()) / 1000.0
+}
+
+/// Update all the mirrors under a directory once
+pub fn run_cycle(
+    root: &Path,
+    update_options: &UpdateOptions,
+    options: &DaemonOptions,
+    cycle: u64,
+) -> Result<CycleReport, Error> {
+    let start = Instant::now();
+    let mut failed = 0;
+    let mut failures = Vec::new();
+    if root.join(COLLECTION_CONFIG).is_file() {
+        failures = init_collection(root)?;
+    }
+    for (mirror, e) in &failures {
+        error!(
+            "cycle={} mirror={:?} status=error error={:?}",
+            cycle,
+            mirror,
+            e.to_string(),
+        );
+        failed += 1;
+    }
+    let now = chrono::Utc::now();
+    let mut mirrors = find_mirrors(root)?;
+    mirrors.retain(|mirror| {
+        let due = is_due(mirror, now);
+        if !due {
+            info!(
+                "cycle={} mirror={:?} status=gone, backing off",
+                cycle, mirror,
+            );
+        }
+        due
+    });
+    let delays: Vec<_> = mirrors.iter()
+        .map(|m| offset(m, options.stagger))
+        .collect();
+    let results =
+        update_staggered(&mirrors, &delays, update_options, options.jobs);
+    for (mirror, (res, duration)) in mirrors.iter().zip(&results) {
+        match res {
+            Ok(()) => info!(
+                "cycle={} mirror={:?} status=ok duration={:.1}",
+                cycle,
+                mirror,
+                seconds(*duration),
+            ),
+            Err(Error::Interrupted) => info!(
+                "cycle={} mirror={:?} status=interrupted duration={:.1}",
+                cycle,
+                mirror,
+                seconds(*duration),
+            ),
+            Err(e) => {
+                error!(
+                    "cycle={} mirror={:?} status=error duration={:.1} \
+                     error={:?}",
+                    cycle,
+                    mirror,
+                    seconds(*duration),
+                    e.to_string(),
+                );
+                failed += 1;
+            }
+        }
+    }
+    Ok(CycleReport {
+        mirrors: mirrors.len() + failures.len(),
+        failed,
+        duration: start.elapsed(),
+    })
+}
+
+/// Update all the mirrors under a directory, until a shutdown is requested
+///
+/// A cycle starts every `interval`, or right after the previous one if it
+/// took longer than that.
+pub fn run(
+    root: &Path,
+    update_options: &UpdateOptions,
+    options: &DaemonOptions,
+) {
+    run_then(root, update_options, options, || {})
+}
+
+/// Update all the mirrors under a directory until a shutdown is requested,
+/// calling `after_cycle` at the end of each cycle
+pub fn run_then<F: FnMut()>(
+    root: &Path,
+    update_options: &UpdateOptions,
+    options: &DaemonOptions,
+    mut after_cycle: F,
+) {
+    systemd::start_watchdog();
+    systemd::notify("READY=1");
+    let mut cycle = 0;
+    while !shutdown::requested() {
+        cycle += 1;
+        let start = Instant::now();
+        match run_cycle(root, update_options, options, cycle) {
+            Ok(report) => {
+                info!(
+                    "cycle={} status=done mirrors={} failed={} \
+                     duration={:.1}",
+                    cycle,
+                    report.mirrors,
+                    report.failed,
+                    seconds(report.duration),
+                );
+                systemd::notify(&format!(
+                    "STATUS=Cycle {} updated {} mirrors, {} failed",
+                    cycle, report.mirrors, report.failed,
+                ));
+            }
+            Err(e) => {
+                error!(
+                    "cycle={} status=error error={:?}",
+                    cycle,
+                    e.to_string(),
+                );
+                systemd::notify(&format!(
+                    "STATUS=Cycle {} failed: {}",
+                    cycle, e,
+                ));
+            }
+        }
+        after_cycle();
+        let elapsed = start.elapsed();
+        if elapsed < options.interval {
+            shutdown::sleep(options.interval - elapsed);
+        } else {
+            warn!(
+                "cycle={} status=overrun duration={:.1}",
+                cycle,
+                seconds(elapsed),
+            );
+        }
+    }
+    info!("cycle={} status=stopped", cycle);
+    systemd::notify("STOPPING=1");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::{offset, parse_interval};
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_interval("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_interval(" 1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(
+            parse_interval("2d").unwrap(),
+            Duration::from_secs(172_800),
+        );
+        assert!(parse_interval("h").is_err());
+        assert!(parse_interval("-1h").is_err());
+        assert!(parse_interval("1 hour").is_err());
+        assert!(parse_interval("36500d").is_ok());
+        assert!(parse_interval("36501d").is_err());
+        assert!(parse_interval("99999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_offset() {
+        let stagger = Duration::from_secs(600);
+        let a = offset(Path::new("/srv/mirrors/a"), stagger);
+        assert!(a < stagger);
+        assert_eq!(a, offset(Path::new("/srv/mirrors/a"), stagger));
+        assert_ne!(a, offset(Path::new("/srv/mirrors/b"), stagger));
+        assert_eq!(
+            offset(Path::new("/srv/mirrors/a"), Duration::from_secs(0)),
+            Duration::from_secs(0),
+        );
+    }
+}
@@ -0,0 +1,660 @@
+//! The archive database, and upgrading it from older versions
+//!
+//! Changes to the schema are made through migrations, applied in order when
+//! the database is opened. The number of migrations that were applied is
+//! recorded in the `schema_version` table.
+
+use rusqlite::{Connection, OpenFlags, Transaction, TransactionBehavior};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::{Error, git};
+
+/// Changes to the database schema, in order
+///
+/// Never change a migration once released, add a new one. The first one uses
+/// `IF NOT EXISTS`, since archives created before migrations existed already
+/// have some of those tables.
+const MIGRATIONS: &[&str] = &[
+    // 1: everything from before migrations
+    "
+    CREATE TABLE IF NOT EXISTS refs(
+        name TEXT NOT NULL,
+        from_date DATETIME NOT NULL,
+        to_date DATETIME NULL,
+        sha TEXT NOT NULL,
+        tag BOOLEAN NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS fetches(
+        date DATETIME NOT NULL,
+        duration REAL NOT NULL,
+        objects INTEGER NOT NULL,
+        bytes INTEGER NOT NULL,
+        new_refs INTEGER NOT NULL,
+        changed_refs INTEGER NOT NULL,
+        removed_refs INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS rewrite_commits(
+        ref_id INTEGER NOT NULL,
+        sha TEXT NOT NULL,
+        removed BOOLEAN NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS ref_changes(
+        ref_id INTEGER NOT NULL,
+        added INTEGER NOT NULL,
+        removed INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS renames(
+        date DATETIME NOT NULL,
+        old_url TEXT NOT NULL,
+        new_url TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS backups(
+        date DATETIME NOT NULL,
+        remote TEXT NOT NULL,
+        error TEXT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tombstones(
+        date DATETIME NOT NULL,
+        error TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS fork_refs(
+        fork TEXT NOT NULL,
+        name TEXT NOT NULL,
+        from_date DATETIME NOT NULL,
+        to_date DATETIME NULL,
+        sha TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS transcripts(
+        fetch_id INTEGER NOT NULL PRIMARY KEY,
+        stdout BLOB NOT NULL,
+        stderr BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sizes(
+        date DATETIME NOT NULL,
+        objects INTEGER NOT NULL,
+        object_bytes INTEGER NOT NULL,
+        disk_bytes INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS settings(
+        name TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    ",
+    // 2: each run of update, including failed ones
+    "
+    CREATE TABLE updates(
+        start_date DATETIME NOT NULL,
+        end_date DATETIME NOT NULL,
+        success BOOLEAN NOT NULL,
+        error TEXT NULL,
+        new_refs INTEGER NULL,
+        changed_refs INTEGER NULL,
+        removed_refs INTEGER NULL
+    );
+    ",
+    // 3: full-text index of the commit messages
+    "
+    CREATE TABLE commit_messages(
+        sha TEXT NOT NULL PRIMARY KEY,
+        message TEXT NOT NULL
+    );
+    CREATE VIRTUAL TABLE commit_search USING fts5(
+        message,
+        content='commit_messages'
+    );
+    CREATE TRIGGER commit_messages_insert AFTER INSERT ON commit_messages
+    BEGIN
+        INSERT INTO commit_search(rowid, message)
+        VALUES(new.rowid, new.message);
+    END;
+    CREATE TRIGGER commit_messages_delete AFTER DELETE ON commit_messages
+    BEGIN
+        INSERT INTO commit_search(commit_search, rowid, message)
+        VALUES('delete', old.rowid, old.message);
+    END;
+    ",
+    // 4: metadata of the latest commits of the branches, for the web view
+    "
+    CREATE TABLE commits(
+        sha TEXT NOT NULL PRIMARY KEY,
+        parent TEXT NULL,
+        author TEXT NOT NULL,
+        committer TEXT NOT NULL,
+        date DATETIME NOT NULL,
+        subject TEXT NOT NULL
+    );
+    ",
+    // 5: content of the annotated tags, the sha of tags in refs
+    "
+    CREATE TABLE tag_objects(
+        sha TEXT NOT NULL PRIMARY KEY,
+        target TEXT NOT NULL,
+        tagger TEXT NOT NULL,
+        date DATETIME NULL,
+        message TEXT NOT NULL
+    );
+    ",
+    // 6: branches that were probably renamed, linking rows of refs
+    "
+    CREATE TABLE branch_renames(
+        date DATETIME NOT NULL,
+        old_ref_id INTEGER NOT NULL,
+        new_ref_id INTEGER NOT NULL
+    );
+    ",
+    // 7: notes from the user, on a snapshot or a row of refs
+    "
+    CREATE TABLE annotations(
+        created DATETIME NOT NULL,
+        snapshot DATETIME NULL,
+        ref_id INTEGER NULL,
+        note TEXT NOT NULL
+    );
+    ",
+    // 8: indexes for the queries of the web view and of update
+    "
+    CREATE INDEX refs_from_date ON refs(from_date);
+    CREATE INDEX refs_to_date ON refs(to_date);
+    CREATE INDEX refs_snapshot ON refs(tag, to_date, from_date);
+    CREATE INDEX refs_name ON refs(name, tag, to_date);
+    ",
+    // 9: periods of history that retention policies must not expire
+    "
+    CREATE TABLE legal_holds(
+        created DATETIME NOT NULL,
+        from_date DATETIME NULL,
+        to_date DATETIME NULL,
+        reason TEXT NOT NULL
+    );
+    ",
+    // 10: hash chain over the changes recorded by each update
+    "
+    CREATE TABLE audit(
+        date DATETIME NOT NULL,
+        kind TEXT NOT NULL,
+        previous TEXT NULL,
+        hash TEXT NOT NULL
+    );
+    ",
+    // 11: proofs from a timestamping service that audit entries existed
+    "
+    CREATE TABLE timestamps(
+        audit_id INTEGER NOT NULL,
+        date DATETIME NOT NULL,
+        proof BLOB NULL,
+        error TEXT NULL
+    );
+    ",
+    // 12: which fetch opened and closed each row of refs
+    "
+    ALTER TABLE refs ADD COLUMN fetch_id INTEGER NULL;
+    ALTER TABLE refs ADD COLUMN to_fetch_id INTEGER NULL;
+    ALTER TABLE fork_refs ADD COLUMN fetch_id INTEGER NULL;
+    ALTER TABLE fork_refs ADD COLUMN to_fetch_id INTEGER NULL;
+    UPDATE refs SET
+        fetch_id=(SELECT max(rowid) FROM fetches WHERE date=from_date),
+        to_fetch_id=(SELECT max(rowid) FROM fetches WHERE date=to_date);
+    UPDATE fork_refs SET
+        fetch_id=(SELECT max(rowid) FROM fetches WHERE date=from_date),
+        to_fetch_id=(SELECT max(rowid) FROM fetches WHERE date=to_date);
+    ",
+    // 13: refs whose history was removed on request
+    "
+    CREATE TABLE redactions(
+        date DATETIME NOT NULL,
+        fork TEXT NULL,
+        name TEXT NOT NULL,
+        tag BOOLEAN NOT NULL,
+        intervals INTEGER NOT NULL,
+        reason TEXT NOT NULL
+    );
+    ",
+];
+
+/// Name of the database file, in the mirror
+pub(crate) const FILENAME: &str = "gitarchive.sqlite3";
+
+lazy_static! {
+    /// Directory holding the databases, see `set_database_dir()`
+    static ref DATABASE_DIR: RwLock<Option<PathBuf>> = RwLock::new(
+        std::env::var_os("DOUBLEGIT_DATABASE_DIR").map(PathBuf::from)
+    );
+}
+
+/// Keep the databases of all the mirrors in a directory
+///
+/// By default, this is the `DOUBLEGIT_DATABASE_DIR` environment variable if
+/// set, otherwise each database is in its mirror. The `doublegit.database`
+/// setting of a mirror takes precedence over this.
+pub fn set_database_dir<P: Into<PathBuf>>(dir: P) {
+    *DATABASE_DIR.write().unwrap() = Some(dir.into());
+}
+
+/// Find the database of a mirror
+///
+/// This is the `doublegit.database` setting (relative to the mirror), or a
+/// file in the directory set with `set_database_dir()` named after the full
+/// path of the mirror, or `gitarchive.sqlite3` in the mirror.
+pub fn path(repository: &Path) -> Result<PathBuf, Error> {
+    if let Some(path) = git::mirror_config(repository)?.get("database") {
+        return Ok(repository.join(path));
+    }
+    if let Some(ref dir) = *DATABASE_DIR.read().unwrap() {
+        let mut name = String::new();
+        for c in repository.canonicalize()?.to_string_lossy().chars() {
+            match c {
+                '%' => name.push_str("%25"),
+                '/' => name.push_str("%2F"),
+                '\\' => name.push_str("%5C"),
+                ':' => name.push_str("%3A"),
+                c => name.push(c),
+            }
+        }
+        return Ok(dir.join(format!("{}.sqlite3", name)));
+    }
+    Ok(repository.join(FILENAME))
+}
+
+/// Find the key of a mirror's encrypted database
+///
+/// This is the `doublegit.databaseKey` setting (or the environment variable
+/// named by `doublegit.databaseKeyEnv`, or what `doublegit.databaseKeyCommand`
+/// prints), otherwise the `DOUBLEGIT_DATABASE_KEY` environment variable. If
+/// there is none, the database is not encrypted.
+pub(crate) fn key(repository: &Path) -> Result<Option<String>, Error> {
+    let config = git::mirror_config(repository)?;
+    if let Some(key) = git::secret_setting(&config, "databaseKey")? {
+        return Ok(Some(key));
+    }
+    Ok(std::env::var("DOUBLEGIT_DATABASE_KEY").ok().filter(|k| !k.is_empty()))
+}
+
+/// Quote a string for SQL, where parameters can't be used
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Check that encrypted databases can be opened, i.e. SQLite is SQLCipher
+#[cfg(feature = "sqlcipher")]
+fn require_sqlcipher() -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+fn require_sqlcipher() -> Result<(), Error> {
+    Err(Error::Config(
+        "a database key is set, but doublegit was built without SQLCipher \
+         support (feature \"sqlcipher\")".into(),
+    ))
+}
+
+/// Attach the database of another mirror, for queries across both
+pub(crate) fn attach(
+    db: &Connection,
+    path: &Path,
+    name: &str,
+    key: Option<&str>,
+) -> Result<(), Error> {
+    let path = path.to_str().ok_or_else(|| {
+        Error::NotFound(format!(
+            "UTF-8 path to database, got {}",
+            path.display()
+        ))
+    })?;
+    match key {
+        Some(key) => {
+            require_sqlcipher()?;
+            db.execute(
+                &format!("ATTACH DATABASE ? AS {} KEY ?;", name),
+                &[path, key],
+            )?;
+        }
+        None => {
+            db.execute(
+                &format!("ATTACH DATABASE ? AS {};", name),
+                &[path],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Make a consistent copy of a database, even while it is being written to
+///
+/// The copy is encrypted with the same key as the original.
+pub(crate) fn copy(
+    db: &Connection,
+    dest: &Path,
+    key: Option<&str>,
+) -> Result<(), Error> {
+    match key {
+        // The backup API doesn't work with encrypted databases, export the
+        // content instead
+        Some(key) => {
+            attach(db, dest, "copy", Some(key))?;
+            db.query_row(
+                "SELECT sqlcipher_export('copy');",
+                rusqlite::NO_PARAMS,
+                |_| (),
+            )?;
+            db.execute("DETACH DATABASE copy;", rusqlite::NO_PARAMS)?;
+        }
+        None => {
+            db.backup(rusqlite::DatabaseName::Main, dest, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// How long SQLite waits for a lock held by another connection, in seconds
+const BUSY_TIMEOUT: u64 = 5;
+
+/// How many times we try again when the database is locked anyway
+const BUSY_RETRIES: u32 = 5;
+
+/// Open a connection with a busy timeout, unlocking it with the key if any
+fn connect_with_flags(
+    path: &Path,
+    key: Option<&str>,
+    flags: OpenFlags,
+) -> Result<Connection, Error> {
+    if key.is_some() {
+        require_sqlcipher()?;
+    }
+    let db = Connection::open_with_flags(path, flags)?;
+    // Unlock the encrypted database, before anything else is done with it
+    if let Some(key) = key {
+        db.execute_batch(&format!("PRAGMA key = {};", quote(key)))?;
+    }
+    db.busy_timeout(Duration::from_secs(BUSY_TIMEOUT))?;
+    Ok(db)
+}
+
+/// Open a connection, in WAL mode with a busy timeout
+///
+/// WAL lets the web server read while `update` writes, and the timeout makes
+/// the writers wait for each other instead of failing right away.
+pub(crate) fn connect(
+    path: &Path,
+    key: Option<&str>,
+) -> Result<Connection, Error> {
+    let db = connect_with_flags(path, key, OpenFlags::default())?;
+    let mode: String = retry(|| {
+        db.query_row(
+            "PRAGMA journal_mode=WAL;",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )
+    })?;
+    if mode != "wal" {
+        warn!("Couldn't enable WAL mode on database, using {}", mode);
+    }
+    Ok(db)
+}
+
+/// Whether an error is a transient locking error, worth trying again
+pub fn is_busy(error: &rusqlite::Error) -> bool {
+    match *error {
+        rusqlite::Error::SqliteFailure(ref e, _) => {
+            e.code == rusqlite::ErrorCode::DatabaseBusy
+                || e.code == rusqlite::ErrorCode::DatabaseLocked
+        }
+        _ => false,
+    }
+}
+
+/// Run some database operation, trying again if the database is locked
+///
+/// This is for the cases where the busy timeout isn't enough, for example
+/// SQLite will return `SQLITE_BUSY` immediately if waiting could deadlock.
+pub fn retry<T, F>(mut f: F) -> rusqlite::Result<T>
+where
+    F: FnMut() -> rusqlite::Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(ref e) if is_busy(e) && attempt < BUSY_RETRIES => {
+                warn!("Database is locked, trying again...");
+                std::thread::sleep(Duration::from_millis(100 << attempt));
+                attempt += 1;
+            }
+            r => return r,
+        }
+    }
+}
+
+/// Start a write transaction
+///
+/// The lock is taken right away (`BEGIN IMMEDIATE`), so that we wait for
+/// other writers here, with the busy timeout, rather than fail with
+/// `SQLITE_BUSY` halfway through because our snapshot became stale.
+pub fn begin(db: &mut Connection) -> Result<Transaction<'_>, Error> {
+    let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    Ok(tx)
+}
+
+/// Find the database of an existing archive
+fn existing_path(repository: &Path) -> Result<PathBuf, Error> {
+    if !repository.is_dir() {
+        return Err(Error::NotFound(format!(
+            "no mirror at {}",
+            repository.display()
+        )));
+    }
+    let path = path(repository)?;
+    if !path.exists() {
+        return Err(Error::NotFound(format!(
+            "no archive database for {} at {}",
+            repository.display(),
+            path.display(),
+        )));
+    }
+    Ok(path)
+}
+
+/// Open the database of an existing archive, upgrading it if needed
+pub fn open(repository: &Path) -> Result<Connection, Error> {
+    let path = existing_path(repository)?;
+    let key = key(repository)?;
+    let mut db = connect(&path, key.as_ref().map(String::as_str))?;
+    migrate(&mut db)?;
+    Ok(db)
+}
+
+/// Open the database of an existing archive without writing to it
+///
+/// Nothing is upgraded, and the journal mode is left as it is. This fails if
+/// the database isn't at the latest schema version, since the queries expect
+/// it.
+pub fn open_read_only(repository: &Path) -> Result<Connection, Error> {
+    let path = existing_path(repository)?;
+    let key = key(repository)?;
+    let db = connect_read_only(&path, key.as_ref().map(String::as_str))?;
+    let version = stored_version(&db)?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::SchemaTooNew(version));
+    } else if version < MIGRATIONS.len() {
+        return Err(Error::Config(format!(
+            "the database is at schema version {}, run `doublegit update` \
+             to upgrade it to {}",
+            version,
+            MIGRATIONS.len(),
+        )));
+    }
+    Ok(db)
+}
+
+/// Open a connection that can't write to the database
+pub(crate) fn connect_read_only(
+    path: &Path,
+    key: Option<&str>,
+) -> Result<Connection, Error> {
+    connect_with_flags(
+        path,
+        key,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+}
+
+/// Open the database of an archive, creating it if it doesn't exist
+pub fn create(repository: &Path) -> Result<Connection, Error> {
+    let path = path(repository)?;
+    if !path.exists() {
+        warn!("Database doesn't exist, creating tables...");
+    }
+    let key = key(repository)?;
+    let mut db = connect(&path, key.as_ref().map(String::as_str))?;
+    migrate(&mut db)?;
+    Ok(db)
+}
+
+/// Get the schema version of a database, 0 if it predates migrations
+pub fn schema_version(db: &Connection) -> Result<usize, Error> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version(version INTEGER NOT NULL);",
+        rusqlite::NO_PARAMS,
+    )?;
+    stored_version(db)
+}
+
+/// Get the schema version of a database without creating the table
+pub fn stored_version(db: &Connection) -> Result<usize, Error> {
+    let exists: bool = db.query_row(
+        "
+        SELECT count(*) > 0 FROM sqlite_master
+        WHERE type='table' AND name='schema_version';
+        ",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(0);
+    }
+    let version: Option<i64> = db.query_row(
+        "SELECT max(version) FROM schema_version;",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    Ok(version.unwrap_or(0) as usize)
+}
+
+/// Get the schema version this version of doublegit upgrades databases to
+pub fn latest_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Apply the migrations that the database is missing
+///
+/// Each one is done in its own transaction. A database with a version we
+/// don't know about was upgraded by a newer doublegit, and we refuse to use it.
+pub fn migrate(db: &mut Connection) -> Result<(), Error> {
+    let version = schema_version(db)?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::SchemaTooNew(version));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Upgrading database to schema version {}", i + 1);
+        let tx = begin(db)?;
+        tx.execute_batch(migration)?;
+        tx.execute(
+            "INSERT INTO schema_version(version) VALUES(?);",
+            &[(i + 1) as i64],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use crate::Error;
+    use super::{MIGRATIONS, begin, create, migrate, open, schema_version};
+
+    #[test]
+    fn test_migrate() {
+        // Database from before migrations, with only some tables
+        let mut db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "
+            CREATE TABLE refs(
+                name TEXT NOT NULL,
+                from_date DATETIME NOT NULL,
+                to_date DATETIME NULL,
+                sha TEXT NOT NULL,
+                tag BOOLEAN NOT NULL
+            );
+            INSERT INTO refs(name, from_date, to_date, sha, tag)
+            VALUES('master', '2019-01-01 00:00:00', NULL, 'abc', 0);
+            ",
+        ).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), 0);
+
+        migrate(&mut db).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), MIGRATIONS.len());
+        let count: i64 = db.query_row(
+            "SELECT count(*) FROM refs;",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(count, 1);
+        let count: i64 = db.query_row(
+            "SELECT count(*) FROM sizes;",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(count, 0);
+
+        // Migrating again does nothing
+        migrate(&mut db).unwrap();
+        let count: i64 = db.query_row(
+            "SELECT count(*) FROM schema_version;",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(count, MIGRATIONS.len() as i64);
+
+        // Database from the future
+        db.execute(
+            "INSERT INTO schema_version(version) VALUES(?);",
+            &[MIGRATIONS.len() as i64 + 1],
+        ).unwrap();
+        match migrate(&mut db) {
+            Err(Error::SchemaTooNew(v)) => assert_eq!(v, MIGRATIONS.len() + 1),
+            _ => panic!("Database from the future was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_wal() {
+        let test_dir = tempfile::Builder::new()
+            .prefix("doublegit_test_")
+            .tempdir().unwrap();
+        let mut writer = create(test_dir.path()).unwrap();
+        let mode: String = writer.query_row(
+            "PRAGMA journal_mode;",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(mode, "wal");
+
+        // Readers are not blocked by a write in progress
+        let tx = begin(&mut writer).unwrap();
+        tx.execute(
+            "INSERT INTO settings(name, value) VALUES('test', 'value');",
+            rusqlite::NO_PARAMS,
+        ).unwrap();
+        let reader = open(test_dir.path()).unwrap();
+        let count: i64 = reader.query_row(
+            "SELECT count(*) FROM settings;",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(count, 0);
+        tx.commit().unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//! Diagnosing problems with the Git executable and with mirrors
+//!
+//! `status` reports how updates went; this looks for the causes, including
+//! settings that don't break anything yet, and says how to fix each one.
+
+use std::path::Path;
+
+use crate::{
+    Error, KeepNamespaces, config_problems, db, git, recorded_keep_namespaces,
+};
+use crate::archive::check_consistency;
+
+/// Version of Git needed to ignore the user's global config
+const MIN_GIT_VERSION: (u32, u32, u32) = (2, 32, 0);
+
+/// The fetch refspec set by `init`
+const DEFAULT_REFSPEC: &str = "+refs/heads/*:refs/remotes/origin/*";
+
+/// Outcome of one of the checks
+#[derive(Debug)]
+pub struct Finding {
+    /// What was checked, e.g. `database`
+    pub check: &'static str,
+    /// What was found
+    pub message: String,
+    /// How to fix it, if this is a problem
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: String) -> Finding {
+        Finding { check, message, fix: None }
+    }
+
+    fn problem(check: &'static str, message: String, fix: String) -> Finding {
+        Finding { check, message, fix: Some(fix) }
+    }
+
+    /// Whether this needs attention
+    pub fn is_problem(&self) -> bool {
+        self.fix.is_some()
+    }
+}
+
+/// Check the Git executable doublegit runs
+pub fn check_git() -> Finding {
+    match git::version() {
+        Ok(version) if version < MIN_GIT_VERSION => Finding::problem(
+            "git",
+            format!(
+                "Git {}.{}.{} reads the user's global config, which can \
+                 change how mirrors are fetched",
+                version.0, version.1, version.2,
+            ),
+            format!(
+                "install Git {}.{} or later, and select it with `doublegit \
+                 --git` or DOUBLEGIT_GIT",
+                MIN_GIT_VERSION.0, MIN_GIT_VERSION.1,
+            ),
+        ),
+        Ok(version) => Finding::ok(
+            "git",
+            format!("Git {}.{}.{}", version.0, version.1, version.2),
+        ),
+        Err(e) => Finding::problem(
+            "git",
+            format!("can't run Git: {}", e),
+            "install Git, or select it with `doublegit --git` or \
+             DOUBLEGIT_GIT"
+                .into(),
+        ),
+    }
+}
+
+/// Check a mirror
+///
+/// If `online` is set, this also connects to the remote, to check that the
+/// URL and credentials work. Nothing is changed either way.
+pub fn check_mirror(
+    repository: &Path,
+    online: bool,
+) -> Result<Vec<Finding>, Error> {
+    let config = git::mirror_config(repository)?;
+    let git_config = |key: &str| {
+        format!("git -C {} config {}", repository.display(), key)
+    };
+    let mut findings = Vec::new();
+
+    let problems = config_problems(repository)?;
+    if problems.is_empty() {
+        findings.push(Finding::ok("config", "settings are valid".into()));
+    }
+    for problem in problems {
+        findings.push(Finding::problem(
+            "config",
+            problem,
+            format!("correct the setting with `{}`", git_config("...")),
+        ));
+    }
+
+    // Updates pass their own refspecs, but the configured ones are used by
+    // `git fetch` run by hand, which could then overwrite or prune keep-refs
+    let keep = KeepNamespaces::from_config(&config)?;
+    let refspecs = git::get_config_all(repository, "remote.origin.fetch")?;
+    let prune = git::get_config_all(repository, "remote.origin.prune")?
+        .into_iter()
+        .chain(git::get_config_all(repository, "fetch.prune")?)
+        .any(|v| v == "true");
+    let mut refspecs_ok = true;
+    for refspec in refspecs.iter().filter(|r| *r != DEFAULT_REFSPEC) {
+        refspecs_ok = false;
+        let destination = refspec.rsplit(':').next().unwrap_or("");
+        let destination = destination.trim_end_matches('*');
+        let message = if !destination.is_empty()
+            && (keep.branches.starts_with(destination)
+                || keep.tags.starts_with(destination))
+        {
+            format!(
+                "remote.origin.fetch {:?} writes over the keep-refs, {}",
+                refspec,
+                if prune {
+                    "and prune deletes them, losing the archived objects"
+                } else {
+                    "losing track of the archived objects"
+                },
+            )
+        } else {
+            format!(
+                "remote.origin.fetch {:?} is not the one doublegit sets up",
+                refspec,
+            )
+        };
+        findings.push(Finding::problem(
+            "fetch",
+            message,
+            format!(
+                "run `{} '{}'`",
+                git_config("--replace-all remote.origin.fetch"),
+                DEFAULT_REFSPEC,
+            ),
+        ));
+    }
+    let mirror = git::get_config_all(repository, "remote.origin.mirror")?;
+    if mirror.iter().any(|v| v == "true") {
+        refspecs_ok = false;
+        findings.push(Finding::problem(
+            "fetch",
+            "remote.origin.mirror is set, `git fetch` and `git push` would \
+             mirror all refs, including the keep-refs"
+                .into(),
+            format!("run `{}`", git_config("--unset remote.origin.mirror")),
+        ));
+    }
+    if refspecs_ok {
+        findings.push(Finding::ok(
+            "fetch",
+            format!(
+                "refspecs don't touch the keep-refs{}",
+                if prune { " (prune is on)" } else { "" },
+            ),
+        ));
+    }
+
+    let (finding, current) = check_database(repository)?;
+    findings.push(finding);
+
+    // Only look at the objects if the database can be read as it is
+    if current {
+        let db = db::open_read_only(repository)?;
+        let recorded = recorded_keep_namespaces(&db)?;
+        if recorded != keep {
+            findings.push(Finding::ok(
+                "keep-refs",
+                format!(
+                    "keep-refs are under {} and {}, they will be moved to \
+                     {} and {} on the next update",
+                    recorded.branches, recorded.tags, keep.branches,
+                    keep.tags,
+                ),
+            ));
+        }
+        let report = check_consistency(repository)?;
+        if !report.missing.is_empty() {
+            findings.push(Finding::problem(
+                "objects",
+                format!(
+                    "{} objects recorded in the database are missing, e.g. \
+                     {}",
+                    report.missing.len(),
+                    report.missing[0],
+                ),
+                "fetch them from a clone that still has them with `git \
+                 fetch <clone> <sha>`, or restore the mirror from a backup"
+                    .into(),
+            ));
+        }
+        if !report.unprotected.is_empty()
+            || !report.mismatched.is_empty()
+            || !report.superfluous.is_empty()
+        {
+            findings.push(Finding::problem(
+                "objects",
+                format!(
+                    "keep-refs are inconsistent: {} objects unprotected, {} \
+                     mismatched keep-refs, {} superfluous",
+                    report.unprotected.len(),
+                    report.mismatched.len(),
+                    report.superfluous.len(),
+                ),
+                format!(
+                    "run `doublegit verify --repair {}`",
+                    repository.display(),
+                ),
+            ));
+        }
+        if report.is_ok() {
+            findings.push(Finding::ok(
+                "objects",
+                "all recorded objects are present and kept".into(),
+            ));
+        }
+    }
+
+    if online {
+        findings.push(match git::check_remote(repository) {
+            Ok(()) => Finding::ok("remote", "origin is reachable".into()),
+            Err(e) => {
+                let fix = if config.contains_key("token")
+                    || config.contains_key("tokenenv")
+                    || config.contains_key("tokencommand")
+                {
+                    "check that the access token is valid, not expired, and \
+                     can read the repository"
+                } else if config.contains_key("sshkey") {
+                    "check that doublegit.sshKey is authorized to read the \
+                     repository"
+                } else {
+                    "check remote.origin.url, and set up credentials if the \
+                     repository is private"
+                };
+                Finding::problem(
+                    "remote",
+                    format!("can't reach origin: {}", e),
+                    fix.into(),
+                )
+            }
+        });
+    }
+
+    Ok(findings)
+}
+
+/// Check that the database exists, can be read, and has a known schema
+///
+/// Also returns whether the database is at the latest schema version, so
+/// that it can be queried without upgrading it.
+fn check_database(repository: &Path) -> Result<(Finding, bool), Error> {
+    let path = db::path(repository)?;
+    if !path.exists() {
+        let finding = Finding::problem(
+            "database",
+            format!("{} doesn't exist", path.display()),
+            format!(
+                "run `doublegit update {}` to create it, or set \
+                 doublegit.database if it was moved",
+                repository.display(),
+            ),
+        );
+        return Ok((finding, false));
+    }
+    let version = db::key(repository).and_then(|key| {
+        let db =
+            db::connect_read_only(&path, key.as_ref().map(String::as_str))?;
+        db::stored_version(&db)
+    });
+    let latest = db::latest_version();
+    let current = version.as_ref().ok() == Some(&latest);
+    let finding = match version {
+        Err(e) => Finding::problem(
+            "database",
+            format!("can't read {}: {}", path.display(), e),
+            "check doublegit.databaseKey, and that the file is a doublegit \
+             database"
+                .into(),
+        ),
+        Ok(version) if version > latest => Finding::problem(
+            "database",
+            format!(
+                "schema version {} is from a newer doublegit (this one has \
+                 {})",
+                version, latest,
+            ),
+            "upgrade doublegit".into(),
+        ),
+        Ok(version) if version < latest => Finding::ok(
+            "database",
+            format!(
+                "schema version {}, upgraded to {} on the next update (the \
+                 objects are checked after that)",
+                version, latest,
+            ),
+        ),
+        Ok(version) => {
+            Finding::ok("database", format!("schema version {}", version))
+        }
+    };
+    Ok((finding, current))
+}
@@ -0,0 +1,435 @@
+//! Export archived content to other formats
+
+use rusqlite::Connection;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{MirrorStatus, RefDiff};
+use crate::{Error, RefName, db, git, refs_at};
+
+/// Export the refs alive at a given snapshot as a Git bundle
+///
+/// If `date` is `None`, the current refs are exported. Branches are written as
+/// `refs/heads/<name>` and tags as `refs/tags/<name>`, as they were upstream.
+pub fn export_bundle(
+    repository: &Path,
+    date: Option<&str>,
+    output: &Path,
+) -> Result<(), Error> {
+    let db = db::open(repository)?;
+    let refs = refs_at(&db, date)?;
+    if refs.is_empty() {
+        return Err(Error::NotFound(format!(
+            "no refs alive at {}",
+            date.unwrap_or("latest")
+        )));
+    }
+    let refs: Vec<_> = refs
+        .into_iter()
+        .map(|(ref_, sha)| {
+            let name = if ref_.tag {
+                ref_.name.with_prefix("refs/tags/")
+            } else {
+                ref_.name.with_prefix("refs/heads/")
+            };
+            (name, sha)
+        })
+        .collect();
+    info!("Bundling {} refs", refs.len());
+    git::create_bundle(repository, &refs, output)
+}
+
+/// Write the files of a branch as they were at a given snapshot
+///
+/// If `date` is `None`, the current position of the branch is used. If no
+/// branch is given, `master` is used, or the only branch if there is just one.
+/// `dest` must not exist or be empty.
+///
+/// Returns the SHA-1 of the commit that was checked out.
+pub fn checkout_at(
+    repository: &Path,
+    date: Option<&str>,
+    branch: Option<&str>,
+    dest: &Path,
+) -> Result<String, Error> {
+    if dest.exists() && std::fs::read_dir(dest)?.next().is_some() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not empty", dest.display()),
+        )));
+    }
+    let db = db::open(repository)?;
+    let branches: Vec<_> = refs_at(&db, date)?
+        .into_iter()
+        .filter(|(ref_, _)| !ref_.tag)
+        .collect();
+    let found = match branch {
+        Some(branch) => branches
+            .iter()
+            .find(|(ref_, _)| ref_.name.as_bytes() == branch.as_bytes()),
+        None if branches.len() == 1 => branches.first(),
+        None => branches
+            .iter()
+            .find(|(ref_, _)| ref_.name.as_bytes() == b"master"),
+    };
+    let sha = match found {
+        Some((_, sha)) => sha.clone(),
+        None => {
+            return Err(Error::NotFound(format!(
+                "branch {} at {}",
+                branch.unwrap_or("master"),
+                date.unwrap_or("latest")
+            )));
+        }
+    };
+    info!("Checking out {} into {}", sha, dest.display());
+    git::checkout_tree(repository, &sha, dest)?;
+    Ok(sha)
+}
+
+/// Export the whole archive as a git-fast-export stream
+///
+/// This covers all the archived objects, including branches that were deleted
+/// or rewritten upstream and the tags that were kept.
+pub fn export_fast_export<W: Write>(
+    repository: &Path,
+    out: &mut W,
+) -> Result<(), Error> {
+    git::fast_export(repository, out)
+}
+
+/// Outcome of `replay()`
+#[derive(Debug)]
+pub struct ReplayReport {
+    /// Number of branches written
+    pub branches: usize,
+    /// Number of commits made, one per change of a branch
+    pub commits: usize,
+    /// Positions of branches that were skipped because the commit is missing
+    pub missing: usize,
+}
+
+/// Make a repository with one commit per snapshot of each branch
+///
+/// Each branch gets a linear history: a commit for each position it had
+/// upstream, with the tree of the commit it pointed to and dated when the
+/// archive saw it, then a commit with an empty tree if it was deleted. Git
+/// tools can then go through the timeline, e.g. `git bisect` finds the
+/// snapshot a change appeared in. The new repository is bare, and has its
+/// own copy of the objects.
+pub fn replay(
+    repository: &Path,
+    output: &Path,
+) -> Result<ReplayReport, Error> {
+    if output.exists() && std::fs::read_dir(output)?.next().is_some() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not empty", output.display()),
+        )));
+    }
+    let db = db::open(repository)?;
+    let history = ref_history(&db)?;
+    git::init_bare(output)?;
+    git::add_alternate(output, &repository.canonicalize()?.join("objects"))?;
+
+    let mut report = ReplayReport { branches: 0, commits: 0, missing: 0 };
+    let mut branches = Vec::new();
+    let mut intervals = history.iter().filter(|i| !i.tag).peekable();
+    while let Some(first) = intervals.next() {
+        let name = &first.name;
+        let mut tip: Option<String> = None;
+        let mut last_date = first.from_date.clone();
+        let mut interval = Some(first);
+        while let Some(i) = interval {
+            // Deleted, before being created again or for good
+            let deleted = i.to_date.as_ref().filter(|to| {
+                match intervals.peek() {
+                    Some(next) if next.name == *name => next.from_date != **to,
+                    _ => true,
+                }
+            });
+            if git::object_exists(repository, &i.sha)? {
+                let message = format!(
+                    "{} at {}\n\nSnapshot {}\n",
+                    name, i.sha, i.from_date,
+                );
+                tip = Some(git::commit_tree(
+                    output,
+                    Some(&i.sha),
+                    tip.as_ref().map(String::as_str),
+                    &message,
+                    &i.from_date,
+                )?);
+                report.commits += 1;
+            } else {
+                warn!("{} at {} is missing, skipping it", name, i.sha);
+                report.missing += 1;
+            }
+            if let Some(to) = deleted {
+                let message =
+                    format!("{} deleted\n\nSnapshot {}\n", name, to);
+                tip = Some(git::commit_tree(
+                    output,
+                    None,
+                    tip.as_ref().map(String::as_str),
+                    &message,
+                    to,
+                )?);
+                report.commits += 1;
+            }
+            last_date = i.to_date.as_ref().unwrap_or(&i.from_date).clone();
+            interval = match intervals.peek() {
+                Some(next) if next.name == *name => intervals.next(),
+                _ => None,
+            };
+        }
+        if let Some(tip) = tip {
+            branches.push((name.clone(), tip, last_date));
+        }
+    }
+
+    // HEAD is master, or the branch that changed last
+    let head = branches
+        .iter()
+        .find(|(name, _, _)| name.as_bytes() == b"master")
+        .or_else(|| branches.iter().max_by(|a, b| a.2.cmp(&b.2)));
+    if let Some((name, _, _)) = head {
+        git::set_head(output, name)?;
+    }
+    report.branches = branches.len();
+    let refs: Vec<_> = branches
+        .into_iter()
+        .map(|(name, tip, _)| (name.with_prefix("refs/heads/"), Some(tip)))
+        .collect();
+    git::update_refs(output, &refs)?;
+    git::dissociate(output)?;
+    info!(
+        "Replayed {} branches as {} commits",
+        report.branches, report.commits,
+    );
+    Ok(report)
+}
+
+/// One row of the refs history: a ref pointing to a commit for some time
+#[derive(Serialize)]
+struct RefInterval {
+    name: RefName,
+    tag: bool,
+    sha: String,
+    from_date: String,
+    to_date: Option<String>,
+}
+
+/// Read the whole history of the refs, ordered by name then date
+fn ref_history(db: &Connection) -> Result<Vec<RefInterval>, Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT name, tag, sha, from_date, to_date FROM refs
+        ORDER BY tag, name, from_date;
+        ",
+    )?;
+    let mut history = Vec::new();
+    for row in stmt.query_map(rusqlite::NO_PARAMS, |row| RefInterval {
+        name: row.get(0),
+        tag: row.get(1),
+        sha: row.get(2),
+        from_date: row.get(3),
+        to_date: row.get(4),
+    })? {
+        history.push(row?);
+    }
+    Ok(history)
+}
+
+/// Quote a field for CSV, if needed
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Write a value as JSON, indented, followed by a newline
+///
+/// This is used for the `--json` output of the commands, e.g. with the
+/// `LogEntry`, `TagEntry`, `SnapshotRef`, or `ArchiveStats` they return.
+pub fn write_json<T, W>(value: &T, out: &mut W) -> Result<(), Error>
+where
+    T: Serialize + ?Sized,
+    W: Write,
+{
+    serde_json::to_writer_pretty(&mut *out, value)
+        .map_err(std::io::Error::from)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Export the history of the branches and tags as JSON
+///
+/// This is an array with one object for each time a ref pointed to a commit:
+/// `name`, `tag` (boolean), `sha`, `from_date`, and `to_date` (`null` if the
+/// ref still points there).
+pub fn export_history_json<W: Write>(
+    repository: &Path,
+    out: &mut W,
+) -> Result<(), Error> {
+    let db = db::open_read_only(repository)?;
+    write_json(&ref_history(&db)?, out)
+}
+
+/// Write an error as JSON, an object with only a `error` field
+pub fn write_error_json<W: Write>(
+    error: &str,
+    out: &mut W,
+) -> Result<(), Error> {
+    #[derive(Serialize)]
+    struct ErrorJson<'a> {
+        error: &'a str,
+    }
+
+    write_json(&ErrorJson { error }, out)
+}
+
+/// Write the outcome of updating mirrors as JSON
+///
+/// This is an array with one object per mirror: `repository`, `error`
+/// (`null` if the update succeeded), and `changes`, the refs created, moved,
+/// or deleted, each with `name`, `tag` (boolean), `old` and `new` (`null` for
+/// a created or deleted ref).
+pub fn write_update_json<W: Write>(
+    updates: &[(PathBuf, Result<Vec<RefDiff>, Error>)],
+    out: &mut W,
+) -> Result<(), Error> {
+    #[derive(Serialize)]
+    struct UpdateJson<'a> {
+        repository: String,
+        error: Option<String>,
+        changes: &'a [RefDiff],
+    }
+
+    let updates: Vec<_> = updates
+        .iter()
+        .map(|(repository, result)| UpdateJson {
+            repository: repository.to_string_lossy().into_owned(),
+            error: result.as_ref().err().map(ToString::to_string),
+            changes: match result {
+                Ok(changes) => changes,
+                Err(_) => &[],
+            },
+        })
+        .collect();
+    write_json(&updates, out)
+}
+
+/// A date with a message, e.g. of the last failed update
+#[derive(Serialize)]
+struct DatedMessage<'a> {
+    date: &'a str,
+    message: &'a str,
+}
+
+impl<'a> DatedMessage<'a> {
+    fn new(value: &'a Option<(String, String)>) -> Option<DatedMessage<'a>> {
+        value.as_ref().map(|(date, message)| DatedMessage { date, message })
+    }
+}
+
+/// Write the health of mirrors from `archive::status()` as JSON
+///
+/// This is an array with one object per mirror: `repository`, `error` if its
+/// status couldn't be read, or `last_success`, `last_error` (an object with
+/// `date` and `message`), `failing`, `live_refs`, `dead_refs`,
+/// `config_problems`, `journal_pending`, `gone` (an object with `date` and
+/// `message`, if the upstream repository was deleted), `consistent`, and
+/// `ok`.
+pub fn write_status_json<W: Write>(
+    statuses: &[(PathBuf, Result<MirrorStatus, Error>)],
+    out: &mut W,
+) -> Result<(), Error> {
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum StatusJson<'a> {
+        Error {
+            repository: String,
+            error: String,
+        },
+        Status {
+            repository: String,
+            last_success: &'a Option<String>,
+            last_error: Option<DatedMessage<'a>>,
+            failing: bool,
+            live_refs: u64,
+            dead_refs: u64,
+            config_problems: &'a [String],
+            journal_pending: bool,
+            gone: Option<DatedMessage<'a>>,
+            consistent: bool,
+            ok: bool,
+        },
+    }
+
+    let statuses: Vec<_> = statuses
+        .iter()
+        .map(|(repository, result)| {
+            let repository = repository.to_string_lossy().into_owned();
+            match result {
+                Ok(status) => StatusJson::Status {
+                    repository,
+                    last_success: &status.last_success,
+                    last_error: DatedMessage::new(&status.last_error),
+                    failing: status.is_failing(),
+                    live_refs: status.live_refs,
+                    dead_refs: status.dead_refs,
+                    config_problems: &status.config_problems,
+                    journal_pending: status.journal_pending,
+                    gone: DatedMessage::new(&status.gone),
+                    consistent: status.verify.is_ok(),
+                    ok: status.is_ok(),
+                },
+                Err(e) => StatusJson::Error {
+                    repository,
+                    error: e.to_string(),
+                },
+            }
+        })
+        .collect();
+    write_json(&statuses, out)
+}
+
+/// Export the history of the branches and tags as CSV
+///
+/// The columns are the same as the JSON export, `tag` is `0` or `1` and
+/// `to_date` is empty if the ref still points there.
+pub fn export_history_csv<W: Write>(
+    repository: &Path,
+    out: &mut W,
+) -> Result<(), Error> {
+    let db = db::open_read_only(repository)?;
+    writeln!(out, "name,tag,sha,from_date,to_date")?;
+    for row in ref_history(&db)? {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            csv_field(&row.name.to_string()),
+            row.tag as u8,
+            row.sha,
+            row.from_date,
+            row.to_date.as_ref().map(String::as_str).unwrap_or(""),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::csv_field;
+
+    #[test]
+    fn test_quoting() {
+        assert_eq!(csv_field("master"), "master");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+    }
+}
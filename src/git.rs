@@ -4,12 +4,19 @@
 //! formats.
 
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{Read, Write};
 use std::ops::Not;
 use std::path::Path;
 use std::process;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::{Error, Ref};
+use crate::{Error, MAX_RETRY_DELAY_SECS, Ref, RefName, UpdateOptions};
+use crate::progress::{Progress, parse_fetch_progress};
+use crate::shutdown;
 
 /// A fetch operation
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -46,273 +53,2952 @@ pub struct FetchOutput {
     pub new: HashSet<Ref>,
     pub changed: HashSet<Ref>,
     pub removed: HashSet<Ref>,
+    /// New URL of the remote, if the server redirected us
+    pub redirect: Option<String>,
+    /// What git-fetch printed, as-is
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
 }
 
-/// Run git-fetch on a repository and parse the ref changes
-pub fn fetch(repository: &Path) -> Result<FetchOutput, Error> {
-    let output = process::Command::new("git")
-        .args(&[
-            "fetch",
-            "--prune",
-            "origin",
-            "+refs/tags/*:refs/tags/*",
-            "+refs/heads/*:refs/remotes/origin/*",
-        ])
-        .current_dir(repository)
-        .stdin(process::Stdio::null())
-        .stdout(process::Stdio::inherit())
+impl FetchOutput {
+    fn empty() -> FetchOutput {
+        FetchOutput {
+            new: HashSet::new(),
+            changed: HashSet::new(),
+            removed: HashSet::new(),
+            redirect: None,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    /// Record a single ref update in the right set
+    fn record(&mut self, op: Operation, ref_: Ref) -> Result<(), Error> {
+        let kind = if ref_.tag { "tag" } else { "branch" };
+        match op {
+            Operation::New => {
+                info!("New {} {}", kind, ref_.name);
+                self.new.insert(ref_);
+            }
+            Operation::FastForward | Operation::Forced | Operation::Tag => {
+                info!("Updated {} {}", kind, ref_.name);
+                self.changed.insert(ref_);
+            }
+            Operation::Pruned => {
+                info!("Pruned {} {}", kind, ref_.name);
+                self.removed.insert(ref_);
+            }
+            Operation::Reject => {
+                return Err(Error::Git(format!(
+                    "Error updating ref {}",
+                    ref_.fullname()
+                )));
+            }
+            Operation::Noop => {}
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// The Git executable, see `set_git_binary()`
+    static ref GIT_BINARY: RwLock<OsString> = RwLock::new(
+        std::env::var_os("DOUBLEGIT_GIT").unwrap_or_else(|| "git".into())
+    );
+}
+
+/// The null device, used to point Git configuration at nothing
+#[cfg(windows)]
+const NULL_DEVICE: &str = "NUL";
+#[cfg(not(windows))]
+const NULL_DEVICE: &str = "/dev/null";
+
+/// Set the Git executable to run
+///
+/// By default, this is the `DOUBLEGIT_GIT` environment variable if set, or
+/// `git` from the `PATH`.
+pub fn set_git_binary<P: Into<OsString>>(path: P) {
+    *GIT_BINARY.write().unwrap() = path.into();
+}
+
+/// Make a Git command with a controlled environment
+///
+/// The system and global Git configuration and the `GIT_*` environment
+/// variables are ignored, so that updates behave the same whichever machine or
+/// user they run as. Hooks and prompts are disabled, and messages are not
+/// translated, since we have to parse some of them.
+pub fn command() -> process::Command {
+    let mut cmd = process::Command::new(&*GIT_BINARY.read().unwrap());
+    for (key, _) in std::env::vars_os() {
+        if key.to_str().map_or(false, |k| k.starts_with("GIT_")) {
+            cmd.env_remove(key);
+        }
+    }
+    cmd.env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", NULL_DEVICE)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env("LC_ALL", "C")
+        .arg("-c")
+        .arg(format!("core.hooksPath={}", NULL_DEVICE));
+    cmd
+}
+
+/// Check that a hash is a full hexadecimal object ID before passing it to Git
+///
+/// Abbreviated hashes and revision expressions are refused, so values coming
+/// from the database or from upstream can't be taken for an option or select
+/// something else.
+pub fn check_sha(sha: &str) -> Result<(), Error> {
+    if (sha.len() == 40 || sha.len() == 64)
+        && sha.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        Ok(())
+    } else {
+        Err(Error::Git(format!("Invalid object ID {:?}", sha)))
+    }
+}
+
+/// Check that a ref name is safe to pass to Git on the command-line
+///
+/// This applies the rules of git-check-ref-format (allowing one-level names
+/// such as `HEAD`), and also refuses names starting with `-`, which Git would
+/// parse as an option. Upstream can create refs that Git accepts but that look
+/// like options (e.g. `refs/tags/--all`), which is why we always pass refs
+/// with their full name.
+pub fn check_ref_name(name: &[u8]) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name[0] != b'-'
+        && name != b"@"
+        && !name.ends_with(b"/")
+        && !name.ends_with(b".")
+        && !name.windows(2).any(|w| w == b".." || w == b"@{")
+        && !name.iter().any(|&b| {
+            b < 0x20 || b == 0x7f || b" ~^:?*[\\".contains(&b)
+        })
+        && name.split(|&b| b == b'/').all(|component| {
+            !component.is_empty()
+                && component[0] != b'.'
+                && !component.ends_with(b".lock")
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::Git(format!(
+            "Invalid ref name {:?}",
+            RefName::from(name).to_string()
+        )))
+    }
+}
+
+/// Get the version of the Git executable, as (major, minor, patch)
+pub fn version() -> Result<(u32, u32, u32), Error> {
+    let output = command()
+        .arg("version")
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
         .output()?;
     if !output.status.success() {
         return Err(Error::Git(format!(
-            "`git fetch` returned {}",
+            "`git version` returned {}",
             output.status
         )));
     }
-    parse_fetch_output(&output.stderr)
+    let output = String::from_utf8_lossy(&output.stdout);
+    parse_version(&output)
+        .ok_or_else(|| Error::git("Can't parse `git version` output"))
 }
 
-/// Parse git-fetch output, broken out for unit testing
-fn parse_fetch_output(output: &[u8]) -> Result<FetchOutput, Error> {
-    lazy_static! {
-        static ref _RE_FETCH: Regex = Regex::new(
-            r"^ ([+t*! -]) +([^ ]+|\[[^\]]+\]) +([^ ]+) +-> +([^ ]+)(?: +(.+))?$"
-        ).unwrap();
+/// Parse `git version` output, e.g. `git version 2.41.0.windows.1`
+fn parse_version(output: &str) -> Option<(u32, u32, u32)> {
+    let version = output.trim().split(' ').nth(2)?;
+    let mut parts = version.split('.').map(|p| p.parse::<u32>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    let patch = parts.next().and_then(Result::ok).unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Read the `doublegit.*` settings from the mirror's Git config
+///
+/// The keys are returned without the `doublegit.` prefix, lowercased as Git
+/// does (e.g. `sshkey`). Settings missing from the mirror take their value
+/// from the `mirror` section of the global config, if any.
+pub fn mirror_config(
+    repository: &Path,
+) -> Result<HashMap<String, String>, Error> {
+    let output = command()
+        .args(&["config", "--null", "--get-regexp", r"^doublegit\."])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    let mut config = crate::config::mirror_defaults();
+    // Exit code 1 means no matching key
+    if output.status.code() == Some(1) {
+        return Ok(config);
+    } else if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git config` returned {}",
+            output.status
+        )));
     }
-    let mut new = HashSet::new();
-    let mut changed = HashSet::new();
-    let mut removed = HashSet::new();
-    for line in output.split(|&b| b == b'\n') {
-        let line = std::str::from_utf8(line)
-            .map_err(|_| Error::git("Non-utf8 branch name"))?;
-        if let Some(m) = _RE_FETCH.captures(line) {
-            info!("> {}", line);
-            let op = m.get(1).map_or("", |m| m.as_str());
-            let to = m.get(4).map_or("", |m| m.as_str());
-
-            let op = parse_operation(op)?;
-            match op {
-                Operation::New => {
-                    if !to.contains('/') { // tag
-                        let ref_ = Ref {
-                            name: to.into(),
-                            tag: true,
-                        };
-                        info!("New tag {}", ref_.name);
-                        new.insert(ref_);
-                    } else {
-                        let ref_ = Ref::parse_remote_ref(to)?;
-                        info!("New branch {}", ref_.name);
-                        new.insert(ref_);
-                    }
-                }
-                Operation::FastForward | Operation::Forced => {
-                    let ref_ = Ref::parse_remote_ref(to)?;
-                    info!("Updated branch {}", ref_.name);
-                    changed.insert(ref_);
+    for entry in output.stdout.split(|&b| b == 0) {
+        let entry = String::from_utf8_lossy(entry);
+        let mut parts = entry.splitn(2, '\n');
+        if let (Some(key), value) = (parts.next(), parts.next()) {
+            if key.starts_with("doublegit.") {
+                config.insert(
+                    key[10..].to_owned(),
+                    value.unwrap_or("").to_owned(),
+                );
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Read all the settings in a file in Git config format, in order
+///
+/// Sections and keys are lowercased by Git, subsections are not.
+pub fn config_file(file: &Path) -> Result<Vec<(String, String)>, Error> {
+    let output = command()
+        .arg("config")
+        .arg("--file")
+        .arg(file)
+        .args(&["--null", "--list"])
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Config(format!(
+            "can't read {}: `git config` returned {}",
+            file.display(),
+            output.status
+        )));
+    }
+    let mut settings = Vec::new();
+    for entry in output.stdout.split(|&b| b == 0) {
+        if entry.is_empty() {
+            continue;
+        }
+        let entry = String::from_utf8_lossy(entry);
+        let mut parts = entry.splitn(2, '\n');
+        if let Some(key) = parts.next() {
+            settings.push((
+                key.to_owned(),
+                parts.next().unwrap_or("").to_owned(),
+            ));
+        }
+    }
+    Ok(settings)
+}
+
+/// Read the projects listed in the config file of a collection
+///
+/// Those are the `project.<name>.url` settings, in the order of the file.
+pub fn collection_projects(
+    file: &Path,
+) -> Result<Vec<(String, String)>, Error> {
+    let output = command()
+        .arg("config")
+        .arg("--file")
+        .arg(file)
+        .args(&["--null", "--get-regexp", r"^project\..*\.url$"])
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    // Exit code 1 means no matching key
+    if output.status.code() == Some(1) {
+        return Ok(Vec::new());
+    } else if !output.status.success() {
+        return Err(Error::Config(format!(
+            "can't read {}: `git config` returned {}",
+            file.display(),
+            output.status
+        )));
+    }
+    let mut projects = Vec::new();
+    for entry in output.stdout.split(|&b| b == 0) {
+        let entry = String::from_utf8_lossy(entry);
+        let mut parts = entry.splitn(2, '\n');
+        if let (Some(key), Some(url)) = (parts.next(), parts.next()) {
+            // The section is lowercased, the subsection is not
+            let name = &key[8..key.len() - 4];
+            projects.push((name.to_owned(), url.to_owned()));
+        }
+    }
+    Ok(projects)
+}
+
+/// Quote a string for the shell, as used by `GIT_SSH_COMMAND`
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Build the `GIT_SSH_COMMAND` from the mirror's settings, if any is set
+///
+/// The recognized settings are:
+/// * `doublegit.sshKey`: the private key to use, instead of the agent's
+/// * `doublegit.sshKnownHosts`: `strict`, `accept-new`, or `off`
+/// * `doublegit.sshKnownHostsFile`: the known_hosts file to use
+/// * `doublegit.sshOptions`: extra options added to the command
+fn ssh_command(
+    config: &HashMap<String, String>,
+) -> Result<Option<String>, Error> {
+    let mut cmd = String::from("ssh");
+    let mut configured = false;
+    if let Some(key) = config.get("sshkey") {
+        cmd.push_str(" -i ");
+        cmd.push_str(&shell_quote(key));
+        cmd.push_str(" -o IdentitiesOnly=yes -o IdentityAgent=none");
+        cmd.push_str(" -o BatchMode=yes");
+        configured = true;
+    }
+    if let Some(policy) = config.get("sshknownhosts") {
+        match policy.as_str() {
+            "strict" => cmd.push_str(" -o StrictHostKeyChecking=yes"),
+            "accept-new" => {
+                cmd.push_str(" -o StrictHostKeyChecking=accept-new")
+            }
+            "off" => {
+                cmd.push_str(" -o StrictHostKeyChecking=no");
+                cmd.push_str(" -o UserKnownHostsFile=");
+                cmd.push_str(NULL_DEVICE);
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "doublegit.sshKnownHosts should be strict, accept-new, \
+                     or off, not {:?}",
+                    policy
+                )));
+            }
+        }
+        configured = true;
+    }
+    if let Some(file) = config.get("sshknownhostsfile") {
+        cmd.push_str(" -o UserKnownHostsFile=");
+        cmd.push_str(&shell_quote(file));
+        configured = true;
+    }
+    if let Some(options) = config.get("sshoptions") {
+        cmd.push(' ');
+        cmd.push_str(options);
+        configured = true;
+    }
+    Ok(if configured { Some(cmd) } else { None })
+}
+
+/// Get the access token for HTTPS remotes from the mirror's settings
+///
+/// The token is either given directly in `doublegit.token`, read from the
+/// environment variable named by `doublegit.tokenEnv`, or printed by the
+/// shell command in `doublegit.tokenCommand`.
+fn access_token(
+    config: &HashMap<String, String>,
+) -> Result<Option<String>, Error> {
+    secret_setting(config, "token")
+}
+
+/// Get a secret from the mirror's settings
+///
+/// Like for `doublegit.token`, it can be given directly as `doublegit.<name>`,
+/// read from the environment variable named by `doublegit.<name>Env`, or
+/// printed by the shell command in `doublegit.<name>Command`.
+pub fn secret_setting(
+    config: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let key = name.to_lowercase();
+    if let Some(secret) = config.get(&key) {
+        Ok(Some(secret.clone()))
+    } else if let Some(var) = config.get(&format!("{}env", key)) {
+        match std::env::var(var) {
+            Ok(secret) => Ok(Some(secret)),
+            Err(_) => Err(Error::Config(format!(
+                "environment variable {} from doublegit.{}Env is not set",
+                var, name,
+            ))),
+        }
+    } else if let Some(command) = config.get(&format!("{}command", key)) {
+        let output = process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(process::Stdio::null())
+            .stderr(process::Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::Config(format!(
+                "doublegit.{}Command returned {}",
+                name, output.status
+            )));
+        }
+        let secret = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        Ok(Some(secret))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Get the proxy to use for HTTP(S) remotes from the mirror's settings
+fn proxy_url(
+    config: &HashMap<String, String>,
+) -> Result<Option<&str>, Error> {
+    let proxy = match config.get("proxy") {
+        Some(p) => p.as_str(),
+        None => return Ok(None),
+    };
+    const SCHEMES: &[&str] = &[
+        "http://", "https://", "socks4://", "socks4a://", "socks5://",
+        "socks5h://",
+    ];
+    if SCHEMES.iter().any(|s| proxy.starts_with(s)) {
+        Ok(Some(proxy))
+    } else {
+        Err(Error::Config(format!("invalid doublegit.proxy {:?}", proxy)))
+    }
+}
+
+/// Get the Git settings tuning the fetch protocol from the mirror's settings
+///
+/// `protocolversion` forces a version of the wire protocol (v2 lets the
+/// server only advertise the refs we ask for), and `negotiationalgorithm`
+/// picks how commits we have are sent to the server.
+fn protocol_settings(
+    config: &HashMap<String, String>,
+) -> Result<Vec<String>, Error> {
+    let mut settings = Vec::new();
+    if let Some(version) = config.get("protocolversion") {
+        if version != "0" && version != "1" && version != "2" {
+            return Err(Error::Config(format!(
+                "invalid doublegit.protocolVersion {:?}",
+                version
+            )));
+        }
+        settings.push(format!("protocol.version={}", version));
+    }
+    if let Some(algorithm) = config.get("negotiationalgorithm") {
+        const ALGORITHMS: &[&str] =
+            &["consecutive", "default", "noop", "skipping"];
+        if !ALGORITHMS.contains(&algorithm.as_str()) {
+            return Err(Error::Config(format!(
+                "invalid doublegit.negotiationAlgorithm {:?}",
+                algorithm
+            )));
+        }
+        settings.push(format!("fetch.negotiationAlgorithm={}", algorithm));
+    }
+    Ok(settings)
+}
+
+/// Get the `--negotiation-tip` options limiting the commits we tell the
+/// server about, if `limithaves` is set
+///
+/// By default Git sends commits from all the refs, including every keep-ref,
+/// which gets slow when there are tens of thousands of them. Only sending the
+/// remote-tracking branches of the given remotes and the tags is usually
+/// enough for the server to find what we have.
+fn negotiation_tips(
+    config: &HashMap<String, String>,
+    remotes: &[&str],
+) -> Result<Vec<String>, Error> {
+    if !crate::config_bool(config, "limithaves")? {
+        return Ok(Vec::new());
+    }
+    let mut tips: Vec<String> = remotes
+        .iter()
+        .map(|r| format!("--negotiation-tip=refs/remotes/{}/*", r))
+        .collect();
+    tips.push("--negotiation-tip=refs/tags/*".into());
+    Ok(tips)
+}
+
+/// Credential helper answering with the token found in the environment
+///
+/// Passing the token through the environment keeps it off command-lines.
+const TOKEN_HELPER: &str = "!f() { \
+    test \"$1\" = get || exit 0; \
+    echo \"username=$DOUBLEGIT_TOKEN_USER\"; \
+    echo \"password=$DOUBLEGIT_TOKEN\"; \
+}; f";
+
+/// Make a Git command for an operation that talks to the remote
+///
+/// This sets up the environment according to the mirror's settings (e.g. SSH
+/// authentication, HTTPS token). The Git subcommand still has to be added.
+fn remote_command(repository: &Path) -> Result<process::Command, Error> {
+    let config = mirror_config(repository)?;
+    let mut cmd = command();
+    cmd.current_dir(repository);
+    if let Some(ssh) = ssh_command(&config)? {
+        debug!("Using GIT_SSH_COMMAND={}", ssh);
+        cmd.env("GIT_SSH_COMMAND", ssh);
+    }
+    if let Some(token) = access_token(&config)? {
+        debug!("Using access token through credential helper");
+        let user = config
+            .get("tokenuser")
+            .map(String::as_str)
+            .unwrap_or("x-access-token");
+        // The empty value resets the list of helpers
+        cmd.args(&["-c", "credential.helper="])
+            .arg("-c")
+            .arg(format!("credential.helper={}", TOKEN_HELPER))
+            .env("DOUBLEGIT_TOKEN_USER", user)
+            .env("DOUBLEGIT_TOKEN", token);
+    }
+    if let Some(proxy) = proxy_url(&config)? {
+        debug!("Using proxy {}", proxy);
+        cmd.arg("-c").arg(format!("http.proxy={}", proxy));
+    }
+    if let Some(no_proxy) = config.get("noproxy") {
+        cmd.env("NO_PROXY", no_proxy).env("no_proxy", no_proxy);
+    }
+    for setting in protocol_settings(&config)? {
+        debug!("Using {}", setting);
+        cmd.arg("-c").arg(setting);
+    }
+    Ok(cmd)
+}
+
+/// Check the settings used to talk to the remote, without running anything
+///
+/// Returns a description of each problem, those would make fetches fail.
+pub fn remote_config_problems(
+    config: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Err(e) = ssh_command(config) {
+        problems.push(e.to_string());
+    }
+    if let Some(key) = config.get("sshkey") {
+        if !Path::new(key).is_file() {
+            problems.push(format!("doublegit.sshKey {:?} doesn't exist", key));
+        }
+    }
+    if let Err(e) = proxy_url(config) {
+        problems.push(e.to_string());
+    }
+    if let Err(e) = protocol_settings(config) {
+        problems.push(e.to_string());
+    }
+    if let Err(e) = negotiation_tips(config, &[]) {
+        problems.push(e.to_string());
+    }
+    problems
+}
+
+/// Name of the file recording that a fetch was cut short by the byte budget
+///
+/// Its presence makes the next fetch resume by getting refs one at a time. If
+/// a single ref didn't fit in the whole budget, it contains its name.
+const TRUNCATED_MARKER: &str = "doublegit-truncated";
+
+/// Namespace where refs are stored while resuming a truncated fetch
+const PARTIAL_NAMESPACE: &str = "refs/doublegit-partial/";
+
+/// Limits enforced on a running command
+#[derive(Default)]
+struct Limits<'a> {
+    /// Maximum duration after which the command is killed
+    timeout: Option<Duration>,
+    /// Repository and number of bytes its packs are allowed to grow by
+    transfer: Option<(&'a Path, u64)>,
+    /// Where to report the progress read from stderr, and for which remote
+    progress: Option<(Arc<dyn Progress>, &'a str)>,
+    /// Kill the command if a shutdown is requested
+    interrupt: bool,
+}
+
+/// Get the total size of the pack files in a repository, including the
+/// temporary ones being received
+fn pack_size(repository: &Path) -> Result<u64, Error> {
+    let pack_dir = repository.join("objects").join("pack");
+    if !pack_dir.is_dir() {
+        return Ok(0);
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(pack_dir)? {
+        // Files might get renamed while we're listing
+        if let Ok(meta) = entry?.metadata() {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Run a command and capture its output, killing it if it goes past limits
+///
+/// Only the streams that were set to `Stdio::piped()` get captured.
+fn output_with_limits(
+    cmd: &mut process::Command,
+    limits: &Limits,
+) -> Result<process::Output, Error> {
+    if limits.timeout.is_none()
+        && limits.transfer.is_none()
+        && limits.progress.is_none()
+        && !limits.interrupt
+    {
+        return Ok(cmd.output()?);
+    }
+
+    // Read the pipes from separate threads, so the child doesn't block
+    fn reader<R: Read + Send + 'static>(
+        pipe: Option<R>,
+    ) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buf).ok();
+            }
+            buf
+        })
+    }
+
+    let initial_size = match limits.transfer {
+        Some((repository, _)) => pack_size(repository)?,
+        None => 0,
+    };
+    let mut child = cmd.spawn()?;
+    let stdout = reader(child.stdout.take());
+    let stderr = match limits.progress {
+        Some((ref progress, remote)) => progress_reader(
+            child.stderr.take(),
+            progress.clone(),
+            remote.to_owned(),
+        ),
+        None => reader(child.stderr.take()),
+    };
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(process::Output {
+                status,
+                stdout: stdout.join().unwrap_or_default(),
+                stderr: stderr.join().unwrap_or_default(),
+            });
+        }
+        // Don't join the reader threads if we kill the child, the pipes might
+        // be held open by one of its own subprocesses (e.g. ssh)
+        if let Some(timeout) = limits.timeout {
+            if start.elapsed() >= timeout {
+                child.kill()?;
+                child.wait()?;
+                return Err(Error::Git(format!(
+                    "Command timed out after {}s",
+                    timeout.as_secs()
+                )));
+            }
+        }
+        if limits.interrupt && shutdown::requested() {
+            child.kill()?;
+            child.wait()?;
+            return Err(Error::Interrupted);
+        }
+        if let Some((repository, max_bytes)) = limits.transfer {
+            let size = pack_size(repository)?;
+            if size.saturating_sub(initial_size) > max_bytes {
+                child.kill()?;
+                child.wait()?;
+                return Err(Error::TransferLimit(max_bytes));
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Read the stderr of `git fetch --progress` from a separate thread
+///
+/// The progress lines are reported rather than captured, so the output is
+/// the same as without `--progress`.
+fn progress_reader<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    progress: Arc<dyn Progress>,
+    remote: String,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let mut pipe = match pipe {
+            Some(pipe) => pipe,
+            None => return output,
+        };
+        // Git rewrites the progress lines in place with '\r'
+        let mut line = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let len = match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            for &b in &buf[..len] {
+                if b != b'\r' && b != b'\n' {
+                    line.push(b);
+                    continue;
                 }
-                Operation::Pruned => {
-                    if !to.contains('/') { // tag
-                        let ref_ = Ref {
-                            name: to.into(),
-                            tag: true,
-                        };
-                        info!("Pruned tag {}", ref_.name);
-                        removed.insert(ref_);
-                    } else {
-                        let ref_ = Ref::parse_remote_ref(to)?;
-                        info!("Pruned branch {}", ref_.name);
-                        removed.insert(ref_);
+                match parse_fetch_progress(&String::from_utf8_lossy(&line)) {
+                    Some(p) => progress.fetch(&remote, &p),
+                    None if line.is_empty() => {}
+                    None => {
+                        output.extend_from_slice(&line);
+                        output.push(b'\n');
                     }
                 }
-                Operation::Tag => {
-                    let ref_ = Ref {
-                        name: to.into(),
-                        tag: true,
-                    };
-                    info!("Updated tag {}", ref_.name);
-                    changed.insert(ref_);
+                line.clear();
+            }
+        }
+        output.extend_from_slice(&line);
+        output
+    })
+}
+
+/// Run a git-fetch command, retrying on failure according to `options`
+///
+/// Exceeding the transfer limit is not retried.
+fn run_fetch(
+    cmd: &mut process::Command,
+    options: &UpdateOptions,
+    limits: &Limits,
+) -> Result<process::Output, Error> {
+    let mut attempt = 0;
+    loop {
+        let error = match output_with_limits(cmd, limits) {
+            Ok(output) => {
+                if output.status.success() {
+                    return Ok(output);
                 }
-                Operation::Reject => {
-                    return Err(Error::Git(format!(
-                        "Error updating ref {}",
-                        to
-                    )));
+                // Show the error messages we captured
+                std::io::stderr().write_all(&output.stderr).ok();
+                // No point retrying if the repository is gone
+                if let Some(message) = parse_not_found(&output.stderr) {
+                    return Err(Error::UpstreamGone(message));
                 }
-                Operation::Noop => {}
+                Error::Git(format!("`git fetch` returned {}", output.status))
+            }
+            Err(e @ Error::TransferLimit(_)) => return Err(e),
+            Err(e @ Error::Interrupted) => return Err(e),
+            Err(e) => e,
+        };
+        if attempt >= options.retries || shutdown::requested() {
+            return Err(error);
+        }
+        let longest = Duration::from_secs(MAX_RETRY_DELAY_SECS);
+        let delay = options
+            .backoff
+            .checked_mul(2u32.pow(attempt.min(16)))
+            .map_or(longest, |delay| delay.min(longest));
+        warn!("{}, retrying in {}s", error, delay.as_secs());
+        if !shutdown::sleep(delay) {
+            return Err(Error::Interrupted);
+        }
+        attempt += 1;
+    }
+}
+
+/// Find the message saying that the remote repository doesn't exist
+fn parse_not_found(output: &[u8]) -> Option<String> {
+    lazy_static! {
+        static ref _RE_NOT_FOUND: Regex = Regex::new(
+            r"(?i)repository (?:'[^']*' )?not found|could not be found|does not appear to be a git repository"
+        ).unwrap();
+    }
+    String::from_utf8_lossy(output)
+        .lines()
+        .find(|line| _RE_NOT_FOUND.is_match(line))
+        .map(|line| line.trim().to_owned())
+}
+
+/// Resume a truncated fetch, getting the upstream refs one at a time
+///
+/// The refs are stored under `PARTIAL_NAMESPACE` so that objects received
+/// before the budget runs out again are kept for the next attempt, without
+/// touching the refs that are being recorded.
+///
+/// A ref that goes over the budget on its own would never make progress, so
+/// its name is written to the marker and it is fetched without the limit on
+/// the next attempt.
+///
+/// Returns the number of bytes received.
+fn fetch_incrementally(
+    repository: &Path,
+    options: &UpdateOptions,
+) -> Result<u64, Error> {
+    let marker = repository.join(TRUNCATED_MARKER);
+    let oversized = std::fs::read(&marker)?;
+    let output = remote_command(repository)?
+        .args(&["ls-remote", "--", "origin", "refs/heads/*", "refs/tags/*"])
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git ls-remote` returned {}",
+            output.status
+        )));
+    }
+    let initial_size = pack_size(repository)?;
+    for line in output.stdout.split(|&b| b == b'\n') {
+        let mut fields = line.splitn(2, |&b| b == b'\t');
+        let (sha, refname) = match (fields.next(), fields.next()) {
+            (Some(sha), Some(refname)) => {
+                (String::from_utf8_lossy(sha), RefName::from(refname))
             }
+            _ => continue,
+        };
+        if refname.as_bytes().ends_with(b"^{}") {
+            continue;
+        }
+        check_ref_name(refname.as_bytes())?;
+        if object_exists(repository, &sha)? {
+            continue;
+        }
+        let used = pack_size(repository)?.saturating_sub(initial_size);
+        let unlimited = refname.as_bytes() == &oversized[..];
+        let limits = Limits {
+            timeout: options.timeout,
+            transfer: match options.max_bytes {
+                Some(max) if !unlimited => {
+                    Some((repository, max.saturating_sub(used)))
+                }
+                _ => None,
+            },
+            progress: None,
+            interrupt: true,
+        };
+        if unlimited {
+            warn!(
+                "{} doesn't fit in the byte budget, fetching it without limit",
+                refname,
+            );
         } else {
-            info!("! {}", line);
+            info!("Fetching {} on its own", refname);
+        }
+        // Don't follow tags nor update remote-tracking branches, we want the
+        // full fetch to see those changes
+        let mut cmd = remote_command(repository)?;
+        cmd.args(&["fetch", "--no-tags", "--refmap="]);
+        if let Some(depth) = options.depth {
+            cmd.arg(format!("--depth={}", depth));
+        }
+        let mut refspec = OsString::from("+");
+        refspec.push(refname.to_os_string());
+        refspec.push(":");
+        refspec.push(refname.with_prefix(PARTIAL_NAMESPACE).to_os_string());
+        cmd.args(&["--", "origin"])
+            .arg(refspec)
+            .current_dir(repository)
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null());
+        match run_fetch(&mut cmd, options, &limits) {
+            Ok(_) => {}
+            Err(e @ Error::TransferLimit(_)) => {
+                if used == 0 {
+                    std::fs::write(&marker, refname.as_bytes())?;
+                }
+                return Err(e);
+            }
+            Err(e) => return Err(e),
         }
     }
-    Ok(FetchOutput { new, changed, removed })
+    Ok(pack_size(repository)?.saturating_sub(initial_size))
 }
 
-/// Get the SHA-1 hash for a reference, using git-rev-parse
-pub fn get_sha(repository: &Path, refname: &str) -> Result<String, Error> {
-    let output = process::Command::new("git")
-        .args(&["rev-parse", refname])
+/// Run git-fetch on a repository and parse the ref changes
+///
+/// This uses `git fetch --porcelain` when available (Git 2.41 and up), and
+/// falls back to parsing the human-readable output otherwise.
+///
+/// Failed fetches are retried according to `options`, waiting exponentially
+/// longer between attempts. If the fetch goes over the byte budget, it is
+/// aborted and the next call will resume it.
+///
+/// A depth can only be given for a new or already shallow mirror, it would
+/// otherwise cut the history that was already fetched.
+pub fn fetch(
+    repository: &Path,
+    options: &UpdateOptions,
+) -> Result<FetchOutput, Error> {
+    if options.depth.is_some()
+        && !repository.join("shallow").exists()
+        && !list_ref_names(repository, "refs/")?.is_empty()
+    {
+        return Err(Error::Config(
+            "A depth can't be set on a mirror that already has full history"
+                .into(),
+        ));
+    }
+
+    let marker = repository.join(TRUNCATED_MARKER);
+    let mut used = 0;
+    if marker.exists() {
+        warn!("Previous fetch was truncated, resuming");
+        used = fetch_incrementally(repository, options)?;
+    }
+
+    let porcelain = version()? >= (2, 41, 0);
+    let mut cmd = remote_command(repository)?;
+    cmd.arg("fetch");
+    if porcelain {
+        cmd.arg("--porcelain");
+    }
+    if options.progress.is_some() {
+        cmd.arg("--progress");
+    }
+    if let Some(depth) = options.depth {
+        cmd.arg(format!("--depth={}", depth));
+    }
+    cmd.args(negotiation_tips(&mirror_config(repository)?, &["origin"])?);
+    cmd.args(&[
+        "--prune",
+        "--",
+        "origin",
+        "+refs/tags/*:refs/tags/*",
+        "+refs/heads/*:refs/remotes/origin/*",
+    ]);
+    cmd.current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::piped());
+    if porcelain {
+        cmd.stdout(process::Stdio::piped());
+    } else {
+        cmd.stdout(process::Stdio::inherit());
+    }
+
+    let limits = Limits {
+        timeout: options.timeout,
+        transfer: options.max_bytes.map(|max| {
+            (repository, max.saturating_sub(used))
+        }),
+        progress: options.progress.clone().map(|p| (p, "origin")),
+        interrupt: true,
+    };
+    let output = match run_fetch(&mut cmd, options, &limits) {
+        Ok(output) => output,
+        Err(e @ Error::TransferLimit(_)) => {
+            std::fs::write(&marker, b"")?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Clean up after resuming
+    if marker.exists() {
+        for (refname, _) in list_refs(repository, PARTIAL_NAMESPACE)? {
+            delete_ref(repository, &refname)?;
+        }
+        std::fs::remove_file(&marker)?;
+    }
+
+    let mut result = if porcelain {
+        std::io::stderr().write_all(&output.stderr).ok();
+        parse_porcelain_fetch_output(&output.stdout)?
+    } else {
+        parse_fetch_output(&output.stderr)?
+    };
+    result.redirect = parse_redirect(&output.stderr);
+    result.stdout = output.stdout;
+    result.stderr = output.stderr;
+    Ok(result)
+}
+
+/// Find the URL the server redirected us to in git-fetch's messages
+///
+/// Git follows HTTP redirects (e.g. when a GitHub repository is renamed or
+/// transferred) but only prints a warning.
+fn parse_redirect(output: &[u8]) -> Option<String> {
+    const PREFIX: &str = "warning: redirecting to ";
+    for line in output.split(|&b| b == b'\n') {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+        if line.starts_with(PREFIX) {
+            let url = line[PREFIX.len()..].trim_end_matches('/');
+            return Some(url.to_owned());
+        }
+    }
+    None
+}
+
+/// Get the URL of the `origin` remote
+pub fn remote_url(repository: &Path) -> Result<String, Error> {
+    let output = command()
+        .args(&["config", "--get", "remote.origin.url"])
         .current_dir(repository)
         .stdin(process::Stdio::null())
         .stderr(process::Stdio::inherit())
         .output()?;
     if !output.status.success() {
         return Err(Error::Git(format!(
-            "`git rev-parse` returned {}",
+            "`git config --get remote.origin.url` returned {}",
             output.status
         )));
     }
-    let sha = std::str::from_utf8(&output.stdout)
-        .map_err(|_| Error::git("Non-utf8 sha?!"))?;
-    Ok(sha.trim().into())
+    let url = std::str::from_utf8(&output.stdout)
+        .map_err(|_| Error::git("Non-utf8 remote URL"))?;
+    Ok(url.trim_end().to_owned())
+}
+
+/// Get all the values of a key in the repository's own config
+pub fn get_config_all(
+    repository: &Path,
+    key: &str,
+) -> Result<Vec<String>, Error> {
+    let output = command()
+        .args(&["config", "--local", "--null", "--get-all", key])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    // Exit code 1 means the key is not set
+    if output.status.code() == Some(1) {
+        return Ok(Vec::new());
+    } else if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git config --get-all {}` returned {}",
+            key,
+            output.status
+        )));
+    }
+    Ok(output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|v| !v.is_empty())
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+        .collect())
+}
+
+/// Check that the remote can be reached with the mirror's settings
+///
+/// This lists its branches without fetching anything. The error contains the
+/// message from Git, e.g. why authentication failed.
+pub fn check_remote(repository: &Path) -> Result<(), Error> {
+    let output = remote_command(repository)?
+        .args(&["ls-remote", "--heads", "--", "origin"])
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::piped())
+        .output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let message = stderr
+        .lines()
+        .find(|l| l.starts_with("fatal: "))
+        .map(|l| &l[7..])
+        .or_else(|| stderr.lines().rev().find(|l| !l.trim().is_empty()));
+    match message {
+        Some(line) => Err(Error::Git(line.trim().to_owned())),
+        None => Err(Error::Git(format!(
+            "`git ls-remote` returned {}",
+            output.status
+        ))),
+    }
+}
+
+/// Set a value in the repository's config
+pub fn set_config(
+    repository: &Path,
+    key: &str,
+    value: &str,
+) -> Result<(), Error> {
+    let status = command()
+        .args(&["config", key, value])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git config {}` returned {}",
+            key,
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Remove a setting from the repository's config, if it is set
+pub fn unset_config(repository: &Path, key: &str) -> Result<(), Error> {
+    let status = command()
+        .args(&["config", "--unset-all", key])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    // Exit code 5 means the key is not set
+    if !status.success() && status.code() != Some(5) {
+        return Err(Error::Git(format!(
+            "`git config --unset-all {}` returned {}",
+            key,
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Change a setting in a file in Git config format, or remove it if `value`
+/// is `None`
+///
+/// The file is created if needed.
+pub fn write_config_file(
+    file: &Path,
+    key: &str,
+    value: Option<&str>,
+) -> Result<(), Error> {
+    let mut cmd = command();
+    cmd.arg("config").arg("--file").arg(file);
+    match value {
+        Some(value) => cmd.args(&[key, value]),
+        None => cmd.args(&["--unset-all", key]),
+    };
+    let status = cmd.stdin(process::Stdio::null()).status()?;
+    // Exit code 5 means there was nothing to unset
+    let unset = value.is_none() && status.code() == Some(5);
+    if !(status.success() || unset) {
+        return Err(Error::Config(format!(
+            "can't write {}: `git config` returned {}",
+            file.display(),
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Create a new bare repository
+pub fn init_bare(repository: &Path) -> Result<(), Error> {
+    let status = command()
+        .args(&["init", "--bare", "--quiet"])
+        .arg(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!("`git init` returned {}", status)));
+    }
+    Ok(())
+}
+
+/// Add a directory to the repository's alternate object stores
+///
+/// Git then finds objects there as if they were in the repository. Nothing
+/// is done if it's already listed.
+pub fn add_alternate(repository: &Path, objects: &Path) -> Result<(), Error> {
+    let path = repository.join("objects").join("info").join("alternates");
+    let mut alternates = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            String::new()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let objects = objects
+        .to_str()
+        .ok_or_else(|| Error::git("Non-utf8 alternate path"))?;
+    if alternates.lines().any(|line| line == objects) {
+        return Ok(());
+    }
+    if !alternates.is_empty() && !alternates.ends_with('\n') {
+        alternates.push('\n');
+    }
+    alternates.push_str(objects);
+    alternates.push('\n');
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, alternates)?;
+    Ok(())
+}
+
+/// Copy the objects found in alternates into the repository, and stop using
+/// them, like `git clone --dissociate`
+pub fn dissociate(repository: &Path) -> Result<(), Error> {
+    let status = command()
+        .args(&["repack", "-a", "-d", "-q"])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!("`git repack` returned {}", status)));
+    }
+    let path = repository.join("objects").join("info").join("alternates");
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Copy all the objects of a local repository, including those only found in
+/// its reflogs
+///
+/// The objects are written as a single pack.
+pub fn copy_objects(source: &Path, repository: &Path) -> Result<(), Error> {
+    let mut pack = command()
+        .args(&["pack-objects", "--all", "--reflog", "--stdout", "-q"])
+        .current_dir(source)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::inherit())
+        .spawn()?;
+    let index = command()
+        .args(&["index-pack", "--stdin"])
+        .current_dir(repository)
+        .stdin(pack.stdout.take().unwrap())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .status();
+    let status = pack.wait()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git pack-objects` returned {}",
+            status
+        )));
+    }
+    let status = index?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git index-pack` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// An entry of a reflog
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    /// Where the ref pointed before, `None` if it was created
+    pub old: Option<String>,
+    /// Where the ref was set to
+    pub new: String,
+    /// When, as a Unix timestamp
+    pub timestamp: i64,
+}
+
+/// Get the entries of the reflog of a ref, oldest first
+///
+/// A ref without a reflog has no entries.
+pub fn reflog(
+    repository: &Path,
+    name: &RefName,
+) -> Result<Vec<ReflogEntry>, Error> {
+    check_ref_name(name.as_bytes())?;
+    let mut path = OsString::from("logs/");
+    path.push(name.to_os_string());
+    let output = command()
+        .args(&["rev-parse", "--git-path"])
+        .arg(path)
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git rev-parse --git-path` returned {}",
+            output.status
+        )));
+    }
+    let path = String::from_utf8_lossy(&output.stdout);
+    let path = repository.join(path.trim_end_matches('\n'));
+    match std::fs::read(&path) {
+        Ok(log) => parse_reflog(&log),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Vec::new())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Parse a reflog file, broken out for unit testing
+///
+/// Each line has the format
+/// `<old-sha> <new-sha> <name> <<email>> <timestamp> <timezone>\t<message>`.
+fn parse_reflog(log: &[u8]) -> Result<Vec<ReflogEntry>, Error> {
+    let mut entries = Vec::new();
+    for line in log.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        let line = String::from_utf8_lossy(line);
+        let invalid = || {
+            Error::Git(format!("Invalid line in reflog: {:?}", line))
+        };
+        let fields = line.split('\t').next().unwrap_or("");
+        let mut words = fields.split(' ');
+        let (old, new) = match (words.next(), words.next()) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return Err(invalid()),
+        };
+        let timestamp = fields
+            .rsplit(' ')
+            .nth(1)
+            .and_then(|t| t.parse().ok())
+            .ok_or_else(invalid)?;
+        if check_sha(old).is_err() || check_sha(new).is_err() {
+            return Err(invalid());
+        }
+        entries.push(ReflogEntry {
+            old: if old.bytes().all(|b| b == b'0') {
+                None
+            } else {
+                Some(old.to_owned())
+            },
+            new: new.to_owned(),
+            timestamp,
+        });
+    }
+    Ok(entries)
+}
+
+/// Copy all the refs of a local repository under a namespace
+///
+/// This gets all the objects they need as well, always kept in a pack so that
+/// other repositories using this one as alternate can drop their copies. Refs
+/// that have been deleted from `source` are removed from the namespace.
+pub fn fetch_all_refs(
+    repository: &Path,
+    source: &Path,
+    namespace: &str,
+) -> Result<(), Error> {
+    let status = command()
+        .args(&["-c", "fetch.unpackLimit=1"])
+        .args(&["fetch", "--quiet", "--prune", "--no-tags", "--"])
+        .arg(source)
+        .arg(format!("+refs/*:{}*", namespace))
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!("`git fetch` returned {}", status)));
+    }
+    Ok(())
+}
+
+/// Fetch the branches of a fork into `refs/remotes/<fork>/`
+///
+/// Tags are not fetched, since they would conflict with those of `origin`.
+pub fn fetch_fork(
+    repository: &Path,
+    options: &UpdateOptions,
+    fork: &str,
+) -> Result<(), Error> {
+    let mut cmd = remote_command(repository)?;
+    cmd.args(&["fetch", "--prune", "--no-tags"]);
+    if options.progress.is_some() {
+        cmd.arg("--progress");
+    }
+    if let Some(depth) = options.depth {
+        cmd.arg(format!("--depth={}", depth));
+    }
+    cmd.args(negotiation_tips(
+        &mirror_config(repository)?,
+        &["origin", fork],
+    )?);
+    cmd.arg("--")
+        .arg(fork)
+        .arg(format!("+refs/heads/*:refs/remotes/{}/*", fork))
+        .stdin(process::Stdio::null());
+    let limits = Limits {
+        timeout: options.timeout,
+        transfer: None,
+        progress: options.progress.clone().map(|p| (p, fork)),
+        interrupt: true,
+    };
+    run_fetch(&mut cmd, options, &limits)?;
+    Ok(())
+}
+
+/// Parse `git fetch --porcelain` output, broken out for unit testing
+///
+/// Each line has the format `<flag> <old-sha> <new-sha> <local-ref>`.
+fn parse_porcelain_fetch_output(output: &[u8]) -> Result<FetchOutput, Error> {
+    let mut result = FetchOutput::empty();
+    for line in output.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let error = || Error::Git(format!(
+            "Parse error: {:?}",
+            String::from_utf8_lossy(line)
+        ));
+        info!("> {}", RefName::from(line));
+        if line.len() < 2 || line[1] != b' ' {
+            return Err(error());
+        }
+        let op = std::str::from_utf8(&line[0..1]).map_err(|_| error())?;
+        let op = parse_operation(op)?;
+        let mut fields = line[2..].split(|&b| b == b' ');
+        let to = match (fields.next(), fields.next(), fields.next()) {
+            (Some(_old), Some(_new), Some(to)) if fields.next().is_none() => {
+                to
+            }
+            _ => return Err(error()),
+        };
+        let ref_ = Ref::parse_local_ref(to)?;
+        result.record(op, ref_)?;
+    }
+    Ok(result)
+}
+
+/// Parse git-fetch output, broken out for unit testing
+fn parse_fetch_output(output: &[u8]) -> Result<FetchOutput, Error> {
+    lazy_static! {
+        static ref _RE_FETCH: regex::bytes::Regex = regex::bytes::Regex::new(
+            r"(?-u)^ ([+t*!= -]) +([^ ]+|\[[^\]]+\]) +([^ ]+) +-> +([^ ]+)(?: +(.+))?$"
+        ).unwrap();
+    }
+    let mut result = FetchOutput::empty();
+    for line in output.split(|&b| b == b'\n') {
+        if let Some(m) = _RE_FETCH.captures(line) {
+            info!("> {}", RefName::from(line));
+            let op = m.get(1).map_or(&b""[..], |m| m.as_bytes());
+            let to = m.get(4).map_or(&b""[..], |m| m.as_bytes());
+
+            // The operation is a single ASCII character
+            let op = parse_operation(&String::from_utf8_lossy(op))?;
+            // Branches are remote-tracking refs, anything else is a tag,
+            // which can have slashes in its name too
+            let ref_ = if op == Operation::Tag || !to.starts_with(b"origin/")
+            {
+                Ref {
+                    name: to.into(),
+                    tag: true,
+                }
+            } else {
+                Ref::parse_remote_ref(to)?
+            };
+            result.record(op, ref_)?;
+        } else {
+            info!("! {}", RefName::from(line));
+        }
+    }
+    Ok(result)
+}
+
+/// Number and size of the objects in a repository
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ObjectCounts {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+/// Count the objects in a repository, loose and packed, using
+/// git-count-objects
+pub fn count_objects(repository: &Path) -> Result<ObjectCounts, Error> {
+    let output = command()
+        .args(&["count-objects", "-v"])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git count-objects` returned {}",
+            output.status
+        )));
+    }
+    Ok(parse_count_objects(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse git-count-objects output, broken out for unit testing
+fn parse_count_objects(output: &str) -> ObjectCounts {
+    let mut counts = ObjectCounts::default();
+    for line in output.lines() {
+        let mut fields = line.splitn(2, ": ");
+        let (key, value) = match (fields.next(), fields.next()) {
+            (Some(key), Some(value)) => match value.trim().parse::<u64>() {
+                Ok(value) => (key, value),
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        match key {
+            "count" | "in-pack" => counts.objects += value,
+            // Sizes are in KiB
+            "size" | "size-pack" => counts.bytes += value * 1024,
+            _ => {}
+        }
+    }
+    counts
+}
+
+/// Get the SHA-1 hash for a reference, using git-rev-parse
+pub fn get_sha<R: AsRef<[u8]>>(
+    repository: &Path,
+    refname: R,
+) -> Result<String, Error> {
+    let refname = RefName::from(refname.as_ref());
+    check_ref_name(refname.as_bytes())?;
+    let output = command()
+        .args(&["rev-parse", "--verify"])
+        .arg(refname.to_os_string())
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git rev-parse` returned {}",
+            output.status
+        )));
+    }
+    let sha = std::str::from_utf8(&output.stdout)
+        .map_err(|_| Error::git("Non-utf8 sha?!"))?;
+    Ok(sha.trim().into())
+}
+
+/// Get the full SHA-1 of a commit from a possibly abbreviated one, if it
+/// exists
+pub fn resolve_commit(
+    repository: &Path,
+    sha: &str,
+) -> Result<Option<String>, Error> {
+    if sha.len() < 4 || !sha.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::Git(format!("Invalid SHA-1 {:?}", sha)));
+    }
+    let output = command()
+        .args(&["rev-parse", "--verify", "--quiet"])
+        .arg(format!("{}^{{commit}}", sha))
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    // Exit code 1 means it doesn't exist, or is ambiguous
+    if output.status.code() == Some(1) {
+        return Ok(None);
+    } else if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git rev-parse` returned {}",
+            output.status
+        )));
+    }
+    let sha = std::str::from_utf8(&output.stdout)
+        .map_err(|_| Error::git("Non-utf8 sha?!"))?;
+    Ok(Some(sha.trim().into()))
+}
+
+/// Check whether a commit is an ancestor of another (or the same)
+pub fn is_ancestor(
+    repository: &Path,
+    ancestor: &str,
+    commit: &str,
+) -> Result<bool, Error> {
+    check_sha(ancestor)?;
+    check_sha(commit)?;
+    let status = command()
+        .args(&["merge-base", "--is-ancestor", ancestor, commit])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .status()?;
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(Error::Git(format!(
+            "`git merge-base --is-ancestor` returned {}",
+            status
+        ))),
+    }
+}
+
+/// Count the commits only reachable from `old` and only from `new`
+///
+/// For a branch that moved from `old` to `new`, those are the number of
+/// commits removed and added.
+pub fn count_divergence(
+    repository: &Path,
+    old: &str,
+    new: &str,
+) -> Result<(u64, u64), Error> {
+    check_sha(old)?;
+    check_sha(new)?;
+    let output = command()
+        .args(&["rev-list", "--left-right", "--count"])
+        .arg(format!("{}...{}", old, new))
+        .arg("--")
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git rev-list --count` returned {}",
+            output.status
+        )));
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut fields = output.split_whitespace().map(str::parse);
+    match (fields.next(), fields.next()) {
+        (Some(Ok(left)), Some(Ok(right))) => Ok((left, right)),
+        _ => Err(Error::Git(format!(
+            "Invalid `git rev-list --count` output: {:?}",
+            output
+        ))),
+    }
+}
+
+/// List the commits reachable from `include` but not from `exclude`
+pub fn rev_list(
+    repository: &Path,
+    include: &str,
+    exclude: &str,
+) -> Result<Vec<String>, Error> {
+    check_sha(include)?;
+    check_sha(exclude)?;
+    let output = command()
+        .arg("rev-list")
+        .arg(include)
+        .arg(format!("^{}", exclude))
+        .arg("--")
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git rev-list` returned {}",
+            output.status
+        )));
+    }
+    let mut commits = Vec::new();
+    for line in output.stdout.split(|&b| b == b'\n') {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| Error::git("Non-utf8 sha?!"))?;
+        if line.is_empty().not() {
+            commits.push(line.into());
+        }
+    }
+    Ok(commits)
+}
+
+/// Make a "raw" reference with the given full path at the given SHA-1
+///
+/// Those need NOT be actual branches (e.g. refs/heads/). The full path should
+/// be given, starting with `refs/`.
+pub fn make_ref(
+    repository: &Path,
+    name: &str,
+    sha: &str,
+) -> Result<(), Error> {
+    check_ref_name(name.as_bytes())?;
+    check_sha(sha)?;
+    let status = command()
+        .args(&["update-ref", "--", name, sha])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git update-ref` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Create a commit object, without updating any ref
+///
+/// The commit has the tree of the commit `source`, or the empty tree if it
+/// is `None`. It is authored and committed by doublegit at `date`, in the
+/// database's format.
+pub fn commit_tree(
+    repository: &Path,
+    source: Option<&str>,
+    parent: Option<&str>,
+    message: &str,
+    date: &str,
+) -> Result<String, Error> {
+    let tree = match source {
+        Some(sha) => {
+            check_sha(sha)?;
+            format!("{}^{{tree}}", sha)
+        }
+        None => {
+            let output = command()
+                .arg("mktree")
+                .current_dir(repository)
+                .stdin(process::Stdio::null())
+                .stderr(process::Stdio::inherit())
+                .output()?;
+            if !output.status.success() {
+                return Err(Error::Git(format!(
+                    "`git mktree` returned {}",
+                    output.status
+                )));
+            }
+            String::from_utf8_lossy(&output.stdout).trim().to_owned()
+        }
+    };
+    let date = format!("{} +0000", date);
+    let mut cmd = command();
+    cmd.args(&["commit-tree", "-m", message]);
+    if let Some(parent) = parent {
+        check_sha(parent)?;
+        cmd.args(&["-p", parent]);
+    }
+    let output = cmd
+        .arg(&tree)
+        .env("GIT_AUTHOR_NAME", "doublegit")
+        .env("GIT_AUTHOR_EMAIL", "doublegit@localhost")
+        .env("GIT_AUTHOR_DATE", &date)
+        .env("GIT_COMMITTER_NAME", "doublegit")
+        .env("GIT_COMMITTER_EMAIL", "doublegit@localhost")
+        .env("GIT_COMMITTER_DATE", &date)
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git commit-tree` returned {}",
+            output.status
+        )));
+    }
+    let sha = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    check_sha(&sha)?;
+    Ok(sha)
+}
+
+/// Point HEAD to a branch, which doesn't have to exist
+pub fn set_head(repository: &Path, branch: &RefName) -> Result<(), Error> {
+    let name = branch.with_prefix("refs/heads/");
+    check_ref_name(name.as_bytes())?;
+    let status = command()
+        .arg("symbolic-ref")
+        .arg("HEAD")
+        .arg(name.to_os_string())
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git symbolic-ref` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Determines if a given name or hash is an annotated tag or not
+///
+/// This will return false for commits, branches (and other references pointing
+/// to commits), and light-weight tags.
+pub fn is_annotated_tag(
+    repository: &Path,
+    target: &str,
+) -> Result<bool, Error> {
+    check_sha(target)?;
+    let output = command()
+        .args(&["cat-file", "-t", target])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git cat-file -t` returned {}",
+            output.status
+        )));
+    }
+    Ok(output.stdout == b"tag\n")
+}
+
+/// List the keep-branches matching a git-for-each-ref filter
+///
+/// Returns the full names of the refs matching `pattern` (e.g.
+/// `refs/heads/keep-*`). Using git-for-each-ref rather than git-branch lets
+/// Git use the commit-graph file.
+fn filter_keep_branches(
+    repository: &Path,
+    pattern: &str,
+    filter: &str,
+) -> Result<Vec<String>, Error> {
+    let output = command()
+        .args(&["for-each-ref", "--format=%(refname)"])
+        .arg(filter)
+        .arg(pattern)
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git for-each-ref {}` returned {}",
+            filter,
+            output.status
+        )));
+    }
+    let mut refs = Vec::new();
+    for line in output.stdout.split(|&b| b == b'\n') {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| Error::git("Non-utf8 branch name"))?
+            .trim();
+        if line.is_empty().not() {
+            refs.push(line.into());
+        }
+    }
+    Ok(refs)
+}
+
+/// List all the keep-branches included in the given one (e.g. parents)
+///
+/// Those are branches that are alive if the given branch is alive, and are
+/// therefore superfluous for garbage-collection-prevention purposes.
+pub fn included_branches(
+    repository: &Path,
+    pattern: &str,
+    target: &str,
+) -> Result<Vec<String>, Error> {
+    check_sha(target)?;
+    filter_keep_branches(repository, pattern, &format!("--merged={}", target))
+}
+
+/// List all the keep-branches that include the given one (e.g. more recent)
+///
+/// Those are branches that keep the given branch alive, making it superfluous
+/// for garbage-collection-prevention purposes.
+pub fn including_branches(
+    repository: &Path,
+    pattern: &str,
+    target: &str,
+) -> Result<Vec<String>, Error> {
+    filter_keep_branches(
+        repository,
+        pattern,
+        &format!("--contains={}", target),
+    )
+}
+
+/// Write or update the commit-graph file, speeding up reachability queries
+pub fn write_commit_graph(repository: &Path) -> Result<(), Error> {
+    let mut cmd = command();
+    cmd.args(&["commit-graph", "write", "--reachable"]);
+    // Incremental files are available since Git 2.24
+    if version()? >= (2, 24, 0) {
+        cmd.arg("--split");
+    }
+    let status = cmd
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git commit-graph write` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Check whether an object exists in the repository
+pub fn object_exists(repository: &Path, sha: &str) -> Result<bool, Error> {
+    check_sha(sha)?;
+    let status = command()
+        .args(&["cat-file", "-e", sha])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+/// List refs matching a pattern with the SHA-1 they point to, keeping the
+/// names as bytes
+///
+/// Unlike `list_refs()`, this doesn't fail on names that are not valid UTF-8,
+/// so it can be used on refs coming from upstream.
+pub fn list_ref_names(
+    repository: &Path,
+    pattern: &str,
+) -> Result<Vec<(RefName, String)>, Error> {
+    let output = command()
+        .args(&["for-each-ref", "--format=%(objectname) %(refname)", pattern])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git for-each-ref` returned {}",
+            output.status
+        )));
+    }
+    let mut refs = Vec::new();
+    for line in output.stdout.split(|&b| b == b'\n') {
+        let mut fields = line.splitn(2, |&b| b == b' ');
+        if let (Some(sha), Some(name)) = (fields.next(), fields.next()) {
+            let sha = String::from_utf8_lossy(sha).into_owned();
+            refs.push((name.into(), sha));
+        }
+    }
+    Ok(refs)
+}
+
+/// List the refs matching a pattern, with the object they point to
+///
+/// The pattern is either a prefix (e.g. `refs/tags/`) or a glob (e.g.
+/// `refs/heads/keep-*`), as understood by git-for-each-ref.
+pub fn list_refs(
+    repository: &Path,
+    pattern: &str,
+) -> Result<Vec<(String, String)>, Error> {
+    let output = command()
+        .args(&["for-each-ref", "--format=%(objectname) %(refname)", pattern])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git for-each-ref` returned {}",
+            output.status
+        )));
+    }
+    let mut refs = Vec::new();
+    for line in output.stdout.split(|&b| b == b'\n') {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| Error::git("Non-utf8 ref name"))?;
+        if line.is_empty().not() {
+            let mut fields = line.splitn(2, ' ');
+            match (fields.next(), fields.next()) {
+                (Some(sha), Some(name)) => refs.push((name.into(), sha.into())),
+                _ => return Err(Error::git("Invalid for-each-ref output")),
+            }
+        }
+    }
+    Ok(refs)
+}
+
+/// Delete a "raw" reference, given its full path starting with `refs/`
+pub fn delete_ref(repository: &Path, name: &str) -> Result<(), Error> {
+    check_ref_name(name.as_bytes())?;
+    let status = command()
+        .args(&["update-ref", "-d", "--", name])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git update-ref -d` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Update or delete many refs in a single transaction
+///
+/// Each ref is set to the given SHA-1, or deleted if it is `None`.
+pub fn update_refs(
+    repository: &Path,
+    refs: &[(RefName, Option<String>)],
+) -> Result<(), Error> {
+    for (name, sha) in refs {
+        check_ref_name(name.as_bytes())?;
+        if let Some(sha) = sha {
+            check_sha(sha)?;
+        }
+    }
+    let mut child = command()
+        .args(&["update-ref", "--stdin"])
+        .current_dir(repository)
+        .stdin(process::Stdio::piped())
+        .spawn()?;
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        for (name, sha) in refs {
+            match sha {
+                Some(_) => stdin.write_all(b"update ")?,
+                None => stdin.write_all(b"delete ")?,
+            }
+            stdin.write_all(name.as_bytes())?;
+            if let Some(sha) = sha {
+                write!(stdin, " {}", sha)?;
+            }
+            stdin.write_all(b"\n")?;
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git update-ref --stdin` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Move all the refs into the packed-refs file
+pub fn pack_refs(repository: &Path) -> Result<(), Error> {
+    let status = command()
+        .args(&["pack-refs", "--all", "--prune"])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git pack-refs` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Write the files of a commit to a directory, without any Git metadata
+///
+/// A temporary index is used, so the mirror is left untouched.
+pub fn checkout_tree(
+    repository: &Path,
+    sha: &str,
+    dest: &Path,
+) -> Result<(), Error> {
+    check_sha(sha)?;
+    let tmp = tempfile::Builder::new()
+        .prefix("doublegit_checkout_")
+        .tempdir()?;
+    let index = tmp.path().join("index");
+    let status = command()
+        .args(&["read-tree", sha])
+        .env("GIT_INDEX_FILE", &index)
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!("`git read-tree` returned {}", status)));
+    }
+    std::fs::create_dir_all(dest)?;
+    let status = command()
+        .arg("--work-tree")
+        .arg(dest.canonicalize()?)
+        .args(&["checkout-index", "--all"])
+        .env("GIT_INDEX_FILE", &index)
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git checkout-index` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Create a bundle file containing exactly the given refs
+///
+/// The refs are given as full names (e.g. `refs/heads/master`) with the SHA-1
+/// they should point to. Since those refs don't necessarily exist in the
+/// repository, they are created in a temporary repository borrowing its
+/// objects through alternates.
+pub fn create_bundle(
+    repository: &Path,
+    refs: &[(RefName, String)],
+    output: &Path,
+) -> Result<(), Error> {
+    let objects = repository.canonicalize()?.join("objects");
+    let tmp = tempfile::Builder::new()
+        .prefix("doublegit_bundle_")
+        .tempdir()?;
+    let status = command()
+        .args(&["init", "--bare", "--quiet"])
+        .arg(tmp.path())
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!("`git init` returned {}", status)));
+    }
+    std::fs::write(
+        tmp.path().join("objects").join("info").join("alternates"),
+        format!("{}\n", objects.display()),
+    )?;
+    let refs: Vec<_> = refs
+        .iter()
+        .map(|(name, sha)| (name.clone(), Some(sha.clone())))
+        .collect();
+    update_refs(tmp.path(), &refs)?;
+
+    // Make the output path absolute, since git runs in the temporary dir
+    let output = if output.is_absolute() {
+        output.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(output)
+    };
+    let status = command()
+        .args(&["bundle", "create"])
+        .arg(&output)
+        .arg("--all")
+        .current_dir(tmp.path())
+        .stdin(process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git bundle create` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Get the messages of the commits reachable from some commits but not others
+///
+/// If `tips` is `None`, all the refs are used. The commits are given as
+/// SHA-1s, so they can be written to git-log's standard input, there might be
+/// a lot of them. Messages that are not valid UTF-8 are converted lossily.
+pub fn commit_messages(
+    repository: &Path,
+    tips: Option<&[String]>,
+    exclude: &[String],
+) -> Result<Vec<(String, String)>, Error> {
+    for sha in tips.unwrap_or(&[]).iter().chain(exclude) {
+        check_sha(sha)?;
+    }
+    let mut cmd = command();
+    cmd.args(&["log", "-z", "--format=%H%n%B", "--stdin"]);
+    if tips.is_none() {
+        cmd.arg("--all");
+    }
+    let mut child = cmd
+        .current_dir(repository)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::inherit())
+        .spawn()?;
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        for sha in tips.unwrap_or(&[]) {
+            writeln!(stdin, "{}", sha)?;
+        }
+        for sha in exclude {
+            writeln!(stdin, "^{}", sha)?;
+        }
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git log` returned {}",
+            output.status
+        )));
+    }
+    let mut messages = Vec::new();
+    for entry in output.stdout.split(|&b| b == 0) {
+        let entry = String::from_utf8_lossy(entry);
+        let mut fields = entry.splitn(2, '\n');
+        if let (Some(sha), Some(message)) = (fields.next(), fields.next()) {
+            messages.push((sha.to_owned(), message.trim_end().to_owned()));
+        }
+    }
+    Ok(messages)
+}
+
+/// Metadata of a commit, as shown in the web view
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub sha: String,
+    /// First parent, `None` for a root commit
+    pub parent: Option<String>,
+    /// Author, as `Name <email>`
+    pub author: String,
+    /// Committer, as `Name <email>`
+    pub committer: String,
+    /// Author date, UTC
+    pub date: String,
+    /// First line of the message
+    pub subject: String,
+}
+
+/// Summarize the changes to the files between two commits, with `--stat`
+pub fn diff_stat(
+    repository: &Path,
+    old: &str,
+    new: &str,
+) -> Result<String, Error> {
+    check_sha(old)?;
+    check_sha(new)?;
+    let output = command()
+        .args(&["diff", "--stat", old, new, "--"])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git diff` returned {}",
+            output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A commit with its full message and the changes it made
+#[cfg(feature = "web")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitDetails {
+    pub sha: String,
+    pub parents: Vec<String>,
+    /// Author, as `Name <email>`
+    pub author: String,
+    /// Author date, UTC
+    pub author_date: String,
+    /// Committer, as `Name <email>`
+    pub committer: String,
+    /// Committer date, UTC
+    pub commit_date: String,
+    pub message: String,
+    /// Unified diff against the first parent
+    pub diff: String,
+    /// Whether the diff was cut at `MAX_DIFF` bytes
+    pub truncated: bool,
+}
+
+/// Maximum size of the diff read by `show_commit()`
+#[cfg(feature = "web")]
+pub const MAX_DIFF: u64 = 1 << 20;
+
+/// Read a commit and its diff against its first parent
+#[cfg(feature = "web")]
+pub fn show_commit(
+    repository: &Path,
+    sha: &str,
+) -> Result<CommitDetails, Error> {
+    check_sha(sha)?;
+    let output = command()
+        .args(&["log", "-z", "--max-count=1"])
+        .arg("--format=%H%x1f%P%x1f%an <%ae>%x1f%at%x1f%cn <%ce>%x1f%ct%x1f%B")
+        .arg(sha)
+        .arg("--")
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::NotFound(format!("commit {}", sha)));
+    }
+    let record = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> =
+        record.trim_end_matches('\0').splitn(7, '\x1f').collect();
+    if fields.len() != 7 {
+        return Err(Error::git("Invalid git-log output"));
+    }
+    let date = |timestamp: &str| -> Result<String, Error> {
+        let timestamp = timestamp
+            .parse::<i64>()
+            .map_err(|_| Error::git("Invalid date in git-log output"))?;
+        Ok(chrono::NaiveDateTime::from_timestamp(timestamp, 0)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string())
+    };
+
+    let mut child = command()
+        .args(&["show", "--no-color", "--format=", "-m", "--first-parent"])
+        .args(&["-M", sha, "--"])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::inherit())
+        .spawn()?;
+    let mut diff = Vec::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .take(MAX_DIFF + 1)
+        .read_to_end(&mut diff)?;
+    let truncated = diff.len() as u64 > MAX_DIFF;
+    if truncated {
+        diff.truncate(MAX_DIFF as usize);
+        // The rest of the diff is not needed
+        child.kill().ok();
+        child.wait()?;
+    } else {
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(Error::Git(format!("`git show` returned {}", status)));
+        }
+    }
+
+    Ok(CommitDetails {
+        sha: fields[0].to_owned(),
+        parents: fields[1]
+            .split(' ')
+            .filter(|p| !p.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+        author: fields[2].to_owned(),
+        author_date: date(fields[3])?,
+        committer: fields[4].to_owned(),
+        commit_date: date(fields[5])?,
+        message: fields[6].trim_end().to_owned(),
+        diff: String::from_utf8_lossy(&diff).into_owned(),
+        truncated,
+    })
+}
+
+/// An entry of a directory, listed by `ls_tree()`
+#[cfg(feature = "web")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    /// File mode, e.g. `100644`
+    pub mode: String,
+    /// `blob`, `tree`, or `commit` for submodules
+    pub kind: String,
+    pub sha: String,
+    pub name: String,
+}
+
+/// List a directory of a commit
+///
+/// `path` is relative to the root of the repository, empty for the root.
+#[cfg(feature = "web")]
+pub fn ls_tree(
+    repository: &Path,
+    sha: &str,
+    path: &str,
+) -> Result<Vec<TreeEntry>, Error> {
+    check_sha(sha)?;
+    let output = command()
+        .args(&["ls-tree", "-z"])
+        .arg(format!("{}:{}", sha, path))
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::NotFound(format!("directory {}", path)));
+    }
+    parse_ls_tree(&output.stdout)
+}
+
+/// Parse the output of `git ls-tree -z`, broken out for unit testing
+#[cfg(feature = "web")]
+fn parse_ls_tree(output: &[u8]) -> Result<Vec<TreeEntry>, Error> {
+    let mut entries = Vec::new();
+    for record in output.split(|&b| b == 0) {
+        if record.is_empty() {
+            continue;
+        }
+        // <mode> SP <type> SP <sha> TAB <name>
+        let tab = match record.iter().position(|&b| b == b'\t') {
+            Some(tab) => tab,
+            None => return Err(Error::git("Invalid git-ls-tree output")),
+        };
+        let info = String::from_utf8_lossy(&record[..tab]);
+        let fields: Vec<&str> = info.split(' ').collect();
+        if fields.len() != 3 {
+            return Err(Error::git("Invalid git-ls-tree output"));
+        }
+        entries.push(TreeEntry {
+            mode: fields[0].to_owned(),
+            kind: fields[1].to_owned(),
+            sha: fields[2].to_owned(),
+            name: String::from_utf8_lossy(&record[tab + 1..]).into_owned(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Read a file of a commit
+#[cfg(feature = "web")]
+pub fn read_blob(
+    repository: &Path,
+    sha: &str,
+    path: &str,
+) -> Result<Vec<u8>, Error> {
+    check_sha(sha)?;
+    let output = command()
+        .args(&["cat-file", "blob"])
+        .arg(format!("{}:{}", sha, path))
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::NotFound(format!("file {}", path)));
+    }
+    Ok(output.stdout)
+}
+
+/// Lines of a file that come from the same commit, found by `blame()`
+#[cfg(feature = "web")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameHunk {
+    /// The commit that last changed those lines
+    pub sha: String,
+    /// Author, as `Name <email>`
+    pub author: String,
+    /// Author date, UTC
+    pub date: String,
+    /// First line of the commit message
+    pub summary: String,
+    /// First line of the hunk in the file, starting at 1
+    pub start: u32,
+    /// Number of lines
+    pub lines: u32,
+}
+
+/// Find which commit last changed each line of a file
+///
+/// The hunks are ordered by line.
+#[cfg(feature = "web")]
+pub fn blame(
+    repository: &Path,
+    sha: &str,
+    path: &str,
+) -> Result<Vec<BlameHunk>, Error> {
+    check_sha(sha)?;
+    let output = command()
+        .args(&["blame", "--incremental", sha, "--"])
+        .arg(path)
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::NotFound(format!("file {}", path)));
+    }
+    let mut hunks = parse_blame(&output.stdout)?;
+    hunks.sort_by_key(|h| h.start);
+    Ok(hunks)
+}
+
+/// Parse the output of `git blame --incremental`, broken out for unit testing
+///
+/// The details of a commit are only given for its first hunk, they are
+/// copied to the next ones.
+#[cfg(feature = "web")]
+fn parse_blame(output: &[u8]) -> Result<Vec<BlameHunk>, Error> {
+    let invalid = || Error::git("Invalid git-blame output");
+    let output = String::from_utf8_lossy(output);
+    let mut commits: HashMap<String, (String, String, String)> =
+        HashMap::new();
+    let mut hunks = Vec::new();
+    let mut lines = output.lines();
+    while let Some(header) = lines.next() {
+        // <sha> <original line> <final line> <number of lines>
+        let fields: Vec<&str> = header.split(' ').collect();
+        if fields.len() != 4 || check_sha(fields[0]).is_err() {
+            return Err(invalid());
+        }
+        let sha = fields[0].to_owned();
+        let start = fields[2].parse().map_err(|_| invalid())?;
+        let count = fields[3].parse().map_err(|_| invalid())?;
+        let (mut name, mut mail, mut date, mut summary) =
+            (None, None, None, None);
+        loop {
+            let line = lines.next().ok_or_else(invalid)?;
+            let (key, value) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => (line, ""),
+            };
+            match key {
+                "author" => name = Some(value),
+                "author-mail" => mail = Some(value),
+                "author-time" => {
+                    let timestamp =
+                        value.parse::<i64>().map_err(|_| invalid())?;
+                    date = Some(
+                        chrono::NaiveDateTime::from_timestamp(timestamp, 0)
+                            .format("%Y-%m-%d %H:%M:%S")
+                            .to_string(),
+                    );
+                }
+                "summary" => summary = Some(value),
+                "filename" => break,
+                _ => {}
+            }
+        }
+        if let (Some(name), Some(mail), Some(date), Some(summary)) =
+            (name, mail, date, summary)
+        {
+            commits.insert(
+                sha.clone(),
+                (format!("{} {}", name, mail), date, summary.to_owned()),
+            );
+        }
+        let (author, date, summary) =
+            commits.get(&sha).cloned().ok_or_else(invalid)?;
+        hunks.push(BlameHunk {
+            sha,
+            author,
+            date,
+            summary,
+            start,
+            lines: count,
+        });
+    }
+    Ok(hunks)
+}
+
+/// A line matching `grep()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrepLine {
+    /// The commit or tag it was found in
+    pub sha: String,
+    pub path: String,
+    /// Line number, starting at 1
+    pub line: u32,
+    pub text: String,
+}
+
+/// Number of revisions given to each `git grep` command
+const GREP_BATCH: usize = 100;
+
+/// Search the files of commits or tags with `git grep`
+///
+/// The pattern is a basic regular expression, as in `git grep`. Binary files
+/// are skipped.
+pub fn grep(
+    repository: &Path,
+    pattern: &str,
+    ignore_case: bool,
+    revisions: &[String],
+) -> Result<Vec<GrepLine>, Error> {
+    let mut lines = Vec::new();
+    for batch in revisions.chunks(GREP_BATCH) {
+        let mut cmd = command();
+        cmd.args(&["grep", "-z", "-n", "-I"]);
+        if ignore_case {
+            cmd.arg("-i");
+        }
+        cmd.arg("-e").arg(pattern);
+        for sha in batch {
+            check_sha(sha)?;
+            cmd.arg(sha);
+        }
+        let output = cmd
+            .arg("--")
+            .current_dir(repository)
+            .stdin(process::Stdio::null())
+            .stderr(process::Stdio::inherit())
+            .output()?;
+        // Exit code 1 means there was no match
+        if !output.status.success() && output.status.code() != Some(1) {
+            return Err(Error::Git(format!(
+                "`git grep` returned {}",
+                output.status
+            )));
+        }
+        lines.extend(parse_grep(&output.stdout)?);
+    }
+    Ok(lines)
+}
+
+/// Parse the output of `git grep -z -n` on revisions, broken out for unit
+/// testing
+fn parse_grep(output: &[u8]) -> Result<Vec<GrepLine>, Error> {
+    let mut lines = Vec::new();
+    let mut rest = output;
+    while !rest.is_empty() {
+        // <sha>:<path>\0<line>\0<text>\n
+        let mut fields = rest.splitn(3, |&b| b == 0);
+        let (name, line, text) =
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(line), Some(text))
+                    if name.len() > 41 && name[40] == b':' =>
+                {
+                    (name, line, text)
+                }
+                _ => return Err(Error::git("Invalid git-grep output")),
+            };
+        let line = String::from_utf8_lossy(line).parse().map_err(|_| {
+            Error::git("Invalid line number in git-grep output")
+        })?;
+        let end = text.iter().position(|&b| b == b'\n').unwrap_or(text.len());
+        lines.push(GrepLine {
+            sha: String::from_utf8_lossy(&name[..40]).into_owned(),
+            path: String::from_utf8_lossy(&name[41..]).into_owned(),
+            line,
+            text: String::from_utf8_lossy(&text[..end]).into_owned(),
+        });
+        rest = &text[(end + 1).min(text.len())..];
+    }
+    Ok(lines)
+}
+
+/// Get the last commits on a branch, following first parents
+pub fn log_commits(
+    repository: &Path,
+    tip: &str,
+    number: usize,
+) -> Result<Vec<CommitInfo>, Error> {
+    log_commits_from(repository, tip, 0, number)
+}
+
+/// Get commits on a branch, following first parents, after skipping the
+/// `skip` last ones
+///
+/// This returns fewer commits if the branch is too short, none if there are
+/// only `skip` commits or less.
+pub fn log_commits_from(
+    repository: &Path,
+    tip: &str,
+    skip: usize,
+    number: usize,
+) -> Result<Vec<CommitInfo>, Error> {
+    check_sha(tip)?;
+    let output = command()
+        .args(&["log", "-z", "--first-parent"])
+        .arg(format!("--skip={}", skip))
+        .arg(format!("--max-count={}", number))
+        .arg("--format=%H%x1f%P%x1f%an <%ae>%x1f%cn <%ce>%x1f%at%x1f%s")
+        .arg(tip)
+        .arg("--")
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git log` returned {}",
+            output.status
+        )));
+    }
+    parse_log_commits(&output.stdout)
+}
+
+/// Parse the output of `log_commits()`, broken out for unit testing
+fn parse_log_commits(output: &[u8]) -> Result<Vec<CommitInfo>, Error> {
+    let mut commits = Vec::new();
+    for record in output.split(|&b| b == 0) {
+        let record = String::from_utf8_lossy(record);
+        let record = record.trim_start_matches('\n');
+        if record.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = record.splitn(6, '\x1f').collect();
+        if fields.len() != 6 {
+            return Err(Error::git("Invalid git-log output"));
+        }
+        let timestamp = fields[4]
+            .parse::<i64>()
+            .map_err(|_| Error::git("Invalid date in git-log output"))?;
+        commits.push(CommitInfo {
+            sha: fields[0].to_owned(),
+            parent: fields[1].split(' ').next()
+                .filter(|p| !p.is_empty())
+                .map(ToOwned::to_owned),
+            author: fields[2].to_owned(),
+            committer: fields[3].to_owned(),
+            date: chrono::NaiveDateTime::from_timestamp(timestamp, 0)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            subject: fields[5].to_owned(),
+        });
+    }
+    Ok(commits)
+}
+
+/// Content of an annotated tag object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagInfo {
+    /// SHA-1 of the tag object
+    pub sha: String,
+    /// SHA-1 of the object it points to
+    pub target: String,
+    /// Tagger, as `Name <email>`
+    pub tagger: String,
+    /// Tagger date, UTC, if the tag has one (very old tags don't)
+    pub date: Option<String>,
+    /// Message, including the signature if any
+    pub message: String,
+}
+
+/// Read the annotated tags that refs matching a pattern point to
+///
+/// Refs to other kinds of objects are ignored.
+pub fn annotated_tags(
+    repository: &Path,
+    pattern: &str,
+) -> Result<Vec<TagInfo>, Error> {
+    let output = command()
+        .arg("for-each-ref")
+        .arg(
+            "--format=%(objecttype)%00%(objectname)%00%(*objectname)%00\
+             %(taggername) %(taggeremail)%00%(taggerdate:unix)%00\
+             %(contents)%00",
+        )
+        .arg(pattern)
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "`git for-each-ref` returned {}",
+            output.status
+        )));
+    }
+    parse_annotated_tags(&output.stdout)
+}
+
+/// Parse the output of `annotated_tags()`, broken out for unit testing
+fn parse_annotated_tags(output: &[u8]) -> Result<Vec<TagInfo>, Error> {
+    let mut tags = Vec::new();
+    let mut fields = output.split(|&b| b == 0);
+    while let Some(type_) = fields.next() {
+        let type_ = String::from_utf8_lossy(type_);
+        let type_ = type_.trim_start_matches('\n');
+        if type_.is_empty() {
+            continue;
+        }
+        let mut field = || {
+            fields
+                .next()
+                .map(|f| String::from_utf8_lossy(f).into_owned())
+                .ok_or_else(|| Error::git("Invalid for-each-ref output"))
+        };
+        let (sha, target, tagger, date, message) =
+            (field()?, field()?, field()?, field()?, field()?);
+        if type_ != "tag" {
+            continue;
+        }
+        let date = match date.parse::<i64>() {
+            Ok(t) => Some(
+                chrono::NaiveDateTime::from_timestamp(t, 0)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+            ),
+            Err(_) => None,
+        };
+        tags.push(TagInfo {
+            sha,
+            target,
+            tagger: tagger.trim().to_owned(),
+            date,
+            message,
+        });
+    }
+    Ok(tags)
+}
+
+/// Write a git-fast-export stream of every ref in the repository
+///
+/// This includes the keep-branches and kept tags, so all the objects ever
+/// archived are covered. The stream can only create tags under `refs/tags/`,
+/// so `git fast-import` puts the kept tags there, under their full ref name.
+pub fn fast_export<W: Write>(
+    repository: &Path,
+    out: &mut W,
+) -> Result<(), Error> {
+    let mut child = command()
+        .args(&["fast-export", "--all", "--signed-tags=verbatim"])
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::inherit())
+        .spawn()?;
+    if let Err(e) = std::io::copy(child.stdout.as_mut().unwrap(), out) {
+        // Don't leave it blocked writing to the pipe
+        child.kill().ok();
+        child.wait()?;
+        return Err(e.into());
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::Git(format!(
+            "`git fast-export` returned {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Start `git archive` writing a commit's files as a gzipped tarball
+///
+/// The files are put under `prefix`, e.g. `my-repo-1234567890/`. The tarball
+/// is read from the child's stdout, and it has to be waited for.
+#[cfg(feature = "web")]
+pub fn archive_tar_gz(
+    repository: &Path,
+    sha: &str,
+    prefix: &str,
+) -> Result<process::Child, Error> {
+    check_sha(sha)?;
+    let child = command()
+        .args(&["archive", "--format=tar.gz"])
+        .arg(format!("--prefix={}", prefix))
+        .arg(sha)
+        .current_dir(repository)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+    Ok(child)
 }
 
-/// Make a branch with the given name at the given commit identified by SHA-1
+/// Start `git upload-pack` for a client cloning or fetching over HTTP
 ///
-/// Those are actual branches (e.g. refs/heads/) that will be listed in
-/// git-branch output (and therefore by `included_branches()` and
-/// `including_branches()`).
-pub fn make_branch(
+/// This is the stateless mode of the smart HTTP protocol: with
+/// `advertise_refs`, it lists the refs (including the keep-refs), otherwise
+/// it answers the client's `request` and sends the pack. `protocol` is the
+/// client's `Git-Protocol` header, e.g. `version=2`. The response is read
+/// from the child's stdout, and it has to be waited for.
+#[cfg(feature = "web")]
+pub fn upload_pack(
     repository: &Path,
-    name: &str,
-    sha: &str,
-) -> Result<(), Error> {
-    let status = process::Command::new("git")
-        .args(&["branch", "-f", name, sha])
+    advertise_refs: bool,
+    request: Vec<u8>,
+    protocol: Option<&str>,
+) -> Result<process::Child, Error> {
+    let mut cmd = command();
+    cmd.args(&["upload-pack", "--stateless-rpc"]);
+    if advertise_refs {
+        cmd.arg("--advertise-refs");
+    }
+    if let Some(protocol) = protocol {
+        cmd.env("GIT_PROTOCOL", protocol);
+    }
+    let mut child = cmd
+        .arg(".")
         .current_dir(repository)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    thread::spawn(move || stdin.write_all(&request));
+    Ok(child)
+}
+
+/// Push refs to a remote
+pub fn push(
+    repository: &Path,
+    remote: &str,
+    refspecs: &[String],
+) -> Result<(), Error> {
+    let status = remote_command(repository)?
+        .args(&["push", "--"])
+        .arg(remote)
+        .args(refspecs)
         .stdin(process::Stdio::null())
         .status()?;
     if !status.success() {
-        return Err(Error::Git(format!("`git branch -f` returned {}", status)));
+        return Err(Error::Git(format!("`git push` returned {}", status)));
     }
     Ok(())
 }
 
-/// Make a "raw" reference with the given full path at the given SHA-1
-///
-/// Those need NOT be actual branches (e.g. refs/heads/). The full path should
-/// be given, starting with `refs/`.
-///
-/// This is useful to create refs that will not be listed in git-branch output
-/// and therefore will not appear in `included_branches()` and
-/// `including_branches()` output.
-pub fn make_ref(
-    repository: &Path,
-    name: &str,
-    sha: &str,
-) -> Result<(), Error> {
-    let status = process::Command::new("git")
-        .args(&["update-ref", name, sha])
-        .current_dir(repository)
+/// Push all the refs to a remote, making it an exact copy
+pub fn push_mirror(repository: &Path, remote: &str) -> Result<(), Error> {
+    let output = remote_command(repository)?
+        .args(&["push", "--mirror", "--quiet", "--"])
+        .arg(remote)
         .stdin(process::Stdio::null())
-        .status()?;
-    if !status.success() {
+        .output()?;
+    if !output.status.success() {
         return Err(Error::Git(format!(
-            "`git update-ref` returned {}",
-            status
+            "`git push --mirror` returned {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
         )));
     }
     Ok(())
 }
 
-/// Determines if a given name or hash is an annotated tag or not
-///
-/// This will return false for commits, branches (and other references pointing
-/// to commits), and light-weight tags.
-pub fn is_annotated_tag(
-    repository: &Path,
-    target: &str,
-) -> Result<bool, Error> {
-    let output = process::Command::new("git")
-        .args(&["cat-file", "-t", target])
+/// Write all the refs and the objects they reference to a bundle file
+pub fn bundle_all(repository: &Path, path: &Path) -> Result<(), Error> {
+    let output = command()
+        .args(&["bundle", "create"])
+        .arg(path)
+        .arg("--all")
         .current_dir(repository)
         .stdin(process::Stdio::null())
-        .stderr(process::Stdio::inherit())
         .output()?;
     if !output.status.success() {
         return Err(Error::Git(format!(
-            "`git cat-file -t` returned {}",
-            output.status
+            "`git bundle create` returned {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
         )));
     }
-    Ok(output.stdout == b"tag\n")
+    Ok(())
 }
 
-/// List all the branches included in the given one (e.g. parents)
-///
-/// Those are branches that are alive if the given branch is alive, and are
-/// therefore superfluous for garbage-collection-prevention purposes.
-pub fn included_branches(
+/// Get the set of objects that refs matching the patterns point to
+pub fn ref_targets(
     repository: &Path,
-    target: &str,
-) -> Result<Vec<String>, Error> {
-    let output = process::Command::new("git")
-        .args(&["branch", "--merged", target])
+    patterns: &[&str],
+) -> Result<HashSet<String>, Error> {
+    let output = command()
+        .args(&["for-each-ref", "--format=%(objectname)"])
+        .args(patterns)
         .current_dir(repository)
         .stdin(process::Stdio::null())
         .stderr(process::Stdio::inherit())
         .output()?;
     if !output.status.success() {
         return Err(Error::Git(format!(
-            "`git branch --merged` returned {}",
+            "`git for-each-ref` returned {}",
             output.status
         )));
     }
-    let mut refs = Vec::new();
-    for line in output.stdout.split(|&b| b == b'\n') {
-        let line = std::str::from_utf8(line)
-            .map_err(|_| Error::git("Non-utf8 branch name"))?
-            .trim();
-        if line.is_empty().not() {
-            refs.push(line.into());
-        }
-    }
-    Ok(refs)
+    let output = std::str::from_utf8(&output.stdout)
+        .map_err(|_| Error::git("Non-utf8 sha?!"))?;
+    Ok(output.lines().map(Into::into).collect())
+}
+
+/// Get the set of all the commits reachable from any ref
+pub fn all_commits(repository: &Path) -> Result<HashSet<String>, Error> {
+    reachable_commits(repository, &["--all"], &[])
 }
 
-/// List all the branches that include the given one (e.g. more recent)
+/// Get the set of commits reachable from the given revisions
 ///
-/// Those are branches that keep the given branch alive, making it superfluous
-/// for garbage-collection-prevention purposes.
-pub fn including_branches(
+/// `args` are passed on the command-line (e.g. `--glob=refs/heads/*`), while
+/// `revisions` are fed through standard input, so there can be many.
+pub fn reachable_commits(
     repository: &Path,
-    target: &str,
-) -> Result<Vec<String>, Error> {
-    let output = process::Command::new("git")
-        .args(&["branch", "--contains", target])
+    args: &[&str],
+    revisions: &[String],
+) -> Result<HashSet<String>, Error> {
+    let mut child = command()
+        .arg("rev-list")
+        .args(args)
+        .arg("--stdin")
         .current_dir(repository)
-        .stdin(process::Stdio::null())
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
         .stderr(process::Stdio::inherit())
-        .output()?;
-    if !output.status.success() {
-        return Err(Error::Git(format!(
-            "`git branch --contains` returned {}",
-            output.status
-        )));
-    }
-    let mut refs = Vec::new();
-    for line in output.stdout.split(|&b| b == b'\n') {
-        let line = std::str::from_utf8(line)
-            .map_err(|_| Error::git("Non-utf8 branch name"))?
-            .trim();
-        if line.is_empty().not() {
-            refs.push(line.into());
-        }
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = revisions.join("\n");
+    let writer = thread::spawn(move || {
+        stdin.write_all(input.as_bytes()).and_then(|_| stdin.write_all(b"\n"))
+    });
+    let mut output = Vec::new();
+    child.stdout.as_mut().unwrap().read_to_end(&mut output)?;
+    let status = child.wait()?;
+    writer.join().unwrap_or(Ok(())).ok();
+    if !status.success() {
+        return Err(Error::Git(format!("`git rev-list` returned {}", status)));
     }
-    Ok(refs)
+    let output = std::str::from_utf8(&output)
+        .map_err(|_| Error::git("Non-utf8 sha?!"))?;
+    Ok(output.lines().map(Into::into).collect())
 }
 
-/// Delete a branch
+/// Repack a repository, with `git repack -adkl` or `git gc --prune=now`
 ///
-/// This will fail if the branch doesn't exist.
-pub fn delete_branch(repository: &Path, name: &str) -> Result<(), Error> {
-    let status = process::Command::new("git")
-        .args(&["branch", "-D", name])
+/// Without `prune`, unreachable objects are kept in the new pack. Objects
+/// found in alternates are dropped rather than copied in. A reachability
+/// bitmap is written, unless there are alternates (Git can't write one then).
+pub fn gc(repository: &Path, prune: bool) -> Result<(), Error> {
+    let bitmaps = !repository
+        .join("objects")
+        .join("info")
+        .join("alternates")
+        .exists();
+    let mut cmd = command();
+    if prune {
+        cmd.arg("-c")
+            .arg(format!("repack.writeBitmaps={}", bitmaps))
+            .args(&["gc", "--quiet", "--prune=now"]);
+    } else {
+        cmd.args(&["repack", "-a", "-d", "-k", "-l", "--quiet"]);
+        if bitmaps {
+            cmd.arg("-b");
+        }
+    }
+    let status = cmd
         .current_dir(repository)
         .stdin(process::Stdio::null())
-        .stdout(process::Stdio::null())
         .status()?;
     if !status.success() {
-        return Err(Error::Git(format!("`git branch -D` returned {}", status)));
+        return Err(Error::Git(format!(
+            "`git {}` returned {}",
+            if prune { "gc" } else { "repack" },
+            status
+        )));
     }
     Ok(())
 }
@@ -320,7 +3006,20 @@ pub fn delete_branch(repository: &Path, name: &str) -> Result<(), Error> {
 #[cfg(test)]
 mod tests {
     use crate::Ref;
-    use crate::git::{Operation, parse_operation, parse_fetch_output};
+    use crate::git::{
+        Operation, parse_operation, parse_fetch_output,
+        Limits, ObjectCounts, output_with_limits, parse_count_objects,
+        CommitInfo, parse_log_commits, TagInfo, parse_annotated_tags,
+        access_token, check_ref_name, check_sha, command, negotiation_tips,
+        parse_not_found, parse_porcelain_fetch_output, parse_redirect,
+        ReflogEntry, parse_reflog, parse_version, protocol_settings,
+        proxy_url, ssh_command, GrepLine, parse_grep,
+    };
+    #[cfg(feature = "web")]
+    use super::{TreeEntry, parse_ls_tree, BlameHunk, parse_blame};
+    use std::collections::HashMap;
+    use std::process;
+    use std::time::Duration;
 
     #[test]
     fn test_parse_operation() {
@@ -374,4 +3073,551 @@ From github.com:remram44/doublegit
             ].iter().cloned().collect(),
         );
     }
+
+    #[test]
+    fn test_parse_fetch_tags() {
+        let stderr: &[u8] = b"
+From ../origin
+ t [tag update]      release/1.0 -> release/1.0
+ + 8fda1c0...a64697b tag2       -> tag2  (forced update)
+ * [new tag]         tag3       -> tag3
+ = [up to date]      master     -> origin/master
+";
+        let output = parse_fetch_output(stderr).unwrap();
+        assert!(output.new.contains(&Ref { name: "tag3".into(), tag: true }));
+        assert_eq!(output.changed.len(), 2);
+        assert!(output.changed.contains(
+            &Ref { name: "release/1.0".into(), tag: true },
+        ));
+        assert!(output.changed.contains(
+            &Ref { name: "tag2".into(), tag: true },
+        ));
+        assert!(output.removed.is_empty());
+
+        // New and pruned tags with slashes are not taken for branches
+        let stderr: &[u8] = b"
+From ../origin
+ * [new tag]         release/1.0 -> release/1.0
+ - [deleted]         (none)     -> release/0.9
+ - [deleted]         (none)     -> origin/feature/x
+";
+        let output = parse_fetch_output(stderr).unwrap();
+        assert_eq!(
+            output.new,
+            [Ref { name: "release/1.0".into(), tag: true }]
+                .iter().cloned().collect(),
+        );
+        assert_eq!(
+            output.removed,
+            [
+                Ref { name: "release/0.9".into(), tag: true },
+                Ref { name: "feature/x".into(), tag: false },
+            ].iter().cloned().collect(),
+        );
+    }
+
+    #[test]
+    fn test_parse_porcelain_fetch() {
+        let stdout: &[u8] = b"\
+* 0000000000000000000000000000000000000000 ae79568054d9fa2e4956968310655e9bcbd60e2f refs/remotes/origin/master
+  673b728a0000000000000000000000000000000 466e90b00000000000000000000000000000000 refs/remotes/origin/feature/x
+- 8dcda34bbae83d2e3d856cc5dbc356ee6e947619 0000000000000000000000000000000000000000 refs/remotes/origin/old
++ 8fda1c0cfb4957e376fba4b53bf3ce080e25300c a64697beb90c35d198fd25f2985cbc9e1ac1783e refs/tags/tag2
+t ae79568054d9fa2e4956968310655e9bcbd60e2f 8dcda34bbae83d2e3d856cc5dbc356ee6e947619 refs/tags/origin/tag1
+- ae79568054d9fa2e4956968310655e9bcbd60e2f 0000000000000000000000000000000000000000 refs/tags/gone
+
+";
+        let output = parse_porcelain_fetch_output(stdout).unwrap();
+        assert_eq!(
+            output.new,
+            [
+                Ref {
+                    name: "master".into(),
+                    tag: false,
+                },
+            ].iter().cloned().collect(),
+        );
+        assert_eq!(
+            output.changed,
+            [
+                Ref {
+                    name: "feature/x".into(),
+                    tag: false,
+                },
+                Ref {
+                    name: "tag2".into(),
+                    tag: true,
+                },
+                Ref {
+                    name: "origin/tag1".into(),
+                    tag: true,
+                },
+            ].iter().cloned().collect(),
+        );
+        assert_eq!(
+            output.removed,
+            [
+                Ref {
+                    name: "old".into(),
+                    tag: false,
+                },
+                Ref {
+                    name: "gone".into(),
+                    tag: true,
+                },
+            ].iter().cloned().collect(),
+        );
+
+        // Unusual lines
+        assert!(parse_porcelain_fetch_output(b"From ../origin\n").is_err());
+        assert!(parse_porcelain_fetch_output(b"* abc def\n").is_err());
+        assert!(parse_porcelain_fetch_output(
+            b"* 0000 1111 refs/heads/master\n",
+        ).is_err());
+        assert!(parse_porcelain_fetch_output(
+            b"! 0000 1111 refs/remotes/origin/master\n",
+        ).is_err());
+        assert!(parse_porcelain_fetch_output(
+            b"= 1111 1111 refs/remotes/origin/master\n",
+        ).unwrap().changed.is_empty());
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("git version 2.39.5\n"), Some((2, 39, 5)));
+        assert_eq!(
+            parse_version("git version 2.41.0.windows.1"),
+            Some((2, 41, 0)),
+        );
+        assert_eq!(parse_version("git version 3.0"), Some((3, 0, 0)));
+        assert_eq!(parse_version("garbage"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_timeout() {
+        let output = output_with_limits(
+            process::Command::new("echo")
+                .arg("hello")
+                .stdout(process::Stdio::piped()),
+            &Limits {
+                timeout: Some(Duration::from_secs(5)),
+                ..Default::default()
+            },
+        ).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hello\n");
+
+        assert!(output_with_limits(
+            process::Command::new("sleep").arg("10"),
+            &Limits {
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        ).is_err());
+    }
+
+    #[test]
+    fn test_parse_count_objects() {
+        let output = "\
+count: 12
+size: 48
+in-pack: 1024
+packs: 1
+size-pack: 300
+prune-packable: 0
+garbage: 0
+size-garbage: 0
+";
+        assert_eq!(
+            parse_count_objects(output),
+            ObjectCounts {
+                objects: 1036,
+                bytes: 348 * 1024,
+            },
+        );
+    }
+
+    #[test]
+    fn test_parse_log_commits() {
+        let output = b"\
+aaaa\x1fbbbb cccc\x1fAlice <alice@example.org>\x1fBob <bob@example.org>\
+\x1f1552755600\x1fMerge branch 'feature'\0\
+bbbb\x1f\x1fAlice <alice@example.org>\x1fAlice <alice@example.org>\
+\x1f1552752000\x1fFirst\x1fcommit\0";
+        assert_eq!(
+            parse_log_commits(output).unwrap(),
+            vec![
+                CommitInfo {
+                    sha: "aaaa".into(),
+                    parent: Some("bbbb".into()),
+                    author: "Alice <alice@example.org>".into(),
+                    committer: "Bob <bob@example.org>".into(),
+                    date: "2019-03-16 17:00:00".into(),
+                    subject: "Merge branch 'feature'".into(),
+                },
+                CommitInfo {
+                    sha: "bbbb".into(),
+                    parent: None,
+                    author: "Alice <alice@example.org>".into(),
+                    committer: "Alice <alice@example.org>".into(),
+                    date: "2019-03-16 16:00:00".into(),
+                    subject: "First\x1fcommit".into(),
+                },
+            ],
+        );
+        assert!(parse_log_commits(b"aaaa\x1fbbbb\0").is_err());
+    }
+
+    #[cfg(feature = "web")]
+    #[test]
+    fn test_parse_blame() {
+        let a = "b535e89641d5a7d89e4f005bb3ed8964ee4fbe4d";
+        let b = "7d2abe2c19adf3a30de2d850630ad4407e786f75";
+        let header = |sha: &str, time: &str, summary: &str| {
+            format!(
+                "author Alice\nauthor-mail <alice@example.org>\n\
+                 author-time {}\nauthor-tz +0000\ncommitter Bob\n\
+                 committer-mail <bob@example.org>\ncommitter-time {}\n\
+                 committer-tz +0000\nsummary {}\nprevious {} README\n",
+                time, time, summary, sha,
+            )
+        };
+        let output = format!(
+            "{a} 3 3 1\n{}filename README\n\
+             {b} 1 1 2\n{}boundary\nfilename README\n\
+             {a} 5 4 1\nfilename README\n",
+            header(b, "1552755600", "Change things"),
+            header(b, "1552752000", "First commit"),
+            a = a,
+            b = b,
+        );
+        let hunk = |sha: &str, date: &str, summary: &str, start, lines| {
+            BlameHunk {
+                sha: sha.into(),
+                author: "Alice <alice@example.org>".into(),
+                date: date.into(),
+                summary: summary.into(),
+                start,
+                lines,
+            }
+        };
+        assert_eq!(
+            parse_blame(output.as_bytes()).unwrap(),
+            vec![
+                hunk(a, "2019-03-16 17:00:00", "Change things", 3, 1),
+                hunk(b, "2019-03-16 16:00:00", "First commit", 1, 2),
+                hunk(a, "2019-03-16 17:00:00", "Change things", 4, 1),
+            ],
+        );
+        assert!(parse_blame(format!("{} 1 1 1\n", a).as_bytes()).is_err());
+        assert!(
+            parse_blame(format!("{} 1 1 1\nfilename a\n", a).as_bytes())
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "web")]
+    #[test]
+    fn test_parse_ls_tree() {
+        let output = b"\
+100644 blob 7d2abe2c19adf3a30de2d850630ad4407e786f75\tREADME\x00\
+040000 tree 1f4a29c78ac2fc2e7a88ea7ce56b3890c31270dc\tsrc dir\x00\
+160000 commit 84bf5f7d57f14291994b128a9d0656f68d1aab6a\tvendor\x00";
+        let entry = |mode: &str, kind: &str, sha: &str, name: &str| {
+            TreeEntry {
+                mode: mode.into(),
+                kind: kind.into(),
+                sha: sha.into(),
+                name: name.into(),
+            }
+        };
+        assert_eq!(
+            parse_ls_tree(output).unwrap(),
+            vec![
+                entry(
+                    "100644", "blob",
+                    "7d2abe2c19adf3a30de2d850630ad4407e786f75", "README",
+                ),
+                entry(
+                    "040000", "tree",
+                    "1f4a29c78ac2fc2e7a88ea7ce56b3890c31270dc", "src dir",
+                ),
+                entry(
+                    "160000", "commit",
+                    "84bf5f7d57f14291994b128a9d0656f68d1aab6a", "vendor",
+                ),
+            ],
+        );
+        assert!(parse_ls_tree(b"100644 blob README\x00").is_err());
+    }
+
+    #[test]
+    fn test_parse_grep() {
+        let a = "7d2abe2c19adf3a30de2d850630ad4407e786f75";
+        let b = "1f4a29c78ac2fc2e7a88ea7ce56b3890c31270dc";
+        let output = format!(
+            "{}:src/a:b.txt\x002\0world foo\n{}:README\x0010\0foo\n",
+            a, b,
+        );
+        assert_eq!(
+            parse_grep(output.as_bytes()).unwrap(),
+            vec![
+                GrepLine {
+                    sha: a.into(),
+                    path: "src/a:b.txt".into(),
+                    line: 2,
+                    text: "world foo".into(),
+                },
+                GrepLine {
+                    sha: b.into(),
+                    path: "README".into(),
+                    line: 10,
+                    text: "foo".into(),
+                },
+            ],
+        );
+        assert!(parse_grep(b"README\x001\0foo\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_reflog() {
+        let log = b"\
+0000000000000000000000000000000000000000 \
+aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa \
+Alice <alice@example.org> 1552752000 -0500\tclone: from /tmp/origin
+aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa \
+bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb \
+Alice <alice@example.org> 1552755600 +0000\tfetch: fast-forward
+";
+        assert_eq!(
+            parse_reflog(log).unwrap(),
+            vec![
+                ReflogEntry {
+                    old: None,
+                    new: "a".repeat(40),
+                    timestamp: 1552752000,
+                },
+                ReflogEntry {
+                    old: Some("a".repeat(40)),
+                    new: "b".repeat(40),
+                    timestamp: 1552755600,
+                },
+            ],
+        );
+        assert_eq!(parse_reflog(b"").unwrap(), vec![]);
+        assert!(parse_reflog(b"aaaa bbbb A <a@b> yesterday +0000\t\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_annotated_tags() {
+        let output = b"\
+tag\0aaaa\0bbbb\0Alice <alice@example.org>\x001552755600\0Release 1.0\n\
+\nChanges:\n* stuff\n\0
+commit\0bbbb\0\0 \0\0one\n\0
+tag\0cccc\0bbbb\0 \0\0old\n\0
+";
+        assert_eq!(
+            parse_annotated_tags(output).unwrap(),
+            vec![
+                TagInfo {
+                    sha: "aaaa".into(),
+                    target: "bbbb".into(),
+                    tagger: "Alice <alice@example.org>".into(),
+                    date: Some("2019-03-16 17:00:00".into()),
+                    message: "Release 1.0\n\nChanges:\n* stuff\n".into(),
+                },
+                TagInfo {
+                    sha: "cccc".into(),
+                    target: "bbbb".into(),
+                    tagger: "".into(),
+                    date: None,
+                    message: "old\n".into(),
+                },
+            ],
+        );
+        assert!(parse_annotated_tags(b"tag\0aaaa\0bbbb").is_err());
+    }
+
+    #[test]
+    fn test_ssh_command() {
+        let mut config = HashMap::new();
+        assert_eq!(ssh_command(&config).unwrap(), None);
+        config.insert("sshkey".into(), "/keys/it's here".into());
+        config.insert("sshknownhosts".into(), "accept-new".into());
+        config.insert("sshoptions".into(), "-p 2222".into());
+        assert_eq!(
+            ssh_command(&config).unwrap().unwrap(),
+            "ssh -i '/keys/it'\\''s here' \
+             -o IdentitiesOnly=yes -o IdentityAgent=none -o BatchMode=yes \
+             -o StrictHostKeyChecking=accept-new -p 2222",
+        );
+        config.insert("sshknownhosts".into(), "maybe".into());
+        assert!(ssh_command(&config).is_err());
+    }
+
+    #[test]
+    fn test_access_token() {
+        let mut config = HashMap::new();
+        assert_eq!(access_token(&config).unwrap(), None);
+        config.insert(
+            "tokenenv".into(),
+            "DOUBLEGIT_TEST_TOKEN_UNSET".into(),
+        );
+        assert!(access_token(&config).is_err());
+        std::env::set_var("DOUBLEGIT_TEST_TOKEN", "from env");
+        config.insert("tokenenv".into(), "DOUBLEGIT_TEST_TOKEN".into());
+        assert_eq!(access_token(&config).unwrap().unwrap(), "from env");
+        config.insert("token".into(), "direct".into());
+        assert_eq!(access_token(&config).unwrap().unwrap(), "direct");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_access_token_command() {
+        let mut config = HashMap::new();
+        config.insert("tokencommand".into(), "echo ' secret '".into());
+        assert_eq!(access_token(&config).unwrap().unwrap(), "secret");
+        config.insert("tokencommand".into(), "false".into());
+        assert!(access_token(&config).is_err());
+    }
+
+    #[test]
+    fn test_proxy_url() {
+        let mut config = HashMap::new();
+        assert_eq!(proxy_url(&config).unwrap(), None);
+        config.insert("proxy".into(), "socks5h://proxy:1080".into());
+        assert_eq!(proxy_url(&config).unwrap(), Some("socks5h://proxy:1080"));
+        config.insert("proxy".into(), "proxy:3128".into());
+        assert!(proxy_url(&config).is_err());
+    }
+
+    #[test]
+    fn test_command() {
+        let repo = tempfile::Builder::new()
+            .prefix("doublegit_test_")
+            .tempdir().unwrap();
+        assert!(command()
+            .args(&["init", "--bare", "--quiet"])
+            .current_dir(repo.path())
+            .status().unwrap().success());
+
+        // Only the repository's configuration and ours are read
+        let output = command()
+            .args(&["config", "--list", "--show-origin"])
+            .current_dir(repo.path())
+            .output().unwrap();
+        assert!(output.status.success());
+        let output = String::from_utf8(output.stdout).unwrap();
+        for line in output.lines() {
+            assert!(
+                line.starts_with("file:config\t")
+                    || line.starts_with("command line:\t"),
+                "{}",
+                line,
+            );
+        }
+        assert!(output.contains("core.hookspath="));
+    }
+
+    #[test]
+    fn test_check_sha() {
+        assert!(check_sha("0123456789abcdef0123456789abcdef01234567").is_ok());
+        assert!(check_sha("0123456789abcdef").is_err());
+        assert!(check_sha("--all").is_err());
+        assert!(check_sha("HEAD~1").is_err());
+    }
+
+    #[test]
+    fn test_check_ref_name() {
+        for name in &[
+            "HEAD",
+            "refs/heads/master",
+            "refs/tags/v1.0",
+            "refs/tags/--all",
+            "refs/heads/caf\u{e9}",
+        ] {
+            assert!(check_ref_name(name.as_bytes()).is_ok(), "{}", name);
+        }
+        for name in &[
+            "",
+            "--all",
+            "-h",
+            "@",
+            "refs/heads/a..b",
+            "refs/heads/a b",
+            "refs/heads/a\nb",
+            "refs/heads/a:b",
+            "refs/heads/a@{1}",
+            "refs/heads/.hidden",
+            "refs/heads/master.lock",
+            "refs/heads//master",
+            "refs/heads/",
+        ] {
+            assert!(check_ref_name(name.as_bytes()).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_protocol_settings() {
+        let mut config = HashMap::new();
+        assert!(protocol_settings(&config).unwrap().is_empty());
+        assert!(negotiation_tips(&config, &["origin"]).unwrap().is_empty());
+        config.insert("protocolversion".into(), "2".into());
+        config.insert("negotiationalgorithm".into(), "skipping".into());
+        config.insert("limithaves".into(), "true".into());
+        assert_eq!(
+            protocol_settings(&config).unwrap(),
+            vec![
+                "protocol.version=2".to_owned(),
+                "fetch.negotiationAlgorithm=skipping".to_owned(),
+            ],
+        );
+        assert_eq!(
+            negotiation_tips(&config, &["origin"]).unwrap(),
+            vec![
+                "--negotiation-tip=refs/remotes/origin/*".to_owned(),
+                "--negotiation-tip=refs/tags/*".to_owned(),
+            ],
+        );
+        config.insert("protocolversion".into(), "3".into());
+        assert!(protocol_settings(&config).is_err());
+        config.insert("protocolversion".into(), "2".into());
+        config.insert("negotiationalgorithm".into(), "fast".into());
+        assert!(protocol_settings(&config).is_err());
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        let output = b"\
+warning: redirecting to https://github.com/new-owner/repo.git/
+From https://github.com/new-owner/repo
+ * [new branch]      master     -> origin/master
+";
+        assert_eq!(
+            parse_redirect(output),
+            Some("https://github.com/new-owner/repo.git".into()),
+        );
+        assert_eq!(parse_redirect(b"From ../origin\n"), None);
+    }
+
+    #[test]
+    fn test_parse_not_found() {
+        assert_eq!(
+            parse_not_found(
+                b"remote: Repository not found.\n\
+                  fatal: repository 'https://github.com/a/b.git/' not found\n",
+            ),
+            Some("remote: Repository not found.".into()),
+        );
+        assert_eq!(
+            parse_not_found(b"fatal: repository 'https://x/y/' not found\n"),
+            Some("fatal: repository 'https://x/y/' not found".into()),
+        );
+        assert_eq!(
+            parse_not_found(b"fatal: unable to access 'https://x/y/'\n"),
+            None,
+        );
+    }
 }
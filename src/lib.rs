@@ -1,24 +1,50 @@
 extern crate chrono;
+extern crate flate2;
+extern crate fs2;
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate log;
 extern crate regex;
 extern crate rusqlite;
-#[cfg(feature = "web")] #[macro_use] extern crate serde;
-#[cfg(feature = "web")] #[macro_use] extern crate serde_json;
-#[cfg(test)] extern crate tempfile;
+#[macro_use] extern crate serde;
+#[cfg_attr(feature = "web", macro_use)] extern crate serde_json;
+extern crate sha1;
+extern crate signal_hook;
+#[cfg(feature = "web")] extern crate base64;
+#[cfg(feature = "web")] extern crate syntect;
+#[cfg(feature = "web")] extern crate tokio;
+extern crate tempfile;
 
 use rusqlite::Connection;
-use rusqlite::types::ToSql;
-use std::borrow::Cow;
+use rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fmt;
-use std::path::Path;
-use std::time::SystemTime;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+pub mod archive;
+pub mod audit;
+pub mod config;
+pub mod daemon;
+pub mod doctor;
+mod db;
+pub mod export;
 mod git;
+pub mod progress;
+pub mod retention;
+pub mod setup;
+pub mod shutdown;
+pub mod systemd;
 #[cfg(feature = "web")] pub mod web;
 
 #[cfg(test)] mod tests_integration;
 
+pub use crate::db::set_database_dir;
+pub use crate::git::set_git_binary;
+
 /// Error type for this crate
 #[derive(Debug)]
 pub enum Error {
@@ -28,6 +54,24 @@ pub enum Error {
     Git(String),
     /// A general I/O error
     Io(std::io::Error),
+    /// A fetch received more than the configured number of bytes
+    TransferLimit(u64),
+    /// A date given by the user could not be understood
+    InvalidDate(String),
+    /// Something requested was not found in the archive
+    NotFound(String),
+    /// A setting has an invalid value
+    Config(String),
+    /// Another process is already updating this mirror
+    Locked(PathBuf),
+    /// The upstream repository doesn't exist anymore
+    UpstreamGone(String),
+    /// The database was upgraded by a newer version of doublegit
+    SchemaTooNew(usize),
+    /// Removing history was refused because of a legal hold
+    Held(String),
+    /// The operation was stopped because a shutdown was requested
+    Interrupted,
 }
 
 impl Error {
@@ -43,6 +87,31 @@ impl fmt::Display for Error {
             Error::Sqlite(e) => write!(f, "SQLite error: {}", e),
             Error::Git(e) => write!(f, "Git error: {}", e),
             Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::TransferLimit(max) => write!(
+                f,
+                "Fetch exceeded transfer limit of {} bytes, it will be \
+                 resumed next time",
+                max,
+            ),
+            Error::InvalidDate(d) => write!(f, "Invalid date: {}", d),
+            Error::NotFound(e) => write!(f, "Not found: {}", e),
+            Error::Config(e) => write!(f, "Configuration error: {}", e),
+            Error::Locked(p) => write!(
+                f,
+                "{} is being updated by another process",
+                p.display(),
+            ),
+            Error::UpstreamGone(e) => {
+                write!(f, "Upstream repository is gone: {}", e)
+            }
+            Error::SchemaTooNew(v) => write!(
+                f,
+                "Database has schema version {}, it was upgraded by a newer \
+                 version of doublegit",
+                v,
+            ),
+            Error::Held(e) => write!(f, "Legal hold: {}", e),
+            Error::Interrupted => write!(f, "Interrupted by shutdown"),
         }
     }
 }
@@ -61,85 +130,1595 @@ impl From<std::io::Error> for Error {
     }
 }
 
+/// The name of a ref, which Git doesn't require to be valid UTF-8
+///
+/// It is stored as TEXT in the database when it is valid UTF-8 (which is
+/// almost always the case), and as a BLOB otherwise. When displayed, the bytes
+/// that are not valid UTF-8 are percent-encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RefName(Vec<u8>);
+
+impl RefName {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Make a new ref name by adding a prefix, e.g. `refs/heads/`
+    pub fn with_prefix(&self, prefix: &str) -> RefName {
+        let mut name = prefix.as_bytes().to_vec();
+        name.extend_from_slice(&self.0);
+        RefName(name)
+    }
+
+    /// Get the name as a command-line argument for Git
+    pub fn to_os_string(&self) -> OsString {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            OsString::from_vec(self.0.clone())
+        }
+        #[cfg(not(unix))]
+        {
+            OsString::from(String::from_utf8_lossy(&self.0).into_owned())
+        }
+    }
+}
+
+impl AsRef<[u8]> for RefName {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a str> for RefName {
+    fn from(name: &'a str) -> RefName {
+        RefName(name.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for RefName {
+    fn from(name: String) -> RefName {
+        RefName(name.into_bytes())
+    }
+}
+
+impl<'a> From<&'a [u8]> for RefName {
+    fn from(name: &'a [u8]) -> RefName {
+        RefName(name.to_vec())
+    }
+}
+
+impl fmt::Display for RefName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut bytes = &self.0[..];
+        loop {
+            match std::str::from_utf8(bytes) {
+                Ok(s) => return f.write_str(s),
+                Err(e) => {
+                    let (valid, rest) = bytes.split_at(e.valid_up_to());
+                    f.write_str(std::str::from_utf8(valid).unwrap())?;
+                    let invalid = e.error_len().unwrap_or(rest.len());
+                    for b in &rest[..invalid] {
+                        write!(f, "%{:02X}", b)?;
+                    }
+                    bytes = &rest[invalid..];
+                }
+            }
+        }
+    }
+}
+
+/// Serialized as displayed, e.g. in the JSON exports
+impl serde::Serialize for RefName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl ToSql for RefName {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match std::str::from_utf8(&self.0) {
+            Ok(s) => Ok(ToSqlOutput::from(s)),
+            Err(_) => Ok(ToSqlOutput::from(&self.0[..])),
+        }
+    }
+}
+
+impl FromSql for RefName {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<RefName> {
+        match value {
+            ValueRef::Text(s) => Ok(s.into()),
+            ValueRef::Blob(b) => Ok(b.into()),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
 /// A reference, either tag or branch
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Ref {
-    name: String,
+    name: RefName,
     tag: bool,
 }
 
 impl Ref {
     /// Parse a remote ref, either `origin/branch` or `tag`
-    fn parse_remote_ref(refname: &str) -> Result<Ref, Error> {
-        let idx = refname.find('/').ok_or(Error::git("Invalid remote ref"))?;
+    fn parse_remote_ref<R: AsRef<[u8]>>(refname: R) -> Result<Ref, Error> {
+        let refname = refname.as_ref();
+        let idx = refname
+            .iter()
+            .position(|&b| b == b'/')
+            .ok_or(Error::git("Invalid remote ref"))?;
         let remote = &refname[0..idx];
-        if remote != "origin" {
+        if remote != b"origin" {
             return Err(Error::git("Remote ref has invalid remote"));
         }
         let name = &refname[idx + 1..];
         Ok(Ref { name: name.into(), tag: false })
     }
 
-    /// Print the full reference name, e.g. `origin/branch`
-    fn fullname(&self) -> Cow<String> {
+    /// Parse a full local ref, either `refs/remotes/origin/branch` or
+    /// `refs/tags/tag`
+    fn parse_local_ref<R: AsRef<[u8]>>(refname: R) -> Result<Ref, Error> {
+        let refname = refname.as_ref();
+        if refname.starts_with(b"refs/tags/") {
+            Ok(Ref { name: refname[10..].into(), tag: true })
+        } else if refname.starts_with(b"refs/remotes/") {
+            Ref::parse_remote_ref(&refname[13..])
+        } else {
+            Err(Error::Git(format!(
+                "Unexpected local ref {}",
+                RefName::from(refname)
+            )))
+        }
+    }
+
+    /// Get the full local reference name, e.g. `refs/remotes/origin/branch`
+    ///
+    /// Short names are ambiguous, and could be taken for options by Git.
+    fn fullname(&self) -> RefName {
         if self.tag {
-            Cow::Borrowed(&self.name)
+            self.name.with_prefix("refs/tags/")
+        } else {
+            self.name.with_prefix("refs/remotes/origin/")
+        }
+    }
+}
+
+/// Parse a date given by the user into the format used in the database
+///
+/// Accepts `YYYY-MM-DD`, `YYYY-MM-DD HH:MM[:SS]` (or with a `T` separator),
+/// all in UTC. The special value `latest` returns `None`.
+pub fn parse_date(date: &str) -> Result<Option<String>, Error> {
+    use chrono::NaiveDateTime;
+
+    let date = date.trim();
+    if date == "latest" {
+        return Ok(None);
+    }
+    let date = date.replacen('T', " ", 1);
+    let parsed = NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M")
+        })
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(
+                &format!("{} 00:00:00", date),
+                "%Y-%m-%d %H:%M:%S",
+            )
+        })
+        .map_err(|_| Error::InvalidDate(date.clone()))?;
+    Ok(Some(parsed.format("%Y-%m-%d %H:%M:%S").to_string()))
+}
+
+/// Longest delay between two attempts at fetching, a day
+pub(crate) const MAX_RETRY_DELAY_SECS: u64 = 24 * 60 * 60;
+
+/// Parse the delay before the first retry of a fetch, a number of seconds
+///
+/// It can't be more than a day.
+pub fn parse_backoff(value: &str) -> Result<Duration, Error> {
+    match value.trim().parse::<u64>() {
+        Ok(seconds) if seconds <= MAX_RETRY_DELAY_SECS => {
+            Ok(Duration::from_secs(seconds))
+        }
+        _ => Err(Error::Config(format!("invalid retry backoff {:?}", value))),
+    }
+}
+
+/// Get the total size of the files in a directory, recursively
+///
+/// The SQLite WAL is not counted, its size depends on when it was last
+/// checkpointed rather than on what's in the archive.
+fn disk_usage(path: &Path) -> Result<u64, Error> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.ends_with("-wal") || name.ends_with("-shm") {
+            continue;
+        } else if file_type.is_dir() {
+            total += disk_usage(&entry.path())?;
+        } else if file_type.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Find out whether the upstream repository was deleted
+///
+/// Returns the date and error of the failed fetch, unless a fetch succeeded
+/// since then.
+fn upstream_gone(db: &Connection) -> Result<Option<(String, String)>, Error> {
+    match db.query_row(
+        "
+        SELECT date, error FROM tombstones
+        WHERE date > (SELECT coalesce(max(date), '') FROM fetches)
+        ORDER BY date DESC
+        LIMIT 1;
+        ",
+        rusqlite::NO_PARAMS,
+        |row| (row.get(0), row.get(1)),
+    ) {
+        Ok(tombstone) => Ok(Some(tombstone)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read a boolean setting, accepting the same values as Git
+fn config_bool(
+    config: &HashMap<String, String>,
+    key: &str,
+) -> Result<bool, Error> {
+    match config.get(key).map(|v| v.to_lowercase()) {
+        None => Ok(false),
+        Some(ref v) if v == "true" || v == "yes" || v == "on" || v == "1" => {
+            Ok(true)
+        }
+        Some(ref v) if v == "false" || v == "no" || v == "off" || v == "0"
+            || v.is_empty() =>
+        {
+            Ok(false)
+        }
+        Some(v) => Err(Error::Config(format!(
+            "invalid boolean for {}: {:?}",
+            key, v
+        ))),
+    }
+}
+
+/// Check the mirror's settings, without talking to the remote
+///
+/// Returns a description of each problem found.
+pub(crate) fn config_problems(
+    repository: &Path,
+) -> Result<Vec<String>, Error> {
+    let config = git::mirror_config(repository)?;
+    let mut problems = settings_problems(&config);
+    if let Err(e) = git::remote_url(repository) {
+        problems.push(format!("no origin remote: {}", e));
+    }
+    // Don't run doublegit.tokenCommand, only check the environment
+    if !config.contains_key("token") {
+        if let Some(var) = config.get("tokenenv") {
+            if std::env::var_os(var).is_none() {
+                problems.push(format!(
+                    "environment variable {} from doublegit.tokenEnv is not \
+                     set",
+                    var,
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Check the values of the `doublegit.*` settings, on their own
+///
+/// Unlike `config_problems()`, this doesn't depend on the mirror nor on the
+/// environment, so it also applies to the defaults from the global config.
+pub(crate) fn settings_problems(
+    config: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut problems = git::remote_config_problems(config);
+    let mut check = |result: Result<(), Error>| match result {
+        Ok(()) => {}
+        Err(Error::Config(message)) => problems.push(message),
+        Err(e) => problems.push(e.to_string()),
+    };
+    check(KeepNamespaces::from_config(config).map(|_| ()));
+    check(fork_names(config).map(|_| ()));
+    check(config_bool(config, "keeptranscripts").map(|_| ()));
+    if let Some(value) = config.get("retention") {
+        check(retention::parse_retention(value).map(|_| ()));
+    }
+    problems
+}
+
+/// Compress a fetch transcript with gzip, to be stored in the database
+fn compress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::best(),
+    );
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompress a fetch transcript read from the database
+fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    use std::io::Read;
+
+    let mut output = Vec::new();
+    flate2::read::GzDecoder::new(data).read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// Get the names of the remotes to archive as forks of `origin`
+///
+/// Those are listed in `doublegit.forks`, separated by spaces. Their branches
+/// are fetched into `refs/remotes/<fork>/`.
+fn fork_names(config: &HashMap<String, String>) -> Result<Vec<&str>, Error> {
+    let forks: Vec<&str> = match config.get("forks") {
+        Some(forks) => forks.split_whitespace().collect(),
+        None => return Ok(Vec::new()),
+    };
+    for fork in &forks {
+        if *fork == "origin"
+            || fork.contains('/')
+            || git::check_ref_name(fork.as_bytes()).is_err()
+        {
+            return Err(Error::Config(format!("invalid fork {:?}", fork)));
+        }
+    }
+    Ok(forks)
+}
+
+/// Record the branches of a fork, comparing them with the database
+///
+/// Returns the commits that are newly recorded.
+fn record_fork(
+    tx: &Connection,
+    repository: &Path,
+    fork: &str,
+    date: &str,
+    fetch_id: i64,
+) -> Result<Vec<String>, Error> {
+    let prefix = format!("refs/remotes/{}/", fork);
+    let current: HashMap<RefName, String> =
+        git::list_ref_names(repository, &prefix)?
+            .into_iter()
+            .map(|(name, sha)| (name.as_bytes()[prefix.len()..].into(), sha))
+            .collect();
+    let mut recorded = HashMap::new();
+    {
+        let mut stmt = tx.prepare(
+            "
+            SELECT name, sha FROM fork_refs
+            WHERE fork=? AND to_date IS NULL;
+            ",
+        )?;
+        let rows = stmt.query_map(&[fork], |row| {
+            (row.get::<_, RefName>(0), row.get::<_, String>(1))
+        })?;
+        for row in rows {
+            let (name, sha) = row?;
+            recorded.insert(name, sha);
+        }
+    }
+
+    let mut close_ref = tx.prepare(
+        "
+        UPDATE fork_refs SET to_date=?, to_fetch_id=?
+        WHERE fork=? AND name=? AND to_date IS NULL;
+        ",
+    )?;
+    for (name, sha) in &recorded {
+        if current.get(name) != Some(sha) {
+            info!("Fork {} branch {} moved from {}", fork, name, sha);
+            close_ref.execute(&[&date as &dyn ToSql, &fetch_id, &fork, name])?;
+        }
+    }
+    let mut insert_ref = tx.prepare(
+        "
+        INSERT INTO fork_refs(fork, name, from_date, to_date, sha, fetch_id)
+        VALUES(?, ?, ?, NULL, ?, ?);
+        ",
+    )?;
+    let mut new = Vec::new();
+    for (name, sha) in &current {
+        if recorded.get(name) != Some(sha) {
+            info!("Fork {} branch {} now at {}", fork, name, sha);
+            insert_ref.execute(
+                &[&fork as &dyn ToSql, name, &date, sha, &fetch_id],
+            )?;
+            new.push(sha.clone());
+        }
+    }
+    Ok(new)
+}
+
+/// Get the refs that were alive at a given date, with their SHA-1
+///
+/// If no date is given, the refs that are currently alive are returned.
+fn refs_at(
+    db: &Connection,
+    date: Option<&str>,
+) -> Result<Vec<(Ref, String)>, Error> {
+    let map = |row: &rusqlite::Row| (
+        Ref { name: row.get(0), tag: row.get(1) },
+        row.get::<_, String>(2),
+    );
+    let mut refs = Vec::new();
+    if let Some(date) = date {
+        let mut stmt = db.prepare(
+            "
+            SELECT name, tag, sha FROM refs
+            WHERE from_date <= ?
+                AND (to_date IS NULL OR to_date > ?)
+            ORDER BY tag, name;
+            ",
+        )?;
+        for row in stmt.query_map(&[date, date], map)? {
+            refs.push(row?);
+        }
+    } else {
+        let mut stmt = db.prepare(
+            "
+            SELECT name, tag, sha FROM refs
+            WHERE to_date IS NULL
+            ORDER BY tag, name;
+            ",
+        )?;
+        for row in stmt.query_map(rusqlite::NO_PARAMS, map)? {
+            refs.push(row?);
+        }
+    }
+    Ok(refs)
+}
+
+/// Where the refs protecting archived objects from garbage collection live
+///
+/// Each ref is named by one of those prefixes followed by the SHA-1 it points
+/// to. They are set per mirror with `doublegit.keepBranchPrefix` and
+/// `doublegit.keepTagPrefix`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepNamespaces {
+    /// Prefix of the refs keeping commits alive
+    pub branches: String,
+    /// Prefix of the refs keeping annotated tags alive
+    pub tags: String,
+}
+
+impl Default for KeepNamespaces {
+    fn default() -> KeepNamespaces {
+        KeepNamespaces {
+            branches: "refs/heads/keep-".into(),
+            tags: "refs/kept-tags/tag-".into(),
+        }
+    }
+}
+
+impl KeepNamespaces {
+    /// Read the namespaces from the mirror's settings
+    fn from_config(
+        config: &HashMap<String, String>,
+    ) -> Result<KeepNamespaces, Error> {
+        let mut keep = KeepNamespaces::default();
+        if let Some(prefix) = config.get("keepbranchprefix") {
+            keep.branches = prefix.clone();
+        }
+        if let Some(prefix) = config.get("keeptagprefix") {
+            keep.tags = prefix.clone();
+        }
+        for prefix in &[&keep.branches, &keep.tags] {
+            // Refs under those would be pruned or overwritten by fetch
+            if !prefix.starts_with("refs/")
+                || prefix.starts_with("refs/remotes/")
+                || prefix.starts_with("refs/tags/")
+            {
+                return Err(Error::Config(format!(
+                    "invalid keep-ref prefix {:?}",
+                    prefix
+                )));
+            }
+        }
+        if keep.branches.starts_with(&keep.tags)
+            || keep.tags.starts_with(&keep.branches)
+        {
+            return Err(Error::Config(
+                "keep-ref prefixes for branches and tags overlap".into(),
+            ));
+        }
+        Ok(keep)
+    }
+
+    /// Name of the ref keeping a commit alive
+    fn branch(&self, sha: &str) -> String {
+        format!("{}{}", self.branches, sha)
+    }
+
+    /// Name of the ref keeping an annotated tag alive
+    fn tag(&self, sha: &str) -> String {
+        format!("{}{}", self.tags, sha)
+    }
+
+    /// Pattern matching all the refs keeping commits alive
+    fn branch_pattern(&self) -> String {
+        format!("{}*", self.branches)
+    }
+
+    /// Pattern matching all the refs keeping annotated tags alive
+    fn tag_pattern(&self) -> String {
+        format!("{}*", self.tags)
+    }
+}
+
+/// Get the keep-ref namespaces recorded in the database, where the last
+/// update left the refs
+fn recorded_keep_namespaces(
+    db: &Connection,
+) -> Result<KeepNamespaces, Error> {
+    let mut recorded = KeepNamespaces::default();
+    let mut stmt = db.prepare(
+        "
+        SELECT name, value FROM settings
+        WHERE name IN ('keep_branch_prefix', 'keep_tag_prefix');
+        ",
+    )?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+        (row.get::<_, String>(0), row.get::<_, String>(1))
+    })?;
+    for row in rows {
+        let (name, value) = row?;
+        if name == "keep_branch_prefix" {
+            recorded.branches = value;
         } else {
-            Cow::Owned(format!("origin/{}", self.name))
+            recorded.tags = value;
+        }
+    }
+    Ok(recorded)
+}
+
+/// Get the keep-ref namespaces of a mirror, moving the refs if they changed
+///
+/// The namespaces in use are recorded in the database, so that changing the
+/// settings moves the existing refs over to the new ones.
+fn keep_namespaces(
+    repository: &Path,
+    db: &Connection,
+) -> Result<KeepNamespaces, Error> {
+    let keep = KeepNamespaces::from_config(&git::mirror_config(repository)?)?;
+    let current = recorded_keep_namespaces(db)?;
+    if current != keep {
+        let moves = [
+            (&current.branches, &keep.branches),
+            (&current.tags, &keep.tags),
+        ];
+        for &(old, new) in &moves {
+            if old == new {
+                continue;
+            }
+            warn!("Moving keep-refs from {} to {}", old, new);
+            for (name, sha) in git::list_refs(repository, &format!("{}*", old))?
+            {
+                let moved = format!("{}{}", new, &name[old.len()..]);
+                git::make_ref(repository, &moved, &sha)?;
+                git::delete_ref(repository, &name)?;
+            }
+        }
+        db.execute(
+            "
+            INSERT OR REPLACE INTO settings(name, value)
+            VALUES ('keep_branch_prefix', ?), ('keep_tag_prefix', ?);
+            ",
+            &[&keep.branches, &keep.tags],
+        )?;
+    }
+    Ok(keep)
+}
+
+/// Create a ref to prevent an object from being garbage collected
+///
+/// Annotated tags get a ref in the tags namespace, while commits get a
+/// keep-branch (which might be found superfluous later).
+fn keep_object(
+    repository: &Path,
+    keep: &KeepNamespaces,
+    sha: &str,
+    tag: bool,
+) -> Result<(), Error> {
+    if tag && git::is_annotated_tag(repository, sha)? {
+        git::make_ref(repository, &keep.tag(sha), sha)
+    } else {
+        git::make_ref(repository, &keep.branch(sha), sha)
+    }
+}
+
+/// Add the messages of new commits to the full-text index
+///
+/// Everything reachable from the keep-refs is already in there, so only the
+/// commits reachable from the objects about to be kept need to be added. If
+/// the index was never completed (e.g. archives from before it existed), all
+/// the commits get indexed.
+fn index_commits(
+    tx: &Connection,
+    repository: &Path,
+    keep: &KeepNamespaces,
+    kept: &[(String, bool)],
+) -> Result<(), Error> {
+    let complete = match tx.query_row(
+        "SELECT value FROM settings WHERE name='commit_index';",
+        rusqlite::NO_PARAMS,
+        |_| (),
+    ) {
+        Ok(()) => true,
+        Err(rusqlite::Error::QueryReturnedNoRows) => false,
+        Err(e) => return Err(e.into()),
+    };
+    let messages = if complete {
+        if kept.is_empty() {
+            return Ok(());
+        }
+        let tips: Vec<String> =
+            kept.iter().map(|(sha, _)| sha.clone()).collect();
+        let mut exclude = Vec::new();
+        for pattern in &[keep.branch_pattern(), keep.tag_pattern()] {
+            for (_, sha) in git::list_refs(repository, pattern)? {
+                exclude.push(sha);
+            }
+        }
+        git::commit_messages(repository, Some(&tips), &exclude)?
+    } else {
+        info!("Indexing all the commit messages");
+        git::commit_messages(repository, None, &[])?
+    };
+    debug!("Indexing {} commit messages", messages.len());
+    let mut stmt = tx.prepare(
+        "INSERT OR IGNORE INTO commit_messages(sha, message) VALUES(?, ?);",
+    )?;
+    for (sha, message) in &messages {
+        stmt.execute(&[sha, message])?;
+    }
+    tx.execute(
+        "
+        INSERT OR REPLACE INTO settings(name, value)
+        VALUES('commit_index', 'complete');
+        ",
+        rusqlite::NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+/// Store the content of the annotated tags
+///
+/// All the annotated tags ever archived have a keep-ref, so this also fills
+/// in the tags archived before this was recorded.
+fn record_tags(
+    tx: &Connection,
+    repository: &Path,
+    keep: &KeepNamespaces,
+) -> Result<(), Error> {
+    let mut stmt = tx.prepare(
+        "
+        INSERT OR IGNORE INTO tag_objects(sha, target, tagger, date, message)
+        VALUES(?, ?, ?, ?, ?);
+        ",
+    )?;
+    for tag in git::annotated_tags(repository, &keep.tag_pattern())? {
+        stmt.execute(&[
+            &tag.sha as &dyn ToSql,
+            &tag.target,
+            &tag.tagger,
+            &tag.date,
+            &tag.message,
+        ])?;
+    }
+    Ok(())
+}
+
+/// Number of commits of each branch whose metadata is kept in the database
+pub(crate) const CACHED_COMMITS: usize = 10;
+
+/// Store the metadata of the latest commits of updated branches
+///
+/// This is what the web view shows, so it doesn't have to run Git.
+fn cache_commits(
+    tx: &Connection,
+    repository: &Path,
+    kept: &[(String, bool)],
+) -> Result<(), Error> {
+    let mut stmt = tx.prepare(
+        "
+        INSERT OR IGNORE INTO commits(
+            sha, parent, author, committer, date, subject
+        )
+        VALUES(?, ?, ?, ?, ?, ?);
+        ",
+    )?;
+    for (sha, tag) in kept {
+        if *tag {
+            continue;
+        }
+        for commit in git::log_commits(repository, sha, CACHED_COMMITS)? {
+            stmt.execute(&[
+                &commit.sha as &dyn ToSql,
+                &commit.parent,
+                &commit.author,
+                &commit.committer,
+                &commit.date,
+                &commit.subject,
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of the file recording the refs from before an update
+///
+/// It is removed once the database transaction is committed; finding it means
+/// the previous update was interrupted, unless its token was committed.
+pub(crate) const JOURNAL: &str = "doublegit-journal";
+
+/// Prefix of the line identifying the update that wrote a journal
+const JOURNAL_TOKEN: &[u8] = b"# update ";
+
+/// Namespaces of the refs written by git-fetch
+const FETCHED_REFS: &[&str] = &["refs/remotes/", "refs/tags/"];
+
+/// Write the state of the fetched refs to the journal
+///
+/// Returns the token identifying this update, which gets committed with its
+/// changes as the `journal` setting.
+fn write_journal(repository: &Path) -> Result<String, Error> {
+    let mut refs = Vec::new();
+    for prefix in FETCHED_REFS {
+        refs.extend(git::list_ref_names(repository, prefix)?);
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let token = format!(
+        "{}.{:09}-{}",
+        now.as_secs(),
+        now.subsec_nanos(),
+        std::process::id(),
+    );
+    write_journal_file(&repository.join(JOURNAL), &refs, Some(&token))?;
+    Ok(token)
+}
+
+/// Write a journal file listing refs and their SHA-1
+///
+/// A journal without a token is always replayed, this is how other commands
+/// have the next update reset the refs.
+pub(crate) fn write_journal_file(
+    path: &Path,
+    refs: &[(RefName, String)],
+    token: Option<&str>,
+) -> Result<(), Error> {
+    let mut journal = Vec::new();
+    if let Some(token) = token {
+        journal.extend_from_slice(JOURNAL_TOKEN);
+        journal.extend_from_slice(token.as_bytes());
+        journal.push(b'\n');
+    }
+    for (name, sha) in refs {
+        journal.extend_from_slice(sha.as_bytes());
+        journal.push(b' ');
+        journal.extend_from_slice(name.as_bytes());
+        journal.push(b'\n');
+    }
+    // Write then rename, so the journal is never seen half-written
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, journal)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Put the fetched refs back in the state recorded in the journal
+///
+/// The commits they pointed to are still in the repository, since the update
+/// that got interrupted had recorded them already. Fetching again then
+/// reports the same changes, which get recorded this time.
+///
+/// Returns whether any ref had to be put back.
+fn replay_journal(repository: &Path) -> Result<bool, Error> {
+    let (_, mut journaled) = read_journal(repository)?;
+    let mut changes = Vec::new();
+    for prefix in FETCHED_REFS {
+        for (name, sha) in git::list_ref_names(repository, prefix)? {
+            match journaled.remove(&name) {
+                Some(ref old) if *old == sha => {}
+                old => changes.push((name, old)),
+            }
+        }
+    }
+    changes.extend(journaled.into_iter().map(|(name, sha)| (name, Some(sha))));
+    for (name, sha) in &changes {
+        debug!("Rolling back {} to {:?}", name, sha);
+    }
+    git::update_refs(repository, &changes)?;
+    Ok(!changes.is_empty())
+}
+
+/// Read the token and the refs recorded in the journal
+fn read_journal(
+    repository: &Path,
+) -> Result<(Option<String>, HashMap<RefName, String>), Error> {
+    let journal = std::fs::read(repository.join(JOURNAL))?;
+    let mut token = None;
+    let mut journaled = HashMap::new();
+    for line in journal.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        if line.starts_with(JOURNAL_TOKEN) {
+            let value = &line[JOURNAL_TOKEN.len()..];
+            token = Some(String::from_utf8_lossy(value).into_owned());
+            continue;
+        }
+        let mut fields = line.splitn(2, |&b| b == b' ');
+        match (fields.next(), fields.next()) {
+            (Some(sha), Some(name)) => {
+                let sha = String::from_utf8_lossy(sha).into_owned();
+                journaled.insert(RefName::from(name), sha);
+            }
+            _ => return Err(Error::Git(format!(
+                "Invalid line in journal: {:?}",
+                String::from_utf8_lossy(line)
+            ))),
+        }
+    }
+    Ok((token, journaled))
+}
+
+/// Whether the update that wrote the journal was committed
+///
+/// This happens if it stopped between committing and removing the journal;
+/// the journal is then stale, and must not be replayed.
+pub(crate) fn journal_committed(
+    repository: &Path,
+    db: &Connection,
+) -> Result<bool, Error> {
+    let token = match read_journal(repository)?.0 {
+        Some(token) => token,
+        None => return Ok(false),
+    };
+    match db.query_row(
+        "SELECT value FROM settings WHERE name='journal';",
+        rusqlite::NO_PARAMS,
+        |row| row.get::<_, String>(0),
+    ) {
+        Ok(committed) => Ok(committed == token),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record the token of the journal in the transaction of the update
+fn commit_journal(tx: &Connection, token: &str) -> Result<(), Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO settings(name, value) VALUES('journal', ?);",
+        &[token],
+    )?;
+    Ok(())
+}
+
+/// Take the lock of a mirror, held for the duration of an update
+///
+/// This is an advisory lock on a file in the repository, so it is released
+/// even if the process gets killed.
+fn lock(repository: &Path) -> Result<std::fs::File, Error> {
+    use fs2::FileExt;
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(repository.join("doublegit.lock"))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(Error::Locked(repository.to_owned()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A bare repository holding the objects of several mirrors
+///
+/// Each mirror borrows its objects through Git alternates, so that forks of a
+/// project are only stored once. The refs of each mirror are copied in the
+/// store under `refs/mirrors/<name>/`, which keeps the objects alive when
+/// running `git gc` in the store.
+struct SharedStore {
+    path: PathBuf,
+    name: String,
+}
+
+impl SharedStore {
+    /// Read the `sharedstore` and `sharedstorename` settings of a mirror
+    ///
+    /// A relative path is relative to the mirror. The name defaults to the
+    /// mirror's directory name.
+    fn from_config(
+        repository: &Path,
+        config: &HashMap<String, String>,
+    ) -> Result<Option<SharedStore>, Error> {
+        let path = match config.get("sharedstore") {
+            Some(path) => repository.join(path),
+            None => return Ok(None),
+        };
+        let name = match config.get("sharedstorename") {
+            Some(name) => name.clone(),
+            None => repository
+                .canonicalize()?
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or_else(|| {
+                    Error::Config("can't name mirror in shared store".into())
+                })?
+                .into(),
+        };
+        if name.contains('/')
+            || git::check_ref_name(format!("refs/mirrors/{}", name).as_bytes())
+                .is_err()
+        {
+            return Err(Error::Config(format!(
+                "invalid name in shared store {:?}",
+                name
+            )));
+        }
+        Ok(Some(SharedStore { path, name }))
+    }
+
+    fn namespace(&self) -> String {
+        format!("refs/mirrors/{}/", self.name)
+    }
+
+    /// Lock the store, waiting for other mirrors to be done with it
+    fn lock(&self) -> Result<std::fs::File, Error> {
+        use fs2::FileExt;
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.path.join("doublegit.lock"))?;
+        file.lock_exclusive()?;
+        Ok(file)
+    }
+}
+
+/// Borrow objects from the shared store, creating it if needed
+///
+/// This is done before fetching, so objects that another mirror already has
+/// are not downloaded again.
+fn link_store(repository: &Path, store: &SharedStore) -> Result<(), Error> {
+    std::fs::create_dir_all(&store.path)?;
+    let _lock = store.lock()?;
+    if !store.path.join("objects").is_dir() {
+        info!("Creating shared store {}", store.path.display());
+        git::init_bare(&store.path)?;
+        git::set_config(&store.path, "core.logAllRefUpdates", "false")?;
+    }
+
+    // Two mirrors with the same name would delete each other's refs
+    let owner = repository.canonicalize()?;
+    let owner = owner
+        .to_str()
+        .ok_or_else(|| Error::Config("non-utf8 mirror path".into()))?;
+    let key = format!("mirror.{}.path", store.name);
+    match git::mirror_config(&store.path)?.get(&key) {
+        Some(path) if path != owner => {
+            return Err(Error::Config(format!(
+                "name {} is already used in the shared store by {}",
+                store.name, path
+            )));
+        }
+        Some(_) => {}
+        None => {
+            git::set_config(
+                &store.path,
+                &format!("doublegit.{}", key),
+                owner,
+            )?;
+        }
+    }
+
+    let objects = store.path.canonicalize()?.join("objects");
+    git::add_alternate(repository, &objects)
+}
+
+/// Copy the refs of a mirror to the shared store, with their objects
+///
+/// After this, the mirror's copies of those objects can be dropped, which
+/// `git::gc()` does.
+fn sync_store(repository: &Path, store: &SharedStore) -> Result<(), Error> {
+    let _lock = store.lock()?;
+    info!("Copying refs to shared store {}", store.path.display());
+    git::fetch_all_refs(
+        &store.path,
+        &repository.canonicalize()?,
+        &store.namespace(),
+    )
+}
+
+/// Name of the file that makes a directory a collection of mirrors
+pub const COLLECTION_CONFIG: &str = "doublegit.conf";
+
+fn is_mirror(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir()
+}
+
+fn is_collection(path: &Path) -> bool {
+    path.join(COLLECTION_CONFIG).is_file()
+}
+
+/// Create the missing mirrors of a collection, and of the collections in it
+///
+/// A collection is a directory with a `doublegit.conf` file, in the format
+/// of Git config, listing the projects to mirror in subdirectories of the
+/// same name:
+///
+/// ```text
+/// [project "my-repo"]
+///     url = https://github.com/me/my-repo.git
+/// ```
+///
+/// Problems with a project don't stop the others from being set up, they are
+/// returned with the path of the project.
+pub fn init_collection(path: &Path) -> Result<Vec<(PathBuf, Error)>, Error> {
+    let mut failures = Vec::new();
+    for (name, url) in git::collection_projects(&path.join(COLLECTION_CONFIG))?
+    {
+        let repository = path.join(&name);
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.contains(|c| c == '/' || c == '\\')
+        {
+            failures.push((
+                repository,
+                Error::Config(format!("invalid project name {:?}", name)),
+            ));
+        } else if !repository.exists() {
+            info!("Creating mirror of {} in {}", url, repository.display());
+            if let Err(e) = init(&url, &repository) {
+                failures.push((repository, e));
+            }
+        }
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?.path();
+        if !is_mirror(&entry) && is_collection(&entry) {
+            match init_collection(&entry) {
+                Ok(f) => failures.extend(f),
+                Err(e) => failures.push((entry, e)),
+            }
+        }
+    }
+    Ok(failures)
+}
+
+/// Find the mirrors to update from a path given by the user
+///
+/// This is either a mirror itself, or a directory whose subdirectories are
+/// mirrors, or collections of mirrors (searched recursively).
+pub fn find_mirrors(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    fn find(path: &Path, mirrors: &mut Vec<PathBuf>) -> Result<(), Error> {
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?.path();
+            if is_mirror(&entry) {
+                mirrors.push(entry);
+            } else if is_collection(&entry) {
+                find(&entry, mirrors)?;
+            }
+        }
+        Ok(())
+    }
+
+    if is_mirror(path) {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut mirrors = Vec::new();
+    find(path, &mut mirrors)?;
+    if mirrors.is_empty() {
+        return Err(Error::NotFound(format!(
+            "no mirrors in {}",
+            path.display()
+        )));
+    }
+    mirrors.sort();
+    Ok(mirrors)
+}
+
+/// Convert a file name pattern with `*` and `?` wildcards to a regex
+fn glob_regex(pattern: &str) -> regex::Regex {
+    let mut re = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    regex::Regex::new(&re).unwrap()
+}
+
+/// Expand the `*` and `?` wildcards in a path given by the user
+///
+/// This is for patterns that the shell didn't expand, e.g. because they were
+/// quoted. Like the shell, wildcards don't match across directories or
+/// hidden files. A path without wildcards is returned as-is.
+pub fn expand_glob(pattern: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = vec![PathBuf::new()];
+    let mut wildcards = false;
+    for component in pattern.components() {
+        let name = match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        };
+        let name = match name {
+            Some(name) if name.contains('*') || name.contains('?') => name,
+            _ => {
+                for path in &mut paths {
+                    path.push(component.as_os_str());
+                }
+                continue;
+            }
+        };
+        wildcards = true;
+        let regex = glob_regex(name);
+        let mut matches = Vec::new();
+        for dir in &paths {
+            let entries = if dir.as_os_str().is_empty() {
+                std::fs::read_dir(".")
+            } else {
+                std::fs::read_dir(dir)
+            };
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            let mut found = Vec::new();
+            for entry in entries {
+                let entry = entry?.file_name();
+                if let Some(entry) = entry.to_str() {
+                    if regex.is_match(entry)
+                        && (!entry.starts_with('.') || name.starts_with('.'))
+                    {
+                        found.push(dir.join(entry));
+                    }
+                }
+            }
+            found.sort();
+            matches.extend(found);
+        }
+        paths = matches;
+    }
+    if wildcards && paths.is_empty() {
+        return Err(Error::NotFound(format!(
+            "nothing matches {}",
+            pattern.display(),
+        )));
+    }
+    Ok(paths)
+}
+
+/// Update many repositories, with up to `jobs` updates running at a time
+///
+/// Returns the result of each update, in the same order as `repositories`.
+pub fn update_many(
+    repositories: &[PathBuf],
+    options: &UpdateOptions,
+    jobs: usize,
+) -> Vec<Result<(), Error>> {
+    let delays = vec![Duration::from_secs(0); repositories.len()];
+    update_staggered(repositories, &delays, options, jobs)
+        .into_iter()
+        .map(|(res, _)| res)
+        .collect()
+}
+
+/// Update many repositories, starting each one after the given delay
+///
+/// Returns the result and duration of each update, in the same order as
+/// `repositories`.
+pub(crate) fn update_staggered(
+    repositories: &[PathBuf],
+    delays: &[Duration],
+    options: &UpdateOptions,
+    jobs: usize,
+) -> Vec<(Result<(), Error>, Duration)> {
+    use std::sync::{Arc, Mutex, mpsc};
+
+    let start = Instant::now();
+    let mut queue = repositories.iter()
+        .cloned()
+        .zip(delays.iter().cloned())
+        .enumerate()
+        .collect::<Vec<_>>();
+    queue.sort_by_key(|&(idx, (_, delay))| (delay, idx));
+    queue.reverse();
+    let queue = Arc::new(Mutex::new(queue));
+    let (sender, receiver) = mpsc::channel();
+    let mut workers = Vec::new();
+    for _ in 0..jobs.max(1).min(repositories.len()) {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        let options = options.clone();
+        workers.push(std::thread::spawn(move || loop {
+            let next = queue.lock().unwrap().pop();
+            let (idx, (repository, delay)) = match next {
+                Some(n) => n,
+                None => break,
+            };
+            // Updates that didn't start yet are skipped on shutdown
+            let elapsed = start.elapsed();
+            let waited = elapsed >= delay || shutdown::sleep(delay - elapsed);
+            if !waited || shutdown::requested() {
+                let res = Err(Error::Interrupted);
+                sender.send((idx, (res, Duration::from_secs(0)))).unwrap();
+                continue;
+            }
+            let update_start = Instant::now();
+            let res = update(&repository, &options);
+            sender.send((idx, (res, update_start.elapsed()))).unwrap();
+        }));
+    }
+    drop(sender);
+
+    let mut results: Vec<_> = receiver.iter().collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    results.sort_by_key(|&(idx, _)| idx);
+    results.into_iter().map(|(_, res)| res).collect()
+}
+
+/// Options controlling how a repository gets updated
+#[derive(Debug, Clone)]
+pub struct UpdateOptions {
+    /// Number of times to retry `git fetch` after a failure
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each following one, up to
+    /// a day
+    pub backoff: Duration,
+    /// Maximum duration of a single `git fetch`, after which it is killed
+    pub timeout: Option<Duration>,
+    /// Maximum number of bytes to receive, after which the fetch is aborted
+    /// (it will resume on the next update)
+    pub max_bytes: Option<u64>,
+    /// Limit the fetched history to this many commits (shallow fetch)
+    pub depth: Option<u32>,
+    /// Run a garbage collection every this many updates, which also writes
+    /// reachability bitmaps
+    pub gc_every: Option<u32>,
+    /// Update the commit-graph file after fetching
+    pub commit_graph: bool,
+    /// Called as the update makes progress
+    pub progress: Option<Arc<dyn progress::Progress>>,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> UpdateOptions {
+        UpdateOptions {
+            retries: 3,
+            backoff: Duration::from_secs(5),
+            timeout: None,
+            max_bytes: None,
+            depth: None,
+            gc_every: None,
+            commit_graph: true,
+            progress: None,
         }
     }
 }
 
+/// Create a new mirror of a remote repository
+///
+/// This creates a bare repository at the given path, set up to fetch from
+/// `url` as `origin`. It doesn't fetch anything, call `update()` for that.
+pub fn init(url: &str, repository: &Path) -> Result<(), Error> {
+    if repository.exists() && std::fs::read_dir(repository)?.next().is_some()
+    {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not empty", repository.display()),
+        )));
+    }
+    git::init_bare(repository)?;
+    git::set_config(repository, "core.logAllRefUpdates", "false")?;
+    git::set_config(repository, "remote.origin.url", url)?;
+    git::set_config(
+        repository,
+        "remote.origin.fetch",
+        "+refs/heads/*:refs/remotes/origin/*",
+    )?;
+    Ok(())
+}
+
 /// Update a repository, fetching new changes and updating the database
-pub fn update(repository: &Path) -> Result<(), Error> {
-    update_with_date(repository, SystemTime::now())
+pub fn update(
+    repository: &Path,
+    options: &UpdateOptions,
+) -> Result<(), Error> {
+    update_with_date(repository, options, SystemTime::now())
 }
 
 /// Update a repository, providing the current date
+///
+/// Each run is recorded in the `updates` table, whether it succeeded or not.
 pub fn update_with_date<Date>(
     repository: &Path,
+    options: &UpdateOptions,
     date: Date,
 ) -> Result<(), Error>
 where
     Date: Into<chrono::DateTime<chrono::Utc>>,
 {
     info!("Updating {:?}...", repository);
+    let _lock = lock(repository)?;
 
-    // Open database
-    let mut db = {
-        let db_path = repository.join("gitarchive.sqlite3");
-        let exists = db_path.exists();
-        let db = Connection::open(db_path)?;
-        if !exists {
-            warn!("Database doesn't exist, creating tables...");
+    let date = date.into();
+    let start = Instant::now();
+    let mut result = fetch_and_record(repository, options, date);
+    if let Some(ref progress) = options.progress {
+        progress.finish();
+    }
+
+    // Put the refs back now rather than on the next update, so a mirror
+    // that is not going to be updated for a while is left consistent
+    if result.is_err()
+        && shutdown::requested()
+        && repository.join(JOURNAL).exists()
+    {
+        warn!("Update interrupted, rolling back refs");
+        if let Err(e) = replay_journal(repository).and_then(|_| {
+            Ok(std::fs::remove_file(repository.join(JOURNAL))?)
+        }) {
+            warn!("Couldn't roll back refs: {}", e);
+        }
+        result = Err(Error::Interrupted);
+    }
+    let end_date = date
+        + chrono::Duration::from_std(start.elapsed())
+            .unwrap_or_else(|_| chrono::Duration::zero());
+
+    // Record the run, for monitoring
+    let counts = match result {
+        Ok(ref counts) => Some(counts),
+        Err(_) => None,
+    };
+    let error = result.as_ref().err().map(ToString::to_string);
+    let recorded = db::create(repository).and_then(|db| {
+        db::retry(|| {
             db.execute(
                 "
-                CREATE TABLE refs(
-                    name TEXT NOT NULL,
-                    from_date DATETIME NOT NULL,
-                    to_date DATETIME NULL,
-                    sha TEXT NOT NULL,
-                    tag BOOLEAN NOT NULL
-                );
+                INSERT INTO updates(
+                    start_date, end_date, success, error,
+                    new_refs, changed_refs, removed_refs
+                )
+                VALUES(?, ?, ?, ?, ?, ?, ?);
                 ",
-                rusqlite::NO_PARAMS,
-            )?;
+                &[
+                    &date.format("%Y-%m-%d %H:%M:%S").to_string()
+                        as &dyn ToSql,
+                    &end_date.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    &result.is_ok(),
+                    &error,
+                    &counts.map(|c| c.new as i64),
+                    &counts.map(|c| c.changed as i64),
+                    &counts.map(|c| c.removed as i64),
+                ],
+            )
+        })?;
+        Ok(())
+    });
+    match result {
+        Err(Error::Interrupted) | Ok(_) => {}
+        Err(ref e) => config::notify_failure(repository, e),
+    }
+    match (result, recorded) {
+        (Err(e), Err(r)) => {
+            warn!("Couldn't record the failed update: {}", r);
+            Err(e)
+        }
+        (Err(e), Ok(())) => Err(e),
+        (Ok(_), r) => r,
+    }
+}
+
+/// Number of refs that were created, moved, or deleted by an update
+#[derive(Debug, Clone, Copy)]
+struct RefCounts {
+    new: usize,
+    changed: usize,
+    removed: usize,
+}
+
+/// Put back the refs after a failed fetch, and return its error
+///
+/// Nothing gets recorded, so whatever the fetch changed is undone, so the
+/// next update isn't taken for the resumption of an interrupted one (on
+/// shutdown, the caller does it).
+fn abandon_fetch(repository: &Path, error: Error) -> Error {
+    if !shutdown::requested() {
+        let undone = replay_journal(repository).and_then(|_| {
+            std::fs::remove_file(repository.join(JOURNAL))?;
+            Ok(())
+        });
+        if let Err(e) = undone {
+            return e;
         }
-        db
+    }
+    error
+}
+
+/// Fetch the mirror and record the changes, while holding its lock
+fn fetch_and_record(
+    repository: &Path,
+    options: &UpdateOptions,
+    date: chrono::DateTime<chrono::Utc>,
+) -> Result<RefCounts, Error> {
+    // Open database
+    let mut db = db::create(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+    let config = git::mirror_config(repository)?;
+    let store = SharedStore::from_config(repository, &config)?;
+    if let Some(ref store) = store {
+        link_store(repository, store)?;
+    }
+
+    // Journal the refs, so that an update interrupted before the transaction
+    // is committed can be done again from the same state
+    if repository.join(JOURNAL).exists() && journal_committed(repository, &db)?
+    {
+        info!("Previous update was committed, removing its journal");
+        std::fs::remove_file(repository.join(JOURNAL))?;
+    } else if repository.join(JOURNAL).exists() {
+        warn!("Previous update was interrupted, rolling back refs");
+        if replay_journal(repository)? {
+            // Keep-refs might have been created for commits that didn't get
+            // indexed, index everything again
+            db::retry(|| {
+                db.execute(
+                    "DELETE FROM settings WHERE name='commit_index';",
+                    rusqlite::NO_PARAMS,
+                )
+            })?;
+        }
+    }
+    // After a replay, the refs are as the journal says, so it is the same
+    let token = write_journal(repository)?;
+
+    // Do fetch. The database is only locked once it's done, the journal
+    // covers the refs until then
+    let date = date.format("%Y-%m-%d %H:%M:%S").to_string();
+    let counts_before = git::count_objects(repository)?;
+    let start = Instant::now();
+    let out = match git::fetch(repository, options) {
+        Ok(out) => out,
+        Err(Error::UpstreamGone(e)) => {
+            // Record it, the archive is now all that's left
+            warn!("Upstream repository is gone, keeping the archive as is");
+            let tx = db::begin(&mut db)?;
+            if upstream_gone(&tx)?.is_none() {
+                tx.execute(
+                    "INSERT INTO tombstones(date, error) VALUES(?, ?);",
+                    &[&date, &e],
+                )?;
+                audit::record(&tx, audit::UPDATE, &date)?;
+            }
+            commit_journal(&tx, &token)?;
+            tx.commit()?;
+            std::fs::remove_file(repository.join(JOURNAL))?;
+            return Err(Error::UpstreamGone(e));
+        }
+        Err(e) => return Err(abandon_fetch(repository, e)),
     };
-    let tx = db.transaction()?;
+    let duration = start.elapsed();
 
-    // Do fetch
-    let out = git::fetch(repository)?;
+    // Fetch the forks
+    let mut forks = Vec::new();
+    for fork in fork_names(&config)? {
+        match git::fetch_fork(repository, options, fork) {
+            Ok(()) => forks.push(fork),
+            Err(Error::UpstreamGone(e)) => {
+                warn!("Fork {} is gone: {}", fork, e);
+            }
+            Err(e) => return Err(abandon_fetch(repository, e)),
+        }
+    }
 
-    // Convert time to string
-    let date = date.into().format("%Y-%m-%d %H:%M:%S").to_string();
+    let tx = db::begin(&mut db)?;
+    let counts_after = git::count_objects(repository)?;
+    let ref_counts = RefCounts {
+        new: out.new.len(),
+        changed: out.changed.len(),
+        removed: out.removed.len(),
+    };
 
-    // Update database
-    for ref_ in out.removed.iter().chain(out.changed.iter()) {
+    // Record statistics
+    {
+        let duration = duration.as_secs() as f64
+            + f64::from(duration.subsec_millis()) / 1000.0;
+        let objects = counts_after.objects
+            .saturating_sub(counts_before.objects) as i64;
+        let bytes = counts_after.bytes
+            .saturating_sub(counts_before.bytes) as i64;
         tx.execute(
             "
-            UPDATE refs SET to_date=?
+            INSERT INTO fetches(
+                date, duration, objects, bytes,
+                new_refs, changed_refs, removed_refs
+            )
+            VALUES(?, ?, ?, ?, ?, ?, ?);
+            ",
+            &[
+                &date as &dyn ToSql, &duration, &objects, &bytes,
+                &(out.new.len() as i64),
+                &(out.changed.len() as i64),
+                &(out.removed.len() as i64),
+            ],
+        )?;
+    }
+    let fetch_id = tx.last_insert_rowid();
+
+    // Keep what Git said, in case we need to check how it was parsed
+    if config_bool(&config, "keeptranscripts")? {
+        tx.execute(
+            "
+            INSERT INTO transcripts(fetch_id, stdout, stderr)
+            VALUES(?, ?, ?);
+            ",
+            &[
+                &fetch_id as &dyn ToSql,
+                &compress(&out.stdout)?,
+                &compress(&out.stderr)?,
+            ],
+        )?;
+    }
+
+    // Record the upstream moving, e.g. a renamed or transferred repository
+    let mut moved_to = None;
+    if let Some(ref new_url) = out.redirect {
+        let old_url = git::remote_url(repository)?;
+        if *new_url != old_url {
+            warn!("Remote moved from {} to {}", old_url, new_url);
+            tx.execute(
+                "
+                INSERT INTO renames(date, old_url, new_url)
+                VALUES(?, ?, ?);
+                ",
+                &[&date, &old_url, new_url],
+            )?;
+            moved_to = Some(new_url);
+        }
+    }
+
+    // Record the new position of the refs. Hundreds of refs can change in
+    // a single fetch, so the statements are only prepared once
+    {
+        let mut current_ref = tx.prepare(
+            "
+            SELECT rowid, sha FROM refs
+            WHERE name=? AND tag=0 AND to_date IS NULL;
+            ",
+        )?;
+        let mut close_ref = tx.prepare(
+            "
+            UPDATE refs SET to_date=?, to_fetch_id=?
             WHERE
                 name=?
                 AND from_date=(
@@ -150,64 +1729,259 @@ where
                     LIMIT 1
                 );
             ",
-            &[&date, &ref_.name, &ref_.name],
         )?;
-    }
-    for ref_ in out.changed.iter().chain(out.new.iter()) {
-        let sha = git::get_sha(repository, &ref_.fullname())?;
-        tx.execute(
+        let mut insert_ref = tx.prepare(
+            "
+            INSERT INTO refs(name, from_date, to_date, sha, tag, fetch_id)
+            VALUES(?, ?, NULL, ?, ?, ?);
+            ",
+        )?;
+        let mut insert_change = tx.prepare(
             "
-            INSERT INTO refs(name, from_date, to_date, sha, tag)
-            VALUES(?, ?, NULL, ?, ?);
+            INSERT INTO ref_changes(ref_id, added, removed)
+            VALUES(?, ?, ?);
             ",
-            &[&ref_.name, &date, &sha, &ref_.tag as &dyn ToSql],
         )?;
+        let mut insert_rewrite = tx.prepare(
+            "
+            INSERT INTO rewrite_commits(ref_id, sha, removed)
+            VALUES(?, ?, ?);
+            ",
+        )?;
+        let mut insert_rename = tx.prepare(
+            "
+            INSERT INTO branch_renames(date, old_ref_id, new_ref_id)
+            VALUES(?, ?, ?);
+            ",
+        )?;
+        let mut find_branch = |name: &RefName| {
+            match current_ref.query_row(
+                &[name],
+                |row| (row.get::<_, i64>(0), row.get::<_, String>(1)),
+            ) {
+                Ok(row) => Ok(Some(row)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        };
+
+        // Remember where the changed branches were, to analyze rewrites
+        let mut old_shas = HashMap::new();
+        for ref_ in out.changed.iter().filter(|r| !r.tag) {
+            if let Some((_, sha)) = find_branch(&ref_.name)? {
+                old_shas.insert(ref_, sha);
+            }
+        }
+
+        // Remember where the deleted branches were, to detect renames
+        let mut removed_branches = Vec::new();
+        for ref_ in out.removed.iter().filter(|r| !r.tag) {
+            if let Some(row) = find_branch(&ref_.name)? {
+                removed_branches.push(row);
+            }
+        }
+        let mut new_branches = Vec::new();
+
+        // Update database
+        for ref_ in out.removed.iter().chain(out.changed.iter()) {
+            close_ref.execute(
+                &[&date as &dyn ToSql, &fetch_id, &ref_.name, &ref_.name],
+            )?;
+        }
+        let total = out.changed.len() + out.new.len();
+        for (i, ref_) in out.changed.iter().chain(out.new.iter()).enumerate() {
+            if let Some(ref progress) = options.progress {
+                progress.refs(i, total);
+            }
+            let sha = git::get_sha(repository, ref_.fullname())?;
+            let ref_id = insert_ref.insert(
+                &[&ref_.name as &dyn ToSql, &date, &sha, &ref_.tag, &fetch_id],
+            )?;
+            if !ref_.tag && out.new.contains(ref_) {
+                new_branches.push((ref_id, sha.clone()));
+            }
+
+            if let Some(old_sha) = old_shas.get(ref_) {
+                let (removed_count, added_count) =
+                    git::count_divergence(repository, old_sha, &sha)?;
+                insert_change.execute(&[
+                    &ref_id as &dyn ToSql,
+                    &(added_count as i64),
+                    &(removed_count as i64),
+                ])?;
+
+                // If the branch was force-pushed, record the commits that were
+                // removed from it, and those that replaced them
+                if removed_count > 0 {
+                    let removed = git::rev_list(repository, old_sha, &sha)?;
+                    let added = git::rev_list(repository, &sha, old_sha)?;
+                    info!(
+                        "{:?} was rewritten, {} commits removed, {} added",
+                        ref_, removed.len(), added.len(),
+                    );
+                    for (commit, is_removed) in removed
+                        .iter()
+                        .map(|c| (c, true))
+                        .chain(added.iter().map(|c| (c, false)))
+                    {
+                        insert_rewrite.execute(
+                            &[&ref_id as &dyn ToSql, commit, &is_removed],
+                        )?;
+                    }
+                }
+            }
+        }
+
+        if let Some(ref progress) = options.progress {
+            progress.refs(total, total);
+        }
+
+        // A branch deleted while another one appears at the same commit was
+        // probably renamed, unless it's ambiguous
+        for (old_id, sha) in &removed_branches {
+            let old_count =
+                removed_branches.iter().filter(|(_, s)| s == sha).count();
+            let new: Vec<_> =
+                new_branches.iter().filter(|(_, s)| s == sha).collect();
+            if old_count == 1 && new.len() == 1 {
+                insert_rename.execute(
+                    &[&date as &dyn ToSql, old_id, &new[0].0],
+                )?;
+            }
+        }
     }
 
-    // Create refs to prevent garbage collection
+    let mut kept = Vec::new();
     for ref_ in out.changed.iter().chain(out.new.iter()) {
-        let sha = git::get_sha(repository, &ref_.fullname())?;
-        if ref_.tag && git::is_annotated_tag(repository, &sha)? {
-            info!("{:?} making ref {}", ref_, sha);
-            git::make_ref(
-                repository,
-                &format!("refs/kept-tags/tag-{}", sha),
-                &sha,
-            )?;
-        } else {
-            info!("{:?} making branch {}", ref_, sha);
-            git::make_branch(repository, &format!("keep-{}", sha), &sha)?;
+        let sha = git::get_sha(repository, ref_.fullname())?;
+        info!("{:?} keeping {}", ref_, sha);
+        kept.push((sha, ref_.tag));
+    }
+
+    // Record the forks
+    for fork in forks {
+        for sha in record_fork(&tx, repository, fork, &date, fetch_id)? {
+            kept.push((sha, false));
         }
     }
 
+    index_commits(&tx, repository, &keep, &kept)?;
+    cache_commits(&tx, repository, &kept)?;
+
+    // Create refs to prevent garbage collection
+    for (sha, tag) in &kept {
+        keep_object(repository, &keep, sha, *tag)?;
+    }
+
+    // Record the message of the annotated tags, e.g. release notes
+    record_tags(&tx, repository, &keep)?;
+
+    // Update the commit-graph, used by the reachability queries below
+    if options.commit_graph && !kept.is_empty() {
+        git::write_commit_graph(repository)?;
+    }
+
     // Remove superfluous branches
-    for ref_ in out.changed.iter().chain(out.new.iter()) {
-        let sha = git::get_sha(repository, &ref_.fullname())?;
-        let keeper = format!("keep-{}", sha);
+    let pattern = keep.branch_pattern();
+    for (sha, tag) in &kept {
+        let keeper = keep.branch(sha);
         // Parents of this branch are superfluous
-        for br in git::included_branches(repository, &sha)? {
+        for br in git::included_branches(repository, &pattern, sha)? {
             if br != keeper {
-                git::delete_branch(repository, &br)?;
+                git::delete_ref(repository, &br)?;
             }
         }
         // This branch is superfluous if it is included in others
         // If the ref is an annotated tag, this wrongly checks if the commit
         // is included in other branches, so skip on annotated tags
-        if !(ref_.tag && git::is_annotated_tag(repository, &sha)?)
-            && git::including_branches(repository, &sha)?.len() > 1
+        if !(*tag && git::is_annotated_tag(repository, sha)?)
+            && git::including_branches(repository, &pattern, sha)?.len() > 1
         {
-            git::delete_branch(repository, &keeper)?;
+            git::delete_ref(repository, &keeper)?;
         }
     }
 
+    // Chain the changes to the previous ones, so editing them later shows
+    let (audit_id, audit_hash) = audit::record(&tx, audit::UPDATE, &date)?;
+
+    commit_journal(&tx, &token)?;
     tx.commit()?;
+    std::fs::remove_file(repository.join(JOURNAL))?;
 
-    Ok(())
+    // Fetch directly from the new location from now on
+    if let Some(url) = moved_to {
+        git::set_config(repository, "remote.origin.url", url)?;
+    }
+
+    if let Some(ref store) = store {
+        sync_store(repository, store)?;
+    }
+
+    // Repack the repository if it's time
+    if let Some(every) = options.gc_every {
+        let count: i64 = db.query_row(
+            "SELECT count(*) FROM fetches;",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        if every > 0 && count % i64::from(every) == 0 {
+            info!("Running scheduled garbage collection");
+            archive::gc_locked(repository, false)?;
+        }
+    }
+
+    // Record the size of the mirror, to spot sudden growth
+    let counts = git::count_objects(repository)?;
+    let disk_bytes = disk_usage(repository)?;
+    db::retry(|| {
+        db.execute(
+            "
+            INSERT INTO sizes(date, objects, object_bytes, disk_bytes)
+            VALUES(?, ?, ?, ?);
+            ",
+            &[
+                &date as &dyn ToSql,
+                &(counts.objects as i64),
+                &(counts.bytes as i64),
+                &(disk_bytes as i64),
+            ],
+        )
+    })?;
+
+    // Prove that we saw this state at this time, without failing the update
+    if let Some(command) = config.get("timestampcommand") {
+        audit::timestamp(&db, command, audit_id, &audit_hash, &date)?;
+    }
+
+    // Replicate the archive, without failing the update
+    if let Some(remote) = config.get("backupremote") {
+        info!("Pushing to backup remote {}", remote);
+        let error = match git::push_mirror(repository, remote) {
+            Ok(()) => None,
+            Err(e) => {
+                warn!("Error pushing to backup remote {}: {}", remote, e);
+                Some(e.to_string())
+            }
+        };
+        db::retry(|| {
+            db.execute(
+                "INSERT INTO backups(date, remote, error) VALUES(?, ?, ?);",
+                &[&date as &dyn ToSql, remote, &error],
+            )
+        })?;
+    }
+
+    Ok(ref_counts)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Ref;
+    use std::collections::HashMap;
+
+    use crate::{
+        KeepNamespaces, Ref, RefName, compress, config_bool, decompress,
+        expand_glob, parse_backoff, parse_date,
+    };
 
     #[test]
     fn test_ref_parse() {
@@ -222,23 +1996,165 @@ mod tests {
         assert!(Ref::parse_remote_ref("master").is_err());
     }
 
+    #[test]
+    fn test_ref_parse_local() {
+        assert_eq!(
+            Ref::parse_local_ref("refs/remotes/origin/feature/x").unwrap(),
+            Ref {
+                name: "feature/x".into(),
+                tag: false,
+            },
+        );
+        assert_eq!(
+            Ref::parse_local_ref("refs/tags/release/1.0").unwrap(),
+            Ref {
+                name: "release/1.0".into(),
+                tag: true,
+            },
+        );
+        assert!(Ref::parse_local_ref("refs/remotes/upstream/master").is_err());
+        assert!(Ref::parse_local_ref("refs/heads/master").is_err());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("latest").unwrap(), None);
+        assert_eq!(
+            parse_date("2019-03-16").unwrap().unwrap(),
+            "2019-03-16 00:00:00",
+        );
+        assert_eq!(
+            parse_date("2019-03-16T17:03").unwrap().unwrap(),
+            "2019-03-16 17:03:00",
+        );
+        assert_eq!(
+            parse_date(" 2019-03-16 17:03:12").unwrap().unwrap(),
+            "2019-03-16 17:03:12",
+        );
+        assert!(parse_date("2019-13-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_backoff() {
+        assert_eq!(parse_backoff("5").unwrap().as_secs(), 5);
+        assert_eq!(parse_backoff("86400").unwrap().as_secs(), 86400);
+        assert!(parse_backoff("86401").is_err());
+        assert!(parse_backoff("99999999999999999999").is_err());
+        assert!(parse_backoff("-1").is_err());
+    }
+
     #[test]
     fn test_ref_fullname() {
         assert_eq!(
-            &Ref {
+            Ref {
                 name: "master".into(),
                 tag: false,
             }
-            .fullname() as &str,
-            "origin/master",
+            .fullname(),
+            RefName::from("refs/remotes/origin/master"),
         );
         assert_eq!(
-            &Ref {
+            Ref {
                 name: "release".into(),
                 tag: true,
             }
-            .fullname() as &str,
-            "release",
+            .fullname(),
+            RefName::from("refs/tags/release"),
+        );
+    }
+
+    #[test]
+    fn test_ref_name() {
+        let name = RefName::from(&b"caf\xe9/\xf0\x9f\x8d\xb5"[..]);
+        assert_eq!(name.to_string(), "caf%E9/\u{1f375}");
+        assert_eq!(
+            Ref::parse_local_ref(name.with_prefix("refs/remotes/origin/"))
+                .unwrap(),
+            Ref { name: name.clone(), tag: false },
+        );
+
+        // Stored as BLOB only if it's not valid UTF-8
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        let types: Vec<String> = [name.clone(), RefName::from("master")]
+            .iter()
+            .map(|n| {
+                db.query_row("SELECT typeof(?), ?;", &[n, n], |row| {
+                    assert_eq!(&row.get::<_, RefName>(1), n);
+                    row.get(0)
+                }).unwrap()
+            })
+            .collect();
+        assert_eq!(types, vec!["blob", "text"]);
+    }
+
+    #[test]
+    fn test_config_bool() {
+        let mut config = HashMap::new();
+        assert!(!config_bool(&config, "keeptranscripts").unwrap());
+        config.insert("keeptranscripts".into(), "Yes".into());
+        assert!(config_bool(&config, "keeptranscripts").unwrap());
+        config.insert("keeptranscripts".into(), "off".into());
+        assert!(!config_bool(&config, "keeptranscripts").unwrap());
+        config.insert("keeptranscripts".into(), "maybe".into());
+        assert!(config_bool(&config, "keeptranscripts").is_err());
+    }
+
+    #[test]
+    fn test_compress() {
+        let data = b"From ../origin\n * [new branch] master -> origin/master\n";
+        let compressed = compress(data).unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn test_keep_namespaces() {
+        let mut config = HashMap::new();
+        assert_eq!(
+            KeepNamespaces::from_config(&config).unwrap(),
+            KeepNamespaces::default(),
+        );
+        config.insert("keepbranchprefix".into(), "refs/keep/".into());
+        let keep = KeepNamespaces::from_config(&config).unwrap();
+        assert_eq!(keep.branch("abc"), "refs/keep/abc");
+        assert_eq!(keep.tag("abc"), "refs/kept-tags/tag-abc");
+        config.insert("keeptagprefix".into(), "refs/keep/tag-".into());
+        assert!(KeepNamespaces::from_config(&config).is_err());
+        config.insert("keeptagprefix".into(), "refs/tags/kept-".into());
+        assert!(KeepNamespaces::from_config(&config).is_err());
+        config.insert("keeptagprefix".into(), "kept-".into());
+        assert!(KeepNamespaces::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_expand_glob() {
+        let dir = tempfile::Builder::new()
+            .prefix("doublegit_test_")
+            .tempdir().unwrap();
+        let dir = dir.path();
+        for name in &["a/repo1", "a/repo2", "a/.hidden", "b/repo3", "b/x"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        assert_eq!(
+            expand_glob(&dir.join("*/repo?")).unwrap(),
+            vec![
+                dir.join("a/repo1"),
+                dir.join("a/repo2"),
+                dir.join("b/repo3"),
+            ],
+        );
+        assert_eq!(
+            expand_glob(&dir.join("a/*")).unwrap(),
+            vec![dir.join("a/repo1"), dir.join("a/repo2")],
+        );
+        assert_eq!(
+            expand_glob(&dir.join("a/.h*")).unwrap(),
+            vec![dir.join("a/.hidden")],
+        );
+        assert_eq!(
+            expand_glob(&dir.join("c")).unwrap(),
+            vec![dir.join("c")],
         );
+        assert!(expand_glob(&dir.join("c/*")).is_err());
     }
 }
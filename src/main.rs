@@ -1,10 +1,15 @@
 extern crate clap;
 extern crate env_logger;
+extern crate indicatif;
 extern crate log;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::env;
-use std::path::Path;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Command-line entrypoint
 fn main() {
@@ -20,110 +25,3141 @@ fn main() {
                 .help("Augment verbosity (print more details)")
                 .multiple(true),
         )
+        .arg(
+            Arg::with_name("database-dir")
+                .long("database-dir")
+                .help("Keep the databases of all mirrors in this directory \
+                       (default: $DOUBLEGIT_DATABASE_DIR or in each mirror)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("git")
+                .long("git")
+                .help("Path to the Git executable (default: $DOUBLEGIT_GIT \
+                       or git)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Output JSON, including errors")
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("update")
                 .about("Fetch a repository and update its history")
+                .arg(
+                    Arg::with_name("retries")
+                        .long("retries")
+                        .help("Number of times to retry a failed fetch")
+                        .takes_value(true)
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::with_name("backoff")
+                        .long("retry-backoff")
+                        .help("Seconds to wait before the first retry, \
+                               doubled for each following one, up to a day")
+                        .takes_value(true)
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .help("Kill the fetch if it takes more than this \
+                               many seconds")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-bytes")
+                        .long("max-bytes")
+                        .help("Abort the fetch after receiving this many \
+                               bytes, resuming on the next update")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("depth")
+                        .long("depth")
+                        .help("Limit fetched history to this many commits \
+                               (new or already shallow mirrors only)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-commit-graph")
+                        .long("no-commit-graph")
+                        .help("Don't update the commit-graph file"),
+                )
+                .arg(
+                    Arg::with_name("no-progress")
+                        .long("no-progress")
+                        .help("Don't show progress bars while updating a \
+                               single mirror"),
+                )
+                .arg(
+                    Arg::with_name("gc-every")
+                        .long("gc-every")
+                        .help("Repack the mirror every this many updates")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .help("Number of mirrors to update at the same time")
+                        .takes_value(true)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("exit-code")
+                        .long("exit-code")
+                        .help("Print a summary and exit with 2 if refs \
+                               changed, 0 if nothing changed, 1 on error"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Paths to repositories, or to directories of \
+                               mirrors or collections, which can contain \
+                               wildcards (default: core.root from the \
+                               global config)")
+                        .takes_value(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export-bundle")
+                .about("Export the refs of a snapshot as a Git bundle")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Path of the bundle file to create")
+                        .takes_value(true)
+                        .default_value("snapshot.bundle"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .help("Date of the snapshot, or \"latest\"")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export the whole archive")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Format of the export: the objects as a \
+                               git-fast-export stream, or the refs history \
+                               as JSON or CSV")
+                        .takes_value(true)
+                        .possible_values(&["fast-export", "json", "csv"])
+                        .default_value("fast-export"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("File to write to (default: standard output)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Recreate a deleted or rewritten branch")
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .help("Restore the branch as it was at this date \
+                               (default: where it was last seen)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("push")
+                        .long("push")
+                        .help("Push the branch back upstream instead of \
+                               creating it in the mirror"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .help("Name of the branch to restore")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Repack the mirror, keeping everything recorded")
+                .arg(
+                    Arg::with_name("prune")
+                        .long("prune")
+                        .help("Also delete objects not recorded in the \
+                               archive"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check the integrity of the archive")
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Fix the keep-refs if problems are found"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("consolidate")
+                .about("Delete keep-refs that are not needed")
+                .arg(
+                    Arg::with_name("pack")
+                        .long("pack")
+                        .help("Also move the refs into the packed-refs file"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Create a new mirror of a repository")
+                .arg(
+                    Arg::with_name("no-update")
+                        .long("no-update")
+                        .help("Don't fetch the repository right away"),
+                )
+                .arg(
+                    Arg::with_name("url")
+                        .help("URL of the repository to archive")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path of the mirror to create")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("checkout-at")
+                .about("Write the files of a branch as they were at a date")
+                .arg(
+                    Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .help("Branch to check out (default: master)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .help("Date of the snapshot, or \"latest\"")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dest")
+                        .help("Directory to write the files to, which must \
+                               not exist or be empty")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Summarize the archive, and show the size of the \
+                        mirror after each update")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("transcript")
+                .about("Show what git-fetch printed during an update")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .help("Date of the update, or \"latest\"")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merge another archive of the same repository into \
+                        this one")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to the repository to merge into")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("Path to the other repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .about("Search the names of the branches and tags, and the \
+                        archived commit messages")
+                .arg(
+                    Arg::with_name("limit")
+                        .short("n")
+                        .long("limit")
+                        .help("Maximum number of refs and of commits to show")
+                        .takes_value(true)
+                        .default_value("20"),
+                )
+                .arg(
+                    Arg::with_name("deleted-only")
+                        .long("deleted-only")
+                        .help("Only show what is not upstream anymore"),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .help("Only show refs that existed after this date, \
+                               and commits made after it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .help("Only show refs that existed before this date, \
+                               and commits made before it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("query")
+                        .help("Words to search for (SQLite FTS5 syntax for \
+                               the messages)")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("annotate")
+                .about("Attach a note to a snapshot or to a branch or tag")
+                .arg(
+                    Arg::with_name("list")
+                        .long("list")
+                        .help("Show the notes instead of adding one"),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .help("Attach the note to this branch, as it was at \
+                               that date")
+                        .takes_value(true)
+                        .conflicts_with("tag"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .short("t")
+                        .long("tag")
+                        .help("Attach the note to this tag, as it was at that \
+                               date")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .help("Date of the snapshot")
+                        .required_unless("list")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("note")
+                        .help("Text of the note")
+                        .required_unless("list")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Copy the archive to a directory, even while it is \
+                        being updated")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to the repository to back up")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("Directory to create with the backup")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("expire")
+                .about("Remove history older than the retention policy \
+                        (doublegit.retention)")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Only show what would be removed"),
+                )
+                .arg(
+                    Arg::with_name("repack")
+                        .long("repack")
+                        .help("Run a garbage collection afterwards, freeing \
+                               the space"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("hold")
+                .about("Prevent the expiration of a period of history")
+                .arg(
+                    Arg::with_name("list")
+                        .long("list")
+                        .help("Show the legal holds instead of adding one"),
+                )
+                .arg(
+                    Arg::with_name("release")
+                        .long("release")
+                        .help("Remove the legal hold with this number")
+                        .takes_value(true)
+                        .conflicts_with("list"),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .help("Start of the period (default: the beginning)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .help("End of the period (default: no end)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("reason")
+                        .help("Why this history must be kept")
+                        .required_unless_one(&["list", "release"])
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-audit")
+                .about("Check that the recorded history wasn't edited, \
+                        using the audit log")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("timestamps")
+                .about("Show the proofs from the timestamping service \
+                        (doublegit.timestampCommand)")
+                .arg(
+                    Arg::with_name("proof")
+                        .long("proof")
+                        .help("Write the proof for this audit entry to \
+                               standard output")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("repository")
                         .help("Path to repository")
                         .required(true)
                         .takes_value(true),
                 ),
-        );
-    #[cfg(feature = "web")]
-    let cli = cli.subcommand(
-        SubCommand::with_name("web")
-            .about("Open the repository history web page")
-            .arg(
-                Arg::with_name("host")
-                    .short("-h")
-                    .help("Set the host to bind the server to")
-                    .takes_value(true)
-                    .default_value("127.0.0.1"),
-            )
-            .arg(
-                Arg::with_name("port")
-                    .short("-p")
-                    .help("Set the port number for the server")
-                    .takes_value(true)
-                    .default_value("6617"),
-            )
-            .arg(
-                Arg::with_name("repository")
-                    .help("Path to repository")
-                    .required(true)
-                    .takes_value(true),
-            ),
-    );
-
-    let mut cli = cli;
-    let matches = match cli.get_matches_from_safe_borrow(env::args_os()) {
-        Ok(m) => m,
-        Err(e) => {
-            eprintln!("{}", e);
-            std::process::exit(2);
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Undo what was recorded by an update, for example \
+                        because upstream was broken")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .help("Date of the update, or \"latest\"")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Keep running, updating mirrors periodically")
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .help("Time between updates, e.g. 30m or 1h")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("stagger")
+                        .long("stagger")
+                        .help("Spread the updates of each cycle over this \
+                               long (default: a quarter of the interval)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .help("Number of mirrors to update at the same time")
+                        .takes_value(true)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository, or to a directory of \
+                               mirrors or collections (default: core.root \
+                               from the global config)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("log")
+                .about("Show the history of the refs")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ref")
+                        .help("Name of the branch or tag (default: all)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("Show the branches and tags at a given date")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("date")
+                        .help("Date of the snapshot, or \"latest\"")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Show the branches and tags that changed between two \
+                        dates")
+                .arg(
+                    Arg::with_name("stat")
+                        .long("stat")
+                        .help("Also summarize the changes to the files of \
+                               the refs that moved"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .help("Date of the first snapshot")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .help("Date of the second snapshot, or \"latest\"")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the branches and tags, for scripts")
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .help("List them as they were at this date \
+                               (default: latest)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("branches")
+                        .long("branches")
+                        .help("Only list branches")
+                        .conflicts_with("tags"),
+                )
+                .arg(
+                    Arg::with_name("tags")
+                        .long("tags")
+                        .help("Only list tags"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("when")
+                .about("Show when a commit was on which branches")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("commit")
+                        .help("SHA-1 of the commit, possibly abbreviated")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show the health of a mirror, or of all the mirrors \
+                        in a directory")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository or directory of mirrors \
+                               (default: core.root from the global config)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mirrors")
+                .about("List the mirrors in a directory")
+                .arg(
+                    Arg::with_name("directory")
+                        .help("Path to directory of mirrors (default: \
+                               core.root from the global config)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Write a completion script for a shell")
+                .arg(
+                    Arg::with_name("mirrors")
+                        .long("mirrors")
+                        .help("Also complete repository arguments with the \
+                               mirrors in this directory (default: core.root \
+                               from the global config)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("shell")
+                        .help("Shell to complete for")
+                        .required(true)
+                        .possible_values(&["bash", "zsh", "fish"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-reflog")
+                .about("Add the history found in the reflogs of an existing \
+                        clone, from before the mirror was created")
+                .arg(
+                    Arg::with_name("clone")
+                        .help("Path to the existing clone")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("setup")
+                .about("Create or configure a mirror, asking questions")
+                .arg(
+                    Arg::with_name("no-update")
+                        .long("no-update")
+                        .help("Don't fetch the repository right away"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .help("URL of the repository to archive, or path of \
+                               an existing mirror")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Diagnose problems with Git and with mirrors, and \
+                        suggest fixes")
+                .arg(
+                    Arg::with_name("offline")
+                        .long("offline")
+                        .help("Don't connect to the remotes"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository or directory of mirrors \
+                               (default: core.root from the global config)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("forget")
+                .about("Remove all the history of a ref, e.g. after a \
+                        takedown request")
+                .arg(
+                    Arg::with_name("list")
+                        .long("list")
+                        .help("List the refs that were forgotten"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .help("Forget a tag rather than a branch"),
+                )
+                .arg(
+                    Arg::with_name("fork")
+                        .long("fork")
+                        .help("Forget a branch of a fork")
+                        .takes_value(true)
+                        .conflicts_with("tag"),
+                )
+                .arg(
+                    Arg::with_name("reason")
+                        .long("reason")
+                        .help("Why the ref is forgotten, to keep a record")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Only show what would be removed"),
+                )
+                .arg(
+                    Arg::with_name("no-repack")
+                        .long("no-repack")
+                        .help("Don't run a garbage collection afterwards, \
+                               leaving the objects in the mirror for now"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ref")
+                        .help("Name of the branch or tag")
+                        .required_unless("list")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Build a repository with a commit per snapshot of \
+                        each branch, e.g. for git bisect")
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Repository to create")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Read or change the settings of a mirror, or the \
+                        global config")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(config_command(
+                    "get",
+                    "Print the value of a setting",
+                    "[<repository>] <key>",
+                ))
+                .subcommand(config_command(
+                    "set",
+                    "Change a setting, after checking the new value",
+                    "[<repository>] <key> <value>",
+                ))
+                .subcommand(config_command(
+                    "unset",
+                    "Remove a setting",
+                    "[<repository>] <key>",
+                ))
+                .subcommand(config_command(
+                    "list",
+                    "Print all the settings",
+                    "[<repository>]",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("grep")
+                .about("Search the files of the archived branches and tags, \
+                        including deleted ones")
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .help("Search the refs alive at this date, instead \
+                               of the current ones")
+                        .takes_value(true)
+                        .conflicts_with("all"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Search every commit a ref ever pointed to"),
+                )
+                .arg(
+                    Arg::with_name("ignore-case")
+                        .short("i")
+                        .long("ignore-case")
+                        .help("Ignore case when matching"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .help("Regular expression, as for `git grep`")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tags")
+                .about("Show the history of the tags, with their messages")
+                .arg(
+                    Arg::with_name("changed")
+                        .long("changed")
+                        .help("Only show tags that were moved or deleted"),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bisect-date")
+                .about("Find the first snapshot where a commit stopped (or \
+                        started) being reachable, or a command's result \
+                        changed")
+                .arg(
+                    Arg::with_name("reachable")
+                        .long("reachable")
+                        .help("Test whether this commit is reachable")
+                        .takes_value(true)
+                        .required_unless("run")
+                        .conflicts_with("run"),
+                )
+                .arg(
+                    Arg::with_name("run")
+                        .long("run")
+                        .help("Test whether this shell command succeeds, in \
+                               a checkout of the branch")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .help("Branch to test (default: any ref for \
+                               --reachable, master for --run)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repository")
+                        .help("Path to repository")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        );
+    #[cfg(feature = "web")]
+    let cli = cli.subcommand(
+        SubCommand::with_name("web")
+            .about("Open the repository history web page")
+            .arg(
+                Arg::with_name("host")
+                    .short("-h")
+                    .help("Set the host to bind the server to (default: \
+                           web.host from the global config, or 127.0.0.1)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("port")
+                    .short("-p")
+                    .help("Set the port number for the server (default: \
+                           web.port from the global config, or 6617)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("tls-cert")
+                    .long("tls-cert")
+                    .help("Serve over HTTPS with this certificate chain (PEM)")
+                    .takes_value(true)
+                    .requires("tls-key"),
+            )
+            .arg(
+                Arg::with_name("tls-key")
+                    .long("tls-key")
+                    .help("Private key for --tls-cert (PEM, PKCS#8 or RSA)")
+                    .takes_value(true)
+                    .requires("tls-cert"),
+            )
+            .arg(
+                Arg::with_name("repository")
+                    .help("Path to repository")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    );
+    #[cfg(feature = "web")]
+    let cli = cli.subcommand(
+        SubCommand::with_name("export-static")
+            .about("Render the repository history web pages as a static \
+                    site")
+            .arg(
+                Arg::with_name("repository")
+                    .help("Path to repository")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .help("Directory to create")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    );
+    #[cfg(feature = "web")]
+    let cli = cli.subcommand(
+        SubCommand::with_name("watch")
+            .about("Keep updating the mirrors under a directory, and serve \
+                    their history on the web")
+            .arg(
+                Arg::with_name("interval")
+                    .long("interval")
+                    .help("Time between updates, e.g. 30m or 1h")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("stagger")
+                    .long("stagger")
+                    .help("Spread the updates of each cycle over this long \
+                           (default: a quarter of the interval)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("jobs")
+                    .short("j")
+                    .long("jobs")
+                    .help("Number of mirrors to update at the same time")
+                    .takes_value(true)
+                    .default_value("1"),
+            )
+            .arg(
+                Arg::with_name("host")
+                    .short("-h")
+                    .help("Set the host to bind the server to (default: \
+                           web.host from the global config, or 127.0.0.1)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("port")
+                    .short("-p")
+                    .help("Set the port number for the server (default: \
+                           web.port from the global config, or 6617)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("tls-cert")
+                    .long("tls-cert")
+                    .help("Serve over HTTPS with this certificate chain (PEM)")
+                    .takes_value(true)
+                    .requires("tls-key"),
+            )
+            .arg(
+                Arg::with_name("tls-key")
+                    .long("tls-key")
+                    .help("Private key for --tls-cert (PEM, PKCS#8 or RSA)")
+                    .takes_value(true)
+                    .requires("tls-cert"),
+            )
+            .arg(
+                Arg::with_name("root")
+                    .help("Directory of mirrors or collections (default: \
+                           core.root from the global config)")
+                    .takes_value(true),
+            ),
+    );
+
+    // Parsing changes the App, keep a copy to generate completions from
+    let mut completions_cli = cli.clone();
+    let mut cli = cli;
+    let matches = match cli.get_matches_from_safe_borrow(env::args_os()) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // Whether to write JSON to stdout, --json can be given before or after the
+    // subcommand
+    let json = matches.is_present("json")
+        || matches.subcommand().1.map_or(false, |m| m.is_present("json"));
+
+    macro_rules! check {
+        ($res:expr, $msg:expr,) => {
+            match $res {
+                Ok(r) => r,
+                Err(e) => {
+                    let message = format!("{}: {}", $msg, e);
+                    if json {
+                        let stdout = std::io::stdout();
+                        let _ = doublegit::export::write_error_json(
+                            &message,
+                            &mut stdout.lock(),
+                        );
+                    } else {
+                        eprintln!("{}", message);
+                    }
+                    std::process::exit(1);
+                }
+            }
+        };
+        ($res:expr, $msg:expr) => {
+            check!($res, $msg,)
+        };
+    }
+
+    // Set up logging
+    {
+        let level = match matches.occurrences_of("verbose") {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            2 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        };
+        let mut logger_builder = env_logger::builder();
+        logger_builder.filter(None, level);
+        if let Ok(val) = env::var("DOUBLEGIT_LOG") {
+            logger_builder.parse_filters(&val);
+        }
+        if let Ok(val) = env::var("DOUBLEGIT_LOG_STYLE") {
+            logger_builder.parse_write_style(&val);
+        }
+        logger_builder.init();
+    }
+
+    // Read the global config, which the command-line overrides
+    let config = match doublegit::config::default_path() {
+        Some(path) => check!(
+            doublegit::config::load(&path),
+            "Error reading global config",
+        ),
+        None => Default::default(),
+    };
+    match matches.value_of_os("git") {
+        Some(git) => doublegit::set_git_binary(git),
+        None => if let Some(ref git) = config.git {
+            doublegit::set_git_binary(git);
+        },
+    }
+    match matches.value_of_os("database-dir") {
+        Some(dir) => doublegit::set_database_dir(dir),
+        None => if let Some(ref dir) = config.database_dir {
+            doublegit::set_database_dir(dir);
+        },
+    }
+    doublegit::config::set_global(config.clone());
+
+    // Options shared by the daemon and watch commands
+    let daemon_options = |s_matches: &ArgMatches| {
+        let interval = check!(
+            doublegit::daemon::parse_interval(
+                s_matches.value_of("interval").unwrap(),
+            ),
+            "Invalid interval",
+        );
+        let stagger = match s_matches.value_of("stagger") {
+            Some(s) => check!(
+                doublegit::daemon::parse_interval(s),
+                "Invalid stagger",
+            ),
+            None => interval / 4,
+        };
+        doublegit::daemon::DaemonOptions {
+            interval,
+            stagger,
+            jobs: check!(
+                s_matches.value_of("jobs").unwrap().parse(),
+                "Invalid number of jobs",
+            ),
+        }
+    };
+    // Address shared by the web and watch commands
+    #[cfg(feature = "web")]
+    let web_address = |s_matches: &ArgMatches| {
+        let host = s_matches
+            .value_of("host")
+            .or_else(|| config.web_host.as_ref().map(String::as_str))
+            .unwrap_or("127.0.0.1");
+        let host: std::net::IpAddr = check!(host.parse(), "Invalid address");
+        let port = match s_matches.value_of("port") {
+            Some(port) => check!(port.parse(), "Invalid port number"),
+            None => config.web_port.unwrap_or(6617),
+        };
+        (host, port)
+    };
+    #[cfg(feature = "web")]
+    let web_tls = |s_matches: &ArgMatches| {
+        match (
+            s_matches.value_of_os("tls-cert"),
+            s_matches.value_of_os("tls-key"),
+        ) {
+            (Some(cert), Some(key)) => Some(check!(
+                doublegit::web::Tls::new(Path::new(cert), Path::new(key)),
+                "Invalid TLS setup",
+            )),
+            _ => None,
+        }
+    };
+    #[cfg(feature = "web")]
+    let web_auth = || {
+        check!(doublegit::web::Auth::from_config(&config), "Invalid config")
+    };
+
+    match matches.subcommand_name() {
+        Some("update") => {
+            let s_matches = matches.subcommand_matches("update").unwrap();
+            let paths = match s_matches.values_of_os("repository") {
+                Some(values) => {
+                    let mut paths = Vec::new();
+                    for value in values {
+                        paths.extend(check!(
+                            doublegit::expand_glob(Path::new(value)),
+                            "Invalid arguments",
+                        ));
+                    }
+                    paths
+                }
+                None => vec![check!(
+                    repository_or_root(s_matches, "repository", &config),
+                    "Invalid arguments",
+                )],
+            };
+            let timeout = s_matches.value_of("timeout").map(|t| {
+                Duration::from_secs(check!(t.parse(), "Invalid timeout"))
+            });
+            let mut options = doublegit::UpdateOptions {
+                retries: check!(
+                    s_matches.value_of("retries").unwrap().parse(),
+                    "Invalid retry count",
+                ),
+                backoff: check!(
+                    doublegit::parse_backoff(
+                        s_matches.value_of("backoff").unwrap(),
+                    ),
+                    "Invalid retry backoff",
+                ),
+                timeout,
+                max_bytes: s_matches.value_of("max-bytes").map(|b| {
+                    check!(b.parse(), "Invalid byte limit")
+                }),
+                depth: s_matches.value_of("depth").map(|d| {
+                    check!(d.parse(), "Invalid depth")
+                }),
+                gc_every: s_matches.value_of("gc-every").map(|n| {
+                    check!(n.parse(), "Invalid gc interval")
+                }),
+                commit_graph: !s_matches.is_present("no-commit-graph"),
+                progress: None,
+            };
+            let jobs = check!(
+                s_matches.value_of("jobs").unwrap().parse(),
+                "Invalid number of jobs",
+            );
+            let exit_code = s_matches.is_present("exit-code");
+            check!(
+                doublegit::shutdown::install(),
+                "Error setting up signal handlers",
+            );
+            // With several paths, one that can't be used is reported like a
+            // failed update
+            let mut failures = Vec::new();
+            let mut unusable = Vec::new();
+            let mut mirrors: Vec<PathBuf> = Vec::new();
+            for path in &paths {
+                if path.join(doublegit::COLLECTION_CONFIG).is_file() {
+                    match doublegit::init_collection(path) {
+                        Ok(f) => failures.extend(f),
+                        Err(e) if paths.len() > 1 => {
+                            failures.push((path.clone(), e));
+                            continue;
+                        }
+                        Err(e) => check!(
+                            Err::<(), _>(e),
+                            "Error setting up collection",
+                        ),
+                    }
+                }
+                match doublegit::find_mirrors(path) {
+                    Ok(found) => {
+                        for mirror in found {
+                            if !mirrors.contains(&mirror) {
+                                mirrors.push(mirror);
+                            }
+                        }
+                    }
+                    Err(e) if paths.len() > 1 => {
+                        unusable.push((path.clone(), e));
+                    }
+                    Err(e) => {
+                        check!(Err::<(), _>(e), "Error finding mirrors")
+                    }
+                }
+            }
+            let single = paths.len() == 1
+                && mirrors.len() == 1
+                && mirrors[0] == paths[0];
+            if json {
+                let results = if single {
+                    vec![doublegit::update(&mirrors[0], &options)]
+                } else {
+                    doublegit::update_many(&mirrors, &options, jobs)
+                };
+                let mut reports: Vec<_> = failures
+                    .into_iter()
+                    .chain(unusable)
+                    .map(|(mirror, e)| (mirror, Err(e)))
+                    .collect();
+                for (mirror, res) in mirrors.into_iter().zip(results) {
+                    let res = res.and_then(|()| {
+                        doublegit::archive::last_changes(&mirror)
+                    });
+                    reports.push((mirror, res));
+                }
+                reports.sort_by(|a, b| a.0.cmp(&b.0));
+                let stdout = std::io::stdout();
+                let mut stdout = stdout.lock();
+                check!(
+                    doublegit::export::write_update_json(
+                        &reports,
+                        &mut stdout,
+                    ),
+                    "Error writing report",
+                );
+                if reports.iter().any(|(_, res)| res.is_err()) {
+                    std::process::exit(1);
+                }
+                if exit_code && reports.iter().any(|(_, res)| match res {
+                    Ok(changes) => !changes.is_empty(),
+                    Err(_) => false,
+                }) {
+                    std::process::exit(2);
+                }
+            } else if single {
+                let repository = &mirrors[0];
+                // Bars would get mixed with the log messages
+                if !s_matches.is_present("no-progress")
+                    && matches.occurrences_of("verbose") == 0
+                {
+                    options.progress = Some(Arc::new(ProgressBar::new()));
+                }
+                check!(
+                    doublegit::update(repository, &options),
+                    "Error updating",
+                );
+                if exit_code {
+                    let changes = check!(
+                        doublegit::archive::last_changes(repository),
+                        "Error reading changes",
+                    );
+                    println!(
+                        "{}: {}",
+                        repository.display(),
+                        describe_changes(&changes),
+                    );
+                    if !changes.is_empty() {
+                        std::process::exit(2);
+                    }
+                }
+            } else {
+                for (mirror, e) in &failures {
+                    eprintln!(
+                        "Error setting up {}: {}",
+                        mirror.display(),
+                        e,
+                    );
+                }
+                for (path, e) in &unusable {
+                    eprintln!(
+                        "Error finding mirrors in {}: {}",
+                        path.display(),
+                        e,
+                    );
+                }
+                let results =
+                    doublegit::update_many(&mirrors, &options, jobs);
+                let mut failed = failures.len() + unusable.len();
+                let mut changed = 0;
+                for (mirror, res) in mirrors.iter().zip(&results) {
+                    match res {
+                        Err(e) => {
+                            eprintln!(
+                                "Error updating {}: {}",
+                                mirror.display(),
+                                e,
+                            );
+                            failed += 1;
+                        }
+                        Ok(()) if exit_code => {
+                            match doublegit::archive::last_changes(mirror) {
+                                Ok(ref changes) if changes.is_empty() => {}
+                                Ok(changes) => {
+                                    println!(
+                                        "{}: {}",
+                                        mirror.display(),
+                                        describe_changes(&changes),
+                                    );
+                                    changed += 1;
+                                }
+                                Err(e) => {
+                                    eprintln!(
+                                        "Error reading changes of {}: {}",
+                                        mirror.display(),
+                                        e,
+                                    );
+                                    failed += 1;
+                                }
+                            }
+                        }
+                        Ok(()) => {}
+                    }
+                }
+                if exit_code {
+                    println!(
+                        "Updated {} mirrors, {} changed, {} failed",
+                        mirrors.len() + failures.len() + unusable.len(),
+                        changed,
+                        failed,
+                    );
+                } else {
+                    println!(
+                        "Updated {} mirrors, {} failed",
+                        mirrors.len() + failures.len() + unusable.len(),
+                        failed,
+                    );
+                }
+                if failed > 0 {
+                    std::process::exit(1);
+                } else if changed > 0 {
+                    std::process::exit(2);
+                }
+            }
+        }
+        Some("export-bundle") => {
+            let s_matches =
+                matches.subcommand_matches("export-bundle").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let date = check!(
+                doublegit::parse_date(s_matches.value_of("date").unwrap()),
+                "Invalid snapshot",
+            );
+            let output = s_matches.value_of_os("output").unwrap();
+            check!(
+                doublegit::export::export_bundle(
+                    repository,
+                    date.as_ref().map(String::as_str),
+                    Path::new(output),
+                ),
+                "Error exporting bundle",
+            );
+        }
+        Some("export") => {
+            let s_matches = matches.subcommand_matches("export").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let mut output: Box<dyn Write> =
+                match s_matches.value_of_os("output") {
+                    Some(path) => Box::new(check!(
+                        File::create(path),
+                        "Can't create output file",
+                    )),
+                    None => Box::new(std::io::stdout()),
+                };
+            let res = match s_matches.value_of("format").unwrap() {
+                "fast-export" => doublegit::export::export_fast_export(
+                    repository,
+                    &mut output,
+                ),
+                "json" => doublegit::export::export_history_json(
+                    repository,
+                    &mut output,
+                ),
+                "csv" => doublegit::export::export_history_csv(
+                    repository,
+                    &mut output,
+                ),
+                _ => unreachable!(),
+            };
+            check!(res, "Error exporting");
+        }
+        Some("restore") => {
+            let s_matches = matches.subcommand_matches("restore").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let branch = s_matches.value_of("branch").unwrap();
+            let date = match s_matches.value_of("at") {
+                Some(d) => check!(doublegit::parse_date(d), "Invalid date"),
+                None => None,
+            };
+            let sha = check!(
+                doublegit::archive::restore(
+                    repository,
+                    branch,
+                    date.as_ref().map(String::as_str),
+                    s_matches.is_present("push"),
+                ),
+                "Error restoring branch",
+            );
+            println!("Restored {} to {}", branch, sha);
+        }
+        Some("gc") => {
+            let s_matches = matches.subcommand_matches("gc").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let report = check!(
+                doublegit::archive::gc(
+                    repository,
+                    s_matches.is_present("prune"),
+                ),
+                "Error running gc",
+            );
+            println!(
+                "Checked {} objects, {} needed protecting",
+                report.checked, report.protected,
+            );
+        }
+        Some("verify") => {
+            let s_matches = matches.subcommand_matches("verify").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let repair = s_matches.is_present("repair");
+            let report = check!(
+                doublegit::archive::verify(repository, repair),
+                "Error verifying archive",
+            );
+            println!("Checked {} objects", report.checked);
+            for sha in &report.missing {
+                println!("Missing object: {}", sha);
+            }
+            for sha in &report.unprotected {
+                println!("Not protected by a keep-ref: {}", sha);
+            }
+            for name in &report.mismatched {
+                println!("Keep-ref doesn't match its target: {}", name);
+            }
+            for name in &report.superfluous {
+                println!("Superfluous keep-branch: {}", name);
+            }
+            if report.is_ok() {
+                println!("No problems found");
+            } else if !report.missing.is_empty() {
+                // Missing objects can't be repaired
+                std::process::exit(1);
+            } else if repair {
+                println!("Keep-refs repaired");
+            } else {
+                println!("Run with --repair to fix the keep-refs");
+                std::process::exit(1);
+            }
+        }
+        Some("consolidate") => {
+            let s_matches =
+                matches.subcommand_matches("consolidate").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let report = check!(
+                doublegit::archive::consolidate(
+                    repository,
+                    s_matches.is_present("pack"),
+                ),
+                "Error consolidating keep-refs",
+            );
+            println!(
+                "Deleted {} of {} keep-branches",
+                report.removed.len(),
+                report.branches,
+            );
+        }
+        Some("init") => {
+            let s_matches = matches.subcommand_matches("init").unwrap();
+            let url = s_matches.value_of("url").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            check!(
+                doublegit::init(url, repository),
+                "Error creating mirror",
+            );
+            if !s_matches.is_present("no-update") {
+                check!(
+                    doublegit::update(repository, &Default::default()),
+                    "Error updating",
+                );
+            }
+            println!("Created mirror in {}", repository.display());
+        }
+        Some("checkout-at") => {
+            let s_matches = matches.subcommand_matches("checkout-at").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let date = check!(
+                doublegit::parse_date(s_matches.value_of("date").unwrap()),
+                "Invalid snapshot",
+            );
+            let dest = Path::new(s_matches.value_of_os("dest").unwrap());
+            let sha = check!(
+                doublegit::export::checkout_at(
+                    repository,
+                    date.as_ref().map(String::as_str),
+                    s_matches.value_of("branch"),
+                    dest,
+                ),
+                "Error checking out",
+            );
+            println!("Checked out {} into {}", sha, dest.display());
+        }
+        Some("stats") => {
+            let s_matches = matches.subcommand_matches("stats").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let stats = check!(
+                doublegit::archive::stats(repository),
+                "Error reading statistics",
+            );
+            if json {
+                let stdout = std::io::stdout();
+                let mut stdout = stdout.lock();
+                check!(
+                    doublegit::export::write_json(&stats, &mut stdout),
+                    "Error writing statistics",
+                );
+            } else {
+                println!(
+                    "Snapshots: {} ({} - {})",
+                    stats.snapshots,
+                    stats.first_date.as_ref().map_or("", String::as_str),
+                    stats.last_date.as_ref().map_or("", String::as_str),
+                );
+                println!(
+                    "Branches: {}, tags: {}, deleted branches: {}",
+                    stats.branches, stats.tags, stats.deleted_branches,
+                );
+                println!(
+                    "Archived commits no longer upstream: {}",
+                    stats.unreachable_commits,
+                );
+                for (name, count) in &stats.force_pushes {
+                    println!("Force-pushes to {}: {}", name, count);
+                }
+                println!();
+                println!("{:19}  {:>10}  {:>10}  {:>10}", "date", "objects",
+                         "size", "growth");
+                let mut previous = None;
+                for record in &stats.sizes {
+                    let growth = match previous {
+                        Some(p) => format_growth(record.disk_bytes, p),
+                        None => String::new(),
+                    };
+                    let line = format!(
+                        "{:19}  {:>10}  {:>10}  {:>10}",
+                        record.date,
+                        record.objects,
+                        format_size(record.disk_bytes),
+                        growth,
+                    );
+                    println!("{}", line.trim_end());
+                    previous = Some(record.disk_bytes);
+                }
+            }
+        }
+        Some("transcript") => {
+            let s_matches = matches.subcommand_matches("transcript").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let date = check!(
+                doublegit::parse_date(s_matches.value_of("date").unwrap()),
+                "Invalid date",
+            );
+            let (date, stdout, stderr) = check!(
+                doublegit::archive::transcript(
+                    repository,
+                    date.as_ref().map(String::as_str),
+                ),
+                "Error reading transcript",
+            );
+            eprintln!("Update at {}", date);
+            std::io::stderr().write_all(&stderr).ok();
+            std::io::stdout().write_all(&stdout).ok();
+        }
+        Some("merge") => {
+            let s_matches = matches.subcommand_matches("merge").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let source = s_matches.value_of_os("source").unwrap();
+            let report = check!(
+                doublegit::archive::merge(repository, Path::new(source)),
+                "Error merging archives",
+            );
+            println!(
+                "Merged {} rows of history, kept {} new objects",
+                report.changed,
+                report.kept,
+            );
+        }
+        Some("search") => {
+            let s_matches = matches.subcommand_matches("search").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let date = |name| match s_matches.value_of(name) {
+                Some(d) => check!(doublegit::parse_date(d), "Invalid date"),
+                None => None,
+            };
+            let options = doublegit::archive::SearchOptions {
+                limit: check!(
+                    s_matches.value_of("limit").unwrap().parse(),
+                    "Invalid limit",
+                ),
+                deleted_only: s_matches.is_present("deleted-only"),
+                since: date("since"),
+                until: date("until"),
+            };
+            let results = check!(
+                doublegit::archive::search(
+                    repository,
+                    s_matches.value_of("query").unwrap(),
+                    &options,
+                ),
+                "Error searching",
+            );
+            for ref_ in &results.refs {
+                println!(
+                    "{} {} {} - {}",
+                    if ref_.tag { "tag" } else { "branch" },
+                    ref_.name,
+                    ref_.from_date,
+                    ref_.to_date.as_ref().map_or("now", String::as_str),
+                );
+            }
+            for commit in &results.commits {
+                println!(
+                    "{} {} {}{}",
+                    commit.sha,
+                    commit.date,
+                    commit.message.lines().next().unwrap_or(""),
+                    if commit.upstream { "" } else { " (not upstream)" },
+                );
+            }
+        }
+        Some("annotate") => {
+            let s_matches = matches.subcommand_matches("annotate").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            if s_matches.is_present("list") {
+                let annotations = check!(
+                    doublegit::archive::annotations(repository),
+                    "Error reading notes",
+                );
+                for annotation in &annotations {
+                    let about = match annotation.ref_ {
+                        Some((ref name, tag, ref from_date)) => format!(
+                            "{} {} from {}",
+                            if tag { "tag" } else { "branch" },
+                            name,
+                            from_date,
+                        ),
+                        None => format!(
+                            "snapshot {}",
+                            annotation.snapshot.as_ref().unwrap(),
+                        ),
+                    };
+                    println!("{} ({}): {}", about, annotation.created,
+                             annotation.note);
+                }
+            } else {
+                let date = check!(
+                    doublegit::parse_date(s_matches.value_of("date").unwrap()),
+                    "Invalid snapshot",
+                );
+                let ref_ = match (
+                    s_matches.value_of("branch"),
+                    s_matches.value_of("tag"),
+                ) {
+                    (Some(branch), _) => Some((branch, false)),
+                    (None, Some(tag)) => Some((tag, true)),
+                    (None, None) => None,
+                };
+                check!(
+                    doublegit::archive::annotate(
+                        repository,
+                        date.as_ref().map(String::as_str),
+                        ref_,
+                        s_matches.value_of("note").unwrap(),
+                    ),
+                    "Error adding note",
+                );
+            }
+        }
+        Some("backup") => {
+            let s_matches = matches.subcommand_matches("backup").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let dest = s_matches.value_of_os("destination").unwrap();
+            check!(
+                doublegit::archive::backup(repository, Path::new(dest)),
+                "Error backing up archive",
+            );
+        }
+        Some("expire") => {
+            let s_matches = matches.subcommand_matches("expire").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let options = doublegit::retention::ExpireOptions {
+                dry_run: s_matches.is_present("dry-run"),
+                repack: s_matches.is_present("repack"),
+            };
+            let report = check!(
+                doublegit::retention::expire(repository, &options),
+                "Error expiring history",
+            );
+            println!(
+                "{} {} intervals ended before {}, {} objects no longer kept; \
+                 {} kept by notes or legal holds",
+                if options.dry_run { "Would remove" } else { "Removed" },
+                report.intervals,
+                report.cutoff,
+                report.objects,
+                report.held,
+            );
+        }
+        Some("hold") => {
+            let s_matches = matches.subcommand_matches("hold").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            if s_matches.is_present("list") {
+                let holds = check!(
+                    doublegit::retention::holds(repository),
+                    "Error reading legal holds",
+                );
+                for hold in &holds {
+                    println!(
+                        "{}: from {} to {} ({}): {}",
+                        hold.id,
+                        hold.from_date.as_ref().map_or("the beginning", |d| d),
+                        hold.to_date.as_ref().map_or("now", |d| d),
+                        hold.created,
+                        hold.reason,
+                    );
+                }
+            } else if let Some(id) = s_matches.value_of("release") {
+                let id = check!(id.parse(), "Invalid legal hold number");
+                check!(
+                    doublegit::retention::release(repository, id),
+                    "Error releasing legal hold",
+                );
+            } else {
+                let mut dates = Vec::new();
+                for arg in &["from", "to"] {
+                    dates.push(match s_matches.value_of(arg) {
+                        Some(d) => check!(
+                            doublegit::parse_date(d),
+                            "Invalid date",
+                        ),
+                        None => None,
+                    });
+                }
+                let id = check!(
+                    doublegit::retention::hold(
+                        repository,
+                        dates[0].as_ref().map(String::as_str),
+                        dates[1].as_ref().map(String::as_str),
+                        s_matches.value_of("reason").unwrap(),
+                    ),
+                    "Error adding legal hold",
+                );
+                println!("Added legal hold {}", id);
+            }
+        }
+        Some("verify-audit") => {
+            let s_matches =
+                matches.subcommand_matches("verify-audit").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let report = check!(
+                doublegit::audit::verify(repository),
+                "Error verifying audit log",
+            );
+            println!(
+                "Checked {} of {} audit entries",
+                report.checked,
+                report.entries,
+            );
+            if report.unaudited > 0 {
+                println!(
+                    "{} changes are from before the audit log was started",
+                    report.unaudited,
+                );
+            }
+            for problem in &report.problems {
+                println!("{}", problem);
+            }
+            if report.is_ok() {
+                println!("No problems found");
+            } else {
+                std::process::exit(1);
+            }
+        }
+        Some("timestamps") => {
+            let s_matches = matches.subcommand_matches("timestamps").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let timestamps = check!(
+                doublegit::audit::timestamps(repository),
+                "Error reading timestamps",
+            );
+            if let Some(id) = s_matches.value_of("proof") {
+                let id: i64 = check!(id.parse(), "Invalid audit entry");
+                match timestamps.into_iter()
+                    .rev()
+                    .filter(|t| t.audit_id == id)
+                    .find_map(|t| t.proof)
+                {
+                    Some(proof) => {
+                        std::io::stdout().write_all(&proof).ok();
+                    }
+                    None => {
+                        eprintln!("No proof for audit entry {}", id);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                for timestamp in &timestamps {
+                    match timestamp.error {
+                        Some(ref e) => println!(
+                            "{} ({}) {}: failed: {}",
+                            timestamp.audit_id, timestamp.date,
+                            timestamp.hash, e,
+                        ),
+                        None => println!(
+                            "{} ({}) {}: {} bytes of proof",
+                            timestamp.audit_id, timestamp.date,
+                            timestamp.hash,
+                            timestamp.proof.as_ref().map_or(0, Vec::len),
+                        ),
+                    }
+                }
+            }
+        }
+        Some("rollback") => {
+            let s_matches = matches.subcommand_matches("rollback").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let date = check!(
+                doublegit::parse_date(s_matches.value_of("date").unwrap()),
+                "Invalid date",
+            );
+            let report = check!(
+                doublegit::archive::rollback(
+                    repository,
+                    date.as_ref().map(String::as_str),
+                ),
+                "Error rolling back update",
+            );
+            println!(
+                "Rolled back update at {}: removed {} rows, extended {}, {} \
+                 objects no longer kept",
+                report.date, report.removed, report.extended, report.objects,
+            );
+        }
+        Some("daemon") => {
+            let s_matches = matches.subcommand_matches("daemon").unwrap();
+            let repository = check!(
+                repository_or_root(s_matches, "repository", &config),
+                "Invalid arguments",
+            );
+            let repository = repository.as_path();
+            let options = daemon_options(s_matches);
+            check!(
+                doublegit::shutdown::install(),
+                "Error setting up signal handlers",
+            );
+            doublegit::daemon::run(repository, &Default::default(), &options);
+        }
+        Some("log") => {
+            let s_matches = matches.subcommand_matches("log").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let log = check!(
+                doublegit::archive::ref_log(
+                    repository,
+                    s_matches.value_of("ref"),
+                ),
+                "Error reading history",
+            );
+            if json {
+                let stdout = std::io::stdout();
+                let mut stdout = stdout.lock();
+                check!(
+                    doublegit::export::write_json(&log, &mut stdout),
+                    "Error writing history",
+                );
+            } else {
+                let mut last: Option<(&doublegit::RefName, bool)> = None;
+                for entry in &log {
+                    if last != Some((&entry.name, entry.tag)) {
+                        println!(
+                            "{} {}",
+                            if entry.tag { "tag" } else { "branch" },
+                            entry.name,
+                        );
+                        last = Some((&entry.name, entry.tag));
+                    }
+                    println!(
+                        "  {}  {:<12}  {}",
+                        entry.from_date,
+                        entry.change.name(),
+                        entry.sha,
+                    );
+                    if entry.deleted {
+                        println!(
+                            "  {}  deleted",
+                            entry.to_date.as_ref().unwrap(),
+                        );
+                    }
+                }
+            }
+        }
+        Some("show") => {
+            let s_matches = matches.subcommand_matches("show").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let date = check!(
+                doublegit::parse_date(s_matches.value_of("date").unwrap()),
+                "Invalid date",
+            );
+            let snapshot = check!(
+                doublegit::archive::snapshot(
+                    repository,
+                    date.as_ref().map(String::as_str),
+                    true,
+                ),
+                "Error reading snapshot",
+            );
+            let names: Vec<String> =
+                snapshot.iter().map(|r| r.name.to_string()).collect();
+            let width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+            for (ref_, name) in snapshot.iter().zip(&names) {
+                println!(
+                    "{:<6}  {:<width$}  {}  {}",
+                    if ref_.tag { "tag" } else { "branch" },
+                    name,
+                    ref_.sha,
+                    ref_.subject.as_ref().map_or("", String::as_str),
+                    width = width,
+                );
+            }
+        }
+        Some("diff") => {
+            let s_matches = matches.subcommand_matches("diff").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let from = check!(
+                doublegit::parse_date(s_matches.value_of("from").unwrap()),
+                "Invalid date",
+            );
+            let to = check!(
+                doublegit::parse_date(s_matches.value_of("to").unwrap()),
+                "Invalid date",
+            );
+            let diffs = check!(
+                doublegit::archive::compare_snapshots(
+                    repository,
+                    from.as_ref().map(String::as_str),
+                    to.as_ref().map(String::as_str),
+                    s_matches.is_present("stat"),
+                ),
+                "Error comparing snapshots",
+            );
+            for diff in &diffs {
+                let kind = if diff.tag { "tag" } else { "branch" };
+                match (&diff.old, &diff.new) {
+                    (None, Some(new)) => {
+                        println!("created {} {} {}", kind, diff.name, new);
+                    }
+                    (Some(old), None) => {
+                        println!("deleted {} {} {}", kind, diff.name, old);
+                    }
+                    (Some(old), Some(new)) => {
+                        println!(
+                            "moved   {} {} {}..{}",
+                            kind, diff.name, old, new,
+                        );
+                    }
+                    (None, None) => unreachable!(),
+                }
+                if let Some(ref stat) = diff.stat {
+                    print!("{}", stat);
+                }
+            }
         }
-    };
-
-    macro_rules! check {
-        ($res:expr, $msg:expr,) => {
-            match $res {
-                Ok(r) => r,
-                Err(e) => {
-                    eprintln!("{}: {}", $msg, e);
-                    std::process::exit(1);
+        Some("list") => {
+            let s_matches = matches.subcommand_matches("list").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let date = match s_matches.value_of("at") {
+                Some(d) => check!(doublegit::parse_date(d), "Invalid date"),
+                None => None,
+            };
+            let mut refs = check!(
+                doublegit::archive::snapshot(
+                    repository,
+                    date.as_ref().map(String::as_str),
+                    false,
+                ),
+                "Error listing refs",
+            );
+            if s_matches.is_present("branches") {
+                refs.retain(|r| !r.tag);
+            } else if s_matches.is_present("tags") {
+                refs.retain(|r| r.tag);
+            }
+            if json {
+                let stdout = std::io::stdout();
+                let mut stdout = stdout.lock();
+                check!(
+                    doublegit::export::write_json(&refs, &mut stdout),
+                    "Error writing refs",
+                );
+            } else {
+                for ref_ in &refs {
+                    println!(
+                        "{}\t{}\t{}",
+                        if ref_.tag { "tag" } else { "branch" },
+                        ref_.name,
+                        ref_.sha,
+                    );
                 }
             }
-        };
-        ($res:expr, $msg:expr) => {
-            check!($res, $msg,)
-        };
-    }
-
-    // Set up logging
-    {
-        let level = match matches.occurrences_of("verbose") {
-            0 => log::LevelFilter::Warn,
-            1 => log::LevelFilter::Info,
-            2 => log::LevelFilter::Debug,
-            _ => log::LevelFilter::Trace,
-        };
-        let mut logger_builder = env_logger::builder();
-        logger_builder.filter(None, level);
-        if let Ok(val) = env::var("DOUBLEGIT_LOG") {
-            logger_builder.parse_filters(&val);
         }
-        if let Ok(val) = env::var("DOUBLEGIT_LOG_STYLE") {
-            logger_builder.parse_write_style(&val);
+        Some("when") => {
+            let s_matches = matches.subcommand_matches("when").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let (commit, periods) = check!(
+                doublegit::archive::when(
+                    repository,
+                    s_matches.value_of("commit").unwrap(),
+                ),
+                "Error finding commit",
+            );
+            if periods.is_empty() {
+                println!("{} was never on an archived branch", commit);
+            }
+            for period in &periods {
+                println!(
+                    "{} {} - {}",
+                    period.name,
+                    period.from_date,
+                    period.to_date.as_ref().map_or("now", String::as_str),
+                );
+            }
         }
-        logger_builder.init();
-    }
+        Some("status") => {
+            let s_matches = matches.subcommand_matches("status").unwrap();
+            let repository = check!(
+                repository_or_root(s_matches, "repository", &config),
+                "Invalid arguments",
+            );
+            let repository = repository.as_path();
+            let mirrors = check!(
+                doublegit::find_mirrors(repository),
+                "Error finding mirrors",
+            );
+            let mut healthy = true;
+            if json {
+                let statuses: Vec<_> = mirrors
+                    .into_iter()
+                    .map(|mirror| {
+                        let status = doublegit::archive::status(&mirror);
+                        (mirror, status)
+                    })
+                    .collect();
+                healthy = statuses.iter().all(|(_, status)| match status {
+                    Ok(status) => status.is_ok(),
+                    Err(_) => false,
+                });
+                let stdout = std::io::stdout();
+                let mut stdout = stdout.lock();
+                check!(
+                    doublegit::export::write_status_json(
+                        &statuses,
+                        &mut stdout,
+                    ),
+                    "Error writing status",
+                );
+            } else if mirrors.len() == 1 && mirrors[0] == repository {
+                let status = check!(
+                    doublegit::archive::status(repository),
+                    "Error reading status",
+                );
+                print_status(&status, "");
+                healthy = status.is_ok();
+            } else {
+                for mirror in &mirrors {
+                    println!("{}:", mirror.display());
+                    match doublegit::archive::status(mirror) {
+                        Ok(status) => {
+                            print_status(&status, "  ");
+                            healthy &= status.is_ok();
+                        }
+                        Err(e) => {
+                            println!("  Error reading status: {}", e);
+                            healthy = false;
+                        }
+                    }
+                }
+            }
+            if !healthy {
+                std::process::exit(1);
+            }
+        }
+        Some("mirrors") => {
+            let s_matches = matches.subcommand_matches("mirrors").unwrap();
+            let directory = check!(
+                repository_or_root(s_matches, "directory", &config),
+                "Invalid arguments",
+            );
+            let mirrors = check!(
+                doublegit::find_mirrors(&directory),
+                "Error finding mirrors",
+            );
+            for mirror in &mirrors {
+                println!("{}", mirror.display());
+            }
+        }
+        Some("completions") => {
+            let s_matches =
+                matches.subcommand_matches("completions").unwrap();
+            let shell = match s_matches.value_of("shell").unwrap() {
+                "bash" => clap::Shell::Bash,
+                "zsh" => clap::Shell::Zsh,
+                _ => clap::Shell::Fish,
+            };
+            let mut script = Vec::new();
+            completions_cli.gen_completions_to(
+                "doublegit",
+                shell,
+                &mut script,
+            );
+            let mut script = String::from_utf8(script).unwrap();
+            let directory = s_matches
+                .value_of_os("mirrors")
+                .map(PathBuf::from)
+                .or_else(|| config.root.clone());
+            if let Some(directory) = directory {
+                script = complete_mirrors(
+                    script,
+                    shell,
+                    &directory.to_string_lossy(),
+                );
+            }
+            print!("{}", script);
+        }
+        Some("import-reflog") => {
+            let s_matches =
+                matches.subcommand_matches("import-reflog").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let clone = s_matches.value_of_os("clone").unwrap();
+            let report = check!(
+                doublegit::archive::import_reflog(
+                    Path::new(clone),
+                    repository,
+                ),
+                "Error importing reflogs",
+            );
+            println!(
+                "Imported {} intervals of {} refs, extended {} back in time",
+                report.intervals,
+                report.refs,
+                report.extended,
+            );
+        }
+        Some("setup") => {
+            let s_matches = matches.subcommand_matches("setup").unwrap();
+            let target = s_matches.value_of("target").unwrap();
+            // A local repository to archive is a directory too, but not a
+            // mirror
+            let (url, repository) = if Path::new(target).is_dir()
+                && doublegit::setup::origin_url(Path::new(target)).is_ok()
+            {
+                (None, PathBuf::from(target))
+            } else {
+                let name = target
+                    .trim_end_matches('/')
+                    .rsplit(|c| c == '/' || c == ':')
+                    .next()
+                    .unwrap_or("");
+                let name = name.trim_end_matches(".git");
+                let path = check!(
+                    ask("Path of the mirror", name),
+                    "Error reading answer",
+                );
+                (Some(target), PathBuf::from(path))
+            };
+            let origin = match url {
+                Some(url) => url.to_owned(),
+                None => check!(
+                    doublegit::setup::origin_url(&repository),
+                    "Error reading mirror config",
+                ),
+            };
 
-    match matches.subcommand_name() {
-        Some("update") => {
-            let s_matches = matches.subcommand_matches("update").unwrap();
+            let platform = doublegit::setup::Platform::detect(&origin);
+            let platform = check!(
+                choose(
+                    "Platform",
+                    &["github", "gitlab", "other"],
+                    platform.name(),
+                ),
+                "Error reading answer",
+            );
+            let platform =
+                doublegit::setup::Platform::parse(&platform).unwrap();
+            let wiki = if platform.wiki_url(&origin).is_some() {
+                check!(
+                    choose("Also archive the wiki?", &["y", "n"], "n"),
+                    "Error reading answer",
+                ) == "y"
+            } else {
+                false
+            };
+            let credentials = check!(
+                ask_credentials(),
+                "Error reading answer",
+            );
+            let interval = loop {
+                let interval = check!(
+                    ask("How often to update, e.g. 30m, 1h or 1d", "1h"),
+                    "Error reading answer",
+                );
+                match doublegit::daemon::parse_interval(&interval) {
+                    Ok(_) => break interval,
+                    Err(e) => eprintln!("{}", e),
+                }
+            };
+
+            let options = doublegit::setup::SetupOptions {
+                platform,
+                credentials,
+                wiki,
+            };
+            check!(
+                doublegit::setup::setup(url, &repository, &options),
+                "Error setting up mirror",
+            );
+            if !s_matches.is_present("no-update") {
+                check!(
+                    doublegit::update(&repository, &Default::default()),
+                    "Error updating",
+                );
+            }
+            println!("Set up mirror in {}", repository.display());
+            let parent = match repository.parent() {
+                Some(p) if p != Path::new("") => p,
+                _ => Path::new("."),
+            };
+            println!(
+                "To keep it up to date, run: doublegit daemon --interval {} \
+                 {}",
+                interval,
+                parent.display(),
+            );
+        }
+        Some("doctor") => {
+            let s_matches = matches.subcommand_matches("doctor").unwrap();
+            let online = !s_matches.is_present("offline");
+            let mut healthy = true;
+            let git = doublegit::doctor::check_git();
+            healthy &= !git.is_problem();
+            print_finding(&git, "");
+            let repository = match repository_or_root(
+                s_matches,
+                "repository",
+                &config,
+            ) {
+                Ok(repository) => repository,
+                Err(_) if !s_matches.is_present("repository") => {
+                    if !healthy {
+                        std::process::exit(1);
+                    }
+                    return;
+                }
+                Err(e) => check!(Err::<PathBuf, _>(e), "Invalid arguments"),
+            };
+            let repository = repository.as_path();
+            let mirrors = check!(
+                doublegit::find_mirrors(repository),
+                "Error finding mirrors",
+            );
+            for mirror in &mirrors {
+                println!("{}:", mirror.display());
+                match doublegit::doctor::check_mirror(mirror, online) {
+                    Ok(findings) => {
+                        for finding in &findings {
+                            healthy &= !finding.is_problem();
+                            print_finding(finding, "  ");
+                        }
+                    }
+                    Err(e) => {
+                        println!("  Error checking mirror: {}", e);
+                        healthy = false;
+                    }
+                }
+            }
+            if !healthy {
+                std::process::exit(1);
+            }
+        }
+        Some("forget") => {
+            let s_matches = matches.subcommand_matches("forget").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            if s_matches.is_present("list") {
+                let redactions = check!(
+                    doublegit::archive::redactions(repository),
+                    "Error reading redactions",
+                );
+                for redaction in &redactions {
+                    println!(
+                        "{} {} {}{} ({} intervals): {}",
+                        redaction.date,
+                        if redaction.tag { "tag" } else { "branch" },
+                        redaction.fork.as_ref().map_or(String::new(), |f| {
+                            format!("{}:", f)
+                        }),
+                        redaction.name,
+                        redaction.intervals,
+                        redaction.reason,
+                    );
+                }
+                return;
+            }
+            let options = doublegit::archive::ForgetOptions {
+                dry_run: s_matches.is_present("dry-run"),
+                repack: !s_matches.is_present("no-repack"),
+                reason: s_matches.value_of("reason").unwrap_or("").into(),
+            };
+            let report = check!(
+                doublegit::archive::forget(
+                    repository,
+                    s_matches.value_of("fork"),
+                    s_matches.value_of("ref").unwrap(),
+                    s_matches.is_present("tag"),
+                    &options,
+                ),
+                "Error forgetting ref",
+            );
+            println!(
+                "{} {} intervals and {} notes, {} objects no longer kept",
+                if options.dry_run { "Would remove" } else { "Removed" },
+                report.intervals,
+                report.notes,
+                report.objects,
+            );
+            if report.live {
+                println!(
+                    "The ref still existed upstream, the next update will \
+                     record it again unless it is removed there"
+                );
+            }
+        }
+        Some("replay") => {
+            let s_matches = matches.subcommand_matches("replay").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let output = s_matches.value_of_os("output").unwrap();
+            let report = check!(
+                doublegit::export::replay(
+                    Path::new(repository),
+                    Path::new(output),
+                ),
+                "Error replaying history",
+            );
+            println!(
+                "Wrote {} branches with {} commits",
+                report.branches, report.commits,
+            );
+            if report.missing > 0 {
+                println!(
+                    "Skipped {} snapshots whose commit is missing",
+                    report.missing,
+                );
+            }
+        }
+        Some("config") => {
+            let s_matches = matches.subcommand_matches("config").unwrap();
+            let (action, a_matches) = s_matches.subcommand();
+            let a_matches = a_matches.unwrap();
+            let global = a_matches.is_present("global");
+            let mut args: Vec<&std::ffi::OsStr> = a_matches
+                .values_of_os("args")
+                .map_or_else(Vec::new, Iterator::collect);
+            let expected = match action {
+                "set" => 2,
+                "list" => 0,
+                _ => 1,
+            };
+            if args.len() != expected + if global { 0 } else { 1 } {
+                eprintln!(
+                    "Invalid arguments: give a repository or --global, then \
+                     {}",
+                    match expected {
+                        0 => "nothing else",
+                        1 => "the key",
+                        _ => "the key and the value",
+                    },
+                );
+                std::process::exit(2);
+            }
+            let target = if global {
+                match doublegit::config::default_path() {
+                    Some(path) => path,
+                    None => check!(
+                        Err::<PathBuf, _>(doublegit::Error::Config(
+                            "no location for the global config".into(),
+                        )),
+                        "Invalid arguments",
+                    ),
+                }
+            } else {
+                PathBuf::from(args.remove(0))
+            };
+            let args: Vec<&str> = args
+                .into_iter()
+                .map(|a| {
+                    check!(a.to_str().ok_or("not UTF-8"), "Invalid argument")
+                })
+                .collect();
+            let settings = || {
+                if global {
+                    doublegit::config::global_settings(&target)
+                } else {
+                    doublegit::config::mirror_settings(&target)
+                }
+            };
+            match action {
+                "get" => {
+                    let key = check!(
+                        if global {
+                            doublegit::config::global_setting_name(args[0])
+                        } else {
+                            doublegit::config::mirror_setting_name(args[0])
+                        },
+                        "Invalid setting",
+                    );
+                    let settings = check!(settings(), "Error reading config");
+                    match settings.into_iter().find(|(k, _)| *k == key) {
+                        Some((_, value)) => println!("{}", value),
+                        // Like git config
+                        None => std::process::exit(1),
+                    }
+                }
+                "list" => {
+                    for (key, value) in check!(
+                        settings(),
+                        "Error reading config",
+                    ) {
+                        println!("{}={}", key, value);
+                    }
+                }
+                _ => {
+                    let value = args.get(1).cloned();
+                    check!(
+                        if global {
+                            doublegit::config::set_global_setting(
+                                &target, args[0], value,
+                            )
+                        } else {
+                            doublegit::config::set_mirror_setting(
+                                &target, args[0], value,
+                            )
+                        },
+                        "Error changing setting",
+                    );
+                }
+            }
+        }
+        Some("grep") => {
+            let s_matches = matches.subcommand_matches("grep").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let options = doublegit::archive::GrepOptions {
+                date: match s_matches.value_of("at") {
+                    Some(d) => {
+                        check!(doublegit::parse_date(d), "Invalid date")
+                    }
+                    None => None,
+                },
+                all: s_matches.is_present("all"),
+                ignore_case: s_matches.is_present("ignore-case"),
+            };
+            let matches = check!(
+                doublegit::archive::grep(
+                    repository,
+                    s_matches.value_of("pattern").unwrap(),
+                    &options,
+                ),
+                "Error searching",
+            );
+            for m in &matches {
+                println!(
+                    "{} {} {} - {} {}:{}: {}",
+                    if m.tag { "tag" } else { "branch" },
+                    m.name,
+                    m.from_date,
+                    m.to_date.as_ref().map_or("now", String::as_str),
+                    m.path,
+                    m.line,
+                    m.text,
+                );
+            }
+            if matches.is_empty() {
+                // Like git grep
+                std::process::exit(1);
+            }
+        }
+        Some("tags") => {
+            let s_matches = matches.subcommand_matches("tags").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let repository = Path::new(repository);
+            let history = check!(
+                doublegit::archive::tag_history(
+                    repository,
+                    s_matches.is_present("changed"),
+                ),
+                "Error reading history",
+            );
+            if json {
+                let stdout = std::io::stdout();
+                let mut stdout = stdout.lock();
+                check!(
+                    doublegit::export::write_json(&history, &mut stdout),
+                    "Error writing history",
+                );
+            } else {
+                let mut last = None;
+                for entry in &history {
+                    if last != Some(&entry.name) {
+                        println!("tag {}", entry.name);
+                        last = Some(&entry.name);
+                    }
+                    match (&entry.target, &entry.tagger) {
+                        (Some(target), Some(tagger)) => println!(
+                            "  {}  {} -> {}, by {}{}",
+                            entry.from_date,
+                            entry.sha,
+                            target,
+                            tagger,
+                            entry.tag_date.as_ref().map_or_else(
+                                String::new,
+                                |d| format!(" on {}", d),
+                            ),
+                        ),
+                        _ => println!("  {}  {}", entry.from_date, entry.sha),
+                    }
+                    if let Some(ref message) = entry.message {
+                        for line in message.trim_end().lines() {
+                            println!("      {}", line);
+                        }
+                    }
+                    if entry.deleted {
+                        println!(
+                            "  {}  deleted",
+                            entry.to_date.as_ref().unwrap(),
+                        );
+                    }
+                }
+            }
+        }
+        Some("bisect-date") => {
+            let s_matches = matches.subcommand_matches("bisect-date").unwrap();
             let repository = s_matches.value_of_os("repository").unwrap();
             let repository = Path::new(repository);
-            check!(doublegit::update(repository), "Error updating");
+            let test = match s_matches.value_of("reachable") {
+                Some(commit) => {
+                    doublegit::archive::BisectTest::Reachable(commit.into())
+                }
+                None => doublegit::archive::BisectTest::Command(
+                    s_matches.value_of("run").unwrap().into(),
+                ),
+            };
+            let report = check!(
+                doublegit::archive::bisect_date(
+                    repository,
+                    s_matches.value_of("branch"),
+                    &test,
+                ),
+                "Error bisecting",
+            );
+            match report {
+                Some(report) => {
+                    let (before, after) = if report.initially {
+                        ("passes", "fails")
+                    } else {
+                        ("fails", "passes")
+                    };
+                    println!("last snapshot where it {}: {}", before,
+                             report.before);
+                    println!("first snapshot where it {}: {}", after,
+                             report.after);
+                    println!("({} snapshots tested)", report.tested);
+                }
+                None => {
+                    eprintln!("The result is the same in the first and last \
+                               snapshots");
+                    std::process::exit(1);
+                }
+            }
         }
+        #[cfg(feature = "web")]
         Some("web") => {
             let s_matches = matches.subcommand_matches("web").unwrap();
             let repository = s_matches.value_of_os("repository").unwrap();
             let repository = Path::new(repository);
-            let host = s_matches.value_of("host").unwrap();
-            let host = check!(host.parse(), "Invalid address");
-            let port = check!(
-                s_matches.value_of("port").unwrap().parse(),
-                "Invalid port number",
+            let (host, port) = web_address(s_matches);
+            check!(
+                doublegit::shutdown::install(),
+                "Error setting up signal handlers",
             );
             check!(
-                doublegit::web::serve(repository, host, port),
+                doublegit::web::serve(
+                    repository,
+                    host,
+                    port,
+                    web_auth(),
+                    web_tls(s_matches),
+                ),
                 "Error running server",
             );
         }
+        #[cfg(feature = "web")]
+        Some("export-static") => {
+            let s_matches =
+                matches.subcommand_matches("export-static").unwrap();
+            let repository = s_matches.value_of_os("repository").unwrap();
+            let output = s_matches.value_of_os("output").unwrap();
+            let pages = check!(
+                doublegit::web::export_static(
+                    Path::new(repository),
+                    Path::new(output),
+                ),
+                "Error exporting site",
+            );
+            println!("Wrote {} pages", pages);
+        }
+        #[cfg(feature = "web")]
+        Some("watch") => {
+            let s_matches = matches.subcommand_matches("watch").unwrap();
+            let root = check!(
+                repository_or_root(s_matches, "root", &config),
+                "Invalid arguments",
+            );
+            let options = daemon_options(s_matches);
+            let (host, port) = web_address(s_matches);
+            let roster = check!(
+                doublegit::web::Roster::new(&root),
+                "Error finding mirrors",
+            );
+            check!(
+                doublegit::shutdown::install(),
+                "Error setting up signal handlers",
+            );
+            let refreshed = roster.clone();
+            let daemon = std::thread::spawn(move || {
+                doublegit::daemon::run_then(
+                    &root,
+                    &Default::default(),
+                    &options,
+                    || {
+                        if let Err(e) = refreshed.refresh() {
+                            log::error!("Error finding mirrors: {}", e);
+                        }
+                    },
+                )
+            });
+            let tls = web_tls(s_matches);
+            let served = doublegit::web::serve_roster(
+                roster,
+                host,
+                port,
+                web_auth(),
+                tls,
+            );
+            // Let the running updates stop before exiting
+            doublegit::shutdown::request();
+            daemon.join().ok();
+            check!(served, "Error running server");
+        }
         _ => {
             cli.print_help().expect("Can't print help");
             std::process::exit(2);
         }
     }
 }
+
+/// Build one of the `config` subcommands
+fn config_command<'a, 'b>(
+    name: &'a str,
+    about: &'a str,
+    usage: &'a str,
+) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            Arg::with_name("global")
+                .long("global")
+                .help("Use the global config instead of a mirror's"),
+        )
+        .arg(
+            Arg::with_name("args")
+                .help(usage)
+                .multiple(true),
+        )
+}
+
+/// Format a number of bytes for humans, e.g. `12.3 MiB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Progress bar on the terminal, for `update`
+///
+/// Nothing is drawn if stderr is not a terminal.
+struct ProgressBar(indicatif::ProgressBar);
+
+impl ProgressBar {
+    fn new() -> ProgressBar {
+        let bar = indicatif::ProgressBar::new(0);
+        bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template("{wide_msg} [{bar:30}] {pos}/{len}")
+                .progress_chars("=> "),
+        );
+        ProgressBar(bar)
+    }
+
+    fn set(&self, message: &str, done: u64, total: u64) {
+        self.0.set_message(message);
+        self.0.set_length(total.max(done));
+        self.0.set_position(done);
+    }
+}
+
+impl doublegit::progress::Progress for ProgressBar {
+    fn fetch(
+        &self,
+        remote: &str,
+        progress: &doublegit::progress::FetchProgress,
+    ) {
+        let mut message = format!("{}: {}", remote, progress.stage);
+        if let Some(bytes) = progress.bytes {
+            message.push_str(&format!(", {}", format_size(bytes)));
+        }
+        self.set(&message, progress.done, progress.total.unwrap_or(0));
+    }
+
+    fn refs(&self, done: usize, total: usize) {
+        self.set("Recording refs", done as u64, total as u64);
+    }
+
+    fn finish(&self) {
+        self.0.finish_and_clear();
+    }
+}
+
+/// Format the change from one size to the next, e.g. `+1.2 MiB`
+fn format_growth(size: u64, previous: u64) -> String {
+    if size >= previous {
+        format!("+{}", format_size(size - previous))
+    } else {
+        format!("-{}", format_size(previous - size))
+    }
+}
+
+/// Summarize the changes of an update, e.g. `3 refs changed (1 new, 2
+/// moved)`
+fn describe_changes(changes: &[doublegit::archive::RefDiff]) -> String {
+    if changes.is_empty() {
+        return "no changes".into();
+    }
+    let count = |f: &dyn Fn(&doublegit::archive::RefDiff) -> bool| {
+        changes.iter().filter(|c| f(c)).count()
+    };
+    let kinds = [
+        (count(&|c| c.old.is_none()), "new"),
+        (count(&|c| c.old.is_some() && c.new.is_some()), "moved"),
+        (count(&|c| c.new.is_none()), "deleted"),
+    ];
+    let kinds: Vec<String> = kinds
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, kind)| format!("{} {}", n, kind))
+        .collect();
+    format!(
+        "{} ref{} changed ({})",
+        changes.len(),
+        if changes.len() == 1 { "" } else { "s" },
+        kinds.join(", "),
+    )
+}
+
+/// Print the outcome of a check, for the `doctor` command
+fn print_finding(finding: &doublegit::doctor::Finding, indent: &str) {
+    match finding.fix {
+        None => println!(
+            "{}ok      {}: {}",
+            indent, finding.check, finding.message,
+        ),
+        Some(ref fix) => {
+            println!(
+                "{}PROBLEM {}: {}",
+                indent, finding.check, finding.message,
+            );
+            println!("{}        Fix: {}", indent, fix);
+        }
+    }
+}
+
+/// Print the health of a mirror, for the `status` command
+fn print_status(status: &doublegit::archive::MirrorStatus, indent: &str) {
+    println!(
+        "{}Last successful update: {}",
+        indent,
+        status.last_success.as_ref().map_or("never", String::as_str),
+    );
+    if let Some((date, error)) = &status.last_error {
+        println!("{}Last error: {} {}", indent, date, error);
+    }
+    println!(
+        "{}Refs: {} live, {} deleted",
+        indent, status.live_refs, status.dead_refs,
+    );
+    for problem in &status.config_problems {
+        println!("{}Config problem: {}", indent, problem);
+    }
+    if status.journal_pending {
+        println!(
+            "{}Refs don't match the database, the next update resets them",
+            indent,
+        );
+    }
+    if let Some((date, error)) = &status.gone {
+        println!("{}Upstream deleted since {}: {}", indent, date, error);
+    }
+    let verify = &status.verify;
+    if !verify.is_ok() {
+        println!(
+            "{}Inconsistent with the database: {} missing objects, {} \
+             unprotected, {} mismatched keep-refs, {} superfluous \
+             (see `doublegit verify`)",
+            indent,
+            verify.missing.len(),
+            verify.unprotected.len(),
+            verify.mismatched.len(),
+            verify.superfluous.len(),
+        );
+    }
+    if status.is_failing() {
+        println!("{}Status: failing", indent);
+    } else if status.is_ok() {
+        println!("{}Status: ok", indent);
+    } else {
+        println!("{}Status: needs attention", indent);
+    }
+}
+
+/// Add the completion of repository arguments to a completion script
+///
+/// The mirrors are listed by `doublegit mirrors` when completing, so that new
+/// ones show up without generating the script again.
+fn complete_mirrors(
+    script: String,
+    shell: clap::Shell,
+    directory: &str,
+) -> String {
+    let list = format!(
+        "doublegit mirrors '{}' 2>/dev/null",
+        directory.replace('\'', "'\\''"),
+    );
+    match shell {
+        clap::Shell::Bash => {
+            // Try the mirrors first, falling back to the generated function
+            script + &format!(
+                r#"
+_doublegit_mirrors() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [ "$COMP_CWORD" -ge 2 ] && [ "${{cur#-}}" = "$cur" ]; then
+        COMPREPLY=($(compgen -W "$({})" -- "$cur"))
+        if [ "${{#COMPREPLY[@]}}" -gt 0 ]; then
+            return 0
+        fi
+    fi
+    _doublegit "$@"
+}}
+
+complete -F _doublegit_mirrors -o bashdefault -o default doublegit
+"#,
+                list,
+            )
+        }
+        clap::Shell::Zsh => {
+            // Offer the mirrors for repository arguments, and define the
+            // function before the generated one gets called
+            let function = format!(
+                r#"_doublegit_mirrors() {{
+    local -a mirrors
+    mirrors=(${{(f)"$({})"}})
+    _alternative 'mirrors:mirror:compadd -a mirrors' 'files:file:_files'
+}}
+
+"#,
+                list,
+            );
+            let script: String = script
+                .lines()
+                .map(|line| {
+                    let argument = line
+                        .trim_start_matches('*')
+                        .trim_start_matches('\'')
+                        .trim_start_matches(':');
+                    if argument.starts_with("repository -- ") {
+                        line.replace(":_files'", ":_doublegit_mirrors'")
+                    } else {
+                        line.to_owned()
+                    }
+                })
+                .fold(String::new(), |script, line| script + &line + "\n");
+            match script.rfind("_doublegit \"$@\"") {
+                Some(pos) => {
+                    format!("{}{}{}", &script[..pos], function, &script[pos..])
+                }
+                None => script + &function,
+            }
+        }
+        _ => script + &format!(
+            "complete -c doublegit -n \"not __fish_use_subcommand\" \
+             -a \"({})\"\n",
+            list,
+        ),
+    }
+}
+
+/// Ask a question on the terminal, returning the answer or the default
+fn ask(question: &str, default: &str) -> std::io::Result<String> {
+    {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        if default.is_empty() {
+            write!(stdout, "{}: ", question)?;
+        } else {
+            write!(stdout, "{} [{}]: ", question, default)?;
+        }
+        stdout.flush()?;
+    }
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "no answer",
+        ));
+    }
+    let answer = answer.trim();
+    if answer.is_empty() {
+        Ok(default.to_owned())
+    } else {
+        Ok(answer.to_owned())
+    }
+}
+
+/// Ask a question until the answer is one of the choices
+fn choose(
+    question: &str,
+    choices: &[&str],
+    default: &str,
+) -> std::io::Result<String> {
+    let question = format!("{} ({})", question, choices.join("/"));
+    loop {
+        let answer = ask(&question, default)?.to_lowercase();
+        if choices.contains(&answer.as_str()) {
+            return Ok(answer);
+        }
+        eprintln!("Please answer one of: {}", choices.join(", "));
+    }
+}
+
+/// Ask how to authenticate to the remote, for the `setup` command
+fn ask_credentials() -> std::io::Result<doublegit::setup::Credentials> {
+    use doublegit::setup::Credentials;
+
+    let kind = choose(
+        "Authentication",
+        &["none", "ssh-key", "token", "token-env", "token-command"],
+        "none",
+    )?;
+    let value = |question: &str| -> std::io::Result<String> {
+        loop {
+            let answer = ask(question, "")?;
+            if !answer.is_empty() {
+                return Ok(answer);
+            }
+        }
+    };
+    Ok(match kind.as_str() {
+        "ssh-key" => Credentials::SshKey(
+            value("Path of the SSH private key")?.into(),
+        ),
+        "token" => Credentials::Token(value("Access token")?),
+        "token-env" => Credentials::TokenEnv(
+            value("Environment variable holding the token")?,
+        ),
+        "token-command" => Credentials::TokenCommand(
+            value("Command printing the token")?,
+        ),
+        _ => Credentials::None,
+    })
+}
+
+/// Get a path argument, or the `core.root` setting if it was not given
+fn repository_or_root(
+    matches: &ArgMatches,
+    name: &str,
+    config: &doublegit::config::Config,
+) -> Result<PathBuf, doublegit::Error> {
+    match matches.value_of_os(name) {
+        Some(path) => Ok(path.into()),
+        None => config.root.clone().ok_or_else(|| {
+            doublegit::Error::Config(format!(
+                "no {} given, and no core.root in the global config",
+                name,
+            ))
+        }),
+    }
+}
@@ -0,0 +1,133 @@
+//! Reporting the progress of an update as it happens
+//!
+//! Fetching a large repository can take a while. Set
+//! `UpdateOptions::progress` to get called as Git receives objects and as
+//! the refs get recorded; `doublegit update` uses this to draw progress
+//! bars.
+
+use regex::Regex;
+use std::fmt;
+
+/// A line of progress from `git fetch`, e.g. `Receiving objects: 45%
+/// (450/1000), 1.20 MiB`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FetchProgress {
+    /// What Git is doing, e.g. `Receiving objects` or `Resolving deltas`
+    pub stage: String,
+    /// Whether this was reported by the server (`remote: ` lines)
+    pub remote: bool,
+    /// Number of items done so far
+    pub done: u64,
+    /// Number of items in total, if known
+    pub total: Option<u64>,
+    /// Number of bytes received so far, if reported for this stage
+    pub bytes: Option<u64>,
+}
+
+/// Callbacks for the progress of an update
+///
+/// They are called from the thread reading Git's output, so they should
+/// return quickly. The default implementations do nothing.
+pub trait Progress: Send + Sync {
+    /// `git fetch` made progress, for `origin` or for a fork
+    fn fetch(&self, remote: &str, progress: &FetchProgress) {
+        let _ = (remote, progress);
+    }
+
+    /// Some of the refs that changed were recorded in the database
+    fn refs(&self, done: usize, total: usize) {
+        let _ = (done, total);
+    }
+
+    /// The update of the repository is over, successfully or not
+    fn finish(&self) {}
+}
+
+impl fmt::Debug for dyn Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Parse a line of progress from `git fetch --progress`
+///
+/// Other lines, e.g. the refs that were updated, give `None`.
+pub fn parse_fetch_progress(line: &str) -> Option<FetchProgress> {
+    lazy_static! {
+        static ref _RE_PROGRESS: Regex = Regex::new(
+            r"^(remote: )?([A-Za-z][A-Za-z ]*): +(?:\d+% \((\d+)/(\d+)\)|(\d+))(?:, ([0-9.]+) (bytes|KiB|MiB|GiB))?"
+        ).unwrap();
+    }
+    let caps = _RE_PROGRESS.captures(line.trim())?;
+    let number = |i| caps.get(i).and_then(|m| m.as_str().parse().ok());
+    let done = match number(3) {
+        Some(done) => done,
+        None => number(5)?,
+    };
+    let bytes = caps.get(6).and_then(|size| {
+        let size: f64 = size.as_str().parse().ok()?;
+        let unit = match &caps[7] {
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            _ => 1,
+        };
+        Some((size * f64::from(unit)) as u64)
+    });
+    Some(FetchProgress {
+        stage: caps[2].to_owned(),
+        remote: caps.get(1).is_some(),
+        done,
+        total: number(4),
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FetchProgress, parse_fetch_progress};
+
+    #[test]
+    fn test_parse_fetch_progress() {
+        assert_eq!(
+            parse_fetch_progress("remote: Enumerating objects: 12, done."),
+            Some(FetchProgress {
+                stage: "Enumerating objects".into(),
+                remote: true,
+                done: 12,
+                total: None,
+                bytes: None,
+            }),
+        );
+        assert_eq!(
+            parse_fetch_progress(
+                "Receiving objects:  45% (450/1000), 1.50 MiB | 2.00 MiB/s",
+            ),
+            Some(FetchProgress {
+                stage: "Receiving objects".into(),
+                remote: false,
+                done: 450,
+                total: Some(1000),
+                bytes: Some(1_572_864),
+            }),
+        );
+        assert_eq!(
+            parse_fetch_progress("Resolving deltas: 100% (3/3), done."),
+            Some(FetchProgress {
+                stage: "Resolving deltas".into(),
+                remote: false,
+                done: 3,
+                total: Some(3),
+                bytes: None,
+            }),
+        );
+        assert_eq!(
+            parse_fetch_progress("remote: Total 3 (delta 0), reused 0"),
+            None,
+        );
+        assert_eq!(
+            parse_fetch_progress(" + 1234567...89abcde master -> master"),
+            None,
+        );
+    }
+}
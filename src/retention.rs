@@ -0,0 +1,296 @@
+//! Expiring old history, according to the mirror's retention policy
+//!
+//! The policy is the `doublegit.retention` setting, a duration such as `90d`
+//! or `2y`. Intervals of the refs that ended before that are removed from the
+//! database, and their objects are no longer kept, unless a note or a legal
+//! hold still refers to them.
+
+use rusqlite::types::ToSql;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::archive::{
+    delete_rows, gc_locked, release_objects, unreferenced_objects,
+};
+use crate::{Error, audit, db, git, keep_namespaces, lock};
+
+/// Longest retention accepted, 1000 years
+const MAX_RETENTION_DAYS: i64 = 1000 * 365;
+
+/// Parse a retention duration, a number followed by `d`, `w`, `m` or `y`
+///
+/// Months count as 30 days and years as 365 days, and it can't be more than
+/// 1000 years.
+pub fn parse_retention(value: &str) -> Result<chrono::Duration, Error> {
+    let value = value.trim();
+    let invalid = || {
+        Error::Config(format!("invalid retention duration {:?}", value))
+    };
+    if value.len() < 2 {
+        return Err(invalid());
+    }
+    let (number, unit) = value.split_at(value.len() - 1);
+    let number: i64 = number.parse().map_err(|_| invalid())?;
+    let days = match unit {
+        "d" => 1,
+        "w" => 7,
+        "m" => 30,
+        "y" => 365,
+        _ => return Err(invalid()),
+    };
+    match number.checked_mul(days) {
+        Some(days) if days > 0 && days <= MAX_RETENTION_DAYS => {
+            Ok(chrono::Duration::days(days))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Options controlling how history gets expired
+#[derive(Debug, Clone, Default)]
+pub struct ExpireOptions {
+    /// Only report what would be expired
+    pub dry_run: bool,
+    /// Run a garbage collection afterwards, actually removing the objects
+    pub repack: bool,
+}
+
+/// Outcome of expiring history
+#[derive(Debug)]
+pub struct ExpireReport {
+    /// Date before which history gets expired
+    pub cutoff: String,
+    /// Number of intervals of refs and forks' refs that were removed
+    pub intervals: usize,
+    /// Number of intervals older than the cutoff kept because of a note or a
+    /// legal hold
+    pub held: usize,
+    /// Number of objects that are no longer kept
+    pub objects: usize,
+}
+
+/// Intervals of refs that ended before the cutoff and can be removed
+const EXPIRED_REFS: &str = "
+    SELECT rowid, sha FROM refs r
+    WHERE to_date IS NOT NULL AND to_date <= ?1
+        AND NOT EXISTS (
+            SELECT 1 FROM annotations a
+            WHERE a.ref_id = r.rowid
+                OR (a.snapshot >= r.from_date AND a.snapshot < r.to_date)
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM legal_holds h
+            WHERE (h.from_date IS NULL OR h.from_date < r.to_date)
+                AND (h.to_date IS NULL OR h.to_date > r.from_date)
+        );
+";
+
+/// Intervals of forks' refs that ended before the cutoff and can be removed
+const EXPIRED_FORK_REFS: &str = "
+    SELECT rowid, sha FROM fork_refs r
+    WHERE to_date IS NOT NULL AND to_date <= ?1
+        AND NOT EXISTS (
+            SELECT 1 FROM legal_holds h
+            WHERE (h.from_date IS NULL OR h.from_date < r.to_date)
+                AND (h.to_date IS NULL OR h.to_date > r.from_date)
+        );
+";
+
+/// Expire history according to the mirror's retention policy
+pub fn expire(
+    repository: &Path,
+    options: &ExpireOptions,
+) -> Result<ExpireReport, Error> {
+    expire_with_date(repository, options, SystemTime::now())
+}
+
+/// Expire history according to the retention policy, providing the current
+/// date
+pub fn expire_with_date<Date>(
+    repository: &Path,
+    options: &ExpireOptions,
+    date: Date,
+) -> Result<ExpireReport, Error>
+where
+    Date: Into<chrono::DateTime<chrono::Utc>>,
+{
+    let config = git::mirror_config(repository)?;
+    let retention = match config.get("retention") {
+        Some(value) => parse_retention(value)?,
+        None => {
+            return Err(Error::Config(
+                "no retention policy, set doublegit.retention".into(),
+            ));
+        }
+    };
+    let date = date.into();
+    let cutoff = date
+        .checked_sub_signed(retention)
+        .ok_or_else(|| {
+            Error::Config("retention goes back too far".into())
+        })?
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+
+    let _lock = lock(repository)?;
+    let mut db = db::open(repository)?;
+    let keep = keep_namespaces(repository, &db)?;
+
+    let tx = db::begin(&mut db)?;
+    let select = |sql: &str| -> Result<Vec<(i64, String)>, Error> {
+        let mut stmt = tx.prepare(sql)?;
+        let mut rows = Vec::new();
+        let map = |row: &rusqlite::Row| (row.get(0), row.get(1));
+        for row in stmt.query_map(&[&cutoff], map)? {
+            rows.push(row?);
+        }
+        Ok(rows)
+    };
+    let refs = select(EXPIRED_REFS)?;
+    let fork_refs = select(EXPIRED_FORK_REFS)?;
+    let ended: i64 = tx.query_row(
+        "
+        SELECT
+            (SELECT count(*) FROM refs
+             WHERE to_date IS NOT NULL AND to_date <= ?1)
+            + (SELECT count(*) FROM fork_refs
+               WHERE to_date IS NOT NULL AND to_date <= ?1);
+        ",
+        &[&cutoff],
+        |row| row.get(0),
+    )?;
+    let intervals = refs.len() + fork_refs.len();
+    let held = ended as usize - intervals;
+
+    // Remove the intervals, and what refers to them
+    delete_rows(
+        &tx,
+        &refs.iter().map(|(rowid, _)| *rowid).collect::<Vec<_>>(),
+        &fork_refs.iter().map(|(rowid, _)| *rowid).collect::<Vec<_>>(),
+    )?;
+
+    // Find the objects that are not referenced anymore
+    let objects = unreferenced_objects(
+        &tx,
+        refs.iter().chain(fork_refs.iter()).map(|(_, sha)| sha),
+    )?;
+
+    let report = ExpireReport {
+        cutoff,
+        intervals,
+        held,
+        objects: objects.len(),
+    };
+    if options.dry_run {
+        return Ok(report);
+    }
+    let date = date.format("%Y-%m-%d %H:%M:%S").to_string();
+    audit::record(&tx, audit::EXPIRE, &date)?;
+    tx.commit()?;
+    info!(
+        "Expired {} intervals from before {}, {} objects no longer kept",
+        report.intervals, report.cutoff, report.objects,
+    );
+
+    // Drop their keep-refs
+    release_objects(repository, &db, &keep, &objects)?;
+
+    if options.repack {
+        gc_locked(repository, true)?;
+    }
+
+    Ok(report)
+}
+
+/// A period of history that must not be expired
+#[derive(Debug)]
+pub struct Hold {
+    /// Identifier, to release it
+    pub id: i64,
+    /// When the hold was placed
+    pub created: String,
+    /// Start of the period, or `None` for the beginning of the archive
+    pub from_date: Option<String>,
+    /// End of the period, or `None` for no end
+    pub to_date: Option<String>,
+    /// Why history is kept
+    pub reason: String,
+}
+
+/// Place a legal hold, preventing the expiration of a period of history
+///
+/// Returns the identifier of the hold.
+pub fn hold(
+    repository: &Path,
+    from_date: Option<&str>,
+    to_date: Option<&str>,
+    reason: &str,
+) -> Result<i64, Error> {
+    let db = db::open(repository)?;
+    let created = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    db.execute(
+        "
+        INSERT INTO legal_holds(created, from_date, to_date, reason)
+        VALUES(?, ?, ?, ?);
+        ",
+        &[&created as &dyn ToSql, &from_date, &to_date, &reason],
+    )?;
+    Ok(db.last_insert_rowid())
+}
+
+/// Get all the legal holds, oldest first
+pub fn holds(repository: &Path) -> Result<Vec<Hold>, Error> {
+    let db = db::open(repository)?;
+    let mut stmt = db.prepare(
+        "
+        SELECT rowid, created, from_date, to_date, reason
+        FROM legal_holds
+        ORDER BY created, rowid;
+        ",
+    )?;
+    let rows = stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| Hold {
+            id: row.get(0),
+            created: row.get(1),
+            from_date: row.get(2),
+            to_date: row.get(3),
+            reason: row.get(4),
+        },
+    )?;
+    let mut holds = Vec::new();
+    for row in rows {
+        holds.push(row?);
+    }
+    Ok(holds)
+}
+
+/// Remove a legal hold
+pub fn release(repository: &Path, id: i64) -> Result<(), Error> {
+    let db = db::open(repository)?;
+    let count = db.execute("DELETE FROM legal_holds WHERE rowid=?;", &[&id])?;
+    if count == 0 {
+        return Err(Error::NotFound(format!("legal hold {}", id)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_retention;
+
+    #[test]
+    fn test_parse_retention() {
+        assert_eq!(parse_retention("90d").unwrap().num_days(), 90);
+        assert_eq!(parse_retention("2w").unwrap().num_days(), 14);
+        assert_eq!(parse_retention("6m").unwrap().num_days(), 180);
+        assert_eq!(parse_retention(" 2y ").unwrap().num_days(), 730);
+        assert!(parse_retention("y").is_err());
+        assert!(parse_retention("0d").is_err());
+        assert!(parse_retention("-1y").is_err());
+        assert!(parse_retention("3 years").is_err());
+        assert_eq!(parse_retention("1000y").unwrap().num_days(), 365000);
+        assert!(parse_retention("1001y").is_err());
+        assert!(parse_retention("99999999999999y").is_err());
+    }
+}
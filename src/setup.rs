@@ -0,0 +1,233 @@
+//! Setting up a mirror from the answers to a few questions
+//!
+//! `doublegit setup` asks them on the terminal; this writes the resulting
+//! settings to the mirror's Git config, the same as setting them by hand.
+
+use std::path::{Path, PathBuf};
+
+use crate::{Error, fork_names, git, init};
+
+/// Name of the fork the wiki gets archived as
+pub const WIKI_FORK: &str = "wiki";
+
+/// Where a repository is hosted, which decides some defaults
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    GitHub,
+    GitLab,
+    Other,
+}
+
+impl Platform {
+    /// Guess the platform from the URL of the repository
+    pub fn detect(url: &str) -> Platform {
+        let host = url_host(url).to_lowercase();
+        if host == "github.com" {
+            Platform::GitHub
+        } else if host == "gitlab.com" || host.starts_with("gitlab.") {
+            Platform::GitLab
+        } else {
+            Platform::Other
+        }
+    }
+
+    /// Parse the name of a platform, as given by the user
+    pub fn parse(name: &str) -> Option<Platform> {
+        match name.trim().to_lowercase().as_str() {
+            "github" => Some(Platform::GitHub),
+            "gitlab" => Some(Platform::GitLab),
+            "other" => Some(Platform::Other),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::GitHub => "github",
+            Platform::GitLab => "gitlab",
+            Platform::Other => "other",
+        }
+    }
+
+    /// URL of the wiki of a repository, which is a separate Git repository
+    pub fn wiki_url(self, url: &str) -> Option<String> {
+        if self == Platform::Other {
+            return None;
+        }
+        let url = url.trim_end_matches('/');
+        let url = if url.ends_with(".git") {
+            &url[..url.len() - 4]
+        } else {
+            url
+        };
+        Some(format!("{}.wiki.git", url))
+    }
+
+    /// User name to send along with an access token, if not the default
+    pub fn token_user(self) -> Option<&'static str> {
+        match self {
+            Platform::GitLab => Some("oauth2"),
+            _ => None,
+        }
+    }
+}
+
+/// Get the host name from a URL, or an empty string for a local path
+///
+/// This handles both `scheme://[user@]host[:port]/path` and the scp-like
+/// syntax `[user@]host:path`.
+fn url_host(url: &str) -> &str {
+    let (authority, has_port) = match url.find("://") {
+        Some(pos) => {
+            let rest = &url[pos + 3..];
+            (rest.split('/').next().unwrap_or(""), true)
+        }
+        None => match url.find(':') {
+            Some(pos) if !url[..pos].contains('/') => (&url[..pos], false),
+            _ => return "",
+        },
+    };
+    let host = match authority.rfind('@') {
+        Some(pos) => &authority[pos + 1..],
+        None => authority,
+    };
+    if has_port {
+        host.split(':').next().unwrap_or("")
+    } else {
+        host
+    }
+}
+
+/// How to authenticate to the remote
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    /// Nothing, or whatever the user's SSH agent provides
+    None,
+    /// Private key to use over SSH (`doublegit.sshKey`)
+    SshKey(PathBuf),
+    /// Access token stored in the config (`doublegit.token`)
+    Token(String),
+    /// Environment variable holding the token (`doublegit.tokenEnv`)
+    TokenEnv(String),
+    /// Shell command printing the token (`doublegit.tokenCommand`)
+    TokenCommand(String),
+}
+
+/// Answers to the questions of `doublegit setup`
+#[derive(Debug, Clone)]
+pub struct SetupOptions {
+    pub platform: Platform,
+    pub credentials: Credentials,
+    /// Also archive the wiki of the repository, as a fork
+    pub wiki: bool,
+}
+
+/// Get the URL an existing mirror fetches from
+pub fn origin_url(repository: &Path) -> Result<String, Error> {
+    git::remote_url(repository)
+}
+
+/// Create a mirror if `url` is given, and write the settings to its config
+pub fn setup(
+    url: Option<&str>,
+    repository: &Path,
+    options: &SetupOptions,
+) -> Result<(), Error> {
+    let url = match url {
+        Some(url) => {
+            init(url, repository)?;
+            url.to_owned()
+        }
+        None => git::remote_url(repository)?,
+    };
+
+    let token = match options.credentials {
+        Credentials::None => false,
+        Credentials::SshKey(ref path) => {
+            let path = path.to_str().ok_or_else(|| {
+                Error::Config("non-utf8 SSH key path".into())
+            })?;
+            git::set_config(repository, "doublegit.sshKey", path)?;
+            false
+        }
+        Credentials::Token(ref token) => {
+            git::set_config(repository, "doublegit.token", token)?;
+            true
+        }
+        Credentials::TokenEnv(ref var) => {
+            git::set_config(repository, "doublegit.tokenEnv", var)?;
+            true
+        }
+        Credentials::TokenCommand(ref command) => {
+            git::set_config(repository, "doublegit.tokenCommand", command)?;
+            true
+        }
+    };
+    if token {
+        if let Some(user) = options.platform.token_user() {
+            git::set_config(repository, "doublegit.tokenUser", user)?;
+        }
+    }
+
+    if options.wiki {
+        let wiki = options.platform.wiki_url(&url).ok_or_else(|| {
+            Error::Config(format!(
+                "don't know where the wiki is on platform {}",
+                options.platform.name(),
+            ))
+        })?;
+        git::set_config(
+            repository,
+            &format!("remote.{}.url", WIKI_FORK),
+            &wiki,
+        )?;
+        let config = git::mirror_config(repository)?;
+        let mut forks = fork_names(&config)?;
+        if !forks.contains(&WIKI_FORK) {
+            forks.push(WIKI_FORK);
+            git::set_config(repository, "doublegit.forks", &forks.join(" "))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Platform, url_host};
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(url_host("https://github.com/me/repo.git"), "github.com");
+        assert_eq!(url_host("ssh://git@example.org:2222/repo"), "example.org");
+        assert_eq!(url_host("https://me:pw@example.org/repo"), "example.org");
+        assert_eq!(url_host("git@gitlab.com:me/repo.git"), "gitlab.com");
+        assert_eq!(url_host("/srv/git/repo.git"), "");
+        assert_eq!(url_host("./a:b/repo"), "");
+    }
+
+    #[test]
+    fn test_platform() {
+        assert_eq!(
+            Platform::detect("https://GitHub.com/me/repo"),
+            Platform::GitHub,
+        );
+        assert_eq!(
+            Platform::detect("git@gitlab.example.org:me/repo.git"),
+            Platform::GitLab,
+        );
+        assert_eq!(
+            Platform::detect("https://example.org/repo.git"),
+            Platform::Other,
+        );
+        assert_eq!(
+            Platform::GitHub.wiki_url("https://github.com/me/repo.git/"),
+            Some("https://github.com/me/repo.wiki.git".into()),
+        );
+        assert_eq!(
+            Platform::GitLab.wiki_url("git@gitlab.com:me/repo"),
+            Some("git@gitlab.com:me/repo.wiki.git".into()),
+        );
+        assert_eq!(Platform::Other.wiki_url("/srv/repo.git"), None);
+    }
+}
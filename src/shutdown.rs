@@ -0,0 +1,63 @@
+//! Stopping cleanly on SIGINT and SIGTERM
+//!
+//! Once `install()` was called, those signals don't kill the process, they
+//! only set a flag. Updates that were not started yet are skipped, a running
+//! `git fetch` is stopped and its refs are rolled back, and the daemon and
+//! the web server return, so that locks and transactions are released
+//! normally. A second signal kills the process right away.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+lazy_static! {
+    static ref REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+/// How often waiting threads check whether a shutdown was requested, in
+/// milliseconds
+const POLL_INTERVAL: u64 = 100;
+
+/// Handle SIGINT and SIGTERM by requesting a shutdown
+pub fn install() -> Result<(), Error> {
+    for &signal in &[signal_hook::SIGINT, signal_hook::SIGTERM] {
+        signal_hook::flag::register(signal, REQUESTED.clone())?;
+        // Restore the default action, so that a second signal kills us
+        signal_hook::cleanup::register(
+            signal,
+            vec![signal_hook::SIGINT, signal_hook::SIGTERM],
+        )?;
+    }
+    Ok(())
+}
+
+/// Ask everything to stop, as if a signal was received
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether a shutdown was requested
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Sleep, unless a shutdown is requested
+///
+/// Returns `false` if the sleep was cut short.
+pub fn sleep(duration: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        if requested() {
+            return false;
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= duration {
+            return true;
+        }
+        std::thread::sleep(
+            (duration - elapsed).min(Duration::from_millis(POLL_INTERVAL)),
+        );
+    }
+}
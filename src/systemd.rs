@@ -0,0 +1,122 @@
+//! Integration with systemd: readiness, watchdog, and socket activation
+//!
+//! This implements the protocols of sd_notify(3) and sd_listen_fds(3)
+//! directly, without linking to libsystemd. When not started by systemd, the
+//! environment variables are not set and all of this does nothing.
+
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Tell systemd about a change of state, e.g. `READY=1` or `STATUS=...`
+///
+/// Returns whether the message was sent, i.e. whether the service is of
+/// `Type=notify`.
+pub fn notify(state: &str) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => path,
+            None => return false,
+        };
+        // Abstract socket addresses, starting with '@', are not supported;
+        // systemd itself uses /run/systemd/notify
+        let result = UnixDatagram::unbound()
+            .and_then(|socket| socket.send_to(state.as_bytes(), &path));
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Can't notify systemd at {:?}: {}", path, e);
+                false
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = state;
+        false
+    }
+}
+
+/// Get the time after which systemd considers the service hung, if the
+/// watchdog is enabled (`WatchdogSec=`)
+pub fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        None
+    } else {
+        Some(Duration::from_micros(usec))
+    }
+}
+
+/// Keep the watchdog happy from a background thread, if it is enabled
+///
+/// Pings are sent at half the timeout. Calling this again does nothing.
+pub fn start_watchdog() {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    let timeout = match watchdog_timeout() {
+        Some(timeout) => timeout,
+        None => return,
+    };
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    info!("Pinging the systemd watchdog every {:?}", timeout / 2);
+    std::thread::spawn(move || loop {
+        notify("WATCHDOG=1");
+        std::thread::sleep(timeout / 2);
+    });
+}
+
+/// Take the sockets systemd opened for us, with socket activation
+///
+/// The environment variables are removed, so that processes we start don't
+/// think the sockets are meant for them.
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<std::os::unix::io::RawFd> {
+    /// First file descriptor passed, after stdin, stdout, and stderr
+    const LISTEN_FDS_START: i32 = 3;
+
+    let pid: Option<u32> =
+        env::var("LISTEN_PID").ok().and_then(|p| p.parse().ok());
+    let count: Option<i32> =
+        env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    match (pid, count) {
+        (Some(pid), Some(count)) if pid == process::id() && count > 0 => {
+            (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::listen_fds;
+
+    #[test]
+    fn test_listen_fds() {
+        // Not meant for this process
+        std::env::set_var("LISTEN_PID", "1");
+        std::env::set_var("LISTEN_FDS", "2");
+        assert!(listen_fds().is_empty());
+        assert!(std::env::var_os("LISTEN_FDS").is_none());
+
+        std::env::set_var("LISTEN_PID", process::id().to_string());
+        std::env::set_var("LISTEN_FDS", "2");
+        assert_eq!(listen_fds(), vec![3, 4]);
+        assert!(listen_fds().is_empty());
+    }
+}
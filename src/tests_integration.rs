@@ -4,8 +4,9 @@ use std::collections::HashSet;
 use std::fs;
 use std::io::Write;
 use std::ops::Not;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 
 use crate::git::get_sha;
 
@@ -104,7 +105,7 @@ fn test_update() {
     write("one");
     commit(0, "one");
     let hash_one = "ae79568054d9fa2e4956968310655e9bcbd60e2f";
-    crate::update_with_date(&mirror, time(1)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
     assert!(mirror.join("gitarchive.sqlite3").exists());
     check_db(
         &mirror,
@@ -122,7 +123,7 @@ fn test_update() {
     write("two");
     commit(2, "two");
     let hash_two = "8dcda34bbae83d2e3d856cc5dbc356ee6e947619";
-    crate::update_with_date(&mirror, time(3)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
     check_db(
         &mirror,
         &[
@@ -141,7 +142,7 @@ fn test_update() {
         .args(&["reset", "--keep", hash_one])
         .current_dir(&origin)
         .status().unwrap().success());
-    crate::update_with_date(&mirror, time(4)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(4)).unwrap();
     check_db(
         &mirror,
         &[
@@ -155,6 +156,19 @@ fn test_update() {
         &mirror,
         &[hash_two],
     );
+    check_rewrites(
+        &mirror,
+        &[
+            ("br1", 4, hash_two, true),
+        ],
+    );
+    check_ref_changes(
+        &mirror,
+        &[
+            ("br1", 3, 1, 0),
+            ("br1", 4, 0, 1),
+        ],
+    );
 
     // Delete branch br1, create br2
     assert!(process::Command::new("git")
@@ -168,7 +182,7 @@ fn test_update() {
     write("three");
     commit(5, "three");
     let hash_three = "54356c0e8c1cb663294d64157f517f980e5fbd98";
-    crate::update_with_date(&mirror, time(6)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(6)).unwrap();
     check_db(
         &mirror,
         &[
@@ -193,7 +207,7 @@ fn test_update() {
         .arg(hash_one)
         .current_dir(&origin)
         .status().unwrap().success());
-    crate::update_with_date(&mirror, time(7)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(7)).unwrap();
     check_db(
         &mirror,
         &[
@@ -217,7 +231,7 @@ fn test_update() {
         .envs(env(8))
         .status().unwrap().success());
     let hash_tag2_1 = "8fda1c0cfb4957e376fba4b53bf3ce080e25300c";
-    crate::update_with_date(&mirror, time(8)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(8)).unwrap();
     check_db(
         &mirror,
         &[
@@ -250,7 +264,7 @@ fn test_update() {
         .envs(env(9))
         .status().unwrap().success());
     let hash_tag2_2 = "a64697beb90c35d198fd25f2985cbc9e1ac1783e";
-    crate::update_with_date(&mirror, time(9)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(9)).unwrap();
     check_db(
         &mirror,
         &[
@@ -274,7 +288,7 @@ fn test_update() {
         .args(&["tag", "-d", "tag1", "tag2"])
         .current_dir(&origin)
         .status().unwrap().success());
-    crate::update_with_date(&mirror, time(10)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(10)).unwrap();
     check_db(
         &mirror,
         &[
@@ -315,6 +329,579 @@ fn test_update() {
             .map(|h| format!("tag-{}", h))
             .collect(),
     );
+
+    // Check the fetch statistics
+    let conn = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    let (fetches, new, changed, removed): (i64, i64, i64, i64) = conn
+        .query_row(
+            "
+            SELECT count(*), sum(new_refs), sum(changed_refs),
+                sum(removed_refs)
+            FROM fetches;
+            ",
+            rusqlite::NO_PARAMS,
+            |row| (row.get(0), row.get(1), row.get(2), row.get(3)),
+        )
+        .unwrap();
+    assert_eq!((fetches, new, changed, removed), (8, 4, 4, 3));
+
+    // Export a past snapshot as a bundle
+    let bundle = test_dir.path().join("snapshot.bundle");
+    crate::export::export_bundle(&mirror, Some(&timestr(8)), &bundle)
+        .unwrap();
+    let output = process::Command::new("git")
+        .args(&["bundle", "list-heads"])
+        .arg(&bundle)
+        .current_dir(&mirror)
+        .output().unwrap();
+    assert!(output.status.success());
+    let heads: HashSet<String> = std::str::from_utf8(&output.stdout)
+        .unwrap()
+        .lines()
+        .map(Into::into)
+        .collect();
+    assert_eq!(
+        heads,
+        [
+            format!("{} refs/heads/br2", hash_three),
+            format!("{} refs/tags/tag1", hash_one),
+            format!("{} refs/tags/tag2", hash_tag2_1),
+        ].iter().cloned().collect(),
+    );
+
+    // Restore the deleted branch
+    assert_eq!(
+        crate::archive::restore(&mirror, "br1", None, false).unwrap(),
+        hash_one,
+    );
+    assert_eq!(get_sha(&mirror, "refs/heads/br1").unwrap(), hash_one);
+    assert_eq!(
+        crate::archive::restore(&mirror, "br1", Some(&timestr(3)), false)
+            .unwrap(),
+        hash_two,
+    );
+    assert_eq!(get_sha(&mirror, "refs/heads/br1").unwrap(), hash_two);
+    assert!(crate::archive::restore(&mirror, "br3", None, false).is_err());
+
+    // Garbage-collect after removing a keep-ref, it should come back
+    assert!(process::Command::new("git")
+        .args(&["update-ref", "-d"])
+        .arg(format!("refs/kept-tags/tag-{}", hash_tag2_2))
+        .current_dir(&mirror)
+        .status().unwrap().success());
+    let report = crate::archive::gc(&mirror, true).unwrap();
+    assert_eq!((report.checked, report.protected), (7, 1));
+    assert_eq!(
+        get_sha(&mirror, format!("refs/kept-tags/tag-{}", hash_tag2_2))
+            .unwrap(),
+        hash_tag2_2,
+    );
+
+    // Verify the archive, break it, and repair it
+    assert!(crate::archive::verify(&mirror, false).unwrap().is_ok());
+    assert!(process::Command::new("git")
+        .args(&["branch", "-D"])
+        .arg(format!("keep-{}", hash_three))
+        .current_dir(&mirror)
+        .status().unwrap().success());
+    assert!(process::Command::new("git")
+        .args(&["branch", "-f"])
+        .arg(format!("keep-{}", hash_one))
+        .arg(hash_two)
+        .current_dir(&mirror)
+        .status().unwrap().success());
+    let report = crate::archive::verify(&mirror, true).unwrap();
+    assert!(report.missing.is_empty());
+    assert_eq!(report.unprotected, vec![hash_three.to_owned()]);
+    assert_eq!(
+        report.mismatched,
+        vec![format!("refs/heads/keep-{}", hash_one)],
+    );
+    assert!(report.superfluous.is_empty());
+    assert!(crate::archive::verify(&mirror, false).unwrap().is_ok());
+    assert!(process::Command::new("git")
+        .args(&["branch", "-D", "br1"])
+        .current_dir(&mirror)
+        .status().unwrap().success());
+    check_refs(
+        &mirror,
+        &[
+            hash_two,
+            hash_three,
+        ],
+    );
+
+    // Consolidate after adding a superfluous keep-branch
+    assert!(process::Command::new("git")
+        .args(&["branch", "-f"])
+        .arg(format!("keep-{}", hash_one))
+        .arg(hash_one)
+        .current_dir(&mirror)
+        .status().unwrap().success());
+    let report = crate::archive::consolidate(&mirror, true).unwrap();
+    assert_eq!(report.branches, 3);
+    assert_eq!(report.removed, vec![format!("refs/heads/keep-{}", hash_one)]);
+    check_refs(
+        &mirror,
+        &[
+            hash_two,
+            hash_three,
+        ],
+    );
+    assert!(fs::read_to_string(mirror.join("packed-refs")).unwrap()
+        .contains(&format!("refs/heads/keep-{}", hash_two)));
+
+    // Move the keep-refs to other namespaces
+    for &(key, prefix) in &[
+        ("doublegit.keepBranchPrefix", "refs/keep/commit-"),
+        ("doublegit.keepTagPrefix", "refs/keep/tag-"),
+    ] {
+        assert!(process::Command::new("git")
+            .args(&["config", key, prefix])
+            .current_dir(&mirror)
+            .status().unwrap().success());
+    }
+    assert!(crate::archive::verify(&mirror, false).unwrap().is_ok());
+    check_refs(&mirror, &[]);
+    assert_eq!(
+        get_sha(&mirror, format!("refs/keep/commit-{}", hash_three))
+            .unwrap(),
+        hash_three,
+    );
+    assert_eq!(
+        get_sha(&mirror, format!("refs/keep/tag-{}", hash_tag2_2))
+            .unwrap(),
+        hash_tag2_2,
+    );
+}
+
+/// Run a Git command, with reproducible dates
+fn git(args: &[&str], dir: &Path) {
+    assert!(process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .envs(env(0))
+        .status().unwrap().success());
+}
+
+/// Set up an upstream with a single commit on master, and an empty mirror
+fn setup_mirror(dir: &Path) -> (PathBuf, PathBuf) {
+    let origin = dir.join("origin");
+    fs::create_dir(&origin).unwrap();
+    git(&["init"], &origin);
+    git(&["checkout", "-b", "master"], &origin);
+    git(&["commit", "--allow-empty", "-m", "one"], &origin);
+    let mirror = dir.join("mirror");
+    fs::create_dir(&mirror).unwrap();
+    git(&["init", "--bare"], &mirror);
+    git(&["remote", "add", "origin", "../origin"], &mirror);
+    (origin, mirror)
+}
+
+#[test]
+fn test_forks() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+
+    // Set up a fork with an extra branch
+    let fork = test_dir.path().join("fork");
+    git(&["clone", "origin", "fork"], test_dir.path());
+    git(&["checkout", "-b", "feature"], &fork);
+    git(&["commit", "--allow-empty", "-m", "two"], &fork);
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    let hash_two = get_sha(&fork, "feature").unwrap();
+    git(&["remote", "add", "alice", "../fork"], &mirror);
+    git(&["config", "doublegit.forks", "alice"], &mirror);
+
+    // Also use the protocol tuning, on both fetches
+    git(&["config", "doublegit.protocolVersion", "2"], &mirror);
+    git(&["config", "doublegit.negotiationAlgorithm", "skipping"], &mirror);
+    git(&["config", "doublegit.limitHaves", "true"], &mirror);
+
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    check_fork_refs(
+        &mirror,
+        "alice",
+        &[
+            ("feature", 1, None, &hash_two),
+            ("master", 1, None, &hash_one),
+        ],
+    );
+    check_refs(&mirror, &[&hash_two]);
+
+    // Delete the branch in the fork
+    git(&["checkout", "master"], &fork);
+    git(&["branch", "-D", "feature"], &fork);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    check_fork_refs(
+        &mirror,
+        "alice",
+        &[
+            ("feature", 1, Some(2), &hash_two),
+            ("master", 1, None, &hash_one),
+        ],
+    );
+    check_refs(&mirror, &[&hash_two]);
+    assert!(crate::archive::verify(&mirror, false).unwrap().is_ok());
+}
+
+#[test]
+fn test_failed_fetch() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Nothing listens there
+    git(
+        &["remote", "set-url", "origin", "http://127.0.0.1:1/repo.git"],
+        &mirror,
+    );
+    let options = crate::UpdateOptions {
+        retries: 0,
+        ..Default::default()
+    };
+    assert!(crate::update_with_date(&mirror, &options, time(2)).is_err());
+    assert!(mirror.join(crate::JOURNAL).exists().not());
+    assert!(!crate::archive::status(&mirror).unwrap().journal_pending);
+
+    // A journal of the refs as they are has nothing to roll back, so the
+    // commits wouldn't get indexed again
+    crate::write_journal(&mirror).unwrap();
+    assert!(!crate::replay_journal(&mirror).unwrap());
+    fs::remove_file(mirror.join(crate::JOURNAL)).unwrap();
+
+    // The next update works normally
+    git(&["remote", "set-url", "origin", "../origin"], &mirror);
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &options, time(3)).unwrap();
+    check_refs(&mirror, &[&hash_two]);
+}
+
+#[test]
+fn test_depth() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let shallow = crate::UpdateOptions {
+        depth: Some(1),
+        ..Default::default()
+    };
+
+    // A new mirror can be made shallow, and stays so
+    crate::update_with_date(&mirror, &shallow, time(1)).unwrap();
+    assert!(mirror.join("shallow").exists());
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    let hash_three = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &shallow, time(2)).unwrap();
+
+    // A mirror with full history can't
+    let full = test_dir.path().join("full");
+    fs::create_dir(&full).unwrap();
+    git(&["init", "--bare"], &full);
+    git(&["remote", "add", "origin", "../origin"], &full);
+    crate::update_with_date(&full, &Default::default(), time(1)).unwrap();
+    match crate::update_with_date(&full, &shallow, time(2)) {
+        Err(crate::Error::Config(_)) => {}
+        r => panic!("Unexpected result {:?}", r),
+    }
+    assert!(!full.join("shallow").exists());
+    assert_eq!(
+        get_sha(&full, "refs/remotes/origin/master").unwrap(),
+        hash_three,
+    );
+}
+
+#[test]
+fn test_gc_remote_refs() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Only the remote-tracking branch is left, that doesn't protect it
+    let keep_ref = format!("refs/heads/keep-{}", hash_one);
+    git(&["update-ref", "-d", &keep_ref], &mirror);
+    assert_eq!(
+        get_sha(&mirror, "refs/remotes/origin/master").unwrap(),
+        hash_one,
+    );
+    let report = crate::archive::gc(&mirror, false).unwrap();
+    assert_eq!((report.checked, report.protected), (1, 1));
+    check_refs(&mirror, &[&hash_one]);
+}
+
+#[test]
+fn test_committed_journal() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    crate::write_journal(&mirror).unwrap();
+    let journal = fs::read_to_string(mirror.join(crate::JOURNAL)).unwrap();
+    fs::remove_file(mirror.join(crate::JOURNAL)).unwrap();
+
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    // Pretend the update got killed after committing, but before removing
+    // its journal
+    let db = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    let token: String = db.query_row(
+        "SELECT value FROM settings WHERE name='journal';",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    ).unwrap();
+    let refs = journal.splitn(2, '\n').nth(1).unwrap();
+    fs::write(
+        mirror.join(crate::JOURNAL),
+        format!("# update {}\n{}", token, refs),
+    ).unwrap();
+    assert!(!crate::archive::status(&mirror).unwrap().journal_pending);
+
+    // The next update doesn't roll back and record the changes again
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+    check_db(
+        &mirror,
+        &[
+            ("master", 1, Some(2), &hash_one),
+            ("master", 2, None, &hash_two),
+        ],
+        false,
+    );
+    check_refs(&mirror, &[&hash_two]);
+    assert!(mirror.join(crate::JOURNAL).exists().not());
+    let updates = crate::archive::update_history(&mirror).unwrap();
+    assert_eq!(updates[2].counts, Some((0, 0, 0)));
+}
+
+#[test]
+fn test_interrupted_update() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    assert!(mirror.join(crate::JOURNAL).exists().not());
+
+    // Pretend an update got killed after fetching
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    git(&["checkout", "-b", "new"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::write_journal(&mirror).unwrap();
+    git(&["fetch", "origin"], &mirror);
+
+    // The next update records the changes nonetheless
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    check_db(
+        &mirror,
+        &[
+            ("master", 1, Some(2), &hash_one),
+            ("master", 2, None, &hash_two),
+            ("new", 2, None, &hash_two),
+        ],
+        false,
+    );
+    check_refs(&mirror, &[&hash_two]);
+    assert!(mirror.join(crate::JOURNAL).exists().not());
+
+    // Updates of a locked mirror fail
+    let mirrors = crate::find_mirrors(test_dir.path()).unwrap();
+    assert_eq!(mirrors, vec![mirror.clone()]);
+    {
+        let _lock = crate::lock(&mirror).unwrap();
+        match crate::update_many(&mirrors, &Default::default(), 2).pop() {
+            Some(Err(crate::Error::Locked(_))) => {}
+            r => panic!("Update of locked mirror returned {:?}", r),
+        }
+
+        // So does maintenance that moves refs or drops objects
+        let locked = |r: Result<(), crate::Error>| match r {
+            Err(crate::Error::Locked(_)) => {}
+            r => panic!("Maintenance of locked mirror returned {:?}", r),
+        };
+        locked(crate::archive::gc(&mirror, true).map(drop));
+        locked(crate::archive::verify(&mirror, true).map(drop));
+        locked(crate::archive::consolidate(&mirror, true).map(drop));
+        locked(crate::archive::merge(&mirror, &mirror).map(drop));
+        locked(
+            crate::archive::restore(&mirror, "master", None, false).map(drop),
+        );
+    }
+    let results = crate::update_many(&mirrors, &Default::default(), 2);
+    assert!(results.iter().all(Result::is_ok));
+}
+
+#[test]
+fn test_backup_remote() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    let backup = test_dir.path().join("backup");
+    fs::create_dir(&backup).unwrap();
+    git(&["init", "--bare"], &backup);
+
+    // The refs get pushed, including keep-refs
+    git(&["config", "doublegit.backupRemote", "../backup"], &mirror);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    check_refs(&backup, &[&hash_one]);
+    assert_eq!(
+        get_sha(&backup, "refs/remotes/origin/master").unwrap(),
+        hash_one,
+    );
+
+    // A push failure is recorded, but the update goes through
+    git(&["config", "doublegit.backupRemote", "../missing"], &mirror);
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    check_refs(&mirror, &[&hash_two]);
+    let conn = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT remote, error IS NULL FROM backups ORDER BY date;",
+    ).unwrap();
+    let backups: Vec<_> = stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| (row.get::<_, String>(0), row.get::<_, bool>(1)),
+    ).unwrap().map(Result::unwrap).collect();
+    assert_eq!(
+        backups,
+        vec![
+            ("../backup".to_owned(), true),
+            ("../missing".to_owned(), false),
+        ],
+    );
+}
+
+#[test]
+fn test_init() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, _) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+
+    let mirror = test_dir.path().join("new");
+    crate::init("../origin", &mirror).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    check_db(&mirror, &[("master", 1, None, &hash_one)], false);
+    check_refs(&mirror, &[&hash_one]);
+
+    // Existing directories are not overwritten
+    assert!(crate::init("../origin", &mirror).is_err());
+}
+
+#[test]
+fn test_upstream_gone() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // The deletion gets recorded, without retrying
+    fs::remove_dir_all(&origin).unwrap();
+    match crate::update_with_date(&mirror, &Default::default(), time(2)) {
+        Err(crate::Error::UpstreamGone(_)) => {}
+        r => panic!("Update of deleted upstream returned {:?}", r),
+    }
+    let db = crate::db::open(&mirror).unwrap();
+    let (date, _) = crate::upstream_gone(&db).unwrap().unwrap();
+    assert_eq!(date, timestr(2));
+
+    // Trying again doesn't record it again, and the daemon waits longer and
+    // longer before the next attempt
+    assert!(crate::daemon::is_due(&mirror, time(3)));
+    crate::update_with_date(&mirror, &Default::default(), time(3))
+        .unwrap_err();
+    assert!(crate::daemon::is_due(&mirror, time(4)));
+    crate::update_with_date(&mirror, &Default::default(), time(4))
+        .unwrap_err();
+    assert!(!crate::daemon::is_due(&mirror, time(5)));
+    assert!(crate::daemon::is_due(&mirror, time(6)));
+    let (date, _) = crate::upstream_gone(&db).unwrap().unwrap();
+    assert_eq!(date, timestr(2));
+    let tombstones: i64 = db.query_row(
+        "SELECT count(*) FROM tombstones;",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    ).unwrap();
+    assert_eq!(tombstones, 1);
+
+    // It's forgotten if the upstream comes back
+    fs::create_dir(test_dir.path().join("new")).unwrap();
+    setup_mirror(&test_dir.path().join("new"));
+    fs::rename(test_dir.path().join("new/origin"), &origin).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(6)).unwrap();
+    assert!(crate::upstream_gone(&db).unwrap().is_none());
+    assert!(crate::daemon::is_due(&mirror, time(6)));
+
+    // All the runs were recorded
+    let updates = crate::archive::update_history(&mirror).unwrap();
+    assert_eq!(
+        updates.iter().map(|u| u.start_date.as_str()).collect::<Vec<_>>(),
+        vec![timestr(1), timestr(2), timestr(3), timestr(4), timestr(6)],
+    );
+    assert_eq!(updates[0].counts, Some((1, 0, 0)));
+    assert!(updates[0].error.is_none());
+    assert!(updates[1].counts.is_none());
+    assert!(
+        updates[1].error.as_ref().unwrap().starts_with("Upstream repository")
+    );
+    assert_eq!(updates[4].counts, Some((0, 0, 0)));
+}
+
+fn check_fork_refs(
+    repo: &Path,
+    fork: &str,
+    expected: &[(&str, u32, Option<u32>, &str)],
+) {
+    let expected = expected
+        .iter()
+        .map(|(name, from_date, to_date, sha)| {
+            (
+                name.to_string(),
+                timestr(*from_date),
+                to_date.map(timestr),
+                sha.to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let conn = Connection::open(repo.join("gitarchive.sqlite3")).unwrap();
+    let mut stmt = conn.prepare(
+        "
+        SELECT name, from_date, to_date, sha
+        FROM fork_refs
+        WHERE fork=?
+        ORDER BY from_date, name;
+        ",
+    ).unwrap();
+    let refs: Vec<_> = stmt.query_map(
+        &[&fork],
+        |row| (
+            row.get::<_, String>(0),
+            row.get::<_, String>(1),
+            row.get::<_, Option<String>>(2),
+            row.get::<_, String>(3),
+        ),
+    ).unwrap().map(Result::unwrap).collect();
+
+    assert_eq!(refs, expected);
 }
 
 fn check_db(
@@ -324,7 +911,7 @@ fn check_db(
 ) {
     // Format the expected list: make the dates from numbers
     let expected = expected
-        .into_iter()
+        .iter()
         .map(|(name, from_date, to_date, sha)| {
             (
                 name.to_string(),
@@ -359,25 +946,87 @@ fn check_db(
     assert_eq!(refs, expected);
 }
 
-fn check_refs(repo: &Path, expected: &[&str]) {
-    // Format the expected list (add 'keep-' prefix)
-    let expected = expected.into_iter()
-        .map(|h| format!("keep-{}", h))
-        .collect();
-
-    // Get the actual list from Git
-    let output = process::Command::new("git")
-        .arg("branch")
-        .current_dir(&repo)
-        .output().unwrap();
-    assert!(output.status.success());
-    let mut refs = HashSet::new();
-    for line in output.stdout.split(|&b| b == b'\n') {
-        let line = std::str::from_utf8(line).unwrap().trim();
-        if line.is_empty().not() {
-            refs.insert(line.into());
+fn check_ref_changes(repo: &Path, expected: &[(&str, u32, i64, i64)]) {
+    let expected = expected
+        .iter()
+        .map(|(name, date, added, removed)| {
+            (name.to_string(), timestr(*date), *added, *removed)
+        })
+        .collect::<Vec<_>>();
 
-            // Check that 'keep-abc' points to 'abc'
+    let conn = Connection::open(repo.join("gitarchive.sqlite3")).unwrap();
+    let mut stmt = conn.prepare(
+        "
+        SELECT refs.name, refs.from_date, ref_changes.added,
+            ref_changes.removed
+        FROM ref_changes
+        INNER JOIN refs ON refs.rowid = ref_changes.ref_id
+        ORDER BY refs.from_date, refs.name;
+        ",
+    ).unwrap();
+    let changes: Vec<_> = stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| (
+            row.get::<_, String>(0),
+            row.get::<_, String>(1),
+            row.get::<_, i64>(2),
+            row.get::<_, i64>(3),
+        ),
+    ).unwrap().map(Result::unwrap).collect();
+
+    assert_eq!(changes, expected);
+}
+
+fn check_rewrites(repo: &Path, expected: &[(&str, u32, &str, bool)]) {
+    let expected = expected
+        .iter()
+        .map(|(name, date, sha, removed)| {
+            (name.to_string(), timestr(*date), sha.to_string(), *removed)
+        })
+        .collect::<Vec<_>>();
+
+    let conn = Connection::open(repo.join("gitarchive.sqlite3")).unwrap();
+    let mut stmt = conn.prepare(
+        "
+        SELECT refs.name, refs.from_date, rewrite_commits.sha,
+            rewrite_commits.removed
+        FROM rewrite_commits
+        INNER JOIN refs ON refs.rowid = rewrite_commits.ref_id
+        ORDER BY refs.from_date, refs.name, rewrite_commits.sha;
+        ",
+    ).unwrap();
+    let rewrites: Vec<_> = stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| (
+            row.get::<_, String>(0),
+            row.get::<_, String>(1),
+            row.get::<_, String>(2),
+            row.get::<_, bool>(3),
+        ),
+    ).unwrap().map(Result::unwrap).collect();
+
+    assert_eq!(rewrites, expected);
+}
+
+fn check_refs(repo: &Path, expected: &[&str]) {
+    // Format the expected list (add 'keep-' prefix)
+    let expected = expected.iter()
+        .map(|h| format!("keep-{}", h))
+        .collect();
+
+    // Get the actual list from Git
+    let output = process::Command::new("git")
+        .arg("branch")
+        .current_dir(repo)
+        .output().unwrap();
+    assert!(output.status.success());
+    let mut refs = HashSet::new();
+    for line in output.stdout.split(|&b| b == b'\n') {
+        let line = std::str::from_utf8(line).unwrap().trim();
+        if line.is_empty().not() {
+            refs.insert(line.into());
+
+            // Check that 'keep-abc' points to 'abc'
             if line.starts_with("keep-") {
                 assert_eq!(&line[5..], get_sha(repo, line).unwrap());
             }
@@ -387,3 +1036,2358 @@ fn check_refs(repo: &Path, expected: &[&str]) {
     // Assert
     assert_eq!(refs, expected);
 }
+
+#[cfg(unix)]
+#[test]
+fn test_non_utf8_refs() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    assert!(process::Command::new("git")
+        .args(&[OsStr::new("branch"), OsStr::from_bytes(b"caf\xe9")])
+        .current_dir(&origin)
+        .envs(env(0))
+        .status().unwrap().success());
+    crate::update_with_date(&mirror, &Default::default(), time(1))
+        .unwrap();
+
+    // The name is stored as a BLOB, with its exact bytes
+    let db = crate::db::open(&mirror).unwrap();
+    let mut stmt = db.prepare(
+        "SELECT name FROM refs WHERE typeof(name) = 'blob';",
+    ).unwrap();
+    let names: Vec<crate::RefName> = stmt
+        .query_map(rusqlite::NO_PARAMS, |row| row.get(0)).unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    assert_eq!(names, vec![crate::RefName::from(&b"caf\xe9"[..])]);
+
+    // It's deleted properly too
+    assert!(process::Command::new("git")
+        .args(&[OsStr::new("branch"), OsStr::new("-D"),
+                OsStr::from_bytes(b"caf\xe9")])
+        .current_dir(&origin)
+        .envs(env(0))
+        .status().unwrap().success());
+    crate::update_with_date(&mirror, &Default::default(), time(2))
+        .unwrap();
+    let to_date: Option<String> = db.query_row(
+        "SELECT to_date FROM refs WHERE typeof(name) = 'blob';",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    ).unwrap();
+    assert_eq!(to_date, Some(timestr(2)));
+}
+
+#[test]
+fn test_option_like_refs() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+
+    // This is a valid ref, but a short name would be taken for an option
+    git(&["update-ref", "refs/tags/--all", "HEAD"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(1))
+        .unwrap();
+    let db = crate::db::open(&mirror).unwrap();
+    let sha: String = db.query_row(
+        "SELECT sha FROM refs WHERE name = '--all' AND tag = 1;",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    ).unwrap();
+    assert_eq!(sha, hash_one);
+}
+
+#[test]
+fn test_checkout_at() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    fs::create_dir(origin.join("dir")).unwrap();
+    fs::write(origin.join("dir/file"), b"old\n").unwrap();
+    git(&["add", "dir/file"], &origin);
+    git(&["commit", "-m", "two"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    fs::write(origin.join("dir/file"), b"new\n").unwrap();
+    git(&["commit", "-a", "-m", "three"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let old = test_dir.path().join("old");
+    crate::export::checkout_at(&mirror, Some(&timestr(1)), None, &old)
+        .unwrap();
+    assert_eq!(fs::read(old.join("dir/file")).unwrap(), b"old\n");
+    assert!(old.join(".git").exists().not());
+
+    let new = test_dir.path().join("new");
+    let sha = crate::export::checkout_at(
+        &mirror,
+        None,
+        Some("master"),
+        &new,
+    ).unwrap();
+    assert_eq!(sha, get_sha(&origin, "HEAD").unwrap());
+    assert_eq!(fs::read(new.join("dir/file")).unwrap(), b"new\n");
+
+    // Destination has to be empty, branch has to exist
+    assert!(crate::export::checkout_at(&mirror, None, None, &new).is_err());
+    let other = test_dir.path().join("other");
+    assert!(
+        crate::export::checkout_at(&mirror, None, Some("nope"), &other)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_shared_store() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror_a) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    let mirror_b = test_dir.path().join("mirror_b");
+    fs::create_dir(&mirror_b).unwrap();
+    git(&["init", "--bare"], &mirror_b);
+    git(&["remote", "add", "origin", "../origin"], &mirror_b);
+    git(&["config", "doublegit.sharedStore", "../store"], &mirror_a);
+    git(&["config", "doublegit.sharedStore", "../store"], &mirror_b);
+
+    crate::update_with_date(&mirror_a, &Default::default(), time(1))
+        .unwrap();
+    crate::update_with_date(&mirror_b, &Default::default(), time(1))
+        .unwrap();
+
+    // The store has the refs of both, under their names
+    let store = test_dir.path().join("store");
+    assert_eq!(
+        get_sha(&store, "refs/mirrors/mirror/remotes/origin/master").unwrap(),
+        hash_one,
+    );
+    assert_eq!(
+        get_sha(&store, "refs/mirrors/mirror_b/remotes/origin/master")
+            .unwrap(),
+        hash_one,
+    );
+
+    // After gc, the mirrors don't have their own copies of the objects
+    for mirror in &[&mirror_a, &mirror_b] {
+        crate::archive::gc(mirror, false).unwrap();
+        assert_eq!(crate::git::count_objects(mirror).unwrap().objects, 0);
+        assert!(crate::archive::verify(mirror, false).unwrap().is_ok());
+    }
+    assert_eq!(get_sha(&mirror_b, "refs/remotes/origin/master").unwrap(),
+               hash_one);
+
+    // Names can't be shared
+    let mirror_c = test_dir.path().join("mirror_c");
+    fs::create_dir(&mirror_c).unwrap();
+    git(&["init", "--bare"], &mirror_c);
+    git(&["remote", "add", "origin", "../origin"], &mirror_c);
+    git(&["config", "doublegit.sharedStore", "../store"], &mirror_c);
+    git(&["config", "doublegit.sharedStoreName", "mirror"], &mirror_c);
+    match crate::update_with_date(&mirror_c, &Default::default(), time(1)) {
+        Err(crate::Error::Config(_)) => {}
+        r => panic!("Update with a used name returned {:?}", r),
+    }
+}
+
+#[test]
+fn test_size_history() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7919 % 251) as u8)
+        .collect();
+    fs::write(origin.join("big"), &data).unwrap();
+    git(&["add", "big"], &origin);
+    git(&["commit", "-m", "big"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let history = crate::archive::size_history(&mirror).unwrap();
+    assert_eq!(
+        history.iter().map(|r| r.date.as_str()).collect::<Vec<_>>(),
+        vec![timestr(1), timestr(2)],
+    );
+    assert_eq!(history[0].objects, 2);
+    assert_eq!(history[1].objects, 5);
+    assert!(history[1].disk_bytes > history[0].disk_bytes + 1000);
+}
+
+#[test]
+fn test_transcripts() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["config", "doublegit.keepTranscripts", "true"], &mirror);
+    git(&["branch", "feature"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    // Not kept before the setting
+    match crate::archive::transcript(&mirror, Some(&timestr(1))) {
+        Err(crate::Error::NotFound(_)) => {}
+        r => panic!("Transcript without the setting returned {:?}", r),
+    }
+
+    let (date, stdout, stderr) =
+        crate::archive::transcript(&mirror, None).unwrap();
+    assert_eq!(date, timestr(2));
+    let mut output = stdout;
+    output.extend_from_slice(&stderr);
+    assert!(String::from_utf8(output).unwrap().contains("origin/feature"));
+}
+
+#[test]
+fn test_export_history() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    git(&["tag", "v1"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let mut csv = Vec::new();
+    crate::export::export_history_csv(&mirror, &mut csv).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        format!(
+            "name,tag,sha,from_date,to_date\n\
+             master,0,{one},{t1},{t2}\n\
+             master,0,{two},{t2},\n\
+             v1,1,{two},{t2},\n",
+            one = hash_one,
+            two = hash_two,
+            t1 = timestr(1),
+            t2 = timestr(2),
+        ),
+    );
+
+    let mut json = Vec::new();
+    crate::export::export_history_json(&mirror, &mut json).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+        serde_json::json!([
+            {"name": "master", "tag": false, "sha": hash_one,
+             "from_date": timestr(1), "to_date": timestr(2)},
+            {"name": "master", "tag": false, "sha": hash_two,
+             "from_date": timestr(2), "to_date": null},
+            {"name": "v1", "tag": true, "sha": hash_two,
+             "from_date": timestr(2), "to_date": null},
+        ]),
+    );
+}
+
+#[test]
+fn test_read_only_queries() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    assert_eq!(crate::archive::ref_log(&mirror, None).unwrap().len(), 1);
+
+    // Queries don't upgrade the database, only updates do
+    let db = crate::db::open(&mirror).unwrap();
+    let version = crate::db::stored_version(&db).unwrap();
+    db.execute(
+        "DELETE FROM schema_version WHERE version=?;",
+        &[version as i64],
+    ).unwrap();
+    let results = vec![
+        crate::archive::ref_log(&mirror, None).map(drop),
+        crate::archive::snapshot(&mirror, None, false).map(drop),
+        crate::archive::compare_snapshots(&mirror, None, None, false)
+            .map(drop),
+        crate::archive::when(&mirror, &hash_one).map(drop),
+        crate::archive::tag_history(&mirror, false).map(drop),
+        crate::archive::stats(&mirror).map(drop),
+        crate::export::export_history_json(&mirror, &mut Vec::new()),
+    ];
+    for result in results {
+        match result {
+            Err(crate::Error::Config(_)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+    assert_eq!(crate::db::stored_version(&db).unwrap(), version - 1);
+}
+
+#[test]
+fn test_fast_export() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--amend", "--allow-empty", "-m", "two"], &origin);
+    git(&["tag", "-a", "-m", "Version 1", "v1"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let mut stream = Vec::new();
+    crate::export::export_fast_export(&mirror, &mut stream).unwrap();
+
+    // Import it back, the overwritten commit is still there
+    let imported = test_dir.path().join("imported");
+    fs::create_dir(&imported).unwrap();
+    git(&["init", "--bare"], &imported);
+    let mut child = process::Command::new("git")
+        .arg("fast-import")
+        .current_dir(&imported)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(&stream).unwrap();
+    assert!(child.wait().unwrap().success());
+    let refs = |repo: &Path| {
+        let output = process::Command::new("git")
+            .args(&["for-each-ref", "--format=%(objectname) %(refname)"])
+            .args(&["refs/heads", "refs/remotes", "refs/tags/v1"])
+            .current_dir(repo)
+            .output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(refs(&imported), refs(&mirror));
+    assert_eq!(refs(&mirror).lines().count(), 3);
+    let tag = get_sha(&origin, "refs/tags/v1").unwrap();
+    assert_eq!(get_sha(&imported, "refs/tags/v1").unwrap(), tag);
+    assert!(get_sha(
+        &imported,
+        format!("refs/tags/refs/kept-tags/tag-{}", tag),
+    ).is_ok());
+    assert_eq!(
+        get_sha(&imported, format!("refs/heads/keep-{}", hash_one)).unwrap(),
+        hash_one,
+    );
+    assert_eq!(
+        get_sha(&imported, "refs/remotes/origin/master").unwrap(),
+        hash_two,
+    );
+
+    // A failing output stops the export
+    struct Broken;
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    match crate::export::export_fast_export(&mirror, &mut Broken) {
+        Err(crate::Error::Io(_)) => {}
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_replay() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["checkout", "-b", "feature"], &origin);
+    fs::write(origin.join("file"), b"feature\n").unwrap();
+    git(&["add", "file"], &origin);
+    git(&["commit", "-m", "two"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    git(&["checkout", "master"], &origin);
+    git(&["branch", "-D", "feature"], &origin);
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+
+    let output = test_dir.path().join("replay");
+    let report = crate::export::replay(&mirror, &output).unwrap();
+    assert_eq!(report.branches, 2);
+    assert_eq!(report.commits, 4);
+    assert_eq!(report.missing, 0);
+    assert!(crate::export::replay(&mirror, &output).is_err());
+    assert!(output.join("objects/info/alternates").exists().not());
+
+    let log = |branch: &str| {
+        let output = process::Command::new("git")
+            .args(&["log", "--format=%ci %T", branch])
+            .current_dir(&output)
+            .output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let empty_tree = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+    assert_eq!(
+        log("master"),
+        format!(
+            "{} +0000 {}\n{} +0000 {}\n",
+            timestr(3), empty_tree, timestr(1), empty_tree,
+        ),
+    );
+    let tree_two = process::Command::new("git")
+        .args(&["rev-parse", &format!("{}^{{tree}}", hash_two)])
+        .current_dir(&origin)
+        .output().unwrap();
+    let tree_two = String::from_utf8(tree_two.stdout).unwrap();
+    let tree_two = tree_two.trim();
+    assert_eq!(
+        log("feature"),
+        format!(
+            "{} +0000 {}\n{} +0000 {}\n",
+            timestr(3), empty_tree, timestr(2), tree_two,
+        ),
+    );
+    assert_eq!(
+        fs::read_to_string(output.join("HEAD")).unwrap(),
+        "ref: refs/heads/master\n",
+    );
+}
+
+#[test]
+fn test_progress() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::progress::{FetchProgress, Progress};
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Progress for Recorder {
+        fn fetch(&self, remote: &str, progress: &FetchProgress) {
+            self.0.lock().unwrap().push(format!(
+                "{} {}",
+                remote, progress.stage,
+            ));
+        }
+
+        fn refs(&self, done: usize, total: usize) {
+            self.0.lock().unwrap().push(format!("refs {}/{}", done, total));
+        }
+
+        fn finish(&self) {
+            self.0.lock().unwrap().push("finish".into());
+        }
+    }
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["branch", "other"], &origin);
+    let recorder = Arc::new(Recorder::default());
+    let options = crate::UpdateOptions {
+        progress: Some(recorder.clone()),
+        ..Default::default()
+    };
+    crate::update_with_date(&mirror, &options, time(1)).unwrap();
+    let events = recorder.0.lock().unwrap().clone();
+    assert!(events.iter().any(|e| e.starts_with("origin ")));
+    let refs: Vec<_> = events
+        .iter()
+        .filter(|e| !e.starts_with("origin "))
+        .map(String::as_str)
+        .collect();
+    assert_eq!(refs, ["refs 0/2", "refs 1/2", "refs 2/2", "finish"]);
+}
+
+#[test]
+fn test_merge() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror_a) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror_a, &Default::default(), time(1)).unwrap();
+
+    // Other archive, which sees a commit that gets force-pushed away
+    let mirror_b = test_dir.path().join("mirror_b");
+    fs::create_dir(&mirror_b).unwrap();
+    git(&["init", "--bare"], &mirror_b);
+    git(&["remote", "add", "origin", "../origin"], &mirror_b);
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror_b, &Default::default(), time(2)).unwrap();
+    git(&["reset", "--hard", "HEAD^"], &origin);
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    let hash_three = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror_b, &Default::default(), time(3)).unwrap();
+    crate::update_with_date(&mirror_a, &Default::default(), time(4)).unwrap();
+    assert!(!crate::git::object_exists(&mirror_a, &hash_two).unwrap());
+
+    let report = crate::archive::merge(&mirror_a, &mirror_b).unwrap();
+    assert_eq!(report.changed, 3);
+    assert_eq!(report.kept, 1);
+    check_db(
+        &mirror_a,
+        &[
+            ("master", 1, Some(2), &hash_one),
+            ("master", 2, Some(3), &hash_two),
+            ("master", 3, None, &hash_three),
+        ],
+        false,
+    );
+    check_rewrites(
+        &mirror_a,
+        &[("master", 3, &hash_two, true), ("master", 3, &hash_three, false)],
+    );
+    assert!(crate::git::object_exists(&mirror_a, &hash_two).unwrap());
+    assert!(
+        crate::git::list_refs(&mirror_a, "refs/doublegit-merge/")
+            .unwrap()
+            .is_empty()
+    );
+    let fetches: i64 = Connection::open(mirror_a.join("gitarchive.sqlite3"))
+        .unwrap()
+        .query_row("SELECT count(*) FROM fetches;", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(fetches, 4);
+    assert!(crate::archive::verify(&mirror_a, false).unwrap().is_ok());
+    assert!(crate::audit::verify(&mirror_a).unwrap().is_ok());
+
+    // Merging again changes nothing
+    let report = crate::archive::merge(&mirror_a, &mirror_b).unwrap();
+    assert_eq!(report.changed, 0);
+    assert_eq!(report.kept, 0);
+}
+
+#[test]
+fn test_search() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["commit", "--allow-empty", "-m", "Fix crash on startup"], &origin);
+    let hash_fix = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Rewritten away upstream, still found
+    git(&["reset", "--hard", "HEAD^"], &origin);
+    git(&["commit", "--allow-empty", "-m", "Add feature\n\nFix typo"], &origin);
+    let hash_feature = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    let search_with = |query, options: &crate::archive::SearchOptions| {
+        crate::archive::search(&mirror, query, options)
+            .unwrap()
+            .commits
+            .into_iter()
+            .map(|c| c.sha)
+            .collect::<Vec<_>>()
+    };
+    let search = |query| search_with(query, &Default::default());
+    assert_eq!(search("crash"), vec![hash_fix.clone()]);
+    assert_eq!(search("typo"), vec![hash_feature.clone()]);
+    assert_eq!(search("fix").len(), 2);
+    assert!(search("nothing").is_empty());
+    assert!(
+        crate::archive::search(&mirror, "\"unbalanced", &Default::default())
+            .is_err()
+    );
+
+    // Only what is not upstream anymore
+    let deleted_only = crate::archive::SearchOptions {
+        deleted_only: true,
+        ..Default::default()
+    };
+    assert_eq!(search_with("fix", &deleted_only), vec![hash_fix.clone()]);
+
+    // Branch names match too
+    git(&["branch", "fix-crash"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+    git(&["branch", "-D", "fix-crash"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(4)).unwrap();
+    let results =
+        crate::archive::search(&mirror, "CRASH", &deleted_only).unwrap();
+    assert_eq!(
+        results.refs,
+        vec![crate::archive::RefMatch {
+            name: "fix-crash".into(),
+            tag: false,
+            from_date: timestr(3),
+            to_date: Some(timestr(4)),
+        }],
+    );
+    let until = crate::archive::SearchOptions {
+        until: Some(timestr(3)),
+        ..Default::default()
+    };
+    assert!(
+        crate::archive::search(&mirror, "crash", &until).unwrap().refs
+            .is_empty()
+    );
+    let since = crate::archive::SearchOptions {
+        since: Some(timestr(4)),
+        ..Default::default()
+    };
+    assert!(
+        crate::archive::search(&mirror, "crash", &since).unwrap().refs
+            .is_empty()
+    );
+
+    // Archives without an index get one on the next update
+    let db = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    db.execute_batch(
+        "
+        DELETE FROM commit_messages;
+        DELETE FROM settings WHERE name='commit_index';
+        ",
+    ).unwrap();
+    assert!(search("crash").is_empty());
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+    assert_eq!(search("crash"), vec![hash_fix]);
+    assert_eq!(search("one").len(), 1);
+}
+
+#[test]
+fn test_grep() {
+    use crate::archive::{GrepOptions, grep};
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["checkout", "-b", "feature"], &origin);
+    fs::write(origin.join("key.txt"), "first\nAPI_KEY = 1234\n").unwrap();
+    git(&["add", "key.txt"], &origin);
+    git(&["commit", "-m", "Add key"], &origin);
+    let hash_key = get_sha(&origin, "HEAD").unwrap();
+    git(&["checkout", "master"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Quietly removed upstream
+    git(&["branch", "-D", "feature"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let found = |options: &GrepOptions| -> Vec<_> {
+        grep(&mirror, "api_key", options)
+            .unwrap()
+            .into_iter()
+            .map(|m| {
+                (
+                    m.name.to_string(),
+                    m.sha,
+                    m.from_date,
+                    m.to_date,
+                    m.path,
+                    m.line,
+                )
+            })
+            .collect()
+    };
+    let insensitive = |options: GrepOptions| {
+        found(&GrepOptions { ignore_case: true, ..options })
+    };
+    assert!(insensitive(Default::default()).is_empty());
+    let expected = vec![(
+        "feature".to_owned(),
+        hash_key,
+        timestr(1),
+        Some(timestr(2)),
+        "key.txt".to_owned(),
+        2,
+    )];
+    assert_eq!(
+        insensitive(GrepOptions { all: true, ..Default::default() }),
+        expected,
+    );
+    assert_eq!(
+        insensitive(GrepOptions {
+            date: Some(timestr(1)),
+            ..Default::default()
+        }),
+        expected,
+    );
+    assert!(found(&GrepOptions { all: true, ..Default::default() })
+        .is_empty());
+}
+
+#[test]
+fn test_commit_cache() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    git(&["commit", "--allow-empty", "-m", "two\n\nbody"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    let db = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    let mut stmt = db.prepare(
+        "
+        SELECT sha, parent, author, subject FROM commits
+        ORDER BY date, subject;
+        ",
+    ).unwrap();
+    let commits: Vec<_> = stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| (
+            row.get::<_, String>(0),
+            row.get::<_, Option<String>>(1),
+            row.get::<_, String>(2),
+            row.get::<_, String>(3),
+        ),
+    ).unwrap().map(Result::unwrap).collect();
+    let author = "doublegit <doublegit@example.com>".to_owned();
+    assert_eq!(
+        commits,
+        vec![
+            (hash_one.clone(), None, author.clone(), "one".to_owned()),
+            (hash_two, Some(hash_one), author, "two".to_owned()),
+        ],
+    );
+}
+
+#[test]
+fn test_log_commits_from() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    let tip = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    let subjects = |skip, number| -> Vec<_> {
+        crate::git::log_commits_from(&mirror, &tip, skip, number)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.subject)
+            .collect()
+    };
+    assert_eq!(subjects(0, 2), vec!["three", "two"]);
+    assert_eq!(subjects(1, 10), vec!["two", "one"]);
+    assert_eq!(subjects(3, 10), Vec::<String>::new());
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn test_show_commit() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    fs::write(origin.join("README"), "hello\n").unwrap();
+    git(&["add", "README"], &origin);
+    git(&["commit", "-m", "Add README\n\nWith a body"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    let details = crate::git::show_commit(&mirror, &hash_two).unwrap();
+    assert_eq!(details.sha, hash_two);
+    assert_eq!(details.parents, vec![hash_one]);
+    assert_eq!(details.author, "doublegit <doublegit@example.com>");
+    assert_eq!(details.message, "Add README\n\nWith a body");
+    assert!(details.diff.starts_with("diff --git a/README b/README\n"));
+    assert!(details.diff.ends_with("@@ -0,0 +1 @@\n+hello\n"));
+    assert!(!details.truncated);
+    assert!(
+        crate::git::show_commit(
+            &mirror,
+            "0123456789012345678901234567890123456789",
+        )
+        .is_err()
+    );
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn test_ls_tree() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    fs::create_dir(origin.join("src")).unwrap();
+    fs::write(origin.join("src/main.rs"), "fn main() {}\n").unwrap();
+    fs::write(origin.join("README"), "hello\n").unwrap();
+    git(&["add", "src", "README"], &origin);
+    git(&["commit", "-m", "Add files"], &origin);
+    let sha = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    let names = |path| -> Vec<_> {
+        crate::git::ls_tree(&mirror, &sha, path)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.kind, e.name))
+            .collect()
+    };
+    assert_eq!(
+        names(""),
+        vec![
+            ("blob".to_owned(), "README".to_owned()),
+            ("tree".to_owned(), "src".to_owned()),
+        ],
+    );
+    assert_eq!(names("src"), vec![("blob".to_owned(), "main.rs".to_owned())]);
+    assert!(crate::git::ls_tree(&mirror, &sha, "nope").is_err());
+    assert!(crate::git::ls_tree(&mirror, &sha, "README").is_err());
+    assert_eq!(
+        crate::git::read_blob(&mirror, &sha, "src/main.rs").unwrap(),
+        b"fn main() {}\n",
+    );
+    assert!(crate::git::read_blob(&mirror, &sha, "src").is_err());
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn test_blame() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    fs::write(origin.join("notes"), "one\ntwo\nthree\n").unwrap();
+    git(&["add", "notes"], &origin);
+    git(&["commit", "-m", "Add notes"], &origin);
+    let first = get_sha(&origin, "HEAD").unwrap();
+    fs::write(origin.join("notes"), "one\n2\nthree\nfour\n").unwrap();
+    git(&["commit", "-a", "-m", "Change notes\n\nDetails"], &origin);
+    let second = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    let hunks: Vec<_> = crate::git::blame(&mirror, &second, "notes")
+        .unwrap()
+        .into_iter()
+        .map(|h| (h.sha, h.summary, h.start, h.lines, h.author))
+        .collect();
+    let author = "doublegit <doublegit@example.com>".to_owned();
+    assert_eq!(
+        hunks,
+        vec![
+            (first.clone(), "Add notes".to_owned(), 1, 1, author.clone()),
+            (second.clone(), "Change notes".to_owned(), 2, 1, author.clone()),
+            (first.clone(), "Add notes".to_owned(), 3, 1, author.clone()),
+            (second.clone(), "Change notes".to_owned(), 4, 1, author),
+        ],
+    );
+    assert!(crate::git::blame(&mirror, &second, "nope").is_err());
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn test_upload_pack() {
+    use std::io::Read;
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--amend", "--allow-empty", "-m", "two"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    // The keep-ref of the force-pushed commit is advertised
+    let mut child =
+        crate::git::upload_pack(&mirror, true, Vec::new(), None).unwrap();
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    assert!(child.wait().unwrap().success());
+    assert!(output.contains(" refs/remotes/origin/master"));
+    assert!(output.contains(&format!(
+        "{} refs/heads/keep-{}",
+        hash_one, hash_one,
+    )));
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn test_archive_tar_gz() {
+    use std::io::Read;
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    fs::write(origin.join("README"), "hello\n").unwrap();
+    git(&["add", "README"], &origin);
+    git(&["commit", "-m", "Add README"], &origin);
+    let sha = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    let mut child =
+        crate::git::archive_tar_gz(&mirror, &sha, "master-x/").unwrap();
+    let mut tar = Vec::new();
+    flate2::read::GzDecoder::new(child.stdout.take().unwrap())
+        .read_to_end(&mut tar)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    // The header of the file, then its content
+    let header = tar
+        .chunks(512)
+        .position(|block| block.starts_with(b"master-x/README\0"))
+        .unwrap();
+    assert_eq!(&tar[(header + 1) * 512..][..6], b"hello\n");
+    assert!(crate::git::archive_tar_gz(&mirror, "HEAD", "x/").is_err());
+}
+
+#[test]
+fn test_tag_messages() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    git(&["tag", "-a", "v1.0", "-m", "Release 1.0\n\n* First"], &origin);
+    git(&["tag", "light"], &origin);
+    let tag_sha = get_sha(&origin, "refs/tags/v1.0").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    let db = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    let mut stmt = db.prepare(
+        "
+        SELECT refs.name, tag_objects.target, tag_objects.tagger,
+            tag_objects.date, tag_objects.message
+        FROM refs
+        INNER JOIN tag_objects ON tag_objects.sha = refs.sha
+        WHERE refs.tag=1;
+        ",
+    ).unwrap();
+    let tags: Vec<_> = stmt.query_map(
+        rusqlite::NO_PARAMS,
+        |row| (
+            row.get::<_, String>(0),
+            row.get::<_, String>(1),
+            row.get::<_, String>(2),
+            row.get::<_, Option<String>>(3),
+            row.get::<_, String>(4),
+        ),
+    ).unwrap().map(Result::unwrap).collect();
+    assert_eq!(
+        tags,
+        vec![(
+            "v1.0".to_owned(),
+            hash_one,
+            "doublegit <doublegit@example.com>".to_owned(),
+            Some("2019-03-16 21:00:00".to_owned()),
+            "Release 1.0\n\n* First\n".to_owned(),
+        )],
+    );
+    assert!(tag_sha != tags[0].1);
+}
+
+#[test]
+fn test_bisect_date() {
+    use crate::archive::{BisectTest, bisect_date};
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    // A file appears
+    fs::write(origin.join("bug"), "").unwrap();
+    git(&["add", "bug"], &origin);
+    git(&["commit", "-m", "three"], &origin);
+    for i in 3..5 {
+        crate::update_with_date(&mirror, &Default::default(), time(i))
+            .unwrap();
+    }
+
+    // The commit is dropped by a force-push, the file stays
+    git(&["reset", "--hard", "HEAD~2"], &origin);
+    fs::write(origin.join("bug"), "").unwrap();
+    git(&["add", "bug"], &origin);
+    git(&["commit", "-m", "four"], &origin);
+    for i in 5..8 {
+        crate::update_with_date(&mirror, &Default::default(), time(i))
+            .unwrap();
+    }
+
+    let bisect = |branch, test| {
+        bisect_date(&mirror, branch, &test)
+            .unwrap()
+            .map(|r| (r.initially, r.before, r.after))
+    };
+    assert_eq!(
+        bisect(None, BisectTest::Reachable(hash_two[..10].to_owned())),
+        Some((true, timestr(4), timestr(5))),
+    );
+    assert_eq!(
+        bisect(Some("master"), BisectTest::Command("test -e bug".into())),
+        Some((false, timestr(2), timestr(3))),
+    );
+    assert_eq!(
+        bisect(None, BisectTest::Command("grep -q x bug".into())),
+        None,
+    );
+    assert!(
+        bisect_date(&mirror, None, &BisectTest::Reachable("0123abc".into()))
+            .is_err()
+    );
+}
+
+#[test]
+fn test_tag_history() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    git(&["tag", "-a", "v1.0", "-m", "Release 1.0"], &origin);
+    git(&["tag", "light"], &origin);
+    git(&["tag", "stable"], &origin);
+    let tag_one = get_sha(&origin, "refs/tags/v1.0").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Retagged, and deleted
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    git(&["tag", "-f", "-a", "v1.0", "-m", "Release 1.0, fixed"], &origin);
+    git(&["tag", "-d", "light"], &origin);
+    let tag_two = get_sha(&origin, "refs/tags/v1.0").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let history = |changed_only| -> Vec<_> {
+        crate::archive::tag_history(&mirror, changed_only)
+            .unwrap()
+            .into_iter()
+            .map(|e| {
+                (
+                    e.name.to_string(),
+                    e.sha,
+                    e.to_date,
+                    e.deleted,
+                    e.target,
+                    e.message,
+                )
+            })
+            .collect()
+    };
+    let expected = vec![
+        (
+            "light".to_owned(),
+            hash_one.clone(),
+            Some(timestr(2)),
+            true,
+            None,
+            None,
+        ),
+        (
+            "v1.0".to_owned(),
+            tag_one,
+            Some(timestr(2)),
+            false,
+            Some(hash_one.clone()),
+            Some("Release 1.0\n".to_owned()),
+        ),
+        (
+            "v1.0".to_owned(),
+            tag_two,
+            None,
+            false,
+            Some(hash_two),
+            Some("Release 1.0, fixed\n".to_owned()),
+        ),
+    ];
+    assert_eq!(history(true), expected);
+    let all = history(false);
+    assert_eq!(all.len(), 4);
+    assert_eq!(
+        all[1],
+        ("stable".to_owned(), hash_one, None, false, None, None),
+    );
+}
+
+#[test]
+fn test_branch_renames() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    git(&["branch", "feature-x"], &origin);
+    git(&["branch", "same-1"], &origin);
+    git(&["branch", "same-2"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Renamed, and ambiguous deletions at the same commit
+    git(&["checkout", "feature-x"], &origin);
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    git(&["branch", "-m", "feature-x", "feat/x"], &origin);
+    git(&["branch", "-D", "same-1", "same-2"], &origin);
+    git(&["branch", "same-3", &hash_one], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+
+    assert_eq!(
+        crate::archive::branch_renames(&mirror).unwrap(),
+        vec![crate::archive::BranchRename {
+            date: timestr(3),
+            old_name: "feature-x".into(),
+            new_name: "feat/x".into(),
+            sha: hash_two,
+        }],
+    );
+}
+
+#[test]
+fn test_annotations() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+
+    crate::archive::annotate(
+        &mirror,
+        Some(&timestr(2)),
+        Some(("master", false)),
+        "before the push",
+    ).unwrap();
+    crate::archive::annotate(&mirror, Some(&timestr(4)), None, "pushed")
+        .unwrap();
+    crate::archive::annotate(&mirror, None, Some(("master", false)), "now")
+        .unwrap();
+    assert!(
+        crate::archive::annotate(&mirror, Some(&timestr(0)), None, "no")
+            .is_err()
+    );
+    assert!(
+        crate::archive::annotate(&mirror, None, Some(("nope", false)), "no")
+            .is_err()
+    );
+
+    let annotations: Vec<_> = crate::archive::annotations(&mirror)
+        .unwrap()
+        .into_iter()
+        .map(|a| (a.snapshot, a.ref_, a.note))
+        .collect();
+    assert_eq!(
+        annotations,
+        vec![
+            (
+                None,
+                Some(("master".into(), false, timestr(1))),
+                "before the push".to_owned(),
+            ),
+            (Some(timestr(3)), None, "pushed".to_owned()),
+            (
+                None,
+                Some(("master".into(), false, timestr(3))),
+                "now".to_owned(),
+            ),
+        ],
+    );
+}
+
+#[test]
+fn test_database_location() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    fs::create_dir(test_dir.path().join("databases")).unwrap();
+    git(
+        &["config", "doublegit.database", "../databases/mirror.sqlite3"],
+        &mirror,
+    );
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    assert!(!mirror.join("gitarchive.sqlite3").exists());
+    assert!(test_dir.path().join("databases/mirror.sqlite3").exists());
+    let db = Connection::open(
+        test_dir.path().join("databases/mirror.sqlite3"),
+    ).unwrap();
+    let sha: String = db.query_row(
+        "SELECT sha FROM refs WHERE name = 'master';",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    ).unwrap();
+    assert_eq!(sha, hash_one);
+    assert_eq!(crate::archive::size_history(&mirror).unwrap().len(), 1);
+}
+
+#[test]
+fn test_backup() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Fetch without recording, like an update that is not done yet
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    git(&["fetch", "origin"], &mirror);
+
+    let backup = test_dir.path().join("backup");
+    crate::archive::backup(&mirror, &backup).unwrap();
+    assert!(backup.join("gitarchive.sqlite3").exists());
+
+    // Restore it
+    let restored = test_dir.path().join("restored");
+    git(
+        &[
+            "clone", "--mirror", "--quiet",
+            backup.join("mirror.bundle").to_str().unwrap(),
+            restored.to_str().unwrap(),
+        ],
+        test_dir.path(),
+    );
+    for name in &["config", "gitarchive.sqlite3", "doublegit-journal"] {
+        fs::copy(backup.join(name), restored.join(name)).unwrap();
+    }
+
+    // The commit fetched before the backup gets recorded
+    crate::update_with_date(&restored, &Default::default(), time(2)).unwrap();
+    check_db(
+        &restored,
+        &[
+            ("master", 1, Some(2), &hash_one),
+            ("master", 2, None, &hash_two),
+        ],
+        false,
+    );
+}
+
+#[test]
+fn test_expire() {
+    use crate::retention::{ExpireOptions, expire_with_date, hold, release};
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Force-push, so the first commit is only kept by the archive
+    git(&["commit", "--amend", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    let hash_three = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+
+    let now = time(2) + chrono::Duration::days(1);
+    let dry_run = ExpireOptions { dry_run: true, repack: false };
+    assert!(expire_with_date(&mirror, &dry_run, now).is_err());
+    git(&["config", "doublegit.retention", "1d"], &mirror);
+
+    // Can't expire while on hold
+    let id = hold(&mirror, Some(&timestr(1)), None, "lawsuit").unwrap();
+    let report = expire_with_date(&mirror, &dry_run, now).unwrap();
+    assert_eq!(report.cutoff, timestr(2));
+    assert_eq!((report.intervals, report.held, report.objects), (0, 1, 0));
+    release(&mirror, id).unwrap();
+    let report = expire_with_date(&mirror, &dry_run, now).unwrap();
+    assert_eq!((report.intervals, report.held, report.objects), (1, 0, 1));
+    assert!(crate::git::object_exists(&mirror, &hash_one).unwrap());
+
+    let options = ExpireOptions { dry_run: false, repack: true };
+    let report = expire_with_date(&mirror, &options, now).unwrap();
+    assert_eq!((report.intervals, report.held, report.objects), (1, 0, 1));
+    check_db(
+        &mirror,
+        &[
+            ("master", 2, Some(3), &hash_two),
+            ("master", 3, None, &hash_three),
+        ],
+        false,
+    );
+    assert!(!crate::git::object_exists(&mirror, &hash_one).unwrap());
+    assert!(crate::archive::verify(&mirror, false).unwrap().is_ok());
+    assert!(crate::audit::verify(&mirror).unwrap().is_ok());
+}
+
+#[test]
+fn test_forget() {
+    use crate::archive::{ForgetOptions, forget, redactions};
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    git(&["checkout", "-b", "leak"], &origin);
+    git(&["commit", "--allow-empty", "-m", "password hunter2"], &origin);
+    let hash_leak = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    // Deleted upstream, then put on hold
+    git(&["checkout", "master"], &origin);
+    git(&["branch", "-D", "leak"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    let id = crate::retention::hold(&mirror, None, None, "lawsuit").unwrap();
+    let options = ForgetOptions {
+        repack: true,
+        reason: "takedown".into(),
+        ..Default::default()
+    };
+    match forget(&mirror, None, "leak", false, &options) {
+        Err(crate::Error::Held(_)) => {}
+        r => panic!("forget didn't refuse: {:?}", r),
+    }
+    crate::retention::release(&mirror, id).unwrap();
+    assert!(forget(&mirror, None, "leak", true, &options).is_err());
+
+    let report = forget(&mirror, None, "leak", false, &options).unwrap();
+    assert_eq!((report.intervals, report.objects), (1, 1));
+    assert!(!report.live);
+    check_db(&mirror, &[("master", 1, None, &hash_one)], false);
+    assert!(!crate::git::object_exists(&mirror, &hash_leak).unwrap());
+    assert!(crate::git::object_exists(&mirror, &hash_one).unwrap());
+    let results = crate::archive::search(
+        &mirror,
+        "hunter2",
+        &Default::default(),
+    ).unwrap();
+    assert!(results.commits.is_empty());
+    let redactions = redactions(&mirror).unwrap();
+    assert_eq!(redactions.len(), 1);
+    assert_eq!(redactions[0].name, "leak".into());
+    assert_eq!(redactions[0].intervals, 1);
+    assert_eq!(redactions[0].reason, "takedown");
+    assert!(crate::archive::verify(&mirror, false).unwrap().is_ok());
+    assert!(crate::audit::verify(&mirror).unwrap().is_ok());
+
+    // A live branch gets its mirror ref removed, and comes back on update
+    let report = forget(&mirror, None, "master", false, &options).unwrap();
+    assert!(report.live);
+    assert!(get_sha(&mirror, "refs/remotes/origin/master").is_err());
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+    check_db(&mirror, &[("master", 3, None, &hash_one)], false);
+}
+
+#[test]
+fn test_audit() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    git(&["commit", "--amend", "--allow-empty", "-m", "three"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+
+    let report = crate::audit::verify(&mirror).unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!((report.entries, report.checked), (3, 3));
+
+    // Edit the database, then put it back
+    let db_path = mirror.join("gitarchive.sqlite3");
+    let saved = test_dir.path().join("saved.sqlite3");
+    fs::copy(&db_path, &saved).unwrap();
+    let problems = |sql: &str| {
+        Connection::open(&db_path).unwrap().execute_batch(sql).unwrap();
+        let problems = crate::audit::verify(&mirror).unwrap().problems;
+        fs::copy(&saved, &db_path).unwrap();
+        problems
+    };
+
+    // Rewrite where a branch was
+    assert_eq!(
+        problems(
+            "UPDATE refs SET sha='0000' WHERE from_date = '2019-03-16 17:02:00';",
+        ),
+        vec![
+            "refs recorded by audit entry 2 (2019-03-16 17:02:00) were \
+             modified".to_owned(),
+            "refs recorded by audit entry 3 (2019-03-16 17:03:00) were \
+             modified".to_owned(),
+        ],
+    );
+    // Delete a change
+    assert_eq!(
+        problems(
+            "
+            DELETE FROM refs WHERE from_date = '2019-03-16 17:03:00';
+            UPDATE refs SET to_date = NULL
+            WHERE from_date = '2019-03-16 17:02:00';
+            ",
+        ).len(),
+        1,
+    );
+    // Add a change
+    assert_eq!(
+        problems(
+            "
+            INSERT INTO refs(name, from_date, to_date, sha, tag)
+            VALUES('feature', '2019-03-16 17:04:00', NULL, '0000', 0);
+            ",
+        ),
+        vec![
+            "feature from 2019-03-16 17:04:00 is not in the audit log"
+                .to_owned(),
+        ],
+    );
+    // Remove the log entry too
+    assert_eq!(
+        problems("DELETE FROM audit WHERE rowid = 2;"),
+        vec![
+            "audit entry 3 (2019-03-16 17:03:00) doesn't follow the previous \
+             one".to_owned(),
+            "master from 2019-03-16 17:01:00 was closed at 2019-03-16 \
+             17:02:00, which is not in the audit log".to_owned(),
+            "master from 2019-03-16 17:02:00 is not in the audit log"
+                .to_owned(),
+        ],
+    );
+}
+
+#[test]
+fn test_timestamps() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(
+        &[
+            "config", "doublegit.timestampCommand",
+            "printf 'proof of %s' \"$DOUBLEGIT_AUDIT_HASH\"",
+        ],
+        &mirror,
+    );
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["config", "doublegit.timestampCommand", "false"], &mirror);
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let timestamps = crate::audit::timestamps(&mirror).unwrap();
+    assert_eq!(timestamps.len(), 2);
+    assert_eq!(timestamps[0].audit_id, 1);
+    assert_eq!(timestamps[0].date, timestr(1));
+    assert_eq!(
+        timestamps[0].proof.as_ref().unwrap(),
+        format!("proof of {}", timestamps[0].hash).as_bytes(),
+    );
+    assert_eq!(timestamps[1].audit_id, 2);
+    assert!(timestamps[1].proof.is_none());
+    assert!(timestamps[1].error.is_some());
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+#[test]
+fn test_database_key_unsupported() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (_origin, mirror) = setup_mirror(test_dir.path());
+    git(&["config", "doublegit.databaseKey", "secret"], &mirror);
+    match crate::update_with_date(&mirror, &Default::default(), time(1)) {
+        Err(crate::Error::Config(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(!mirror.join("gitarchive.sqlite3").exists());
+}
+
+#[test]
+fn test_rollback() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    git(&["branch", "other"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    // Each row is linked to the fetches that opened and closed it
+    let conn = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    let mut stmt = conn.prepare(
+        "
+        SELECT name, f.date, t.date FROM refs
+        LEFT OUTER JOIN fetches f ON f.rowid = refs.fetch_id
+        LEFT OUTER JOIN fetches t ON t.rowid = refs.to_fetch_id
+        WHERE tag=0
+        ORDER BY from_date, name;
+        ",
+    ).unwrap();
+    let links: Vec<_> = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+        (
+            row.get::<_, String>(0),
+            row.get::<_, String>(1),
+            row.get::<_, Option<String>>(2),
+        )
+    }).unwrap().map(Result::unwrap).collect();
+    assert_eq!(
+        links,
+        vec![
+            ("master".to_owned(), timestr(1), Some(timestr(2))),
+            ("master".to_owned(), timestr(2), None),
+            ("other".to_owned(), timestr(2), None),
+        ],
+    );
+
+    // Roll back the second fetch
+    let report = crate::archive::rollback(&mirror, None).unwrap();
+    assert_eq!(report.date, timestr(2));
+    assert_eq!(report.removed, 2);
+    assert_eq!(report.extended, 1);
+    check_db(&mirror, &[("master", 1, None, &hash_one)], false);
+    assert!(crate::audit::verify(&mirror).unwrap().is_ok());
+
+    // The next update records the changes again
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+    check_db(
+        &mirror,
+        &[
+            ("master", 1, Some(3), &hash_one),
+            ("master", 3, None, &hash_two),
+            ("other", 3, None, &hash_two),
+        ],
+        false,
+    );
+
+    match crate::archive::rollback(&mirror, Some("2019-01-01 00:00:00")) {
+        Err(crate::Error::NotFound(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_collection() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    let url = origin.to_str().unwrap();
+
+    // A collection, with a nested one
+    let group = test_dir.path().join("group");
+    fs::create_dir_all(group.join("nested")).unwrap();
+    fs::write(
+        group.join(crate::COLLECTION_CONFIG),
+        format!(
+            "[project \"One\"]\n\turl = {0}\n\
+             [project \"../escape\"]\n\turl = {0}\n",
+            url,
+        ),
+    ).unwrap();
+    fs::write(
+        group.join("nested").join(crate::COLLECTION_CONFIG),
+        format!("[project \"two\"]\n\turl = {}\n", url),
+    ).unwrap();
+
+    // Missing mirrors get created, problems are reported for each project
+    let failures = crate::init_collection(&group).unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].0, group.join("../escape"));
+    match failures[0].1 {
+        crate::Error::Config(_) => {}
+        ref e => panic!("unexpected error {:?}", e),
+    }
+    let mirrors = crate::find_mirrors(&group).unwrap();
+    assert_eq!(mirrors, vec![group.join("One"), group.join("nested/two")]);
+    let results = crate::update_many(&mirrors, &Default::default(), 2);
+    assert!(results.iter().all(Result::is_ok));
+    assert_eq!(
+        get_sha(&group.join("nested/two"), "refs/remotes/origin/master")
+            .unwrap(),
+        hash_one,
+    );
+
+    // Existing ones are left alone
+    assert_eq!(crate::init_collection(&group).unwrap().len(), 1);
+    fs::remove_dir_all(&mirror).unwrap();
+    let mirrors = crate::find_mirrors(test_dir.path()).unwrap();
+    assert_eq!(mirrors, vec![group.join("One"), group.join("nested/two")]);
+}
+
+#[test]
+fn test_daemon_cycle() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    let broken = test_dir.path().join("broken");
+    crate::init("../missing", &broken).unwrap();
+
+    let options = crate::daemon::DaemonOptions {
+        interval: Duration::from_secs(3600),
+        stagger: Duration::from_millis(200),
+        jobs: 2,
+    };
+    let update_options = crate::UpdateOptions {
+        retries: 0,
+        ..Default::default()
+    };
+    let report = crate::daemon::run_cycle(
+        test_dir.path(),
+        &update_options,
+        &options,
+        1,
+    ).unwrap();
+    assert_eq!(report.mirrors, 2);
+    assert_eq!(report.failed, 1);
+    assert_eq!(
+        get_sha(&mirror, "refs/remotes/origin/master").unwrap(),
+        hash_one,
+    );
+}
+
+#[test]
+fn test_log() {
+    use crate::archive::Change;
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    git(&["branch", "feature"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    git(&["reset", "--hard", "HEAD~1"], &origin);
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    let hash_three = get_sha(&origin, "HEAD").unwrap();
+    git(&["branch", "-D", "feature"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+
+    let log: Vec<_> = crate::archive::ref_log(&mirror, None)
+        .unwrap()
+        .into_iter()
+        .map(|e| {
+            (
+                e.name.to_string(),
+                e.from_date,
+                e.sha,
+                e.change,
+                e.deleted,
+            )
+        })
+        .collect();
+    assert_eq!(
+        log,
+        vec![
+            (
+                "feature".to_owned(),
+                timestr(2),
+                hash_two.clone(),
+                Change::Created,
+                true,
+            ),
+            (
+                "master".to_owned(),
+                timestr(1),
+                hash_one,
+                Change::Created,
+                false,
+            ),
+            (
+                "master".to_owned(),
+                timestr(2),
+                hash_two.clone(),
+                Change::FastForward,
+                false,
+            ),
+            (
+                "master".to_owned(),
+                timestr(3),
+                hash_three,
+                Change::ForcePush,
+                false,
+            ),
+        ],
+    );
+
+    // Only one ref
+    let log = crate::archive::ref_log(&mirror, Some("feature")).unwrap();
+    assert_eq!(log.len(), 1);
+    let mut json = Vec::new();
+    crate::export::write_json(&log, &mut json).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+        serde_json::json!([
+            {"name": "feature", "tag": false, "sha": hash_two,
+             "from_date": timestr(2), "to_date": timestr(3),
+             "change": "created", "deleted": true},
+        ]),
+    );
+    match crate::archive::ref_log(&mirror, Some("missing")) {
+        Err(crate::Error::NotFound(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    git(&["tag", "-a", "-m", "Release", "v1"], &origin);
+    let hash_tag = get_sha(&origin, "refs/tags/v1").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let summary = |date: Option<&str>| -> Vec<_> {
+        crate::archive::snapshot(&mirror, date, true)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.name.to_string(), r.tag, r.sha, r.subject))
+            .collect()
+    };
+    let expected = vec![
+        ("master".to_owned(), false, hash_one, Some("one".to_owned())),
+        ("v1".to_owned(), true, hash_tag.clone(), Some("one".to_owned())),
+    ];
+    assert_eq!(summary(Some(&timestr(1))), expected);
+    assert_eq!(
+        summary(None),
+        vec![
+            ("master".to_owned(), false, hash_two, Some("two".to_owned())),
+            ("v1".to_owned(), true, hash_tag, Some("one".to_owned())),
+        ],
+    );
+
+    // Commits that are not cached are read from Git
+    let conn = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    conn.execute("DELETE FROM commits;", rusqlite::NO_PARAMS).unwrap();
+    assert_eq!(summary(Some(&timestr(1))), expected);
+
+    // Only from the database, as JSON
+    let refs = crate::archive::snapshot(&mirror, Some(&timestr(1)), false)
+        .unwrap();
+    assert!(refs.iter().all(|r| r.subject.is_none()));
+    let mut json = Vec::new();
+    crate::export::write_json(&refs, &mut json).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+        serde_json::json!([
+            {"name": "master", "tag": false, "sha": expected[0].2},
+            {"name": "v1", "tag": true, "sha": expected[1].2},
+        ]),
+    );
+}
+
+#[test]
+fn test_compare_snapshots() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "HEAD").unwrap();
+    git(&["branch", "old"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    fs::write(origin.join("file.txt"), "hello\n").unwrap();
+    git(&["add", "file.txt"], &origin);
+    git(&["commit", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    git(&["branch", "-D", "old"], &origin);
+    git(&["tag", "v1"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let diffs = crate::archive::compare_snapshots(
+        &mirror,
+        Some(&timestr(1)),
+        None,
+        true,
+    ).unwrap();
+    let summary: Vec<_> = diffs.iter()
+        .map(|d| (d.name.to_string(), d.tag, d.old.clone(), d.new.clone()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (
+                "master".to_owned(),
+                false,
+                Some(hash_one.clone()),
+                Some(hash_two.clone()),
+            ),
+            ("old".to_owned(), false, Some(hash_one.clone()), None),
+            ("v1".to_owned(), true, None, Some(hash_two)),
+        ],
+    );
+    assert!(diffs[0].stat.as_ref().unwrap().contains("file.txt"));
+    assert!(diffs[1].stat.is_none());
+
+    // Nothing changed
+    let diffs = crate::archive::compare_snapshots(
+        &mirror,
+        Some(&timestr(2)),
+        None,
+        false,
+    ).unwrap();
+    assert!(diffs.is_empty());
+}
+
+#[test]
+fn test_when() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "HEAD").unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    git(&["branch", "other", "HEAD~2"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    // Rewritten away from master, on another branch later
+    git(&["reset", "--hard", "HEAD~2"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(3)).unwrap();
+    git(&["branch", "-f", "other", &hash_two], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(4)).unwrap();
+
+    let (sha, periods) =
+        crate::archive::when(&mirror, &hash_two[..10]).unwrap();
+    assert_eq!(sha, hash_two);
+    assert_eq!(
+        periods,
+        vec![
+            crate::archive::Reachability {
+                name: "master".into(),
+                from_date: timestr(1),
+                to_date: Some(timestr(3)),
+            },
+            crate::archive::Reachability {
+                name: "other".into(),
+                from_date: timestr(4),
+                to_date: None,
+            },
+        ],
+    );
+
+    match crate::archive::when(&mirror, "0123456789") {
+        Err(crate::Error::NotFound(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_stats() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    git(&["branch", "feature"], &origin);
+    git(&["tag", "v1", "HEAD~1"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["reset", "--hard", "HEAD~1"], &origin);
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    git(&["branch", "-D", "feature"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let stats = crate::archive::stats(&mirror).unwrap();
+    assert_eq!(stats.snapshots, 2);
+    assert_eq!(stats.first_date, Some(timestr(1)));
+    assert_eq!(stats.last_date, Some(timestr(2)));
+    assert_eq!((stats.branches, stats.tags), (1, 1));
+    assert_eq!(stats.deleted_branches, 1);
+    assert_eq!(stats.force_pushes, vec![("master".into(), 1)]);
+    assert_eq!(stats.unreachable_commits, 1);
+    assert_eq!(stats.sizes.len(), 2);
+
+    let mut json = Vec::new();
+    crate::export::write_json(&stats, &mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["force_pushes"], serde_json::json!({"master": 1}));
+    assert_eq!(json["unreachable_commits"], 1);
+    assert_eq!(json["sizes"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn test_status() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["branch", "feature"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["branch", "-D", "feature"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let status = crate::archive::status(&mirror).unwrap();
+    assert!(status.last_success.as_ref().unwrap() >= &timestr(2));
+    assert!(status.last_error.is_none());
+    assert_eq!((status.live_refs, status.dead_refs), (1, 1));
+    assert!(status.config_problems.is_empty());
+    assert!(!status.journal_pending);
+    assert!(status.is_ok());
+
+    // Failed update, and invalid setting
+    git(&["config", "doublegit.proxy", "example.org:3128"], &mirror);
+    crate::update_with_date(&mirror, &Default::default(), time(3))
+        .unwrap_err();
+    let status = crate::archive::status(&mirror).unwrap();
+    assert!(status.last_error.as_ref().unwrap().0 >= timestr(3));
+    assert!(status.is_failing());
+    assert_eq!(status.config_problems.len(), 1);
+    assert!(status.config_problems[0].contains("doublegit.proxy"));
+    assert!(status.verify.is_ok());
+    assert!(status.gone.is_none());
+    assert!(!status.is_ok());
+
+    // Upstream deleted
+    git(&["config", "--unset", "doublegit.proxy"], &mirror);
+    fs::rename(&origin, test_dir.path().join("moved")).unwrap();
+    crate::update_with_date(&mirror, &Default::default(), time(4))
+        .unwrap_err();
+    let status = crate::archive::status(&mirror).unwrap();
+    assert_eq!(status.gone.as_ref().unwrap().0, timestr(4));
+    assert!(!status.is_ok());
+}
+
+#[test]
+fn test_doctor() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (_origin, mirror) = setup_mirror(test_dir.path());
+    let problems = |online| -> Vec<_> {
+        crate::doctor::check_mirror(&mirror, online)
+            .unwrap()
+            .into_iter()
+            .filter(|f| f.is_problem())
+            .map(|f| f.check)
+            .collect()
+    };
+
+    // Not updated yet
+    assert_eq!(problems(true), vec!["database"]);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    assert!(problems(true).is_empty());
+    assert!(!crate::doctor::check_git().is_problem());
+
+    // Refspec that would overwrite the keep-refs, and unreachable remote
+    git(
+        &["config", "--add", "remote.origin.fetch", "+refs/*:refs/*"],
+        &mirror,
+    );
+    git(&["config", "remote.origin.url", "../nonexistent"], &mirror);
+    assert_eq!(problems(false), vec!["fetch"]);
+    assert_eq!(problems(true), vec!["fetch", "remote"]);
+
+    // Changed keep-ref namespaces are reported, the refs are not moved
+    let keep_refs = || {
+        crate::git::list_refs(&mirror, "refs/heads/keep-*").unwrap().len()
+    };
+    let kept = keep_refs();
+    assert!(kept > 0);
+    git(&["config", "doublegit.keepBranchPrefix", "refs/old-keep-"], &mirror);
+    let findings = crate::doctor::check_mirror(&mirror, false).unwrap();
+    assert!(findings.iter().any(|f| f.check == "keep-refs"));
+    assert_eq!(keep_refs(), kept);
+    git(&["config", "--unset", "doublegit.keepBranchPrefix"], &mirror);
+
+    // An old database is not upgraded
+    let latest = crate::db::latest_version() as i64;
+    let version = || -> i64 {
+        Connection::open(mirror.join("gitarchive.sqlite3"))
+            .unwrap()
+            .query_row(
+                "SELECT max(version) FROM schema_version;",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+    Connection::open(mirror.join("gitarchive.sqlite3"))
+        .unwrap()
+        .execute("DELETE FROM schema_version WHERE version=?;", &[latest])
+        .unwrap();
+    assert_eq!(problems(false), vec!["fetch"]);
+    assert_eq!(version(), latest - 1);
+}
+
+#[test]
+fn test_config_settings() {
+    use crate::config::{
+        global_settings, mirror_settings, set_global_setting,
+        set_mirror_setting,
+    };
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (_origin, mirror) = setup_mirror(test_dir.path());
+    let value = |key: &str| {
+        mirror_settings(&mirror)
+            .unwrap()
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    };
+
+    set_mirror_setting(&mirror, "Proxy", Some("socks5h://localhost:1080"))
+        .unwrap();
+    assert_eq!(
+        value("doublegit.proxy").as_ref().map(String::as_str),
+        Some("socks5h://localhost:1080"),
+    );
+    set_mirror_setting(&mirror, "doublegit.keepTranscripts", Some("true"))
+        .unwrap();
+
+    // Invalid values and unknown settings are rejected, and not written
+    assert!(set_mirror_setting(&mirror, "proxy", Some("bad url")).is_err());
+    assert!(
+        set_mirror_setting(&mirror, "keepTranscripts", Some("maybe"))
+            .is_err()
+    );
+    assert!(set_mirror_setting(&mirror, "nope", Some("1")).is_err());
+    assert_eq!(
+        value("doublegit.keepTranscripts").as_ref().map(String::as_str),
+        Some("true"),
+    );
+
+    set_mirror_setting(&mirror, "proxy", None).unwrap();
+    assert_eq!(value("doublegit.proxy"), None);
+    set_mirror_setting(&mirror, "proxy", None).unwrap();
+
+    // Global config file, created as needed
+    let path = test_dir.path().join("global").join("config");
+    assert!(set_global_setting(&path, "web.port", Some("abc")).is_err());
+    assert!(!path.exists());
+    set_global_setting(&path, "web.port", Some("8080")).unwrap();
+    set_global_setting(&path, "mirror.keepTranscripts", Some("true"))
+        .unwrap();
+    assert!(
+        set_global_setting(&path, "mirror.retention", Some("never"))
+            .is_err()
+    );
+    assert_eq!(
+        global_settings(&path).unwrap(),
+        vec![
+            ("web.port".to_owned(), "8080".to_owned()),
+            ("mirror.keepTranscripts".to_owned(), "true".to_owned()),
+        ],
+    );
+}
+
+#[test]
+fn test_last_changes() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    git(&["branch", "feature"], &origin);
+    git(&["tag", "v1"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    let first = get_sha(&origin, "master").unwrap();
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    git(&["branch", "-D", "feature"], &origin);
+    git(&["branch", "other"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+    let second = get_sha(&origin, "master").unwrap();
+
+    let changes = crate::archive::last_changes(&mirror).unwrap();
+    let changes: Vec<_> = changes
+        .iter()
+        .map(|c| (c.name.to_string(), c.tag, c.old.clone(), c.new.clone()))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            ("feature".into(), false, Some(first.clone()), None),
+            ("master".into(), false, Some(first), Some(second.clone())),
+            ("other".into(), false, None, Some(second)),
+        ],
+    );
+
+    let results = vec![
+        (mirror.clone(), crate::archive::last_changes(&mirror)),
+        (test_dir.path().join("gone"), Err(crate::Error::git("failed"))),
+    ];
+    let mut json = Vec::new();
+    crate::export::write_update_json(&results, &mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json[0]["error"], serde_json::Value::Null);
+    assert_eq!(json[0]["changes"][0]["name"], "feature");
+    assert_eq!(json[0]["changes"][0]["new"], serde_json::Value::Null);
+    assert_eq!(json[0]["changes"].as_array().unwrap().len(), 3);
+    assert_eq!(json[1]["error"], "Git error: failed");
+    assert_eq!(json[1]["changes"], serde_json::json!([]));
+
+    let statuses = vec![(mirror.clone(), crate::archive::status(&mirror))];
+    let mut json = Vec::new();
+    crate::export::write_status_json(&statuses, &mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json[0]["live_refs"], 3);
+    assert_eq!(json[0]["last_error"], serde_json::Value::Null);
+    assert_eq!(json[0]["config_problems"], serde_json::json!([]));
+    assert_eq!(json[0]["ok"], true);
+
+    let mut json = Vec::new();
+    crate::export::write_error_json("a \"quoted\"\nerror", &mut json)
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json, serde_json::json!({"error": "a \"quoted\"\nerror"}));
+}
+
+#[test]
+fn test_global_config() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let path = test_dir.path().join("config");
+
+    // Missing file is the same as an empty one
+    let config = crate::config::load(&path).unwrap();
+    assert!(config.git.is_none());
+    assert!(config.mirror.is_empty());
+
+    fs::write(
+        &path,
+        "[core]\n\
+         \tgit = /usr/local/bin/git\n\
+         \troot = backups\n\
+         [mirror]\n\
+         \tsshKey = /etc/doublegit/id_ed25519\n\
+         [web]\n\
+         \tport = 8080\n\
+         [notify]\n\
+         \tcommand = echo failed\n",
+    ).unwrap();
+    let config = crate::config::load(&path).unwrap();
+    assert_eq!(
+        config.git.as_ref().unwrap(),
+        Path::new("/usr/local/bin/git"),
+    );
+    assert_eq!(config.root, Some(test_dir.path().join("backups")));
+    assert!(config.database_dir.is_none());
+    assert_eq!(
+        config.mirror.get("sshkey").map(String::as_str),
+        Some("/etc/doublegit/id_ed25519"),
+    );
+    assert_eq!(config.web_host, None);
+    assert_eq!(config.web_port, Some(8080));
+    assert_eq!(config.notify_command.as_ref().unwrap(), "echo failed");
+
+    fs::write(&path, "[web]\n\tport = http\n").unwrap();
+    assert!(crate::config::load(&path).is_err());
+
+    // Only one root
+    fs::write(&path, "[core]\n\troot = a\n\troot = b\n").unwrap();
+    assert!(crate::config::load(&path).is_err());
+}
+
+#[test]
+fn test_import_reflog() {
+    use chrono::TimeZone;
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    let hash_one = get_sha(&origin, "master").unwrap();
+    git(&["tag", "v1"], &origin);
+
+    // Someone had a clone, which saw master move and get force-pushed
+    let clone = test_dir.path().join("clone");
+    git(
+        &["clone", "-q", origin.to_str().unwrap(), clone.to_str().unwrap()],
+        test_dir.path(),
+    );
+    let fetch = |hour: u32| {
+        let date = chrono::Utc.ymd(2019, 3, 16).and_hms(hour, 0, 0);
+        assert!(process::Command::new("git")
+            .args(&["fetch", "-q", "origin"])
+            .current_dir(&clone)
+            .envs(env(0))
+            .env("GIT_COMMITTER_DATE", format!("{} +0000", date.timestamp()))
+            .status().unwrap().success());
+        date.format("%Y-%m-%d %H:%M:%S").to_string()
+    };
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    let hash_two = get_sha(&origin, "master").unwrap();
+    let date_two = fetch(22);
+    git(&["reset", "--hard", "HEAD~1"], &origin);
+    git(&["commit", "--allow-empty", "-m", "three"], &origin);
+    let hash_three = get_sha(&origin, "master").unwrap();
+    let date_three = fetch(23);
+
+    // The mirror only starts the next day, after another force-push
+    git(&["reset", "--hard", &hash_one], &origin);
+    let first_update = time(0) + chrono::Duration::days(1);
+    crate::update_with_date(&mirror, &Default::default(), first_update)
+        .unwrap();
+    let first_update = first_update.format("%Y-%m-%d %H:%M:%S").to_string();
+
+    let report = crate::archive::import_reflog(&clone, &mirror).unwrap();
+    assert_eq!((report.refs, report.intervals, report.extended), (2, 3, 1));
+    let date_one = crate::git::log_commits(&mirror, &hash_one, 1).unwrap()
+        .pop().unwrap()
+        .date;
+    let conn = Connection::open(mirror.join("gitarchive.sqlite3")).unwrap();
+    let mut stmt = conn.prepare(
+        "
+        SELECT name, tag, from_date, to_date, sha FROM refs
+        ORDER BY tag, from_date;
+        ",
+    ).unwrap();
+    let refs: Vec<_> = stmt.query_map(rusqlite::NO_PARAMS, |row| (
+        row.get::<_, String>(0),
+        row.get::<_, bool>(1),
+        row.get::<_, String>(2),
+        row.get::<_, Option<String>>(3),
+        row.get::<_, String>(4),
+    )).unwrap().map(Result::unwrap).collect();
+    assert_eq!(
+        refs,
+        vec![
+            (
+                "master".into(), false,
+                date_one.clone(), Some(date_two.clone()), hash_one.clone(),
+            ),
+            (
+                "master".into(), false,
+                date_two, Some(date_three.clone()), hash_two.clone(),
+            ),
+            (
+                "master".into(), false,
+                date_three, Some(first_update.clone()), hash_three,
+            ),
+            (
+                "master".into(), false,
+                first_update.clone(), None, hash_one.clone(),
+            ),
+            ("v1".into(), true, date_one, None, hash_one),
+        ],
+    );
+
+    // The commit only seen by the clone was kept
+    assert!(crate::archive::verify(&mirror, false).unwrap().is_ok());
+    git(&["gc", "--prune=now", "-q"], &mirror);
+    assert!(crate::git::object_exists(&mirror, &hash_two).unwrap());
+    assert!(crate::audit::verify(&mirror).unwrap().is_ok());
+
+    // Importing twice would duplicate the history
+    assert!(crate::archive::import_reflog(&clone, &mirror).is_err());
+}
+
+#[test]
+fn test_setup() {
+    use crate::setup::{Credentials, Platform, SetupOptions, setup};
+
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let origin = test_dir.path().join("origin");
+    let wiki = test_dir.path().join("origin.wiki.git");
+    for (repo, message) in &[(&origin, "one"), (&wiki, "Home")] {
+        fs::create_dir(repo).unwrap();
+        git(&["init"], repo);
+        git(&["checkout", "-b", "master"], repo);
+        git(&["commit", "--allow-empty", "-m", message], repo);
+    }
+    let hash_wiki = get_sha(&wiki, "master").unwrap();
+
+    // Create the mirror, archiving the wiki as a fork
+    let mirror = test_dir.path().join("mirror");
+    let mut options = SetupOptions {
+        platform: Platform::GitLab,
+        credentials: Credentials::SshKey("/etc/doublegit/id_ed25519".into()),
+        wiki: true,
+    };
+    setup(Some(origin.to_str().unwrap()), &mirror, &options).unwrap();
+    let config = crate::git::mirror_config(&mirror).unwrap();
+    assert_eq!(config["sshkey"], "/etc/doublegit/id_ed25519");
+    assert_eq!(config["forks"], "wiki");
+    assert!(!config.contains_key("tokenuser"));
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    check_fork_refs(&mirror, "wiki", &[("master", 1, None, &hash_wiki)]);
+
+    // Configure it again, the wiki is not added twice
+    options.credentials = Credentials::TokenEnv("GITLAB_TOKEN".into());
+    setup(None, &mirror, &options).unwrap();
+    let config = crate::git::mirror_config(&mirror).unwrap();
+    assert_eq!(config["tokenenv"], "GITLAB_TOKEN");
+    assert_eq!(config["tokenuser"], "oauth2");
+    assert_eq!(config["forks"], "wiki");
+
+    // There is no wiki to find elsewhere
+    options.platform = Platform::Other;
+    assert!(setup(None, &mirror, &options).is_err());
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn test_roster() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (_origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+
+    let roster = crate::web::Roster::new(test_dir.path()).unwrap();
+    let first = roster.get("mirror").unwrap();
+    assert!(roster.get("other").is_none());
+
+    // A new mirror shows up on refresh, the existing one is kept open
+    let other = test_dir.path().join("other");
+    crate::init("../origin", &other).unwrap();
+    crate::update_with_date(&other, &Default::default(), time(2)).unwrap();
+    roster.refresh().unwrap();
+    assert!(roster.get("other").is_some());
+    assert!(std::sync::Arc::ptr_eq(&first, &roster.get("mirror").unwrap()));
+
+    // A removed mirror goes away
+    fs::remove_dir_all(&other).unwrap();
+    roster.refresh().unwrap();
+    assert!(roster.get("other").is_none());
+}
+
+#[cfg(feature = "web")]
+#[test]
+fn test_export_static() {
+    let test_dir = tempfile::Builder::new()
+        .prefix("doublegit_test_")
+        .tempdir().unwrap();
+    let (origin, mirror) = setup_mirror(test_dir.path());
+    crate::update_with_date(&mirror, &Default::default(), time(1)).unwrap();
+    git(&["checkout", "-b", "feature/x"], &origin);
+    git(&["commit", "--allow-empty", "-m", "two"], &origin);
+    crate::update_with_date(&mirror, &Default::default(), time(2)).unwrap();
+
+    let site = test_dir.path().join("site");
+    // 4 redirects, master at both snapshots and latest, feature/x at the
+    // second and latest
+    assert_eq!(crate::web::export_static(&mirror, &site).unwrap(), 9);
+    assert!(crate::web::export_static(&mirror, &site).is_err());
+    let index = fs::read_to_string(site.join("index.html")).unwrap();
+    assert!(index.contains("url=_/latest/master/index.html"));
+    let page = fs::read_to_string(
+        site.join("_").join(timestr(2)).join("feature/x/index.html"),
+    ).unwrap();
+    assert!(page.contains("<li>two</li>"));
+    assert!(page.contains(&format!(
+        "href=\"../../../../_/{}/index.html\"",
+        timestr(1),
+    )));
+    assert!(page.contains(&format!(
+        "href=\"../../../../_/{}/master/index.html\"",
+        timestr(2),
+    )));
+    assert!(!page.contains("search"));
+}
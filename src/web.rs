@@ -4,110 +4,699 @@ use handlebars::Handlebars;
 use http::StatusCode;
 use hyper::Body;
 use rusqlite::Connection;
+use percent_encoding::{PATH_SEGMENT_ENCODE_SET, utf8_percent_encode};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::{Arc, Mutex};
-use warp::{self, Filter};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use warp::{self, Filter, Future};
 use warp::path;
 use warp::reply::{Reply, Response};
 
-use crate::Error;
+use crate::{Error, RefName};
+use crate::config::Config;
+use crate::archive::{Change, LogEntry};
 
-/// Start the warp server with our routes
-pub fn serve(
-    repository: &Path,
-    host: std::net::IpAddr,
-    port: u16,
-) -> Result<(), Error> {
-    // Connect to database
-    let db_path = repository.join("gitarchive.sqlite3");
-    let db = Connection::open(db_path)?;
-    let db = Arc::new(Mutex::new(db));
-    let db = warp::any().map(move || db.clone());
+/// A mirror served by the web interface
+pub struct Mirror {
+    path: PathBuf,
+    /// Prefix of its URLs, empty when it is the only mirror served
+    base: String,
+    db: Mutex<Connection>,
+}
+
+impl Mirror {
+    fn open(path: &Path, base: String) -> Result<Mirror, Error> {
+        Ok(Mirror {
+            path: path.to_owned(),
+            base,
+            db: Mutex::new(crate::db::open(path)?),
+        })
+    }
+}
+
+/// The mirrors found under a directory, each served under its relative path
+///
+/// This is shared with the updates in `doublegit watch`, which refreshes it
+/// after each cycle so that new mirrors show up.
+#[derive(Clone)]
+pub struct Roster {
+    root: PathBuf,
+    mirrors: Arc<RwLock<BTreeMap<String, Arc<Mirror>>>>,
+}
+
+impl Roster {
+    /// Find the mirrors under a directory
+    pub fn new(root: &Path) -> Result<Roster, Error> {
+        let roster = Roster {
+            root: root.to_owned(),
+            mirrors: Default::default(),
+        };
+        roster.refresh()?;
+        Ok(roster)
+    }
+
+    /// Look for mirrors again, keeping the ones already open
+    pub fn refresh(&self) -> Result<(), Error> {
+        let found = crate::find_mirrors(&self.root)?;
+        let mut mirrors = self.mirrors.write().unwrap();
+        let mut refreshed = BTreeMap::new();
+        for path in found {
+            let name = match path.strip_prefix(&self.root) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let name = match name.to_str() {
+                Some(name) if !name.is_empty() => name.to_owned(),
+                _ => continue,
+            };
+            let mirror = match mirrors.remove(&name) {
+                Some(mirror) => mirror,
+                None => {
+                    let base = format!(
+                        "/{}",
+                        utf8_percent_encode(&name, PATH_SEGMENT_ENCODE_SET),
+                    );
+                    match Mirror::open(&path, base) {
+                        Ok(mirror) => Arc::new(mirror),
+                        Err(e) => {
+                            warn!("Can't serve {}: {}", path.display(), e);
+                            continue;
+                        }
+                    }
+                }
+            };
+            refreshed.insert(name, mirror);
+        }
+        *mirrors = refreshed;
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Arc<Mirror>> {
+        self.mirrors.read().unwrap().get(name).cloned()
+    }
+}
+
+/// Credentials the web server requires, from the `web` section of the global
+/// config
+///
+/// Without any, pages are served to anyone who can connect. When both are
+/// set, either one is accepted.
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    /// User name and password for HTTP Basic authentication
+    pub basic: Option<(String, String)>,
+    /// Token for HTTP Bearer authentication
+    pub token: Option<String>,
+}
+
+impl Auth {
+    /// Read the credentials from `web.user`, `web.password` and `web.token`
+    pub fn from_config(config: &Config) -> Result<Auth, Error> {
+        let basic = match (&config.web_user, &config.web_password) {
+            (Some(user), Some(password)) => {
+                if user.contains(':') {
+                    return Err(Error::Config(format!(
+                        "invalid web.user {:?}, can't contain ':'",
+                        user,
+                    )));
+                }
+                Some((user.clone(), password.clone()))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(Error::Config(
+                    "web.user and web.password have to be set together"
+                        .into(),
+                ));
+            }
+        };
+        Ok(Auth { basic, token: config.web_token.clone() })
+    }
+
+    /// Whether the `Authorization` header of a request is good enough
+    fn allows(&self, header: Option<&str>) -> bool {
+        if self.basic.is_none() && self.token.is_none() {
+            return true;
+        }
+        let header = match header {
+            Some(header) => header.trim(),
+            None => return false,
+        };
+        let (scheme, credentials) = match header.find(' ') {
+            Some(i) => (&header[..i], header[i + 1..].trim()),
+            None => return false,
+        };
+        if scheme.eq_ignore_ascii_case("basic") {
+            let (user, password) = match self.basic {
+                Some((ref user, ref password)) => (user, password),
+                None => return false,
+            };
+            let decoded = match base64::decode(credentials) {
+                Ok(decoded) => decoded,
+                Err(_) => return false,
+            };
+            let expected = format!("{}:{}", user, password);
+            constant_time_eq(&decoded, expected.as_bytes())
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            match self.token {
+                Some(ref token) => {
+                    constant_time_eq(credentials.as_bytes(), token.as_bytes())
+                }
+                None => false,
+            }
+        } else {
+            false
+        }
+    }
+}
+
+/// Certificate and key to serve over HTTPS, with the `tls` feature
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl Tls {
+    /// Check the certificate chain and the private key, both PEM files
+    ///
+    /// The key can be PKCS#8 or RSA. This fails if doublegit was built
+    /// without TLS support.
+    pub fn new(cert: &Path, key: &Path) -> Result<Tls, Error> {
+        if !cfg!(feature = "tls") {
+            return Err(Error::Config(
+                "doublegit was built without the tls feature".into(),
+            ));
+        }
+        let pem = |path: &Path, marker: &str, what: &str| {
+            let content = std::fs::read(path).map_err(|e| {
+                Error::Config(format!(
+                    "can't read {}: {}",
+                    path.display(),
+                    e,
+                ))
+            })?;
+            let content = String::from_utf8_lossy(&content);
+            if content.contains(marker) {
+                Ok(())
+            } else {
+                Err(Error::Config(format!(
+                    "{} doesn't contain a PEM {}",
+                    path.display(),
+                    what,
+                )))
+            }
+        };
+        pem(cert, "-----BEGIN CERTIFICATE-----", "certificate")?;
+        pem(key, "PRIVATE KEY-----", "private key")?;
+        Ok(Tls { cert: cert.to_owned(), key: key.to_owned() })
+    }
+}
 
-    // Repository path
-    let repo_path = Arc::new(repository.to_path_buf());
-    let repo_path = warp::any().map(move || repo_path.clone());
+/// Compare secrets, in a time that doesn't depend on where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Answer requests that lack the credentials, and reject the others so that
+/// the routes get them
+fn require_auth(
+    auth: Auth,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let auth = Arc::new(auth);
+    warp::header::optional::<String>("authorization").and_then(
+        move |header: Option<String>| {
+            if auth.allows(header.as_ref().map(String::as_str)) {
+                return Err(warp::reject::not_found());
+            }
+            let challenge = if auth.basic.is_some() {
+                "Basic realm=\"doublegit\", charset=\"UTF-8\""
+            } else {
+                "Bearer realm=\"doublegit\""
+            };
+            http::response::Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header("WWW-Authenticate", challenge)
+                .body(Body::from("Authentication required"))
+                .map_err(warp::reject::custom)
+        },
+    )
+}
 
-    // Load templates
+/// Load the templates, from the source tree in debug builds
+fn load_templates() -> Arc<Handlebars> {
     let mut templates = Handlebars::new();
     #[cfg(debug_assertions)]
     templates.register_template_file("browse.html", "src/browse.html").unwrap();
     #[cfg(not(debug_assertions))]
     templates.register_template_string("browse.html", include_str!("browse.html")).unwrap();
-    let templates = Arc::new(templates);
+    #[cfg(debug_assertions)]
+    templates.register_template_file("search.html", "src/search.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("search.html", include_str!("search.html")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("commit.html", "src/commit.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("commit.html", include_str!("commit.html")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("tree.html", "src/tree.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("tree.html", include_str!("tree.html")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("blob.html", "src/blob.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("blob.html", include_str!("blob.html")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("blame.html", "src/blame.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("blame.html", include_str!("blame.html")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("timeline.html", "src/timeline.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("timeline.html", include_str!("timeline.html")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("compare.html", "src/compare.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("compare.html", include_str!("compare.html")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("snapshots.html", "src/snapshots.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("snapshots.html", include_str!("snapshots.html")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("feed.atom", "src/feed.atom").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("feed.atom", include_str!("feed.atom")).unwrap();
+    #[cfg(debug_assertions)]
+    templates.register_template_file("mirrors.html", "src/mirrors.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    templates.register_template_string("mirrors.html", include_str!("mirrors.html")).unwrap();
+    Arc::new(templates)
+}
+
+/// The routes of one mirror, which `mirror` picks
+fn mirror_routes<M>(
+    mirror: M,
+    templates: Arc<Handlebars>,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone
+where
+    M: Filter<Extract = (Arc<Mirror>,), Error = warp::Rejection>
+        + Clone + Send + Sync + 'static,
+{
     let templates = warp::any().map(move || templates.clone());
 
-    let routes =
-        // Index, redirects to a branch in the latest snapshot
-        path::end()
-            .and(db.clone()).and_then(index)
-        // Repo alone ("_"), same as index
-        .or(path!("_").and(path::end())
-            .and(db.clone()).and_then(index))
+    // Index, redirects to a branch in the latest snapshot
+    mirror.clone().and(path::end()).and_then(index)
+        // Repo alone ("_"), lists the snapshots
+        .or(mirror.clone().and(path!("_")).and(path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(templates.clone()).and_then(snapshots))
+        // Search through the commit messages
+        .or(mirror.clone().and(path!("_" / "search")).and(path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(templates.clone()).and_then(search))
+        // Atom feed of the changes to the refs
+        .or(mirror.clone().and(path!("feed.atom")).and(path::end())
+            .and(warp::header::optional::<String>("host"))
+            .and(templates.clone()).and_then(feed))
+        // Cloning over the smart HTTP protocol, read-only
+        .or(mirror.clone().and(path!("info" / "refs")).and(path::end())
+            .and(warp::get2())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("git-protocol"))
+            .and_then(info_refs))
+        .or(mirror.clone().and(path!("git-upload-pack")).and(path::end())
+            .and(warp::post2())
+            .and(warp::header::optional::<String>("content-encoding"))
+            .and(warp::header::optional::<String>("git-protocol"))
+            .and(warp::body::content_length_limit(MAX_UPLOAD_PACK_REQUEST))
+            .and(warp::body::concat())
+            .and_then(upload_pack))
+        // Timeline of the branches over the snapshots
+        .or(mirror.clone().and(path!("_" / "timeline")).and(path::end())
+            .and(templates.clone()).and_then(timeline))
+        // Comparison of two snapshots, "<date1>..<date2>"
+        .or(mirror.clone().and(path!("_" / String)).and(path::end())
+            .and(templates.clone()).and_then(compare))
         // Snapshot without branch, redirect to a branch
-        .or(path!("_" / String).and(path::end())
-            .and(db.clone()).and_then(snapshot))
+        .or(mirror.clone().and(path!("_" / String)).and(path::end())
+            .and_then(snapshot))
         // Browse view, shows a branch in a snapshot
-        .or(path!("_" / String / String).and(path::end())
-            .and(db).and(repo_path).and(templates).and_then(browse));
+        .or(mirror.clone().and(path!("_" / String / String)).and(path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(templates.clone()).and_then(browse))
+        // Commit view, with the diff
+        .or(mirror.clone()
+            .and(path!("_" / String / String / "commit" / String))
+            .and(path::end())
+            .and(templates.clone()).and_then(commit))
+        // Files of a branch in a snapshot
+        .or(mirror.clone().and(path!("_" / String / String / "tree"))
+            .and(path::tail())
+            .and(templates.clone()).and_then(tree))
+        // Content of a file, highlighted or raw
+        .or(mirror.clone().and(path!("_" / String / String / "blob"))
+            .and(path::tail())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(templates.clone()).and_then(blob))
+        // Which commit last changed each line of a file
+        .or(mirror.clone().and(path!("_" / String / String / "blame"))
+            .and(path::tail())
+            .and(templates).and_then(blame))
+        // Tarball of the files of a branch in a snapshot
+        .or(mirror
+            .and(path!("_" / String / String / "archive.tar.gz"))
+            .and(path::end())
+            .and_then(archive))
+}
+
+/// Start the warp server with our routes
+pub fn serve(
+    repository: &Path,
+    host: std::net::IpAddr,
+    port: u16,
+    auth: Auth,
+    tls: Option<Tls>,
+) -> Result<(), Error> {
+    let mirror = Arc::new(Mirror::open(repository, String::new())?);
+    let mirror = warp::any().and_then(move || {
+        Ok::<_, warp::Rejection>(mirror.clone())
+    });
+    let routes = mirror_routes(mirror, load_templates());
+    run_server(routes, host, port, auth, tls)
+}
+
+/// Start the warp server for all the mirrors of a roster
+///
+/// The index lists the mirrors, and each one is served under its name, e.g.
+/// `/my-repo/_/latest/master`. Git can clone them from `/my-repo.git`.
+pub fn serve_roster(
+    roster: Roster,
+    host: std::net::IpAddr,
+    port: u16,
+    auth: Auth,
+    tls: Option<Tls>,
+) -> Result<(), Error> {
+    let templates = load_templates();
+
+    let list = {
+        let roster = roster.clone();
+        let templates = templates.clone();
+        path::end().and_then(move || list_mirrors(&roster, &templates))
+    };
+    let mirror = path::param::<String>().and_then(move |name: String| {
+        percent_encoding::percent_decode(name.as_bytes())
+            .decode_utf8()
+            .ok()
+            .and_then(|name| {
+                // Clone URLs usually end in ".git"
+                roster.get(&name).or_else(|| {
+                    if name.ends_with(".git") {
+                        roster.get(&name[..name.len() - 4])
+                    } else {
+                        None
+                    }
+                })
+            })
+            .ok_or_else(warp::reject::not_found)
+    });
+    let routes = list.or(mirror_routes(mirror, templates));
+    run_server(routes, host, port, auth, tls)
+}
+
+/// Get a future that completes when a shutdown is requested
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    use warp::Stream;
+
+    tokio::timer::Interval::new_interval(Duration::from_millis(100))
+        .take_while(|_| Ok(!crate::shutdown::requested()))
+        .for_each(|_| Ok(()))
+        .map_err(|e| error!("Timer error: {}", e))
+}
+
+/// Run the server, on the socket given by systemd if it was socket-activated
+///
+/// systemd is told when the server is ready to accept connections. The
+/// server returns when a shutdown is requested.
+fn run_server<F, T>(
+    routes: F,
+    host: std::net::IpAddr,
+    port: u16,
+    auth: Auth,
+    tls: Option<Tls>,
+) -> Result<(), Error>
+where
+    F: Filter<Extract = (T,), Error = warp::Rejection>
+        + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    crate::systemd::start_watchdog();
+
+    if auth.basic.is_none() && auth.token.is_none() && !host.is_loopback() {
+        warn!("Serving on {} without authentication", host);
+    }
+    let routes = require_auth(auth).or(routes);
+
+    #[cfg(unix)]
+    {
+        if let Some(&fd) = crate::systemd::listen_fds().first() {
+            use std::os::unix::io::FromRawFd;
+
+            // warp can't do TLS on a socket it didn't open
+            if tls.is_some() {
+                return Err(Error::Config(
+                    "TLS can't be used with systemd socket activation".into(),
+                ));
+            }
+
+            // The socket is ours, systemd passed it and no one else uses it
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            let address = listener.local_addr()?;
+            let listener = tokio::net::TcpListener::from_std(
+                listener,
+                &tokio::reactor::Handle::default(),
+            )?;
+            println!("\n    Starting server on {} (from systemd)\n", address);
+            crate::systemd::notify("READY=1");
+            // warp can't stop this one gracefully, so requests that are
+            // still running when the shutdown is requested are dropped
+            let server = warp::serve(routes)
+                .serve_incoming(listener.incoming())
+                .select(shutdown_signal())
+                .map(|_| ())
+                .map_err(|_| ());
+            let mut runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(server).ok();
+            runtime.shutdown_now().wait().ok();
+            crate::systemd::notify("STOPPING=1");
+            return Ok(());
+        }
+    }
+
+    // Check that we can listen there, warp panics otherwise
+    let address = std::net::TcpListener::bind((host, port))
+        .and_then(|listener| listener.local_addr())
+        .map_err(|e| {
+            Error::Io(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("can't listen on {}:{}: {}", host, port, e),
+            ))
+        })?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let server = warp::serve(routes);
+    let (address, server): (_, Box<dyn Future<Item = (), Error = ()> + Send>) =
+        match tls {
+            #[cfg(feature = "tls")]
+            Some(tls) => {
+                let (address, server) = server
+                    .tls(&tls.cert, &tls.key)
+                    .bind_with_graceful_shutdown(address, shutdown_signal());
+                (address, Box::new(server))
+            }
+            _ => {
+                let (address, server) = server
+                    .bind_with_graceful_shutdown(address, shutdown_signal());
+                (address, Box::new(server))
+            }
+        };
+    println!("\n    Starting server on {}://{}\n", scheme, address);
+    crate::systemd::notify("READY=1");
+    tokio::run(server);
+    crate::systemd::notify("STOPPING=1");
 
-    println!("\n    Starting server on {}:{}\n", host, port);
-    warp::serve(routes).run((host, port));
+    Ok(())
+}
 
+/// Write a page sending the browser to another one, for a static site
+fn write_redirect(path: &Path, target: &str) -> Result<(), Error> {
+    let target = handlebars::html_escape(target);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(
+        path,
+        format!(
+            "<!DOCTYPE html>\n<html>\n  <head>\n    \
+             <meta http-equiv=\"refresh\" content=\"0; url={0}\">\n  \
+             </head>\n  <body>\n    <a href=\"{0}\">{0}</a>\n  \
+             </body>\n</html>\n",
+            target,
+        ),
+    )?;
     Ok(())
 }
 
+/// Render the history of a mirror as a static site, in a new directory
+///
+/// Pages are at the same paths as with `serve()`, plus `index.html`: one for
+/// each branch in each snapshot, including `latest`, and the pages that
+/// would redirect do it with a `<meta>` tag. Links are relative, so the site
+/// can be put anywhere or opened from the disk. Searching, the commit pages,
+/// and the files need the server, so they are left out.
+///
+/// Returns the number of pages written.
+pub fn export_static(repository: &Path, output: &Path) -> Result<usize, Error> {
+    let mirror = Mirror::open(repository, String::new())?;
+    let templates = load_templates();
+    std::fs::create_dir(output)?;
+
+    let (mut dates, main) = {
+        let db = mirror.db.lock().unwrap();
+        (snapshot_dates(&db)?, main_branch(&db, None)?)
+    };
+    dates.push("latest".to_owned());
+
+    let mut pages = 0;
+    if let Some(ref main) = main {
+        write_redirect(
+            &output.join("index.html"),
+            &format!("_/latest/{}/index.html", main),
+        )?;
+        pages += 1;
+    }
+    for date in &dates {
+        let dir = output.join("_").join(date);
+        let (branches, main) = {
+            let mut db = mirror.db.lock().unwrap();
+            let current = match get_snapshot(date, &mut db)?.0 {
+                Some(current) => current,
+                None => continue,
+            };
+            let at = if date == "latest" { None } else { Some(&date[..]) };
+            let main = main_branch(&db, at)?;
+            (get_branches(&current, &mut db)?, main)
+        };
+        if let Some(main) = main {
+            write_redirect(
+                &dir.join("index.html"),
+                &format!("{}/index.html", main),
+            )?;
+            pages += 1;
+        }
+        for (refname, _) in &branches {
+            let depth = 2 + refname.split('/').count();
+            let base = vec![".."; depth].join("/");
+            let mut context = match browse_context(
+                &mirror,
+                &base,
+                date,
+                refname,
+                1,
+                crate::CACHED_COMMITS,
+            )? {
+                Some(context) => context,
+                None => continue,
+            };
+            context["static"] = json!(true);
+            context["suffix"] = json!("/index.html");
+            let page = templates
+                .render("browse.html", &context)
+                .map_err(|e| {
+                    Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        e.to_string(),
+                    ))
+                })?;
+            let path = dir.join(refname).join("index.html");
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, page)?;
+            pages += 1;
+        }
+    }
+    info!("Wrote {} pages to {}", pages, output.display());
+    Ok(pages)
+}
+
+/// Lists the mirrors of a roster
+fn list_mirrors(
+    roster: &Roster,
+    templates: &Handlebars,
+) -> Result<impl Reply, warp::reject::Rejection> {
+    let mirrors: Vec<_> = roster
+        .mirrors
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, mirror)| json!({"name": name, "base": mirror.base}))
+        .collect();
+    templates
+        .render("mirrors.html", &json!({"mirrors": mirrors}))
+        .map_err(warp::reject::custom)
+        .map(warp::reply::html)
+}
+
+/// Find the branch to show by default, at a date or in the latest snapshot
+///
+/// This is "master" if it exists, otherwise whatever branch was last updated.
+fn main_branch(
+    db: &Connection,
+    date: Option<&str>,
+) -> Result<Option<String>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT name FROM refs
+        WHERE tag=0
+            AND (?1 IS NULL OR from_date <= ?1)
+            AND (to_date IS NULL OR to_date > ?1)
+        ORDER BY name = 'master' DESC, from_date DESC, name DESC
+        LIMIT 1;
+        ",
+    )?;
+    let mut rows = stmt.query(&[&date])?;
+    match rows.next() {
+        Some(row) => Ok(Some(row?.get::<_, RefName>(0).to_string())),
+        None => Ok(None),
+    }
+}
+
 /// Redirects to main branch in latest snapshot
 fn index(
-    db: Arc<Mutex<Connection>>,
+    mirror: Arc<Mirror>,
 ) -> Result<Response, warp::reject::Rejection> {
-    let db = db.lock().unwrap();
+    let db = mirror.db.lock().unwrap();
 
     // First we have to find a suitable branch
-    let head = (|| -> Result<String, rusqlite::Error> {
-        // If "master" exists, use that
-        let mut stmt = db.prepare(
-            "
-            SELECT name FROM refs
-            WHERE name='master' AND tag=0 AND to_date IS NULL;
-            ",
-        )?;
-        let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
-        if rows.next().is_some() {
-            Ok("master".into())
-        } else {
-            // Otherwise, use whatever branch was last updated
-            let mut stmt = db.prepare(
-                "
-                SELECT name FROM refs
-                WHERE tag=0 AND to_date IS NULL
-                ORDER BY from_date DESC, name DESC;
-                ",
-            )?;
-            let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
-            if let Some(row) = rows.next() {
-                Ok(row?.get(0))
-            } else {
-                panic!()
-            }
-        }
-    })()
-    .map_err(warp::reject::custom)?;
+    let head = crate::db::retry(|| main_branch(&db, None))
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
     info!("Redirecting to main branch: {}", head);
 
     // Redirect
     http::response::Response::builder()
         .status(StatusCode::FOUND)
-        .header("Location", format!("/_/latest/{}", head))
+        .header("Location", format!("{}/_/latest/{}", mirror.base, head))
         .body(Body::empty())
         .map_err(warp::reject::custom)
 }
 
 /// Redirects to main branch in given snapshot
 fn snapshot(
+    mirror: Arc<Mirror>,
     date: String,
-    db: Arc<Mutex<Connection>>,
 ) -> Result<Response, warp::reject::Rejection> {
     let date = match percent_encoding::percent_decode(date.as_bytes())
         .decode_utf8()
@@ -116,112 +705,189 @@ fn snapshot(
         Err(_) => return Err(warp::reject::not_found()),
     };
 
-    let db = db.lock().unwrap();
+    let db = mirror.db.lock().unwrap();
 
     // First we have to find the main branch
-    let head = (|| -> Result<String, rusqlite::Error> {
-        // If "master" exists, use that
-        let mut stmt = db.prepare(
-            "
-            SELECT name FROM refs
-            WHERE name='master' AND tag=0
-                AND from_date <= ?
-                AND (to_date IS NULL OR to_date > ?);
-            ",
-        )?;
-        let mut rows = stmt.query(&[&date, &date])?;
-        if rows.next().is_some() {
-            Ok("master".into())
-        } else {
-            // Otherwise, use whatever branch was last updated
-            let mut stmt = db.prepare(
-                "
-                SELECT name FROM refs
-                WHERE tag=0
-                    AND from_date <= ?
-                    AND (to_date IS NULL OR to_date >?)
-                ORDER BY from_date DESC, name DESC;
-                ",
-            )?;
-            let mut rows = stmt.query(&[&date, &date])?;
-            if let Some(row) = rows.next() {
-                Ok(row?.get(0))
-            } else {
-                panic!()
-            }
-        }
-    })()
-    .map_err(warp::reject::custom)?;
+    let head = crate::db::retry(|| main_branch(&db, Some(&date)))
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
     info!("Redirecting to main branch at {}: {}", date, head);
 
     // Redirect
     http::response::Response::builder()
         .status(StatusCode::FOUND)
-        .header("Location", format!("/_/{}/{}", date, head))
+        .header(
+            "Location",
+            format!("{}/_/{}/{}", mirror.base, date, head),
+        )
         .body(Body::empty())
         .map_err(warp::reject::custom)
 }
 
+/// Get the dates of all the snapshots, in order
+fn snapshot_dates(db: &Connection) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT from_date FROM refs
+        UNION
+        SELECT to_date FROM refs WHERE to_date IS NOT NULL
+        ORDER BY 1;
+        ",
+    )?;
+    let mut dates = Vec::new();
+    for row in stmt.query_map(rusqlite::NO_PARAMS, |row| row.get(0))? {
+        dates.push(row?);
+    }
+    Ok(dates)
+}
+
+/// Snapshot index, listing all the snapshots by month and day
+///
+/// With `?at=<day>[&time=<HH:MM>]`, redirects to the snapshot at that time,
+/// the end of the day if no time is given.
+fn snapshots(
+    mirror: Arc<Mirror>,
+    params: HashMap<String, String>,
+    templates: Arc<Handlebars>,
+) -> Result<Response, warp::reject::Rejection> {
+    if let Some(day) = params.get("at").filter(|d| !d.is_empty()) {
+        let time = params
+            .get("time")
+            .filter(|t| !t.is_empty())
+            .map_or("23:59:59", String::as_str);
+        let date = match crate::parse_date(&format!("{} {}", day, time)) {
+            Ok(Some(date)) => date,
+            _ => {
+                return http::response::Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("Invalid date"))
+                    .map_err(warp::reject::custom);
+            }
+        };
+        return http::response::Response::builder()
+            .status(StatusCode::FOUND)
+            .header("Location", format!("{}/_/{}", mirror.base, date))
+            .body(Body::empty())
+            .map_err(warp::reject::custom);
+    }
+
+    let dates = {
+        let db = mirror.db.lock().unwrap();
+        crate::db::retry(|| snapshot_dates(&db))
+            .map_err(warp::reject::custom)?
+    };
+    // Newest first, grouped by month ("YYYY-MM") then day ("YYYY-MM-DD")
+    let mut months: Vec<serde_json::Value> = Vec::new();
+    for date in dates.iter().rev() {
+        let (month, day) = (&date[..7], &date[..10]);
+        if months.last().map_or(true, |m| m["month"] != month) {
+            months.push(json!({"month": month, "days": []}));
+        }
+        let days = months.last_mut().unwrap()["days"]
+            .as_array_mut()
+            .unwrap();
+        if days.last().map_or(true, |d| d["day"] != day) {
+            days.push(json!({"day": day, "snapshots": []}));
+        }
+        days.last_mut().unwrap()["snapshots"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({
+                "url": format!("{}/_/{}", mirror.base, date),
+                "time": &date[11..],
+            }));
+    }
+    templates
+        .render(
+            "snapshots.html",
+            &json!({
+                "base": mirror.base,
+                "count": dates.len(),
+                "first": dates.first(),
+                "last": dates.last(),
+                "first_day": dates.first().map(|d| &d[..10]),
+                "last_day": dates.last().map(|d| &d[..10]),
+                "months": months,
+            }),
+        )
+        .map_err(warp::reject::custom)
+        .map(|page| warp::reply::html(page).into_response())
+}
+
+/// Current, previous and next snapshot dates
+type SnapshotDates = (Option<String>, Option<String>, Option<String>);
+
 /// Find the previous/current/next snapshots for a given date
+///
+/// Snapshots are the dates at which refs changed. Each date is looked up with
+/// `max()`/`min()` on `from_date` and `to_date` separately, so that SQLite
+/// can use the `refs_from_date` and `refs_to_date` indexes.
 fn get_snapshot(
     date: &str,
     db: &mut Connection,
-) -> Result<(Option<String>, Option<String>, Option<String>), rusqlite::Error>
+) -> Result<SnapshotDates, rusqlite::Error>
 {
-    let date: Result<(Option<String>, Option<String>, Option<String>), _> =
-        if date == "latest"
-    {
-        db.query_row(
+    let latest;
+    let date = if date == "latest" {
+        latest = db.query_row(
             "
-            WITH dates AS (
-                SELECT from_date AS date FROM refs
-                UNION
-                SELECT to_date AS date FROM refs
-            )
-            SELECT
-                (SELECT date FROM dates
-                 ORDER BY date DESC LIMIT 1) as current,
-                (SELECT date FROM dates
-                 ORDER BY date DESC LIMIT 1 OFFSET 1) AS prev,
-                NULL AS next;
+            SELECT max(date) FROM (
+                SELECT max(from_date) AS date FROM refs
+                UNION ALL
+                SELECT max(to_date) FROM refs
+            );
             ",
             rusqlite::NO_PARAMS,
-            |row| (row.get(0), row.get(1), row.get(2)),
-        )
+            |row| row.get::<_, Option<String>>(0),
+        )?;
+        match latest {
+            Some(ref d) => d,
+            None => return Ok((None, None, None)),
+        }
     } else {
-        db.query_row(
-            "
-            WITH dates AS (
-                SELECT from_date AS date FROM refs
-                UNION
-                SELECT to_date AS date FROM refs
+        date
+    };
+    db.query_row(
+        "
+        WITH current AS (
+            SELECT max(date) AS date FROM (
+                SELECT max(from_date) AS date FROM refs
+                WHERE from_date <= ?1
+                UNION ALL
+                SELECT max(to_date) FROM refs
+                WHERE to_date <= ?1
             )
-            SELECT
-                (SELECT date FROM dates
-                 WHERE date <= ?
-                 ORDER BY date DESC LIMIT 1) as current,
-                (SELECT date FROM dates
-                 WHERE date <= ?
-                 ORDER BY date DESC LIMIT 1 OFFSET 1) AS prev,
-                (SELECT date FROM dates
-                 WHERE date > ?
-                 ORDER BY date LIMIT 1) as next;
-            ",
-            &[&date, &date, &date],
-            |row| (row.get(0), row.get(1), row.get(2)),
         )
-    };
-    match date {
-        Ok(triple) => Ok(triple),
-        Err(rusqlite::Error::QueryReturnedNoRows) => Ok((None, None, None)),
-        Err(e) => {
-            error!("Error: {}", e);
-            Err(e)
-        }
-    }
+        SELECT
+            (SELECT date FROM current) AS current,
+            (SELECT max(date) FROM (
+                SELECT max(from_date) AS date FROM refs
+                WHERE from_date < (SELECT date FROM current)
+                UNION ALL
+                SELECT max(to_date) FROM refs
+                WHERE to_date < (SELECT date FROM current)
+            )) AS prev,
+            (SELECT min(date) FROM (
+                SELECT min(from_date) AS date FROM refs
+                WHERE from_date > ?1
+                UNION ALL
+                SELECT min(to_date) FROM refs
+                WHERE to_date > ?1
+            )) AS next;
+        ",
+        &[&date],
+        |row| (row.get(0), row.get(1), row.get(2)),
+    )
+    .map_err(|e| {
+        error!("Error: {}", e);
+        e
+    })
 }
 
 /// Get a list of branches and their SHA-1 position at a given date
+///
+/// The branches that still exist and the ones that were removed later are
+/// queried separately, each one a range on the `refs_snapshot` index.
 fn get_branches(
     date: &str,
     db: &mut Connection,
@@ -229,16 +895,16 @@ fn get_branches(
     let mut stmt = db.prepare(
         "
         SELECT name, sha FROM refs
-        WHERE tag=0
-            AND from_date <= ?
-            AND (to_date IS NULL OR to_date > ?);
-        ORDER BY name;
+        WHERE tag = 0 AND to_date IS NULL AND from_date <= ?1
+        UNION ALL
+        SELECT name, sha FROM refs
+        WHERE tag = 0 AND to_date > ?1 AND from_date <= ?1;
         ",
     )?;
     let rows = stmt.query_map(
-        &[date, date],
+        &[date],
         |row| (
-            row.get::<_, String>(0),
+            row.get::<_, RefName>(0).to_string(),
             row.get::<_, String>(1),
         ),
     )?;
@@ -250,7 +916,38 @@ fn get_branches(
     Ok(branches)
 }
 
-/// Structure describing commits, extracted from Git
+/// Get the user's notes about a snapshot, or about a branch in it
+fn get_annotations(
+    date: &str,
+    refname: &str,
+    db: &Connection,
+) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT annotations.note
+        FROM annotations
+        LEFT OUTER JOIN refs ON refs.rowid = annotations.ref_id
+        WHERE annotations.snapshot = ?
+            OR (
+                refs.name = ? AND refs.tag = 0
+                AND refs.from_date <= ?
+                AND (refs.to_date IS NULL OR refs.to_date > ?)
+            )
+        ORDER BY annotations.created;
+        ",
+    )?;
+    let rows = stmt.query_map(
+        &[&date, &refname, &date, &date],
+        |row| row.get(0),
+    )?;
+    let mut notes = Vec::new();
+    for row in rows {
+        notes.push(row?);
+    }
+    Ok(notes)
+}
+
+/// Structure describing commits, shown in the browse view
 #[derive(Serialize)]
 struct Commit {
     sha: String,
@@ -259,54 +956,174 @@ struct Commit {
     message: String,
 }
 
-/// Get the latest commits in a branch (possibly SHA-1) from Git
+impl From<crate::git::CommitInfo> for Commit {
+    fn from(info: crate::git::CommitInfo) -> Commit {
+        Commit {
+            sha: info.sha,
+            author: info.author,
+            date: info.date,
+            message: info.subject,
+        }
+    }
+}
+
+/// Get commits in a branch (SHA-1), the latest ones after skipping `skip`
+///
+/// They are read from the `commits` table, filled during updates, with Git as
+/// a fallback if some of them are not in there.
 fn get_commits(
+    db: &Connection,
     repository: &Path,
     target: &str,
+    skip: usize,
     number: usize,
 ) -> Result<Vec<Commit>, String> {
-    let output = process::Command::new("git")
-        .args(&["log", "--format=short"])
-        .arg(format!("{0}~{1}..{0}", target, number))
-        .arg("--")
-        .current_dir(repository)
-        .stdin(process::Stdio::null())
-        .output()
-        .map_err(|_| "Error running Git")?;
-    if !output.status.success() {
-        error!("Error running `git log`: {}", output.status);
-        return Err(format!("Error running `git log`: {}", output.status));
+    match cached_commits(db, target, skip, number) {
+        Ok(Some(commits)) => return Ok(commits),
+        Ok(None) => debug!("Commits of {} are not cached, using Git", target),
+        Err(e) => warn!("Error reading cached commits: {}", e),
     }
+    let commits =
+        crate::git::log_commits_from(repository, target, skip, number)
+        .map_err(|e| {
+            error!("Error running `git log`: {}", e);
+            e.to_string()
+        })?;
+    Ok(commits.into_iter().map(Commit::from).collect())
+}
+
+/// Get commits in a branch from the database, if they're all there
+fn cached_commits(
+    db: &Connection,
+    target: &str,
+    mut skip: usize,
+    number: usize,
+) -> Result<Option<Vec<Commit>>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "SELECT parent, author, date, subject FROM commits WHERE sha=?;",
+    )?;
     let mut commits = Vec::with_capacity(number);
-    for line in output.stdout.split(|&b| b == b'\n') {
-        let line = String::from_utf8_lossy(line);
-        if line.starts_with("commit ") {
-            commits.push(Commit {
-                sha: line[7..].into(),
-                author: String::new(),
-                date: String::new(),
-                message: String::new(),
-            });
-        } else if line.starts_with("Author: ") {
-            commits.last_mut().unwrap().author = line.trim().into();
-        } else if line.starts_with("Date: ") {
-            commits.last_mut().unwrap().date = line.trim().into();
-        } else if line.starts_with("    ") {
-            commits.last_mut().unwrap().message = line.trim().into();
+    let mut next = Some(target.to_owned());
+    while let Some(sha) = next.take() {
+        if commits.len() >= number {
+            break;
         }
+        let mut rows = stmt.query(&[&sha])?;
+        let row = match rows.next() {
+            Some(row) => row?,
+            None => return Ok(None),
+        };
+        next = row.get(0);
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        commits.push(Commit {
+            sha,
+            author: row.get(1),
+            date: row.get(2),
+            message: row.get(3),
+        });
     }
-    Ok(commits)
+    Ok(Some(commits))
+}
+
+/// Get what the browse view shows, or `None` if there is no such branch
+/// in that snapshot
+///
+/// For a specific date and a specific branch, this is a page of the latest
+/// commits (starting at 1), other branches in that snapshot, and the
+/// previous/next snapshots.
+fn browse_context(
+    mirror: &Mirror,
+    base: &str,
+    date: &str,
+    refname: &str,
+    page: usize,
+    per_page: usize,
+) -> Result<Option<serde_json::Value>, Error> {
+    let mut db = mirror.db.lock().unwrap();
+
+    // Load snapshot information
+    let (current, prev_date, next_date) =
+        match crate::db::retry(|| get_snapshot(date, &mut db))? {
+            (Some(current), prev, next) => {
+                info!("Resolved date {} -> {}", date, current);
+                (current, prev, next)
+            }
+            (None, _, _) => return Ok(None),
+        };
+
+    // Load branches
+    let mut branches = crate::db::retry(|| get_branches(&current, &mut db))?;
+    let current_sha = {
+        let idx = match branches.binary_search_by(|br| br.0[..].cmp(refname))
+        {
+            Ok(idx) => idx,
+            Err(_) => {
+                warn!("Requested branch does not exist");
+                return Ok(None);
+            }
+        };
+        branches.remove(idx).1
+    };
+
+    // Load commits, one more to know whether there is a next page
+    let mut commits = get_commits(
+        &db,
+        &mirror.path,
+        &current_sha,
+        (page - 1) * per_page,
+        per_page + 1,
+    )
+    .map_err(Error::Git)?;
+    let more = commits.len() > per_page;
+    commits.truncate(per_page);
+    let page_link = |page| {
+        if per_page == crate::CACHED_COMMITS {
+            format!("?page={}", page)
+        } else {
+            format!("?page={}&per_page={}", page, per_page)
+        }
+    };
+
+    let gone = crate::upstream_gone(&db)?;
+
+    let annotations = crate::db::retry(|| {
+        get_annotations(&current, refname, &db)
+    })?;
+
+    Ok(Some(json!({
+        "base": base,
+        "snapshot": {
+            "current": current, "prev": prev_date, "next": next_date,
+            "req": date,
+        },
+        "refname": refname,
+        "branches": branches,
+        "commits": commits,
+        "page": page,
+        "prev_page": if page > 1 { Some(page_link(page - 1)) } else { None },
+        "next_page": if more { Some(page_link(page + 1)) } else { None },
+        "annotations": annotations,
+        "gone": gone.map(|(date, error)| json!({
+            "date": date, "error": error,
+        })),
+    })))
 }
 
+/// Most commits shown on a page of the browse view
+const MAX_PER_PAGE: usize = 200;
+
 /// Main view, showing information to the user
 ///
-/// For a specific date and a specific branch, show the latest commits, other
-/// branches in that snapshot, and link to the previous/next snapshots.
+/// The commits are paginated with `?page=` (starting at 1) and
+/// `?per_page=`.
 fn browse(
+    mirror: Arc<Mirror>,
     date: String,
     refname: String,
-    db: Arc<Mutex<Connection>>,
-    repository: Arc<PathBuf>,
+    params: HashMap<String, String>,
     templates: Arc<Handlebars>,
 ) -> Result<impl Reply, warp::reject::Rejection> {
     let date = match percent_encoding::percent_decode(date.as_bytes())
@@ -315,50 +1132,1338 @@ fn browse(
         Ok(s) => s,
         Err(_) => return Err(warp::reject::not_found()),
     };
+    let number = |name, default| match params.get(name) {
+        Some(value) => value
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n >= 1)
+            .ok_or_else(warp::reject::not_found),
+        None => Ok(default),
+    };
+    let page = number("page", 1)?;
+    let per_page = number("per_page", crate::CACHED_COMMITS)?;
+    if per_page > MAX_PER_PAGE {
+        return Err(warp::reject::not_found());
+    }
 
-    let mut db = db.lock().unwrap();
-
-    // Load snapshot information
-    let (current, prev_date, next_date) = match get_snapshot(&date, &mut db)
-        .map_err(warp::reject::custom)?
+    let context = browse_context(
+        &mirror,
+        &mirror.base,
+        &date,
+        &refname,
+        page,
+        per_page,
+    )
+    .map_err(warp::reject::custom)?
+    .ok_or_else(warp::reject::not_found)?;
+
+    // Send response
+    templates
+        .render("browse.html", &context)
+        .map_err(warp::reject::custom)
+        .map(warp::reply::html)
+}
+
+/// Split a unified diff into lines, with the kind of each one as a CSS class
+///
+/// Lines in a hunk are told apart by their first character, so that a
+/// removed line starting with `--` is not taken for a file header.
+fn diff_lines(diff: &str) -> Vec<(&'static str, &str)> {
+    let mut in_hunk = false;
+    diff.lines()
+        .map(|line| {
+            let class = if line.starts_with("diff ") {
+                in_hunk = false;
+                "file"
+            } else if line.starts_with("@@") {
+                in_hunk = true;
+                "hunk"
+            } else if !in_hunk {
+                "file"
+            } else if line.starts_with('+') {
+                "added"
+            } else if line.starts_with('-') {
+                "removed"
+            } else {
+                "context"
+            };
+            (class, line)
+        })
+        .collect()
+}
+
+/// Commit view, showing the full message and the diff
+fn commit(
+    mirror: Arc<Mirror>,
+    date: String,
+    refname: String,
+    sha: String,
+    templates: Arc<Handlebars>,
+) -> Result<impl Reply, warp::reject::Rejection> {
+    let date = match percent_encoding::percent_decode(date.as_bytes())
+        .decode_utf8()
     {
-        (Some(current), prev, next) => {
-            info!("Resolved date {} -> {}", date, current);
-            (current, prev, next)
+        Ok(s) => s,
+        Err(_) => return Err(warp::reject::not_found()),
+    };
+    if crate::git::check_sha(&sha).is_err() {
+        return Err(warp::reject::not_found());
+    }
+
+    let details = match crate::git::show_commit(&mirror.path, &sha) {
+        Ok(details) => details,
+        Err(Error::NotFound(_)) => return Err(warp::reject::not_found()),
+        Err(e) => return Err(warp::reject::custom(e)),
+    };
+    let diff: Vec<_> = diff_lines(&details.diff)
+        .into_iter()
+        .map(|(class, text)| json!({"class": class, "text": text}))
+        .collect();
+    templates
+        .render(
+            "commit.html",
+            &json!({
+                "base": mirror.base,
+                "date": date,
+                "refname": refname,
+                "commit": {
+                    "sha": details.sha,
+                    "parents": details.parents,
+                    "author": details.author,
+                    "author_date": details.author_date,
+                    "committer": details.committer,
+                    "commit_date": details.commit_date,
+                    "message": details.message,
+                },
+                "diff": diff,
+                "truncated": details.truncated,
+            }),
+        )
+        .map_err(warp::reject::custom)
+        .map(warp::reply::html)
+}
+
+/// Percent-encode a path for a URL, keeping the slashes
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|part| {
+            utf8_percent_encode(part, PATH_SEGMENT_ENCODE_SET).to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Find where a branch was in a snapshot
+///
+/// Returns the resolved date of the snapshot and the SHA-1, or `None` if
+/// there is no such branch in that snapshot.
+fn branch_at(
+    mirror: &Mirror,
+    date: &str,
+    refname: &str,
+) -> Result<Option<(String, String)>, Error> {
+    let mut db = mirror.db.lock().unwrap();
+    let current = match crate::db::retry(|| get_snapshot(date, &mut db))?.0 {
+        Some(current) => current,
+        None => return Ok(None),
+    };
+    let branches = crate::db::retry(|| get_branches(&current, &mut db))?;
+    Ok(branches
+        .into_iter()
+        .find(|(name, _)| name == refname)
+        .map(|(_, sha)| (current, sha)))
+}
+
+/// Tree view, listing a directory of a branch in a snapshot
+fn tree(
+    mirror: Arc<Mirror>,
+    date: String,
+    refname: String,
+    tail: path::Tail,
+    templates: Arc<Handlebars>,
+) -> Result<impl Reply, warp::reject::Rejection> {
+    let decode = |s: &str| {
+        percent_encoding::percent_decode(s.as_bytes())
+            .decode_utf8()
+            .map(|s| s.into_owned())
+            .map_err(|_| warp::reject::not_found())
+    };
+    let date = decode(&date)?;
+    let dir = decode(tail.as_str())?;
+    let dir = dir.trim_matches('/');
+
+    let (current, sha) = branch_at(&mirror, &date, &refname)
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
+    let mut entries = match crate::git::ls_tree(&mirror.path, &sha, dir) {
+        Ok(entries) => entries,
+        Err(Error::NotFound(_)) => return Err(warp::reject::not_found()),
+        Err(e) => return Err(warp::reject::custom(e)),
+    };
+    // Directories first
+    entries.sort_by(|a, b| {
+        (a.kind != "tree", &a.name).cmp(&(b.kind != "tree", &b.name))
+    });
+    let dir_url = encode_path(dir);
+    let join = |name: &str| {
+        let name = utf8_percent_encode(name, PATH_SEGMENT_ENCODE_SET);
+        if dir.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", dir_url, name)
         }
-        (None, _, _) => return Err(warp::reject::not_found()),
     };
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "name": entry.name,
+                "url": join(&entry.name),
+                "dir": entry.kind == "tree",
+                "submodule": entry.kind == "commit",
+                "sha": entry.sha,
+            })
+        })
+        .collect();
+    let mut parents = Vec::new();
+    let mut path = String::new();
+    for part in dir.split('/').filter(|p| !p.is_empty()) {
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str(part);
+        parents.push(json!({"name": part, "url": encode_path(&path)}));
+    }
+    templates
+        .render(
+            "tree.html",
+            &json!({
+                "base": mirror.base,
+                "date": date,
+                "current": current,
+                "refname": refname,
+                "sha": sha,
+                "path": dir,
+                "parents": parents,
+                "entries": entries,
+            }),
+        )
+        .map_err(warp::reject::custom)
+        .map(warp::reply::html)
+}
 
-    // Load branches
-    let mut branches = get_branches(&current, &mut db)
-        .map_err(warp::reject::custom)?;
-    let current_sha = {
-        let idx = branches.binary_search_by(|br| br.0.cmp(&refname))
-            .map_err(|_| {
-                warn!("Requested branch does not exist");
-                warp::reject::not_found()
-            })?;
-        branches.remove(idx).1
+/// Files larger than this are shown without highlighting
+const MAX_HIGHLIGHT: usize = 512 << 10;
+
+/// Render a file as HTML lines, highlighted according to its name or first
+/// line
+fn highlight(path: &str, text: &str) -> Vec<String> {
+    use syntect::easy::HighlightLines;
+    use syntect::highlighting::{Theme, ThemeSet};
+    use syntect::html::{IncludeBackground, styled_line_to_highlighted_html};
+    use syntect::parsing::SyntaxSet;
+    use syntect::util::LinesWithEndings;
+
+    lazy_static! {
+        static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+        static ref THEME: Theme = ThemeSet::load_defaults()
+            .themes
+            .remove("InspiredGitHub")
+            .unwrap();
+    }
+
+    if text.len() > MAX_HIGHLIGHT {
+        return text
+            .lines()
+            .map(handlebars::html_escape)
+            .collect();
+    }
+    let extension = path.rsplit('/').next().unwrap_or("").rsplit('.').next();
+    let syntax = extension
+        .and_then(|e| SYNTAXES.find_syntax_by_extension(e))
+        .or_else(|| {
+            SYNTAXES.find_syntax_by_first_line(text.lines().next()?)
+        })
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, &THEME);
+    LinesWithEndings::from(text)
+        .map(|line| {
+            // The newline is only needed for parsing
+            let regions: Vec<_> = highlighter
+                .highlight(line, &SYNTAXES)
+                .into_iter()
+                .map(|(style, text)| {
+                    (style, text.trim_end_matches(|c| c == '\n' || c == '\r'))
+                })
+                .collect();
+            styled_line_to_highlighted_html(&regions, IncludeBackground::No)
+        })
+        .collect()
+}
+
+/// Blob view, showing a file of a branch in a snapshot
+///
+/// With `?raw=1`, the file is sent as it is, as plain text so that the
+/// browser doesn't run what it might contain.
+fn blob(
+    mirror: Arc<Mirror>,
+    date: String,
+    refname: String,
+    tail: path::Tail,
+    params: HashMap<String, String>,
+    templates: Arc<Handlebars>,
+) -> Result<Response, warp::reject::Rejection> {
+    let decode = |s: &str| {
+        percent_encoding::percent_decode(s.as_bytes())
+            .decode_utf8()
+            .map(|s| s.into_owned())
+            .map_err(|_| warp::reject::not_found())
+    };
+    let date = decode(&date)?;
+    let path = decode(tail.as_str())?;
+    let path = path.trim_matches('/');
+
+    let (current, sha) = branch_at(&mirror, &date, &refname)
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
+    let content = match crate::git::read_blob(&mirror.path, &sha, path) {
+        Ok(content) => content,
+        Err(Error::NotFound(_)) => return Err(warp::reject::not_found()),
+        Err(e) => return Err(warp::reject::custom(e)),
+    };
+    let text = if content.contains(&0) {
+        None
+    } else {
+        std::str::from_utf8(&content).ok()
+    };
+
+    if params.get("raw").map(String::as_str) == Some("1") {
+        let content_type = if text.is_some() {
+            "text/plain; charset=utf-8"
+        } else {
+            "application/octet-stream"
+        };
+        return http::response::Response::builder()
+            .header("Content-Type", content_type)
+            .header("X-Content-Type-Options", "nosniff")
+            .body(Body::from(content))
+            .map_err(warp::reject::custom);
+    }
+
+    let lines: Vec<_> = text
+        .map(|text| highlight(path, text))
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(i, html)| json!({"number": i + 1, "html": html}))
+        .collect();
+    let (parents, name) = file_parents(path);
+    templates
+        .render(
+            "blob.html",
+            &json!({
+                "base": mirror.base,
+                "date": date,
+                "current": current,
+                "refname": refname,
+                "sha": sha,
+                "path": path,
+                "url": encode_path(path),
+                "name": name,
+                "parents": parents,
+                "binary": text.is_none(),
+                "size": content.len(),
+                "lines": lines,
+            }),
+        )
+        .map_err(warp::reject::custom)
+        .map(|page| warp::reply::html(page).into_response())
+}
+
+/// The directories leading to a file, as links for the tree view, and the
+/// file's name
+fn file_parents(path: &str) -> (Vec<serde_json::Value>, &str) {
+    let mut parents = Vec::new();
+    let mut parent = String::new();
+    let parts: Vec<&str> = path.split('/').collect();
+    for part in &parts[..parts.len() - 1] {
+        if !parent.is_empty() {
+            parent.push('/');
+        }
+        parent.push_str(part);
+        parents.push(json!({"name": part, "url": encode_path(&parent)}));
+    }
+    (parents, parts[parts.len() - 1])
+}
+
+/// Blame view, showing which commit last changed each line of a file
+///
+/// This works the same for branches that were since deleted or
+/// force-pushed, since their commits are kept in the mirror.
+fn blame(
+    mirror: Arc<Mirror>,
+    date: String,
+    refname: String,
+    tail: path::Tail,
+    templates: Arc<Handlebars>,
+) -> Result<impl Reply, warp::reject::Rejection> {
+    let decode = |s: &str| {
+        percent_encoding::percent_decode(s.as_bytes())
+            .decode_utf8()
+            .map(|s| s.into_owned())
+            .map_err(|_| warp::reject::not_found())
     };
+    let date = decode(&date)?;
+    let path = decode(tail.as_str())?;
+    let path = path.trim_matches('/');
 
-    // Load commits
-    let commits = get_commits(&repository, &current_sha, 10)
+    let (current, sha) = branch_at(&mirror, &date, &refname)
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
+    let content = match crate::git::read_blob(&mirror.path, &sha, path) {
+        Ok(content) => content,
+        Err(Error::NotFound(_)) => return Err(warp::reject::not_found()),
+        Err(e) => return Err(warp::reject::custom(e)),
+    };
+    // Nothing to show line by line in a binary file
+    if content.contains(&0) {
+        return Err(warp::reject::not_found());
+    }
+    let text = String::from_utf8_lossy(&content);
+    let hunks = crate::git::blame(&mirror.path, &sha, path)
         .map_err(warp::reject::custom)?;
 
-    // Send response
+    let mut lines: Vec<_> = highlight(path, &text)
+        .into_iter()
+        .enumerate()
+        .map(|(i, html)| json!({"number": i + 1, "html": html}))
+        .collect();
+    // The commit is shown on the first line of each hunk, and the hunks are
+    // shaded alternately
+    for (i, hunk) in hunks.iter().enumerate() {
+        let start = hunk.start as usize - 1;
+        let end = (start + hunk.lines as usize).min(lines.len());
+        for line in lines.get_mut(start..end).unwrap_or(&mut []) {
+            line["alternate"] = json!(i % 2 == 1);
+        }
+        if let Some(line) = lines.get_mut(start) {
+            line["hunk"] = json!({
+                "sha": hunk.sha,
+                "short": &hunk.sha[..10],
+                "author": hunk.author,
+                "date": hunk.date,
+                "summary": hunk.summary,
+            });
+        }
+    }
+    let (parents, name) = file_parents(path);
     templates
         .render(
-            "browse.html",
+            "blame.html",
             &json!({
-                "snapshot": {
-                    "current": current, "prev": prev_date, "next": next_date,
-                    "req": date,
-                },
+                "base": mirror.base,
+                "date": date,
+                "current": current,
                 "refname": refname,
-                "branches": branches,
-                "commits": commits,
+                "sha": sha,
+                "path": path,
+                "url": encode_path(path),
+                "name": name,
+                "parents": parents,
+                "lines": lines,
             }),
         )
         .map_err(warp::reject::custom)
         .map(warp::reply::html)
 }
+
+/// Download of the files of a branch in a snapshot, as a gzipped tarball
+///
+/// The tarball is sent as `git archive` writes it. Its files are in a
+/// directory named after the branch and the commit.
+fn archive(
+    mirror: Arc<Mirror>,
+    date: String,
+    refname: String,
+) -> Result<Response, warp::reject::Rejection> {
+    let date = percent_encoding::percent_decode(date.as_bytes())
+        .decode_utf8()
+        .map_err(|_| warp::reject::not_found())?;
+
+    let (_, sha) = branch_at(&mirror, &date, &refname)
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
+    // Keep the name safe for the header and for the archive's paths
+    let name: String = refname
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+    let name = format!("{}-{}", name.trim_start_matches('.'), &sha[..10]);
+    let child = crate::git::archive_tar_gz(
+        &mirror.path,
+        &sha,
+        &format!("{}/", name),
+    )
+    .map_err(warp::reject::custom)?;
+    http::response::Response::builder()
+        .header("Content-Type", "application/gzip")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.tar.gz\"", name),
+        )
+        .body(stream_output(Vec::new(), child))
+        .map_err(warp::reject::custom)
+}
+
+/// Comparison view, listing the refs that changed between two snapshots
+///
+/// Other dates are rejected, so that `snapshot` gets them.
+fn compare(
+    mirror: Arc<Mirror>,
+    dates: String,
+    templates: Arc<Handlebars>,
+) -> Result<Response, warp::reject::Rejection> {
+    let dates = percent_encoding::percent_decode(dates.as_bytes())
+        .decode_utf8()
+        .map_err(|_| warp::reject::not_found())?;
+    let (from, to) = match dates.find("..") {
+        Some(i) => (&dates[..i], &dates[i + 2..]),
+        None => return Err(warp::reject::not_found()),
+    };
+    let (from_date, to_date) =
+        match (crate::parse_date(from), crate::parse_date(to)) {
+            (Ok(from_date), Ok(to_date)) => (from_date, to_date),
+            _ => {
+                return http::response::Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("Invalid date"))
+                    .map_err(warp::reject::custom);
+            }
+        };
+    let diffs = crate::archive::compare_snapshots(
+        &mirror.path,
+        from_date.as_ref().map(String::as_str),
+        to_date.as_ref().map(String::as_str),
+        true,
+    )
+    .map_err(warp::reject::custom)?;
+    let diffs: Vec<_> = diffs
+        .iter()
+        .map(|diff| {
+            let change = match (&diff.old, &diff.new) {
+                (None, _) => "created",
+                (_, None) => "deleted",
+                _ => "moved",
+            };
+            json!({
+                "name": diff.name.to_string(),
+                "tag": diff.tag,
+                "change": change,
+                "moved": change == "moved",
+                "old": diff.old,
+                "new": diff.new,
+                "stat": diff.stat,
+            })
+        })
+        .collect();
+    templates
+        .render(
+            "compare.html",
+            &json!({
+                "base": mirror.base,
+                "from": from,
+                "to": to,
+                "diffs": diffs,
+            }),
+        )
+        .map_err(warp::reject::custom)
+        .map(|page| warp::reply::html(page).into_response())
+}
+
+/// A branch in the timeline, with what happened to it at each snapshot
+///
+/// `None` means the branch didn't exist at that snapshot. Otherwise this is
+/// the name of the `Change` that made it point to its commit, `unchanged`,
+/// or `deleted` if it was removed at that snapshot.
+type Lane = (String, Vec<Option<&'static str>>);
+
+/// Lay out the history of the branches on the snapshot dates
+fn timeline_lanes(log: &[LogEntry]) -> (Vec<&str>, Vec<Lane>) {
+    let mut dates = BTreeSet::new();
+    for entry in log.iter().filter(|e| !e.tag) {
+        dates.insert(entry.from_date.as_str());
+        if let Some(ref to_date) = entry.to_date {
+            dates.insert(to_date.as_str());
+        }
+    }
+    let dates: Vec<&str> = dates.into_iter().collect();
+    let index = |date: &str| dates.binary_search(&date).unwrap();
+
+    // The log is ordered by name then date
+    let mut lanes: Vec<Lane> = Vec::new();
+    for entry in log.iter().filter(|e| !e.tag) {
+        let name = entry.name.to_string();
+        if lanes.last().map_or(true, |l| l.0 != name) {
+            lanes.push((name, vec![None; dates.len()]));
+        }
+        let cells = &mut lanes.last_mut().unwrap().1;
+        let start = index(&entry.from_date);
+        let end = entry.to_date.as_ref().map_or(dates.len(), |d| index(d));
+        cells[start] = Some(entry.change.name());
+        for cell in &mut cells[start + 1..end] {
+            *cell = Some("unchanged");
+        }
+        if entry.deleted {
+            cells[end] = Some("deleted");
+        }
+    }
+    (dates, lanes)
+}
+
+/// Timeline view, showing when each branch was created, moved and deleted
+fn timeline(
+    mirror: Arc<Mirror>,
+    templates: Arc<Handlebars>,
+) -> Result<impl Reply, warp::reject::Rejection> {
+    let log = crate::archive::ref_log(&mirror.path, None)
+        .map_err(warp::reject::custom)?;
+    let (dates, lanes) = timeline_lanes(&log);
+    let lanes: Vec<_> = lanes
+        .iter()
+        .map(|(name, cells)| {
+            let cells: Vec<_> = cells
+                .iter()
+                .zip(&dates)
+                .map(|(cell, date)| match *cell {
+                    Some(event) => json!({
+                        "event": event,
+                        "symbol": match event {
+                            "created" => "+",
+                            "fast-forward" | "moved" => ">",
+                            "force-push" => "!",
+                            "deleted" => "\u{d7}",
+                            _ => "",
+                        },
+                        "date": date,
+                        "url": format!("{}/_/{}/{}", mirror.base, date, name),
+                        "exists": event != "deleted",
+                    }),
+                    None => json!({}),
+                })
+                .collect();
+            json!({"name": name, "cells": cells})
+        })
+        .collect();
+    templates
+        .render(
+            "timeline.html",
+            &json!({
+                "base": mirror.base,
+                "dates": dates,
+                "lanes": lanes,
+            }),
+        )
+        .map_err(warp::reject::custom)
+        .map(warp::reply::html)
+}
+
+/// Number of changes listed in the Atom feed
+const FEED_ENTRIES: usize = 50;
+
+/// A change to a ref, as listed in the Atom feed
+#[derive(Debug, PartialEq)]
+struct FeedEvent<'a> {
+    date: &'a str,
+    /// One of `created`, `force-pushed`, `moved`, `deleted`
+    change: &'static str,
+    tag: bool,
+    name: String,
+    old: Option<&'a str>,
+    new: Option<&'a str>,
+}
+
+/// Find the changes worth notifying about in the history of the refs,
+/// the most recent first
+///
+/// Fast-forwards are left out, this is about the history being rewritten.
+fn feed_events(log: &[LogEntry]) -> Vec<FeedEvent<'_>> {
+    let mut events = Vec::new();
+    let mut previous: Option<&LogEntry> = None;
+    for entry in log {
+        let name = entry.name.to_string();
+        let old = previous
+            .filter(|p| p.name == entry.name && p.tag == entry.tag)
+            .map(|p| p.sha.as_str());
+        let change = match entry.change {
+            Change::Created => Some("created"),
+            Change::ForcePush => Some("force-pushed"),
+            Change::Moved => Some("moved"),
+            Change::FastForward => None,
+        };
+        if let Some(change) = change {
+            events.push(FeedEvent {
+                date: &entry.from_date,
+                change,
+                tag: entry.tag,
+                name: name.clone(),
+                old: if change == "created" { None } else { old },
+                new: Some(&entry.sha),
+            });
+        }
+        if let (true, Some(to_date)) = (entry.deleted, &entry.to_date) {
+            events.push(FeedEvent {
+                date: to_date,
+                change: "deleted",
+                tag: entry.tag,
+                name,
+                old: Some(&entry.sha),
+                new: None,
+            });
+        }
+        previous = Some(entry);
+    }
+    events.sort_by(|a, b| (b.date, &a.name).cmp(&(a.date, &b.name)));
+    events
+}
+
+/// Atom feed, listing the latest branches and tags created, force-pushed,
+/// moved and deleted
+///
+/// Links are made absolute using the `Host` header.
+fn feed(
+    mirror: Arc<Mirror>,
+    host: Option<String>,
+    templates: Arc<Handlebars>,
+) -> Result<Response, warp::reject::Rejection> {
+    let log = crate::archive::ref_log(&mirror.path, None)
+        .map_err(warp::reject::custom)?;
+    let mut events = feed_events(&log);
+    events.truncate(FEED_ENTRIES);
+
+    let url = format!(
+        "http://{}{}",
+        host.as_ref().map_or("localhost", String::as_str),
+        mirror.base,
+    );
+    let atom_date = |date: &str| format!("{}Z", date.replacen(' ', "T", 1));
+    let entries: Vec<_> = events
+        .iter()
+        .map(|event| {
+            let kind = if event.tag { "Tag" } else { "Branch" };
+            let date =
+                utf8_percent_encode(event.date, PATH_SEGMENT_ENCODE_SET);
+            // Tags and deleted branches can't be browsed, link to the
+            // snapshot instead
+            let path =
+                format!("{}/_/{}/{}", url, date, encode_path(&event.name));
+            let link = if event.tag || event.new.is_none() {
+                format!("{}/_/{}", url, date)
+            } else {
+                path.clone()
+            };
+            let content = match (event.old, event.new) {
+                (Some(old), Some(new)) => format!("{} -> {}", old, new),
+                (Some(old), None) => format!("was {}", old),
+                (None, Some(new)) => format!("at {}", new),
+                (None, None) => String::new(),
+            };
+            json!({
+                "title": format!("{} {} {}", kind, event.name, event.change),
+                "id": format!("{}#{}-{}", path, kind, event.change),
+                "link": link,
+                "updated": atom_date(event.date),
+                "content": content,
+            })
+        })
+        .collect();
+    let name = mirror
+        .path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let page = templates
+        .render(
+            "feed.atom",
+            &json!({
+                "name": name,
+                "url": url,
+                "updated": events
+                    .first()
+                    .map_or("1970-01-01T00:00:00Z".to_owned(), |e| {
+                        atom_date(e.date)
+                    }),
+                "entries": entries,
+            }),
+        )
+        .map_err(warp::reject::custom)?;
+    http::response::Response::builder()
+        .header("Content-Type", "application/atom+xml; charset=utf-8")
+        .body(Body::from(page))
+        .map_err(warp::reject::custom)
+}
+
+/// Find the refs whose name contains the query, ignoring case
+///
+/// Returns the name, whether it is a tag, the last snapshot it was seen in,
+/// and whether it still exists.
+fn search_refs(
+    db: &Connection,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<(String, bool, String, bool)>, rusqlite::Error> {
+    let mut stmt = db.prepare(
+        "
+        SELECT name, tag, max(from_date), max(to_date IS NULL)
+        FROM refs
+        WHERE instr(lower(name), lower(?1)) > 0
+        GROUP BY tag, name
+        ORDER BY tag, name
+        LIMIT ?2;
+        ",
+    )?;
+    let rows = stmt.query_map(
+        &[&query as &dyn rusqlite::types::ToSql, &(limit as i64)],
+        |row| (
+            row.get::<_, RefName>(0).to_string(),
+            row.get::<_, bool>(1),
+            row.get::<_, String>(2),
+            row.get::<_, bool>(3),
+        ),
+    )?;
+    let mut refs = Vec::new();
+    for row in rows {
+        refs.push(row?);
+    }
+    Ok(refs)
+}
+
+/// Search view, over the names of the refs, the commit messages, and SHA-1s
+///
+/// Each result links to a snapshot in which it can be seen. Commits found by
+/// their message link to a search for their SHA-1, which lists the branches
+/// they were on.
+fn search(
+    mirror: Arc<Mirror>,
+    params: HashMap<String, String>,
+    templates: Arc<Handlebars>,
+) -> Result<impl Reply, warp::reject::Rejection> {
+    let query = params.get("q").map(String::as_str).unwrap_or("").trim();
+    let mut context = json!({"base": mirror.base, "query": query});
+    if query.is_empty() {
+        return templates
+            .render("search.html", &context)
+            .map_err(warp::reject::custom)
+            .map(warp::reply::html);
+    }
+
+    let (refs, results) = {
+        let db = mirror.db.lock().unwrap();
+        let refs = crate::db::retry(|| search_refs(&db, query, 50))
+            .map_err(warp::reject::custom)?;
+        (refs, crate::archive::search_commits(&db, query, 50))
+    };
+    let base = &mirror.base;
+    context["refs"] = refs
+        .iter()
+        .map(|(name, tag, date, exists)| {
+            let date = if *exists { "latest" } else { date };
+            json!({
+                "name": name,
+                "tag": tag,
+                "deleted": !exists,
+                "url": if *tag {
+                    format!("{}/_/{}", base, date)
+                } else {
+                    format!("{}/_/{}/{}", base, date, name)
+                },
+            })
+        })
+        .collect();
+    match results {
+        Ok(results) => {
+            context["results"] = results
+                .into_iter()
+                .map(|(sha, message)| {
+                    json!({
+                        "url": format!("{}/_/search?q={}", base, sha),
+                        "sha": sha,
+                        "summary": message.lines().next().unwrap_or(""),
+                    })
+                })
+                .collect();
+        }
+        Err(e) => {
+            // Most likely invalid query syntax
+            warn!("Search for {:?} failed: {}", query, e);
+            context["error"] = json!(e.to_string());
+        }
+    }
+
+    if query.len() >= 4 && query.len() <= 40
+        && query.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        match crate::archive::when(&mirror.path, query) {
+            Ok((sha, periods)) => {
+                let periods: Vec<_> = periods
+                    .iter()
+                    .map(|period| {
+                        json!({
+                            "name": period.name.to_string(),
+                            "from_date": period.from_date,
+                            "to_date": period.to_date,
+                            "url": format!(
+                                "{}/_/{}/{}/commit/{}",
+                                base, period.from_date, period.name, sha,
+                            ),
+                        })
+                    })
+                    .collect();
+                context["commit"] = json!({
+                    "sha": sha,
+                    "periods": periods,
+                });
+            }
+            Err(Error::NotFound(_)) => {}
+            Err(e) => return Err(warp::reject::custom(e)),
+        }
+    }
+
+    templates
+        .render("search.html", &context)
+        .map_err(warp::reject::custom)
+        .map(warp::reply::html)
+}
+
+/// Send what a command writes as the body of a response, as it comes
+///
+/// `prefix` is sent first. The command's output is read from a separate
+/// thread; the command is killed if the client goes away, and the response
+/// is cut short if it fails, so that the client doesn't take it as complete.
+fn stream_output(prefix: Vec<u8>, mut child: process::Child) -> Body {
+    let (mut sender, body) = Body::channel();
+    let mut stdout = child.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        let mut chunk = prefix;
+        let mut buffer = [0; 65536];
+        loop {
+            if !chunk.is_empty() {
+                let ready = hyper::rt::poll_fn(|| sender.poll_ready()).wait();
+                if ready.is_err() {
+                    debug!("Client went away, killing the command");
+                    child.kill().ok();
+                    child.wait().ok();
+                    return;
+                }
+                sender.send_data(chunk.into()).ok();
+            }
+            chunk = match stdout.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => buffer[..n].to_vec(),
+                Err(e) => {
+                    warn!("Error reading from command: {}", e);
+                    child.kill().ok();
+                    break;
+                }
+            };
+        }
+        match child.wait() {
+            Ok(ref status) if status.success() => {}
+            Ok(status) => {
+                warn!("Command returned {}", status);
+                sender.abort();
+            }
+            Err(e) => {
+                warn!("Error waiting for command: {}", e);
+                sender.abort();
+            }
+        }
+    });
+    body
+}
+
+/// Frame a line of the Git protocol, with its length in hexadecimal
+fn pkt_line(data: &str) -> String {
+    format!("{:04x}{}", data.len() + 4, data)
+}
+
+/// Requests to `git-upload-pack` larger than this are refused, before and
+/// after decompression
+///
+/// They list the refs the client wants and the commits it has, which even
+/// for a mirror with many keep-refs is well under that.
+const MAX_UPLOAD_PACK_REQUEST: u64 = 16 << 20;
+
+/// Ref advertisement of the smart HTTP protocol, the first request of
+/// `git clone` and `git fetch`
+///
+/// Only fetching is served; pushes and the "dumb" protocol are refused.
+fn info_refs(
+    mirror: Arc<Mirror>,
+    params: HashMap<String, String>,
+    protocol: Option<String>,
+) -> Result<Response, warp::reject::Rejection> {
+    if params.get("service").map(String::as_str) != Some("git-upload-pack") {
+        return http::response::Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from("This archive is read-only"))
+            .map_err(warp::reject::custom);
+    }
+    let protocol = protocol.as_ref().map(String::as_str);
+    let child =
+        crate::git::upload_pack(&mirror.path, true, Vec::new(), protocol)
+            .map_err(warp::reject::custom)?;
+    // Version 2 starts with its capabilities instead, like git-http-backend
+    let v2 = protocol.map_or(false, |p| p.split(':').any(|v| v == "version=2"));
+    let prefix = if v2 {
+        String::new()
+    } else {
+        format!("{}0000", pkt_line("# service=git-upload-pack\n"))
+    };
+    http::response::Response::builder()
+        .header(
+            "Content-Type",
+            "application/x-git-upload-pack-advertisement",
+        )
+        .header("Cache-Control", "no-cache")
+        .body(stream_output(prefix.into_bytes(), child))
+        .map_err(warp::reject::custom)
+}
+
+/// Answer a fetch over the smart HTTP protocol, sending the pack
+///
+/// Any object in the mirror can be fetched, including the keep-refs of
+/// deleted and force-pushed branches.
+fn upload_pack(
+    mirror: Arc<Mirror>,
+    encoding: Option<String>,
+    protocol: Option<String>,
+    body: warp::body::FullBody,
+) -> Result<Response, warp::reject::Rejection> {
+    use warp::Buf;
+
+    // Git compresses large requests
+    let request = match encoding.as_ref().map(String::as_str) {
+        None => body.bytes().to_vec(),
+        Some("gzip") | Some("x-gzip") => {
+            let mut request = Vec::new();
+            let decoded = flate2::read::GzDecoder::new(body.bytes())
+                .take(MAX_UPLOAD_PACK_REQUEST + 1)
+                .read_to_end(&mut request);
+            if decoded.is_err() {
+                return http::response::Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("Invalid gzip data"))
+                    .map_err(warp::reject::custom);
+            }
+            if request.len() as u64 > MAX_UPLOAD_PACK_REQUEST {
+                return http::response::Response::builder()
+                    .status(StatusCode::PAYLOAD_TOO_LARGE)
+                    .body(Body::from("Request too large"))
+                    .map_err(warp::reject::custom);
+            }
+            request
+        }
+        Some(_) => {
+            return http::response::Response::builder()
+                .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                .body(Body::from("Unsupported Content-Encoding"))
+                .map_err(warp::reject::custom);
+        }
+    };
+    let protocol = protocol.as_ref().map(String::as_str);
+    let child = crate::git::upload_pack(&mirror.path, false, request, protocol)
+        .map_err(warp::reject::custom)?;
+    http::response::Response::builder()
+        .header("Content-Type", "application/x-git-upload-pack-result")
+        .header("Cache-Control", "no-cache")
+        .body(stream_output(Vec::new(), child))
+        .map_err(warp::reject::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{
+        Auth, FeedEvent, cached_commits, diff_lines, feed_events,
+        get_branches, get_snapshot, highlight, main_branch, pkt_line,
+        search_refs, timeline_lanes,
+    };
+    use crate::archive::{Change, LogEntry};
+
+    #[test]
+    fn test_snapshot_queries() {
+        let mut db = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut db).unwrap();
+        db.execute_batch(
+            "
+            INSERT INTO refs(name, from_date, to_date, sha, tag) VALUES
+                ('master', '2019-01-01 00:00:00', '2019-01-03 00:00:00',
+                 'aaa', 0),
+                ('master', '2019-01-03 00:00:00', NULL, 'bbb', 0),
+                ('feature', '2019-01-02 00:00:00', '2019-01-04 00:00:00',
+                 'ccc', 0),
+                ('v1', '2019-01-02 00:00:00', NULL, 'ddd', 1);
+            ",
+        ).unwrap();
+
+        let s = |d: u32| Some(format!("2019-01-0{} 00:00:00", d));
+        assert_eq!(
+            get_snapshot("latest", &mut db).unwrap(),
+            (s(4), s(3), None),
+        );
+        assert_eq!(
+            get_snapshot("2019-01-02 12:00:00", &mut db).unwrap(),
+            (s(2), s(1), s(3)),
+        );
+        assert_eq!(
+            get_snapshot("2018-12-31 00:00:00", &mut db).unwrap(),
+            (None, None, s(1)),
+        );
+        assert_eq!(main_branch(&db, None).unwrap(), Some("master".into()));
+        assert_eq!(
+            main_branch(&db, Some("2018-12-31 00:00:00")).unwrap(),
+            None,
+        );
+
+        let b = |list: &[(&str, &str)]| list.iter()
+            .map(|(n, s)| (n.to_string(), s.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            get_branches("2019-01-02 00:00:00", &mut db).unwrap(),
+            b(&[("feature", "ccc"), ("master", "aaa")]),
+        );
+        assert_eq!(
+            get_branches("2019-01-03 00:00:00", &mut db).unwrap(),
+            b(&[("feature", "ccc"), ("master", "bbb")]),
+        );
+        assert_eq!(
+            get_branches("2019-01-04 00:00:00", &mut db).unwrap(),
+            b(&[("master", "bbb")]),
+        );
+
+        db.execute_batch("DELETE FROM refs WHERE name = 'master';").unwrap();
+        assert_eq!(
+            main_branch(&db, Some("2019-01-03 00:00:00")).unwrap(),
+            Some("feature".into()),
+        );
+        assert_eq!(main_branch(&db, None).unwrap(), None);
+    }
+    #[test]
+    fn test_cached_commits() {
+        let mut db = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut db).unwrap();
+        db.execute_batch(
+            "
+            INSERT INTO commits(sha, parent, author, committer, date,
+                subject) VALUES
+                ('ccc', 'bbb', 'a', 'a', '2019-01-03 00:00:00', 'three'),
+                ('bbb', 'aaa', 'a', 'a', '2019-01-02 00:00:00', 'two'),
+                ('aaa', NULL, 'a', 'a', '2019-01-01 00:00:00', 'one');
+            ",
+        ).unwrap();
+
+        let messages = |target, skip, number| {
+            cached_commits(&db, target, skip, number).unwrap().map(|c| {
+                c.into_iter().map(|c| c.message).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(messages("ccc", 0, 2).unwrap(), vec!["three", "two"]);
+        assert_eq!(messages("ccc", 1, 5).unwrap(), vec!["two", "one"]);
+        assert_eq!(messages("ccc", 3, 5), Some(vec![]));
+        assert_eq!(messages("ddd", 0, 5), None);
+    }
+    #[test]
+    fn test_search_refs() {
+        let mut db = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut db).unwrap();
+        db.execute_batch(
+            "
+            INSERT INTO refs(name, from_date, to_date, sha, tag) VALUES
+                ('master', '2019-01-01 00:00:00', '2019-01-03 00:00:00',
+                 'aaa', 0),
+                ('master', '2019-01-03 00:00:00', NULL, 'bbb', 0),
+                ('feature', '2019-01-02 00:00:00', '2019-01-04 00:00:00',
+                 'ccc', 0),
+                ('Feature-2', '2019-01-02 00:00:00', NULL, 'ccc', 0),
+                ('feat', '2019-01-02 00:00:00', NULL, 'ddd', 1);
+            ",
+        ).unwrap();
+
+        let d = |d: u32| format!("2019-01-0{} 00:00:00", d);
+        assert_eq!(
+            search_refs(&db, "FEAT", 10).unwrap(),
+            vec![
+                ("Feature-2".to_owned(), false, d(2), true),
+                ("feature".to_owned(), false, d(2), false),
+                ("feat".to_owned(), true, d(2), true),
+            ],
+        );
+        assert_eq!(
+            search_refs(&db, "ast", 10).unwrap(),
+            vec![("master".to_owned(), false, d(3), true)],
+        );
+        assert_eq!(search_refs(&db, "feat", 1).unwrap().len(), 1);
+    }
+    #[test]
+    fn test_feed_events() {
+        let entry = |name: &str, sha: &str, from, to: Option<u32>, change| {
+            LogEntry {
+                name: name.to_owned().into(),
+                tag: name.starts_with('v'),
+                sha: sha.into(),
+                from_date: format!("2019-01-0{} 00:00:00", from),
+                to_date: to.map(|d| format!("2019-01-0{} 00:00:00", d)),
+                change,
+                deleted: name == "feature",
+            }
+        };
+        let log = vec![
+            entry("feature", "ccc", 2, Some(4), Change::Created),
+            entry("master", "aaa", 1, Some(2), Change::Created),
+            entry("master", "bbb", 2, Some(3), Change::FastForward),
+            entry("master", "ddd", 3, None, Change::ForcePush),
+            entry("v1", "eee", 1, Some(3), Change::Created),
+            entry("v1", "fff", 3, None, Change::Moved),
+        ];
+        let event = |date, change, name: &str, old, new| FeedEvent {
+            date,
+            change,
+            tag: name.starts_with('v'),
+            name: name.to_owned(),
+            old,
+            new,
+        };
+        let d = |d| match d {
+            1 => "2019-01-01 00:00:00",
+            2 => "2019-01-02 00:00:00",
+            3 => "2019-01-03 00:00:00",
+            _ => "2019-01-04 00:00:00",
+        };
+        assert_eq!(
+            feed_events(&log),
+            vec![
+                event(d(4), "deleted", "feature", Some("ccc"), None),
+                event(d(3), "force-pushed", "master", Some("bbb"),
+                      Some("ddd")),
+                event(d(3), "moved", "v1", Some("eee"), Some("fff")),
+                event(d(2), "created", "feature", None, Some("ccc")),
+                event(d(1), "created", "master", None, Some("aaa")),
+                event(d(1), "created", "v1", None, Some("eee")),
+            ],
+        );
+    }
+    #[test]
+    fn test_auth() {
+        assert!(Auth::default().allows(None));
+
+        let mut config = crate::config::Config {
+            web_user: Some("alice".into()),
+            ..Default::default()
+        };
+        assert!(Auth::from_config(&config).is_err());
+        config.web_password = Some("s3cret".into());
+        let auth = Auth::from_config(&config).unwrap();
+        assert!(!auth.allows(None));
+        // "alice:s3cret"
+        assert!(auth.allows(Some("Basic YWxpY2U6czNjcmV0")));
+        assert!(auth.allows(Some("basic  YWxpY2U6czNjcmV0 ")));
+        // "alice:wrong"
+        assert!(!auth.allows(Some("Basic YWxpY2U6d3Jvbmc=")));
+        assert!(!auth.allows(Some("Basic !!!")));
+        assert!(!auth.allows(Some("Bearer s3cret")));
+
+        config.web_token = Some("t0ken".into());
+        let auth = Auth::from_config(&config).unwrap();
+        assert!(auth.allows(Some("Bearer t0ken")));
+        assert!(auth.allows(Some("Basic YWxpY2U6czNjcmV0")));
+        assert!(!auth.allows(Some("Bearer t0ke")));
+        assert!(!auth.allows(Some("t0ken")));
+
+        config.web_user = Some("a:b".into());
+        assert!(Auth::from_config(&config).is_err());
+    }
+    #[test]
+    fn test_diff_lines() {
+        let diff = "\
+diff --git a/README b/README
+index cabc5df..efb71e6 100644
+--- a/README
++++ b/README
+@@ -1,3 +1,3 @@
+ unchanged
+--- removed
++new
+diff --git a/new b/new
+new file mode 100644
+";
+        let classes: Vec<_> =
+            diff_lines(diff).into_iter().map(|(c, _)| c).collect();
+        assert_eq!(
+            classes,
+            vec![
+                "file", "file", "file", "file", "hunk", "context", "removed",
+                "added", "file", "file",
+            ],
+        );
+        assert_eq!(diff_lines(diff)[6].1, "--- removed");
+    }
+    #[test]
+    fn test_highlight() {
+        let lines = highlight("src/main.rs", "fn main() {\r\n    \"<x>\"\n}");
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("<span"));
+        assert!(lines[1].contains("&lt;x&gt;"));
+        assert!(lines.iter().all(|l| !l.contains('\n') && !l.contains('\r')));
+        assert_eq!(highlight("README", ""), Vec::<String>::new());
+    }
+    #[test]
+    fn test_pkt_line() {
+        assert_eq!(
+            pkt_line("# service=git-upload-pack\n"),
+            "001e# service=git-upload-pack\n",
+        );
+        assert_eq!(pkt_line(""), "0004");
+    }
+    #[test]
+    fn test_timeline_lanes() {
+        let entry = |name: &str, from: u32, to: Option<u32>, change| {
+            LogEntry {
+                name: name.to_owned().into(),
+                tag: name.starts_with('v'),
+                sha: "aaa".into(),
+                from_date: format!("2019-01-0{} 00:00:00", from),
+                to_date: to.map(|d| format!("2019-01-0{} 00:00:00", d)),
+                change,
+                deleted: to.is_some() && name != "master",
+            }
+        };
+        let log = vec![
+            entry("feature", 2, Some(4), Change::Created),
+            entry("master", 1, Some(3), Change::Created),
+            entry("master", 3, None, Change::ForcePush),
+            entry("v1", 5, None, Change::Created),
+        ];
+        let (dates, lanes) = timeline_lanes(&log);
+        assert_eq!(dates.len(), 4);
+        assert_eq!(dates[0], "2019-01-01 00:00:00");
+        assert_eq!(
+            lanes,
+            vec![
+                (
+                    "feature".to_owned(),
+                    vec![None, Some("created"), Some("unchanged"),
+                         Some("deleted")],
+                ),
+                (
+                    "master".to_owned(),
+                    vec![Some("created"), Some("unchanged"),
+                         Some("force-push"), Some("unchanged")],
+                ),
+            ],
+        );
+    }
+}